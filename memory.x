@@ -2,7 +2,7 @@ MEMORY
 {
   FLASH (rx)      : ORIGIN = 0x8000000, LENGTH = 1024K
   /* reserved for config data */
-  CONFIG (rx)     : ORIGIN = 0x8100000, LENGTH = 16K
+  CONFIG (rx)     : ORIGIN = 0x8100000, LENGTH = 64K
   RAM (xrw)       : ORIGIN = 0x20000000, LENGTH = 112K - 4
   /* reserved for DFU trigger message */
   DFU_MSG (wrx)   : ORIGIN = 0x2001BFFC, LENGTH = 4
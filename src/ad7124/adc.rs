@@ -0,0 +1,408 @@
+use super::{
+    data_rate, fs,
+    regs::{self, Register, RegisterData},
+    ExcitationCurrent, FilterType, Input, Mode, Pga, ID,
+};
+use crate::{
+    ad7172::{ChannelCalibration, DataRate, DigitalFilterOrder, PostFilter},
+    timer,
+};
+use core::fmt;
+use log::{info, warn};
+use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+/// Attempts to read the ID after a reset in `reinit()`
+const ID_RETRIES: usize = 10;
+/// Attempts of a register write before giving up on readback errors
+const WRITE_RETRIES: usize = 3;
+/// Channels that `calibrate()` disables during the calibration
+const CALIBRATE_CHANNELS: u8 = 4;
+/// Milliseconds to wait for a calibration, which takes up to 4
+/// conversions at the lowest data rate
+const CALIBRATE_TIMEOUT: u32 = 5000;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    /// Register content differs from the one written on every attempt
+    Readback,
+    /// Not an AD7124-8 by its ID register
+    Id,
+    /// Calibration not done within `CALIBRATE_TIMEOUT`
+    Timeout,
+}
+
+/// AD7124-8 implementation, without the optional SPI CRC
+///
+/// [Manual](https://www.analog.com/media/en/technical-documentation/data-sheets/ad7124-8.pdf)
+pub struct Adc<SPI: Transfer<u8>, NSS: OutputPin> {
+    spi: SPI,
+    nss: NSS,
+}
+
+impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS> {
+    /// Set up the ADC if an AD7124-8 identifies itself, otherwise give
+    /// back the bus so that the driver of another ADC can try it
+    pub fn new(spi: SPI, mut nss: NSS) -> Result<Self, (SPI, NSS)> {
+        let _ = nss.set_high();
+        let mut adc = Adc { spi, nss };
+        match adc.reinit().and_then(|()| adc.identify()) {
+            Ok(adc_id) => {
+                info!("ADC id: {:02X}", adc_id);
+                Ok(adc)
+            }
+            Err(_) => Err((adc.spi, adc.nss)),
+        }
+    }
+
+    /// Reset the ADC and set up the interface. Other registers are
+    /// back at their defaults.
+    pub fn reinit(&mut self) -> Result<(), Error<E>> {
+        self.reset()?;
+
+        let mut adc_id = 0;
+        for _ in 0..ID_RETRIES {
+            adc_id = self.identify()?;
+            if adc_id & 0xF0 == ID {
+                break;
+            }
+        }
+        if adc_id & 0xF0 != ID {
+            warn!("ADC id: {:02X}", adc_id);
+            return Err(Error::Id);
+        }
+
+        self.set_mode(Mode::Standby)
+    }
+
+    /// `0x1X` for AD7124-8
+    pub fn identify(&mut self) -> Result<u8, Error<E>> {
+        self.read_reg(&regs::Id).map(|id| id.id())
+    }
+
+    /// Whether the ADC identifies itself as an AD7124-8
+    pub fn check_id(&mut self) -> bool {
+        matches!(self.identify(), Ok(id) if id & 0xF0 == ID)
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> Result<(), Error<E>> {
+        let mut adc_control = <regs::AdcControl as Register>::Data::empty();
+        adc_control.set_ref_en(true);
+        adc_control.set_power_mode(0b11);
+        adc_control.set_mode(mode);
+        self.write_reg(&regs::AdcControl, &mut adc_control)
+    }
+
+    /// Sample `in_pos` against `in_neg` on channel `index`, with the
+    /// setup of the same index at unity gain
+    pub fn setup_channel(
+        &mut self,
+        index: u8,
+        in_pos: Input,
+        in_neg: Input,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Config { index }, |data| {
+            data.set_bipolar(false);
+            data.set_refbuf_pos(true);
+            data.set_refbuf_neg(true);
+            data.set_ainbuf_pos(true);
+            data.set_ainbuf_neg(true);
+            data.set_ref_sel(0b00);
+            data.set_pga(Pga::X1);
+        })?;
+        self.update_reg(&regs::Filter { index }, |data| {
+            data.set_filter(FilterType::PostFilter);
+            data.set_post_filter(PostFilter::F16SPS);
+        })?;
+        self.update_reg(&regs::Channel { index }, |data| {
+            data.set_setup(index);
+            data.set_enabled(true);
+            data.set_a_in_pos(in_pos);
+            data.set_a_in_neg(in_neg);
+        })?;
+        Ok(())
+    }
+
+    /// Set the gain of the PGA for channel `index`. The conversion of
+    /// `ChannelCalibration` does not divide by it, so readings are of
+    /// the amplified input.
+    #[allow(unused)]
+    pub fn set_pga(&mut self, index: u8, pga: Pga) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Config { index }, |data| data.set_pga(pga))
+    }
+
+    /// Source `current` out of IOUT0 on the AIN pin `pin`, for a
+    /// resistive sensor or an external frontend
+    #[allow(unused)]
+    pub fn set_excitation0(&mut self, pin: u8, current: ExcitationCurrent) -> Result<(), Error<E>> {
+        self.update_reg(&regs::IoControl1, |data| {
+            data.set_iout0_ch(pin);
+            data.set_iout0(current);
+        })
+    }
+
+    /// Source `current` out of IOUT1 on the AIN pin `pin`
+    #[allow(unused)]
+    pub fn set_excitation1(&mut self, pin: u8, current: ExcitationCurrent) -> Result<(), Error<E>> {
+        self.update_reg(&regs::IoControl1, |data| {
+            data.set_iout1_ch(pin);
+            data.set_iout1(current);
+        })
+    }
+
+    pub fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Error<E>> {
+        let offset = self.read_reg(&regs::Offset { index })?.offset();
+        let gain = self.read_reg(&regs::Gain { index })?.gain();
+        let bipolar = self.read_reg(&regs::Config { index })?.bipolar();
+        Ok(ChannelCalibration::new(offset, gain, bipolar))
+    }
+
+    pub fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Offset { index }, |data| {
+            data.set_offset(calibration.offset());
+        })?;
+        self.update_reg(&regs::Gain { index }, |data| {
+            data.set_gain(calibration.gain());
+        })
+    }
+
+    /// Run a calibration on channel `index` with the zero-scale or
+    /// full-scale input applied. Blocks until the ADC is idle again,
+    /// or for at most `CALIBRATE_TIMEOUT`, then resumes continuous
+    /// conversion.
+    pub fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Error<E>> {
+        // Only the channel to calibrate may be enabled
+        let mut enabled = [false; CALIBRATE_CHANNELS as usize];
+        for (other, enabled) in enabled.iter_mut().enumerate() {
+            let other = other as u8;
+            *enabled = self.update_reg(&regs::Channel { index: other }, |data| {
+                let enabled = data.enabled();
+                data.set_enabled(other == index);
+                enabled
+            })?;
+        }
+
+        self.set_mode(mode)?;
+        let start = timer::now();
+        let mut result = Ok(());
+        while self.read_reg(&regs::AdcControl)?.mode() != Mode::Idle {
+            if timer::now().wrapping_sub(start) > CALIBRATE_TIMEOUT {
+                result = Err(Error::Timeout);
+                break;
+            }
+        }
+
+        for (other, enabled) in enabled.iter().enumerate() {
+            self.update_reg(&regs::Channel { index: other as u8 }, |data| {
+                data.set_enabled(*enabled);
+            })?;
+        }
+        self.start_continuous_conversion()?;
+        result
+    }
+
+    pub fn start_continuous_conversion(&mut self) -> Result<(), Error<E>> {
+        self.set_mode(Mode::ContinuousConversion)
+    }
+
+    pub fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Error<E>> {
+        self.read_reg(&regs::Filter { index }).map(|data| {
+            if data.filter() == FilterType::PostFilter {
+                Some(data.post_filter())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Select a postfilter, or the sinc4 filter without
+    pub fn set_postfilter(
+        &mut self,
+        index: u8,
+        filter: Option<PostFilter>,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Filter { index }, |data| match filter {
+            None => data.set_filter(FilterType::Sinc4),
+            Some(filter) => {
+                data.set_filter(FilterType::PostFilter);
+                data.set_post_filter(filter);
+            }
+        })
+    }
+
+    pub fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Error<E>> {
+        self.read_reg(&regs::Filter { index })
+            .map(|data| data.filter().order())
+    }
+
+    /// Select the sinc3 filter for `Sinc3`, or the sinc4 filter
+    /// otherwise. Sinc3 disables the postfilter.
+    pub fn set_filter_order(
+        &mut self,
+        index: u8,
+        order: DigitalFilterOrder,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Filter { index }, |data| match order {
+            DigitalFilterOrder::Sinc3 => data.set_filter(FilterType::Sinc3),
+            _ if data.filter() == FilterType::PostFilter => {}
+            _ => data.set_filter(FilterType::Sinc4),
+        })
+    }
+
+    /// The setting of the AD7172-2 with the closest output data rate
+    pub fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Error<E>> {
+        self.read_reg(&regs::Filter { index })
+            .map(|data| data_rate(data.fs(), data.filter().order()))
+    }
+
+    /// Set the output data rate, disabling the postfilter which would
+    /// override it
+    pub fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Filter { index }, |data| {
+            if data.filter() == FilterType::PostFilter {
+                data.set_filter(FilterType::Sinc4);
+            }
+            data.set_fs(fs(rate, data.filter().order()));
+        })
+    }
+
+    /// Returns the channel the data is from
+    pub fn data_ready(&mut self) -> Result<Option<u8>, Error<E>> {
+        self.read_reg(&regs::Status).map(|status| {
+            if status.ready() {
+                Some(status.channel())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get data
+    pub fn read_data(&mut self) -> Result<u32, Error<E>> {
+        self.read_reg(&regs::Data).map(|data| data.data())
+    }
+
+    fn read_reg<R: regs::Register>(&mut self, reg: &R) -> Result<R::Data, Error<E>> {
+        let mut reg_data = R::Data::empty();
+        self.transfer(0x40 | reg.address(), reg_data.as_mut())?;
+        Ok(reg_data)
+    }
+
+    fn write_reg<R: regs::Register>(
+        &mut self,
+        reg: &R,
+        reg_data: &mut R::Data,
+    ) -> Result<(), Error<E>> {
+        let address = reg.address();
+        for _ in 0..WRITE_RETRIES {
+            let mut data = reg_data.clone();
+            self.transfer(address, data.as_mut())?;
+
+            // Verification
+            let readback_data = self.read_reg(reg)?;
+            if *readback_data == **reg_data {
+                return Ok(());
+            }
+            warn!(
+                "write_reg {:02X}: readback error, {:?}!={:?}, retrying",
+                address, &*readback_data, &**reg_data
+            );
+        }
+        Err(Error::Readback)
+    }
+
+    fn update_reg<R, F, A>(&mut self, reg: &R, f: F) -> Result<A, Error<E>>
+    where
+        R: regs::Register,
+        F: FnOnce(&mut R::Data) -> A,
+    {
+        let mut reg_data = self.read_reg(reg)?;
+        let result = f(&mut reg_data);
+        self.write_reg(reg, &mut reg_data)?;
+        Ok(result)
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error<E>> {
+        let mut buf = [0xFFu8; 8];
+        let _ = self.nss.set_low();
+        let result = self.spi.transfer(&mut buf);
+        let _ = self.nss.set_high();
+        result.map_err(Error::Spi)?;
+        Ok(())
+    }
+
+    fn transfer(&mut self, addr: u8, reg_data: &mut [u8]) -> Result<(), Error<E>> {
+        let mut addr_buf = [addr];
+
+        let _ = self.nss.set_low();
+        let result = match self.spi.transfer(&mut addr_buf) {
+            Ok(_) => self.spi.transfer(reg_data),
+            Err(e) => Err(e),
+        };
+        let _ = self.nss.set_high();
+
+        result.map(|_| ()).map_err(Error::Spi)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockAdc;
+
+    #[test]
+    fn test_new() {
+        let (mock, spi, nss) = MockAdc::ad7124().bus();
+        assert!(Adc::new(spi, nss).is_ok());
+        let mut adc_control = <regs::AdcControl as Register>::Data::empty();
+        adc_control.copy_from_slice(&mock.borrow().registers[0x01][..2]);
+        assert_eq!(adc_control.mode(), Mode::Standby);
+        assert_eq!(adc_control.power_mode(), 0b11);
+        assert!(adc_control.ref_en());
+
+        // Gives back the bus for another driver to try
+        let (mock, spi, nss) = MockAdc::ad7172().bus();
+        assert!(Adc::new(spi, nss).is_err());
+        // Without having written anything
+        assert_eq!(mock.borrow().registers[0x01], [0; 3]);
+    }
+
+    #[test]
+    fn test_channel() {
+        let (mock, spi, nss) = MockAdc::ad7124().bus();
+        let mut adc = Adc::new(spi, nss).unwrap_or_else(|_| panic!("no AD7124"));
+        adc.setup_channel(1, Input::ain(2), Input::ain(3)).unwrap();
+        let registers = mock.borrow().registers;
+        let mut channel = <regs::Channel as Register>::Data::empty();
+        channel.copy_from_slice(&registers[0x0A][..2]);
+        assert!(channel.enabled());
+        assert_eq!(channel.setup(), 1);
+        assert_eq!(channel.a_in_pos(), Input::ain(2));
+        assert_eq!(registers[0x0A][..2], [0x90, 0x43]);
+        let mut config = <regs::Config as Register>::Data::empty();
+        config.copy_from_slice(&registers[0x1A][..2]);
+        assert!(!config.bipolar());
+        assert_eq!(config.pga(), Pga::X1);
+        assert!(config.refbuf_pos() && config.ainbuf_neg());
+        let mut filter = <regs::Filter as Register>::Data::empty();
+        filter.copy_from_slice(&registers[0x22]);
+        assert_eq!(filter.filter(), FilterType::PostFilter);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let (mock, spi, nss) = MockAdc::ad7124().bus();
+        let mut adc = Adc::new(spi, nss).unwrap_or_else(|_| panic!("no AD7124"));
+        let calibration = ChannelCalibration::new(0x80_1234, 0x55_5550, false);
+        adc.set_calibration(1, &calibration).unwrap();
+        assert_eq!(mock.borrow().registers[0x2A], [0x80, 0x12, 0x34]);
+        assert_eq!(mock.borrow().registers[0x32], [0x55, 0x55, 0x50]);
+        let calibration = adc.get_calibration(1).unwrap();
+        assert_eq!(calibration.offset(), 0x80_1234);
+        assert_eq!(calibration.gain(), 0x55_5550);
+    }
+}
@@ -0,0 +1,235 @@
+//! AD7124-8 driver, for variant boards and external sensor frontends
+//! that need its PGA or excitation currents.
+//!
+//! The settings shared with the AD7172-2, such as filters and
+//! calibrations, use the `ad7172` types so that configurations and
+//! commands work the same with either ADC.
+
+use crate::ad7172::{self, DataRate, DigitalFilterOrder};
+use num_traits::float::Float;
+
+mod adc;
+pub mod regs;
+pub use adc::*;
+
+/// Output data rate of the sinc3 and sinc4 filters in full power
+/// mode is this divided by the `fs` field of the filter register
+const FS_RATE: f32 = 614_400.0 / 32.0;
+/// Largest value of the `fs` field
+const FS_MAX: u16 = 2047;
+
+/// AD7124-8 in the upper nibble of the ID register, with the silicon
+/// revision in the lower
+const ID: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    ContinuousConversion = 0b0000,
+    SingleConversion = 0b0001,
+    Standby = 0b0010,
+    PowerDown = 0b0011,
+    Idle = 0b0100,
+    InternalOffsetCalibration = 0b0101,
+    InternalGainCalibration = 0b0110,
+    SystemOffsetCalibration = 0b0111,
+    SystemGainCalibration = 0b1000,
+    Invalid = 0b1111,
+}
+
+impl From<u8> for Mode {
+    fn from(x: u8) -> Self {
+        use Mode::*;
+        match x {
+            0b0000 => ContinuousConversion,
+            0b0001 => SingleConversion,
+            0b0010 => Standby,
+            0b0011 => PowerDown,
+            0b0100 => Idle,
+            0b0101 => InternalOffsetCalibration,
+            0b0110 => InternalGainCalibration,
+            0b0111 => SystemOffsetCalibration,
+            0b1000 => SystemGainCalibration,
+            _ => Invalid,
+        }
+    }
+}
+
+impl From<ad7172::Mode> for Mode {
+    fn from(mode: ad7172::Mode) -> Self {
+        match mode {
+            ad7172::Mode::ContinuousConversion => Mode::ContinuousConversion,
+            ad7172::Mode::SingleConversion => Mode::SingleConversion,
+            ad7172::Mode::Standby => Mode::Standby,
+            ad7172::Mode::PowerDown => Mode::PowerDown,
+            ad7172::Mode::InternalOffsetCalibration => Mode::InternalOffsetCalibration,
+            ad7172::Mode::SystemOffsetCalibration => Mode::SystemOffsetCalibration,
+            ad7172::Mode::SystemGainCalibration => Mode::SystemGainCalibration,
+            ad7172::Mode::Invalid => Mode::Invalid,
+        }
+    }
+}
+
+/// Analog input of a channel, by the codes of the AINP and AINM
+/// fields
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Input(u8);
+
+impl Input {
+    pub const TEMPERATURE: Self = Input(16);
+    pub const AVSS: Self = Input(17);
+    /// (AVDD − AVSS) / 6, positive side
+    pub const ANALOG_SUPPLY_POS: Self = Input(20);
+    /// (AVDD − AVSS) / 6, negative side
+    pub const ANALOG_SUPPLY_NEG: Self = Input(21);
+
+    /// AIN0 to AIN15
+    pub fn ain(pin: u8) -> Self {
+        Input(pin.min(15))
+    }
+
+    /// Value of the AINP or AINM field
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+}
+
+impl From<ad7172::Input> for Input {
+    fn from(input: ad7172::Input) -> Self {
+        match input {
+            ad7172::Input::TemperaturePos => Input::TEMPERATURE,
+            ad7172::Input::TemperatureNeg => Input::AVSS,
+            ad7172::Input::AnalogSupplyPos => Input::ANALOG_SUPPLY_POS,
+            ad7172::Input::AnalogSupplyNeg => Input::ANALOG_SUPPLY_NEG,
+            ad7172::Input::RefPos => Input(18),
+            ad7172::Input::RefNeg => Input::AVSS,
+            input => Input::ain(input as u8),
+        }
+    }
+}
+
+/// Gain of the programmable gain amplifier
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Pga {
+    X1 = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+    X32 = 5,
+    X64 = 6,
+    X128 = 7,
+}
+
+impl From<u8> for Pga {
+    fn from(x: u8) -> Self {
+        use Pga::*;
+        match x & 0b111 {
+            0 => X1,
+            1 => X2,
+            2 => X4,
+            3 => X8,
+            4 => X16,
+            5 => X32,
+            6 => X64,
+            _ => X128,
+        }
+    }
+}
+
+/// Excitation current of IOUT0 or IOUT1, for RTDs and other resistive
+/// sensors
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ExcitationCurrent {
+    Off = 0b000,
+    I50uA = 0b001,
+    I100uA = 0b010,
+    I250uA = 0b011,
+    I500uA = 0b100,
+    I750uA = 0b101,
+    I1000uA = 0b110,
+}
+
+impl From<u8> for ExcitationCurrent {
+    fn from(x: u8) -> Self {
+        use ExcitationCurrent::*;
+        match x {
+            0b001 => I50uA,
+            0b010 => I100uA,
+            0b011 => I250uA,
+            0b100 => I500uA,
+            0b101 => I750uA,
+            0b110 | 0b111 => I1000uA,
+            _ => Off,
+        }
+    }
+}
+
+/// Value of the `filter` field of the filter register
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum FilterType {
+    Sinc4 = 0b000,
+    Sinc3 = 0b010,
+    FastSinc4 = 0b100,
+    FastSinc3 = 0b101,
+    /// 50 Hz and 60 Hz rejection of the `post_filter` field
+    PostFilter = 0b111,
+    Invalid = 0b001,
+}
+
+impl From<u8> for FilterType {
+    fn from(x: u8) -> Self {
+        match x {
+            0b000 => FilterType::Sinc4,
+            0b010 => FilterType::Sinc3,
+            0b100 => FilterType::FastSinc4,
+            0b101 => FilterType::FastSinc3,
+            0b111 => FilterType::PostFilter,
+            _ => FilterType::Invalid,
+        }
+    }
+}
+
+impl FilterType {
+    /// The AD7172-2 filter the closest to this one. The postfilters
+    /// of the AD7172-2 require its Sinc5 + Sinc1 filter.
+    pub fn order(&self) -> DigitalFilterOrder {
+        match self {
+            FilterType::Sinc4 | FilterType::FastSinc4 | FilterType::PostFilter => {
+                DigitalFilterOrder::Sinc5Sinc1
+            }
+            FilterType::Sinc3 | FilterType::FastSinc3 => DigitalFilterOrder::Sinc3,
+            FilterType::Invalid => DigitalFilterOrder::Invalid,
+        }
+    }
+}
+
+/// `fs` field for the output data rate of `rate`
+fn fs(rate: DataRate, order: DigitalFilterOrder) -> u16 {
+    (FS_RATE / rate.output_rate(order))
+        .round()
+        .clamp(1.0, f32::from(FS_MAX)) as u16
+}
+
+/// Output data rate setting the closest to the `fs` field
+fn data_rate(fs: u16, order: DigitalFilterOrder) -> DataRate {
+    DataRate::closest(FS_RATE / f32::from(fs.max(1)), order)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_rate() {
+        let order = DigitalFilterOrder::Sinc5Sinc1;
+        assert_eq!(fs(DataRate::DEFAULT, order), 1920);
+        assert_eq!(data_rate(1920, order), DataRate::DEFAULT);
+        let fast = DataRate::closest(1000.0, order);
+        assert_eq!(data_rate(fs(fast, order), order), fast);
+        assert_eq!(fs(DataRate::closest(1.25, order), order), FS_MAX);
+    }
+}
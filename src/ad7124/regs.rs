@@ -0,0 +1,319 @@
+use bit_field::BitField;
+use core::ops::{Deref, DerefMut};
+
+use super::*;
+use crate::ad7172::PostFilter;
+
+pub trait Register {
+    type Data: RegisterData;
+    fn address(&self) -> u8;
+}
+
+pub trait RegisterData: Clone + Deref<Target = [u8]> + DerefMut {
+    fn empty() -> Self;
+}
+
+macro_rules! def_reg {
+    ($Reg: ident, $reg: ident, $addr: expr, $size: expr) => {
+        /// AD7124 register
+        pub struct $Reg;
+        impl Register for $Reg {
+            /// Register contents
+            type Data = $reg::Data;
+            /// Register address
+            fn address(&self) -> u8 {
+                $addr
+            }
+        }
+        mod $reg {
+            /// Register contents
+            #[derive(Clone)]
+            pub struct Data(pub [u8; $size]);
+            impl super::RegisterData for Data {
+                /// Generate zeroed register contents
+                fn empty() -> Self {
+                    Data([0; $size])
+                }
+            }
+            impl core::ops::Deref for Data {
+                type Target = [u8];
+                fn deref(&self) -> &[u8] {
+                    &self.0
+                }
+            }
+            impl core::ops::DerefMut for Data {
+                fn deref_mut(&mut self) -> &mut [u8] {
+                    &mut self.0
+                }
+            }
+        }
+    };
+    ($Reg: ident, u8, $reg: ident, $addr: expr, $size: expr) => {
+        pub struct $Reg {
+            pub index: u8,
+        }
+        impl Register for $Reg {
+            type Data = $reg::Data;
+            fn address(&self) -> u8 {
+                $addr + self.index
+            }
+        }
+        mod $reg {
+            #[derive(Clone)]
+            pub struct Data(pub [u8; $size]);
+            impl super::RegisterData for Data {
+                fn empty() -> Self {
+                    Data([0; $size])
+                }
+            }
+            impl core::ops::Deref for Data {
+                type Target = [u8];
+                fn deref(&self) -> &[u8] {
+                    &self.0
+                }
+            }
+            impl core::ops::DerefMut for Data {
+                fn deref_mut(&mut self) -> &mut [u8] {
+                    &mut self.0
+                }
+            }
+        }
+    };
+}
+
+macro_rules! reg_bit {
+    ($getter: ident, $byte: expr, $bit: expr, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> bool {
+            self.0[$byte].get_bit($bit)
+        }
+    };
+    ($getter: ident, $setter: ident, $byte: expr, $bit: expr, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> bool {
+            self.0[$byte].get_bit($bit)
+        }
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $setter(&mut self, value: bool) {
+            self.0[$byte].set_bit($bit, value);
+        }
+    };
+}
+
+macro_rules! reg_bits {
+    ($getter: ident, $byte: expr, $bits: expr, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> u8 {
+            self.0[$byte].get_bits($bits)
+        }
+    };
+    ($getter: ident, $setter: ident, $byte: expr, $bits: expr, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> u8 {
+            self.0[$byte].get_bits($bits)
+        }
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $setter(&mut self, value: u8) {
+            self.0[$byte].set_bits($bits, value);
+        }
+    };
+    ($getter: ident, $byte: expr, $bits: expr, $ty: ty, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> $ty {
+            self.0[$byte].get_bits($bits) as $ty
+        }
+    };
+    ($getter: ident, $setter: ident, $byte: expr, $bits: expr, $ty: ty, $doc: expr) => {
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $getter(&self) -> $ty {
+            self.0[$byte].get_bits($bits).into()
+        }
+        #[allow(unused)]
+        #[doc = $doc]
+        pub fn $setter(&mut self, value: $ty) {
+            self.0[$byte].set_bits($bits, value as u8);
+        }
+    };
+}
+
+def_reg!(Status, status, 0x00, 1);
+impl status::Data {
+    /// Is there new data to read?
+    pub fn ready(&self) -> bool {
+        !self.not_ready()
+    }
+
+    reg_bit!(not_ready, 0, 7, "No data ready indicator");
+    reg_bit!(error, 0, 6, "Error flag of the error register");
+    reg_bit!(power_on_reset, 0, 4, "Power-on reset since the last read");
+    reg_bits!(channel, 0, 0..=3, "Channel for which data is ready");
+}
+
+def_reg!(AdcControl, adc_control, 0x01, 2);
+impl adc_control::Data {
+    reg_bit!(
+        ref_en,
+        set_ref_en,
+        0,
+        0,
+        "Enable internal reference, output buffered 2.5 V to REFOUT"
+    );
+    reg_bits!(
+        power_mode,
+        set_power_mode,
+        1,
+        6..=7,
+        "Power mode, full power with `0b11`"
+    );
+    reg_bits!(mode, set_mode, 1, 2..=5, Mode, "Operating mode");
+    reg_bits!(clk_sel, set_clk_sel, 1, 0..=1, "Clock source");
+}
+
+def_reg!(Data, data, 0x02, 3);
+impl data::Data {
+    pub fn data(&self) -> u32 {
+        (u32::from(self.0[0]) << 16) | (u32::from(self.0[1]) << 8) | u32::from(self.0[2])
+    }
+}
+
+def_reg!(IoControl1, io_control1, 0x03, 3);
+impl io_control1::Data {
+    reg_bits!(
+        iout1,
+        set_iout1,
+        1,
+        3..=5,
+        ExcitationCurrent,
+        "Excitation current of IOUT1"
+    );
+    reg_bits!(
+        iout0,
+        set_iout0,
+        1,
+        0..=2,
+        ExcitationCurrent,
+        "Excitation current of IOUT0"
+    );
+    reg_bits!(iout1_ch, set_iout1_ch, 2, 4..=7, "Pin of IOUT1");
+    reg_bits!(iout0_ch, set_iout0_ch, 2, 0..=3, "Pin of IOUT0");
+}
+
+def_reg!(Id, id, 0x05, 1);
+impl id::Data {
+    pub fn id(&self) -> u8 {
+        self.0[0]
+    }
+}
+
+def_reg!(Channel, u8, channel, 0x09, 2);
+impl channel::Data {
+    reg_bit!(enabled, set_enabled, 0, 7, "Channel enabled");
+    reg_bits!(setup, set_setup, 0, 4..=6, "Setup number");
+
+    /// Which input is connected to positive input of this channel
+    #[allow(unused)]
+    pub fn a_in_pos(&self) -> Input {
+        Input::ain((self.0[0].get_bits(0..=2) << 3) | self.0[1].get_bits(5..=7))
+    }
+    /// Set which input is connected to positive input of this channel
+    #[allow(unused)]
+    pub fn set_a_in_pos(&mut self, value: Input) {
+        let value = value.code();
+        self.0[0].set_bits(0..=2, value >> 3);
+        self.0[1].set_bits(5..=7, value & 0x7);
+    }
+    /// Set which input is connected to negative input of this channel
+    #[allow(unused)]
+    pub fn set_a_in_neg(&mut self, value: Input) {
+        self.0[1].set_bits(0..=4, value.code());
+    }
+}
+
+def_reg!(Config, u8, config, 0x19, 2);
+impl config::Data {
+    reg_bit!(
+        bipolar,
+        set_bipolar,
+        0,
+        3,
+        "Unipolar (`false`) or bipolar (`true`) coded output"
+    );
+    reg_bit!(refbuf_pos, set_refbuf_pos, 0, 0, "Enable REF+ input buffer");
+    reg_bit!(refbuf_neg, set_refbuf_neg, 1, 7, "Enable REF- input buffer");
+    reg_bit!(ainbuf_pos, set_ainbuf_pos, 1, 6, "Enable AIN+ input buffer");
+    reg_bit!(ainbuf_neg, set_ainbuf_neg, 1, 5, "Enable AIN- input buffer");
+    reg_bits!(
+        ref_sel,
+        set_ref_sel,
+        1,
+        3..=4,
+        "Reference source, REFIN1 with `0b00`"
+    );
+    reg_bits!(pga, set_pga, 1, 0..=2, Pga, "Gain of the PGA");
+}
+
+def_reg!(Filter, u8, filter, 0x21, 3);
+impl filter::Data {
+    reg_bits!(
+        filter,
+        set_filter,
+        0,
+        5..=7,
+        FilterType,
+        "Type of digital filter"
+    );
+    reg_bits!(
+        post_filter,
+        set_post_filter,
+        0,
+        1..=3,
+        PostFilter,
+        "Postfilter for enhanced 50Hz and 60Hz rejection"
+    );
+
+    /// Output data rate divider
+    pub fn fs(&self) -> u16 {
+        (u16::from(self.0[1].get_bits(0..=2)) << 8) | u16::from(self.0[2])
+    }
+    /// Set the output data rate divider
+    pub fn set_fs(&mut self, value: u16) {
+        self.0[1].set_bits(0..=2, (value >> 8) as u8);
+        self.0[2] = value as u8;
+    }
+}
+
+def_reg!(Offset, u8, offset, 0x29, 3);
+impl offset::Data {
+    #[allow(unused)]
+    pub fn offset(&self) -> u32 {
+        (u32::from(self.0[0]) << 16) | (u32::from(self.0[1]) << 8) | u32::from(self.0[2])
+    }
+    #[allow(unused)]
+    pub fn set_offset(&mut self, value: u32) {
+        self.0[0] = (value >> 16) as u8;
+        self.0[1] = (value >> 8) as u8;
+        self.0[2] = value as u8;
+    }
+}
+
+def_reg!(Gain, u8, gain, 0x31, 3);
+impl gain::Data {
+    #[allow(unused)]
+    pub fn gain(&self) -> u32 {
+        (u32::from(self.0[0]) << 16) | (u32::from(self.0[1]) << 8) | u32::from(self.0[2])
+    }
+    #[allow(unused)]
+    pub fn set_gain(&mut self, value: u32) {
+        self.0[0] = (value >> 16) as u8;
+        self.0[1] = (value >> 8) as u8;
+        self.0[2] = value as u8;
+    }
+}
@@ -1,13 +1,34 @@
 use super::{
     checksum::{Checksum, ChecksumMode},
     regs::{self, Register, RegisterData},
-    DigitalFilterOrder, Input, Mode, PostFilter, RefSource,
+    DataRate, DigitalFilterOrder, Input, Mode, PostFilter, RefSource,
 };
+use crate::timer;
 use core::{fmt, marker::PhantomData};
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
 use uom::si::f64::ElectricPotential;
 
+/// Attempts of a register read before giving up on checksum errors
+const READ_RETRIES: usize = 3;
+/// Attempts to read the ID after a reset in `reinit()`
+const ID_RETRIES: usize = 10;
+/// Milliseconds to wait for a calibration, several conversions at the
+/// lowest data rate
+const CALIBRATE_TIMEOUT: u32 = 3000;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    /// Checksum mismatch on every attempt to read a register
+    Checksum,
+    /// Not an AD7172-2 by its ID register
+    Id,
+    /// Calibration not done within `CALIBRATE_TIMEOUT`
+    Timeout,
+}
+
 /// AD7172-2 implementation
 ///
 /// [Manual](https://www.analog.com/media/en/technical-documentation/data-sheets/AD7172-2.pdf)
@@ -15,18 +36,20 @@ pub struct Adc<SPI: Transfer<u8>, NSS: OutputPin> {
     spi: SPI,
     nss: NSS,
     checksum_mode: ChecksumMode,
+    checksum_errors: u32,
 }
 
 impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS> {
-    pub fn new(spi: SPI, mut nss: NSS) -> Result<Self, SPI::Error> {
+    pub fn new(spi: SPI, mut nss: NSS) -> Result<Self, Error<E>> {
         let _ = nss.set_high();
         let mut adc = Adc {
             spi,
             nss,
             checksum_mode: ChecksumMode::Off,
+            checksum_errors: 0,
         };
         adc.reset()?;
-        adc.set_checksum_mode(ChecksumMode::Crc).unwrap();
+        adc.set_checksum_mode(ChecksumMode::Crc)?;
 
         let mut retries = 0;
         let mut adc_id;
@@ -48,12 +71,43 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(adc)
     }
 
+    /// Reset the ADC, after it has stopped responding, and set up the
+    /// interface as `new()` does. Other registers are back at their
+    /// defaults.
+    pub fn reinit(&mut self) -> Result<(), Error<E>> {
+        self.checksum_mode = ChecksumMode::Off;
+        self.reset()?;
+        self.set_checksum_mode(ChecksumMode::Crc)?;
+
+        let mut adc_id = 0;
+        for _ in 0..ID_RETRIES {
+            adc_id = self.identify()?;
+            if adc_id & 0xFFF0 == 0x00D0 {
+                break;
+            }
+        }
+        if adc_id & 0xFFF0 != 0x00D0 {
+            warn!("ADC id: {:04X}", adc_id);
+            return Err(Error::Id);
+        }
+
+        let mut adc_mode = <regs::AdcMode as Register>::Data::empty();
+        adc_mode.set_ref_en(true);
+        adc_mode.set_mode(Mode::Standby);
+        self.write_reg(&regs::AdcMode, &mut adc_mode)
+    }
+
     /// `0x00DX` for AD7172-2
-    pub fn identify(&mut self) -> Result<u16, SPI::Error> {
+    pub fn identify(&mut self) -> Result<u16, Error<E>> {
         self.read_reg(&regs::Id).map(|id| id.id())
     }
 
-    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) -> Result<(), SPI::Error> {
+    /// Whether the ADC identifies itself as an AD7172-2
+    pub fn check_id(&mut self) -> bool {
+        matches!(self.identify(), Ok(id) if id & 0xFFF0 == 0x00D0)
+    }
+
+    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) -> Result<(), Error<E>> {
         // Cannot use update_reg() here because checksum_mode is
         // updated between read_reg() and write_reg().
         let mut ifmode = self.read_reg(&regs::IfMode)?;
@@ -63,7 +117,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(())
     }
 
-    pub fn set_sync_enable(&mut self, enable: bool) -> Result<(), SPI::Error> {
+    pub fn set_sync_enable(&mut self, enable: bool) -> Result<(), Error<E>> {
         self.update_reg(&regs::GpioCon, |data| {
             data.set_sync_en(enable);
         })
@@ -74,7 +128,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         index: u8,
         in_pos: Input,
         in_neg: Input,
-    ) -> Result<(), SPI::Error> {
+    ) -> Result<(), Error<E>> {
         self.update_reg(&regs::SetupCon { index }, |data| {
             data.set_bipolar(false);
             data.set_refbuf_pos(true);
@@ -87,8 +141,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
             data.set_enh_filt_en(true);
             data.set_enh_filt(PostFilter::F16SPS);
             data.set_order(DigitalFilterOrder::Sinc5Sinc1);
-            // output data rate: 10 Hz
-            data.set_odr(0b10011);
+            data.set_odr(DataRate::DEFAULT.odr());
         })?;
         self.update_reg(&regs::Channel { index }, |data| {
             data.set_setup(index);
@@ -99,7 +152,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(())
     }
 
-    pub fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, SPI::Error> {
+    pub fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Error<E>> {
         let offset = self.read_reg(&regs::Offset { index })?.offset();
         let gain = self.read_reg(&regs::Gain { index })?.gain();
         let bipolar = self.read_reg(&regs::SetupCon { index })?.bipolar();
@@ -110,7 +163,58 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         })
     }
 
-    pub fn start_continuous_conversion(&mut self) -> Result<(), SPI::Error> {
+    pub fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::Offset { index }, |data| {
+            data.set_offset(calibration.offset);
+        })?;
+        self.update_reg(&regs::Gain { index }, |data| {
+            data.set_gain(calibration.gain);
+        })
+    }
+
+    /// Run a system offset or gain calibration on channel `index`
+    /// with the zero-scale or full-scale input applied. Blocks until
+    /// done, or for at most `CALIBRATE_TIMEOUT`, then resumes
+    /// continuous conversion.
+    pub fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Error<E>> {
+        // Only the channel to calibrate may be enabled
+        let mut enabled = [false; 4];
+        for (other, enabled) in enabled.iter_mut().enumerate() {
+            let other = other as u8;
+            *enabled = self.update_reg(&regs::Channel { index: other }, |data| {
+                let enabled = data.enabled();
+                data.set_enabled(other == index);
+                enabled
+            })?;
+        }
+
+        let mut adc_mode = <regs::AdcMode as Register>::Data::empty();
+        adc_mode.set_ref_en(true);
+        adc_mode.set_mode(mode);
+        self.write_reg(&regs::AdcMode, &mut adc_mode)?;
+        let start = timer::now();
+        let mut result = Ok(());
+        while !self.read_reg(&regs::Status)?.ready() {
+            if timer::now().wrapping_sub(start) > CALIBRATE_TIMEOUT {
+                result = Err(Error::Timeout);
+                break;
+            }
+        }
+
+        for (other, enabled) in enabled.iter().enumerate() {
+            self.update_reg(&regs::Channel { index: other as u8 }, |data| {
+                data.set_enabled(*enabled);
+            })?;
+        }
+        self.start_continuous_conversion()?;
+        result
+    }
+
+    pub fn start_continuous_conversion(&mut self) -> Result<(), Error<E>> {
         let mut adc_mode = <regs::AdcMode as Register>::Data::empty();
         adc_mode.set_ref_en(true);
         adc_mode.set_mode(Mode::ContinuousConversion);
@@ -119,7 +223,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(())
     }
 
-    pub fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, SPI::Error> {
+    pub fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Error<E>> {
         self.read_reg(&regs::FiltCon { index }).map(|data| {
             if data.enh_filt_en() {
                 Some(data.enh_filt())
@@ -133,18 +237,54 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         &mut self,
         index: u8,
         filter: Option<PostFilter>,
-    ) -> Result<(), SPI::Error> {
+    ) -> Result<(), Error<E>> {
         self.update_reg(&regs::FiltCon { index }, |data| match filter {
             None => data.set_enh_filt_en(false),
             Some(filter) => {
+                // Postfilters are only available with the Sinc5 + Sinc1 filter
+                data.set_order(DigitalFilterOrder::Sinc5Sinc1);
                 data.set_enh_filt_en(true);
                 data.set_enh_filt(filter);
             }
         })
     }
 
+    pub fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Error<E>> {
+        self.read_reg(&regs::FiltCon { index })
+            .map(|data| data.order())
+    }
+
+    /// Set the order of the digital filter. Sinc3 disables the
+    /// postfilter.
+    pub fn set_filter_order(
+        &mut self,
+        index: u8,
+        order: DigitalFilterOrder,
+    ) -> Result<(), Error<E>> {
+        self.update_reg(&regs::FiltCon { index }, |data| {
+            if order != DigitalFilterOrder::Sinc5Sinc1 {
+                data.set_enh_filt_en(false);
+            }
+            data.set_order(order);
+        })
+    }
+
+    pub fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Error<E>> {
+        self.read_reg(&regs::FiltCon { index })
+            .map(|data| data.odr().into())
+    }
+
+    /// Set the output data rate, disabling the postfilter which would
+    /// override it
+    pub fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Error<E>> {
+        self.update_reg(&regs::FiltCon { index }, |data| {
+            data.set_enh_filt_en(false);
+            data.set_odr(rate.odr());
+        })
+    }
+
     /// Returns the channel the data is from
-    pub fn data_ready(&mut self) -> Result<Option<u8>, SPI::Error> {
+    pub fn data_ready(&mut self) -> Result<Option<u8>, Error<E>> {
         self.read_reg(&regs::Status).map(|status| {
             if status.ready() {
                 Some(status.channel())
@@ -155,25 +295,29 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
     }
 
     /// Get data
-    pub fn read_data(&mut self) -> Result<u32, SPI::Error> {
+    pub fn read_data(&mut self) -> Result<u32, Error<E>> {
         self.read_reg(&regs::Data).map(|data| data.data())
     }
 
-    fn read_reg<R: regs::Register>(&mut self, reg: &R) -> Result<R::Data, SPI::Error> {
+    fn read_reg<R: regs::Register>(&mut self, reg: &R) -> Result<R::Data, Error<E>> {
         let mut reg_data = R::Data::empty();
         let address = 0x40 | reg.address();
         let mut checksum = Checksum::new(self.checksum_mode);
         checksum.feed(&[address]);
         let checksum_out = checksum.result();
 
-        loop {
+        for _ in 0..READ_RETRIES {
             let checksum_in = self.transfer(address, reg_data.as_mut(), checksum_out)?;
 
+            // Start over from the address for each attempt
+            let mut checksum = Checksum::new(self.checksum_mode);
+            checksum.feed(&[address]);
             checksum.feed(&reg_data);
             let checksum_expected = checksum.result();
             if checksum_expected == checksum_in {
-                break;
+                return Ok(reg_data);
             }
+            self.checksum_errors = self.checksum_errors.wrapping_add(1);
             // Retry
             warn!(
                 "read_reg {:02X}: checksum error: {:?}!={:?}, retrying",
@@ -182,14 +326,20 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
                 checksum_in
             );
         }
-        Ok(reg_data)
+        Err(Error::Checksum)
+    }
+
+    /// Number of reads with a checksum mismatch, including the ones
+    /// that succeeded on retry
+    pub fn checksum_errors(&self) -> u32 {
+        self.checksum_errors
     }
 
     fn write_reg<R: regs::Register>(
         &mut self,
         reg: &R,
         reg_data: &mut R::Data,
-    ) -> Result<(), SPI::Error> {
+    ) -> Result<(), Error<E>> {
         loop {
             let address = reg.address();
             let mut checksum = Checksum::new(match self.checksum_mode {
@@ -217,7 +367,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         }
     }
 
-    fn update_reg<R, F, A>(&mut self, reg: &R, f: F) -> Result<A, SPI::Error>
+    fn update_reg<R, F, A>(&mut self, reg: &R, f: F) -> Result<A, Error<E>>
     where
         R: regs::Register,
         F: FnOnce(&mut R::Data) -> A,
@@ -228,12 +378,12 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         Ok(result)
     }
 
-    pub fn reset(&mut self) -> Result<(), SPI::Error> {
+    pub fn reset(&mut self) -> Result<(), Error<E>> {
         let mut buf = [0xFFu8; 8];
         let _ = self.nss.set_low();
         let result = self.spi.transfer(&mut buf);
         let _ = self.nss.set_high();
-        result?;
+        result.map_err(Error::Spi)?;
         Ok(())
     }
 
@@ -242,7 +392,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
         addr: u8,
         reg_data: &mut [u8],
         checksum: Option<u8>,
-    ) -> Result<Option<u8>, SPI::Error> {
+    ) -> Result<Option<u8>, Error<E>> {
         let mut addr_buf = [addr];
 
         let _ = self.nss.set_low();
@@ -256,10 +406,10 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
                 let mut checksum_buf = [checksum_out; 1];
                 match self.spi.transfer(&mut checksum_buf) {
                     Ok(_) => Ok(Some(checksum_buf[0])),
-                    Err(e) => Err(e),
+                    Err(e) => Err(Error::Spi(e)),
                 }
             }
-            (Err(e), _) => Err(e),
+            (Err(e), _) => Err(Error::Spi(e)),
         };
         let _ = self.nss.set_high();
 
@@ -267,7 +417,7 @@ impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc<SPI, NSS>
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChannelCalibration {
     offset: u32,
     gain: u32,
@@ -275,6 +425,24 @@ pub struct ChannelCalibration {
 }
 
 impl ChannelCalibration {
+    pub fn new(offset: u32, gain: u32, bipolar: bool) -> Self {
+        ChannelCalibration {
+            offset,
+            gain,
+            bipolar,
+        }
+    }
+
+    /// Content of the offset register
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Content of the gain register
+    pub fn gain(&self) -> u32 {
+        self.gain
+    }
+
     pub fn convert_data(&self, data: u32) -> ElectricPotential {
         let data = if self.bipolar {
             (data as i32 - 0x80_0000) as f64
@@ -293,3 +461,67 @@ impl ChannelCalibration {
         data * V_REF / 0.75
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockAdc;
+
+    #[test]
+    fn test_new() {
+        let (mock, spi, nss) = MockAdc::ad7172().bus();
+        let mut adc = Adc::new(spi, nss).unwrap();
+        let registers = mock.borrow().registers;
+        let mut if_mode = <regs::IfMode as Register>::Data::empty();
+        if_mode.copy_from_slice(&registers[0x02][..2]);
+        assert!(if_mode.crc() == ChecksumMode::Crc);
+        let mut adc_mode = <regs::AdcMode as Register>::Data::empty();
+        adc_mode.copy_from_slice(&registers[0x01][..2]);
+        assert_eq!(adc_mode.mode() as u8, Mode::Standby as u8);
+        assert!(adc_mode.ref_en());
+        assert_eq!(adc.identify().unwrap(), 0x00D4);
+
+        let (_, spi, nss) = MockAdc::absent().bus();
+        assert!(matches!(Adc::new(spi, nss), Err(Error::Checksum)));
+    }
+
+    #[test]
+    fn test_channel() {
+        let (mock, spi, nss) = MockAdc::ad7172().bus();
+        let mut adc = Adc::new(spi, nss).unwrap();
+        adc.setup_channel(1, Input::Ain2, Input::Ain3).unwrap();
+        let mut channel = <regs::Channel as Register>::Data::empty();
+        channel.copy_from_slice(&mock.borrow().registers[0x11][..2]);
+        assert!(channel.enabled());
+        assert_eq!(channel.setup(), 1);
+        assert_eq!(channel.a_in_pos() as u8, Input::Ain2 as u8);
+        assert_eq!(channel.a_in_neg() as u8, Input::Ain3 as u8);
+        assert_eq!(mock.borrow().registers[0x11][..2], [0x90, 0x43]);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let (mock, spi, nss) = MockAdc::ad7172().bus();
+        let mut adc = Adc::new(spi, nss).unwrap();
+        let calibration = ChannelCalibration::new(0x80_1234, 0x55_5550, false);
+        adc.set_calibration(1, &calibration).unwrap();
+        assert_eq!(mock.borrow().registers[0x31], [0x80, 0x12, 0x34]);
+        assert_eq!(mock.borrow().registers[0x39], [0x55, 0x55, 0x50]);
+        let calibration = adc.get_calibration(1).unwrap();
+        assert_eq!(calibration.offset(), 0x80_1234);
+        assert_eq!(calibration.gain(), 0x55_5550);
+    }
+
+    #[test]
+    fn test_checksum_errors() {
+        let (mock, spi, nss) = MockAdc::ad7172().bus();
+        let mut adc = Adc::new(spi, nss).unwrap();
+        mock.borrow_mut().registers[0x04] = [0x12, 0x34, 0x56];
+        // Retried up to READ_RETRIES times
+        mock.borrow_mut().bad_checksums = READ_RETRIES - 1;
+        assert_eq!(adc.read_data().unwrap(), 0x12_3456);
+        assert_eq!(adc.checksum_errors(), READ_RETRIES as u32 - 1);
+        mock.borrow_mut().bad_checksums = READ_RETRIES;
+        assert!(matches!(adc.read_data(), Err(Error::Checksum)));
+    }
+}
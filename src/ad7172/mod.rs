@@ -5,7 +5,7 @@ use stm32f4xx_hal::{spi, time::MegaHertz};
 
 mod checksum;
 pub mod regs;
-pub use checksum::ChecksumMode;
+pub use checksum::{Checksum, ChecksumMode};
 mod adc;
 pub use adc::*;
 
@@ -216,6 +216,61 @@ impl From<u8> for PostFilter {
     }
 }
 
+/// Output data rates in SPS of the Sinc5 + Sinc1 filter, indexed by
+/// the `odr` field of the filter configuration register
+const DATA_RATES: [f32; 23] = [
+    31_250.0, 31_250.0, 31_250.0, 31_250.0, 31_250.0, 31_250.0, 15_625.0, 10_417.0, 5_208.0,
+    2_597.0, 1_007.0, 503.8, 381.0, 200.3, 100.2, 59.52, 49.68, 20.01, 16.63, 10.0, 5.0, 2.5, 1.25,
+];
+/// Output data rates in SPS of the Sinc3 filter
+const SINC3_DATA_RATES: [f32; 23] = [
+    31_250.0, 31_250.0, 31_250.0, 31_250.0, 31_250.0, 31_250.0, 15_625.0, 10_417.0, 5_208.0,
+    2_604.0, 1_008.0, 504.0, 400.6, 200.3, 100.2, 59.98, 50.0, 20.0, 16.67, 10.0, 5.0, 2.5, 1.25,
+];
+
+/// Output data rate setting of the digital filter, used while the
+/// postfilter is disabled
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataRate(u8);
+
+impl DataRate {
+    /// Output data rate: 10 SPS
+    pub const DEFAULT: Self = DataRate(0b10011);
+
+    pub fn closest(rate: f32, order: DigitalFilterOrder) -> Self {
+        let mut best = DataRate(0);
+        for code in 1..DATA_RATES.len() as u8 {
+            let value = DataRate(code);
+            let error = (rate - value.output_rate(order)).abs();
+            if error < (rate - best.output_rate(order)).abs() {
+                best = value;
+            }
+        }
+        best
+    }
+
+    /// Samples per Second
+    pub fn output_rate(&self, order: DigitalFilterOrder) -> f32 {
+        match order {
+            DigitalFilterOrder::Sinc3 => SINC3_DATA_RATES[usize::from(self.0)],
+            _ => DATA_RATES[usize::from(self.0)],
+        }
+    }
+
+    /// Value of the `odr` register field
+    pub fn odr(&self) -> u8 {
+        self.0
+    }
+}
+
+impl From<u8> for DataRate {
+    fn from(x: u8) -> Self {
+        DataRate(x.min(DATA_RATES.len() as u8 - 1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum DigitalFilterOrder {
     Sinc5Sinc1 = 0b00,
@@ -0,0 +1,399 @@
+//! The sigma-delta ADC that samples the sensors, behind a trait so
+//! that boards with an AD7124-8 in place of the AD7172-2 run the same
+//! firmware. `BoardAdc::detect()` finds out which one is fitted.
+//!
+//! Settings use the `ad7172` types, which the configuration and the
+//! commands already expose.
+
+use crate::{
+    ad7124,
+    ad7172::{self, ChannelCalibration, DataRate, DigitalFilterOrder, Input, Mode, PostFilter},
+    board::SpiError,
+    pins::{AdcNss, AdcSpi},
+};
+use core::fmt;
+use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+pub trait Adc {
+    type Error: fmt::Debug;
+
+    /// Reset the ADC, after it has stopped responding. Channels,
+    /// calibrations and filters are back at their defaults.
+    fn reinit(&mut self) -> Result<(), Self::Error>;
+    /// Whether the ADC identifies itself over SPI
+    fn check_id(&mut self) -> bool;
+    fn set_sync_enable(&mut self, enable: bool) -> Result<(), Self::Error>;
+    fn setup_channel(&mut self, index: u8, in_pos: Input, in_neg: Input)
+        -> Result<(), Self::Error>;
+    fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Self::Error>;
+    fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Self::Error>;
+    /// Run a system calibration on channel `index`, then resume
+    /// continuous conversion
+    fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Self::Error>;
+    fn start_continuous_conversion(&mut self) -> Result<(), Self::Error>;
+    fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Self::Error>;
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) -> Result<(), Self::Error>;
+    fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Self::Error>;
+    fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder)
+        -> Result<(), Self::Error>;
+    fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Self::Error>;
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Self::Error>;
+    /// Returns the channel the data is from
+    fn data_ready(&mut self) -> Result<Option<u8>, Self::Error>;
+    fn read_data(&mut self) -> Result<u32, Self::Error>;
+    /// Reads with a checksum mismatch
+    fn checksum_errors(&self) -> u32;
+    /// Division of the supply voltage by the analog supply input
+    fn supply_divider(&self) -> f64;
+}
+
+impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc for ad7172::Adc<SPI, NSS> {
+    type Error = ad7172::Error<E>;
+
+    fn reinit(&mut self) -> Result<(), Self::Error> {
+        ad7172::Adc::reinit(self)
+    }
+
+    fn check_id(&mut self) -> bool {
+        ad7172::Adc::check_id(self)
+    }
+
+    fn set_sync_enable(&mut self, enable: bool) -> Result<(), Self::Error> {
+        ad7172::Adc::set_sync_enable(self, enable)
+    }
+
+    fn setup_channel(
+        &mut self,
+        index: u8,
+        in_pos: Input,
+        in_neg: Input,
+    ) -> Result<(), Self::Error> {
+        ad7172::Adc::setup_channel(self, index, in_pos, in_neg)
+    }
+
+    fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Self::Error> {
+        ad7172::Adc::get_calibration(self, index)
+    }
+
+    fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Self::Error> {
+        ad7172::Adc::set_calibration(self, index, calibration)
+    }
+
+    fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Self::Error> {
+        ad7172::Adc::calibrate(self, index, mode)
+    }
+
+    fn start_continuous_conversion(&mut self) -> Result<(), Self::Error> {
+        ad7172::Adc::start_continuous_conversion(self)
+    }
+
+    fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Self::Error> {
+        ad7172::Adc::get_postfilter(self, index)
+    }
+
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) -> Result<(), Self::Error> {
+        ad7172::Adc::set_postfilter(self, index, filter)
+    }
+
+    fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Self::Error> {
+        ad7172::Adc::get_filter_order(self, index)
+    }
+
+    fn set_filter_order(
+        &mut self,
+        index: u8,
+        order: DigitalFilterOrder,
+    ) -> Result<(), Self::Error> {
+        ad7172::Adc::set_filter_order(self, index, order)
+    }
+
+    fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Self::Error> {
+        ad7172::Adc::get_data_rate(self, index)
+    }
+
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Self::Error> {
+        ad7172::Adc::set_data_rate(self, index, rate)
+    }
+
+    fn data_ready(&mut self) -> Result<Option<u8>, Self::Error> {
+        ad7172::Adc::data_ready(self)
+    }
+
+    fn read_data(&mut self) -> Result<u32, Self::Error> {
+        ad7172::Adc::read_data(self)
+    }
+
+    fn checksum_errors(&self) -> u32 {
+        ad7172::Adc::checksum_errors(self)
+    }
+
+    fn supply_divider(&self) -> f64 {
+        5.0
+    }
+}
+
+impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc for ad7124::Adc<SPI, NSS> {
+    type Error = ad7124::Error<E>;
+
+    fn reinit(&mut self) -> Result<(), Self::Error> {
+        ad7124::Adc::reinit(self)
+    }
+
+    fn check_id(&mut self) -> bool {
+        ad7124::Adc::check_id(self)
+    }
+
+    /// The SYNC pin of the AD7124 is always enabled
+    fn set_sync_enable(&mut self, _enable: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn setup_channel(
+        &mut self,
+        index: u8,
+        in_pos: Input,
+        in_neg: Input,
+    ) -> Result<(), Self::Error> {
+        ad7124::Adc::setup_channel(self, index, in_pos.into(), in_neg.into())
+    }
+
+    fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Self::Error> {
+        ad7124::Adc::get_calibration(self, index)
+    }
+
+    fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Self::Error> {
+        ad7124::Adc::set_calibration(self, index, calibration)
+    }
+
+    fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Self::Error> {
+        ad7124::Adc::calibrate(self, index, mode.into())
+    }
+
+    fn start_continuous_conversion(&mut self) -> Result<(), Self::Error> {
+        ad7124::Adc::start_continuous_conversion(self)
+    }
+
+    fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Self::Error> {
+        ad7124::Adc::get_postfilter(self, index)
+    }
+
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) -> Result<(), Self::Error> {
+        ad7124::Adc::set_postfilter(self, index, filter)
+    }
+
+    fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Self::Error> {
+        ad7124::Adc::get_filter_order(self, index)
+    }
+
+    fn set_filter_order(
+        &mut self,
+        index: u8,
+        order: DigitalFilterOrder,
+    ) -> Result<(), Self::Error> {
+        ad7124::Adc::set_filter_order(self, index, order)
+    }
+
+    fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Self::Error> {
+        ad7124::Adc::get_data_rate(self, index)
+    }
+
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Self::Error> {
+        ad7124::Adc::set_data_rate(self, index, rate)
+    }
+
+    fn data_ready(&mut self) -> Result<Option<u8>, Self::Error> {
+        ad7124::Adc::data_ready(self)
+    }
+
+    fn read_data(&mut self) -> Result<u32, Self::Error> {
+        ad7124::Adc::read_data(self)
+    }
+
+    /// No SPI CRC in use
+    fn checksum_errors(&self) -> u32 {
+        0
+    }
+
+    fn supply_divider(&self) -> f64 {
+        6.0
+    }
+}
+
+pub enum Error<E = SpiError> {
+    Ad7172(ad7172::Error<E>),
+    Ad7124(ad7124::Error<E>),
+    /// No ADC identified itself at boot
+    Missing,
+}
+
+impl<E: fmt::Debug> fmt::Debug for Error<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Ad7172(e) => e.fmt(fmt),
+            Error::Ad7124(e) => e.fmt(fmt),
+            Error::Missing => "Missing".fmt(fmt),
+        }
+    }
+}
+
+/// The ADC fitted on the board
+pub enum BoardAdc<SPI: Transfer<u8> = AdcSpi, NSS: OutputPin = AdcNss> {
+    Ad7172(ad7172::Adc<SPI, NSS>),
+    Ad7124(ad7124::Adc<SPI, NSS>),
+    /// Neither identified itself, every access fails
+    Missing,
+}
+
+impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> BoardAdc<SPI, NSS> {
+    /// Probe for an AD7124-8, then for an AD7172-2. The AD7124-8 goes
+    /// first, as the AD7172-2 driver waits for its ID for as long as
+    /// the chip answers.
+    pub fn detect(spi: SPI, nss: NSS) -> Result<Self, Error<E>> {
+        match ad7124::Adc::new(spi, nss) {
+            Ok(adc) => Ok(BoardAdc::Ad7124(adc)),
+            Err((spi, nss)) => ad7172::Adc::new(spi, nss)
+                .map(BoardAdc::Ad7172)
+                .map_err(Error::Ad7172),
+        }
+    }
+}
+
+/// Call the same method on either ADC
+macro_rules! dispatch {
+    ($self: ident, $adc: ident => $call: expr) => {
+        match $self {
+            BoardAdc::Ad7172($adc) => $call.map_err(Error::Ad7172),
+            BoardAdc::Ad7124($adc) => $call.map_err(Error::Ad7124),
+            BoardAdc::Missing => Err(Error::Missing),
+        }
+    };
+    ($self: ident, $adc: ident -> $call: expr, $missing: expr) => {
+        match $self {
+            BoardAdc::Ad7172($adc) => $call,
+            BoardAdc::Ad7124($adc) => $call,
+            BoardAdc::Missing => $missing,
+        }
+    };
+}
+
+impl<SPI: Transfer<u8, Error = E>, NSS: OutputPin, E: fmt::Debug> Adc for BoardAdc<SPI, NSS> {
+    type Error = Error<E>;
+
+    fn reinit(&mut self) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::reinit(adc))
+    }
+
+    fn check_id(&mut self) -> bool {
+        dispatch!(self, adc -> Adc::check_id(adc), false)
+    }
+
+    fn set_sync_enable(&mut self, enable: bool) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::set_sync_enable(adc, enable))
+    }
+
+    fn setup_channel(&mut self, index: u8, in_pos: Input, in_neg: Input) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::setup_channel(adc, index, in_pos, in_neg))
+    }
+
+    fn get_calibration(&mut self, index: u8) -> Result<ChannelCalibration, Error<E>> {
+        dispatch!(self, adc => Adc::get_calibration(adc, index))
+    }
+
+    fn set_calibration(
+        &mut self,
+        index: u8,
+        calibration: &ChannelCalibration,
+    ) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::set_calibration(adc, index, calibration))
+    }
+
+    fn calibrate(&mut self, index: u8, mode: Mode) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::calibrate(adc, index, mode))
+    }
+
+    fn start_continuous_conversion(&mut self) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::start_continuous_conversion(adc))
+    }
+
+    fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, Error<E>> {
+        dispatch!(self, adc => Adc::get_postfilter(adc, index))
+    }
+
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::set_postfilter(adc, index, filter))
+    }
+
+    fn get_filter_order(&mut self, index: u8) -> Result<DigitalFilterOrder, Error<E>> {
+        dispatch!(self, adc => Adc::get_filter_order(adc, index))
+    }
+
+    fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::set_filter_order(adc, index, order))
+    }
+
+    fn get_data_rate(&mut self, index: u8) -> Result<DataRate, Error<E>> {
+        dispatch!(self, adc => Adc::get_data_rate(adc, index))
+    }
+
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) -> Result<(), Error<E>> {
+        dispatch!(self, adc => Adc::set_data_rate(adc, index, rate))
+    }
+
+    fn data_ready(&mut self) -> Result<Option<u8>, Error<E>> {
+        dispatch!(self, adc => Adc::data_ready(adc))
+    }
+
+    fn read_data(&mut self) -> Result<u32, Error<E>> {
+        dispatch!(self, adc => Adc::read_data(adc))
+    }
+
+    fn checksum_errors(&self) -> u32 {
+        dispatch!(self, adc -> Adc::checksum_errors(adc), 0)
+    }
+
+    fn supply_divider(&self) -> f64 {
+        dispatch!(self, adc -> Adc::supply_divider(adc), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockAdc;
+
+    #[test]
+    fn test_detect() {
+        let (_, spi, nss) = MockAdc::ad7172().bus();
+        let mut adc = BoardAdc::detect(spi, nss).unwrap();
+        assert!(matches!(adc, BoardAdc::Ad7172(_)));
+        assert!(adc.check_id());
+        assert_eq!(adc.supply_divider(), 5.0);
+
+        let (_, spi, nss) = MockAdc::ad7124().bus();
+        let mut adc = BoardAdc::detect(spi, nss).unwrap();
+        assert!(matches!(adc, BoardAdc::Ad7124(_)));
+        assert!(adc.check_id());
+        assert_eq!(adc.supply_divider(), 6.0);
+
+        let (_, spi, nss) = MockAdc::absent().bus();
+        assert!(matches!(
+            BoardAdc::detect(spi, nss),
+            Err(Error::Ad7172(ad7172::Error::Checksum))
+        ));
+        let mut adc: BoardAdc = BoardAdc::Missing;
+        assert!(!adc.check_id());
+        assert!(matches!(adc.reinit(), Err(Error::Missing)));
+    }
+}
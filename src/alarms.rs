@@ -0,0 +1,441 @@
+//! Alarms that are pushed to all clients when they go off, and latched
+//! until cleared with `alarms clear`.
+//!
+//! Conditions are checked from the main loop by `check()`. An alarm
+//! goes off when its condition appears, and is armed again once the
+//! condition has gone away.
+
+use crate::{
+    channel_state::{ChannelState, SensorFault},
+    channels::{Channels, CHANNELS},
+    command_handler::JsonBuffer,
+    fan_ctrl::FanCtrl,
+    flash_store::{FlashInterface, FlashStore, StoreError},
+    interrupt::{self, Mutex},
+    leds::FaultCode,
+    timer,
+};
+use core::cell::RefCell;
+use heapless::{consts::U8, Vec};
+use log::error;
+use serde::{Deserialize, Serialize};
+use uom::si::{electric_potential::volt, thermodynamic_temperature::degree_celsius};
+
+const FLASH_KEY: &str = "alarms";
+/// Supply voltages below which the `supply` alarm goes off, 10 % below
+/// the nominal rails
+const SUPPLY_3V3_MIN: f64 = 3.0;
+const SUPPLY_5V_MIN: f64 = 4.5;
+/// Threshold of the `dac_feedback` alarm, saved apart from
+/// `AlarmConfig`
+const DAC_FEEDBACK_KEY: &str = "alarm_dac_feedback";
+/// Deviation in volts of the DAC feedback from the DAC setting beyond
+/// which the `dac_feedback` alarm goes off, unless set otherwise
+const DAC_FEEDBACK_DEFAULT: f32 = 0.1;
+/// Time in milliseconds the deviation must last, for the feedback
+/// average to catch up with changes of the output
+const DAC_FEEDBACK_HOLD: u32 = 1000;
+
+static ALARMS: Mutex<RefCell<Alarms>> = Mutex::new(RefCell::new(Alarms::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmKind {
+    Temperature,
+    SensorFault,
+    Clipping,
+    Fan,
+    Supply,
+    DacFeedback,
+}
+
+impl AlarmKind {
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Alarm settings, saved to flash when changed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlarmConfig {
+    /// Temperature window of each channel in degrees Celsius, outside
+    /// of which the `temperature` alarm goes off
+    pub window: [Option<[f32; 2]>; CHANNELS],
+    pub sensor_fault: bool,
+    pub clipping: bool,
+    pub fan: bool,
+}
+
+impl AlarmConfig {
+    const fn new() -> Self {
+        AlarmConfig {
+            window: [None; CHANNELS],
+            sensor_fault: true,
+            clipping: false,
+            fan: true,
+        }
+    }
+
+    pub fn enabled(&self, kind: AlarmKind) -> bool {
+        match kind {
+            // Enabled by setting a window or threshold
+            AlarmKind::Temperature | AlarmKind::DacFeedback => true,
+            AlarmKind::SensorFault => self.sensor_fault,
+            AlarmKind::Clipping => self.clipping,
+            AlarmKind::Fan => self.fan,
+            AlarmKind::Supply => true,
+        }
+    }
+
+    pub fn set_enabled(&mut self, kind: AlarmKind, enabled: bool) {
+        match kind {
+            AlarmKind::Temperature | AlarmKind::Supply | AlarmKind::DacFeedback => {}
+            AlarmKind::SensorFault => self.sensor_fault = enabled,
+            AlarmKind::Clipping => self.clipping = enabled,
+            AlarmKind::Fan => self.fan = enabled,
+        }
+    }
+}
+
+/// Alarm line sent to all clients
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Alarm {
+    kind: AlarmKind,
+    /// `None` for the fan and the supply
+    channel: Option<usize>,
+    sensor_fault: Option<SensorFault>,
+    /// Temperature in degrees Celsius, for the `temperature` alarm
+    temperature: Option<f64>,
+    /// Rails in volts, for the `supply` alarm
+    supply_3v3: Option<f64>,
+    supply_5v: Option<f64>,
+    /// Deviation in volts, for the `dac_feedback` alarm
+    dac_feedback: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct AlarmLine {
+    alarm: Alarm,
+}
+
+impl Alarm {
+    fn new(kind: AlarmKind, channel: Option<usize>) -> Self {
+        Alarm {
+            kind,
+            channel,
+            sensor_fault: None,
+            temperature: None,
+            supply_3v3: None,
+            supply_5v: None,
+            dac_feedback: None,
+        }
+    }
+
+    pub fn json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(&AlarmLine { alarm: *self })
+    }
+}
+
+struct Alarms {
+    config: AlarmConfig,
+    /// Conditions present at the last check, as bits of `AlarmKind`,
+    /// per channel and for the device
+    active: [u8; CHANNELS + 1],
+    /// Alarms that have gone off since the last `alarms clear`
+    latched: [u8; CHANNELS + 1],
+    /// Reset by the watchdog, until `alarms clear`
+    watchdog_reset: bool,
+    /// Deviation in volts beyond which `dac_feedback` goes off, `None`
+    /// if disabled
+    dac_feedback_max: Option<f32>,
+    /// Time since which the DAC feedback of each channel has deviated
+    dac_feedback_since: [Option<u32>; CHANNELS],
+}
+
+impl Alarms {
+    const fn new() -> Self {
+        Alarms {
+            config: AlarmConfig::new(),
+            active: [0; CHANNELS + 1],
+            latched: [0; CHANNELS + 1],
+            watchdog_reset: false,
+            dac_feedback_max: Some(DAC_FEEDBACK_DEFAULT),
+            dac_feedback_since: [None; CHANNELS],
+        }
+    }
+
+    /// Whether the DAC feedback of `channel` has deviated beyond the
+    /// threshold for `DAC_FEEDBACK_HOLD`
+    fn dac_feedback_deviates(&mut self, channel: usize, deviation: Option<f64>, now: u32) -> bool {
+        let deviates = match (self.dac_feedback_max, deviation) {
+            (Some(max), Some(deviation)) => deviation > f64::from(max),
+            _ => false,
+        };
+        let since = &mut self.dac_feedback_since[channel];
+        if !deviates {
+            *since = None;
+            return false;
+        }
+        let since = *since.get_or_insert(now);
+        now.wrapping_sub(since) >= DAC_FEEDBACK_HOLD
+    }
+
+    /// Returns whether the alarm goes off
+    fn update(&mut self, kind: AlarmKind, channel: Option<usize>, condition: bool) -> bool {
+        let index = channel.unwrap_or(CHANNELS);
+        let was_active = self.active[index] & kind.bit() != 0;
+        if condition {
+            self.active[index] |= kind.bit();
+        } else {
+            self.active[index] &= !kind.bit();
+        }
+        if condition && !was_active && self.config.enabled(kind) {
+            self.latched[index] |= kind.bit();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Latched alarms of a channel, for `alarms`
+#[derive(Serialize)]
+struct ChannelStatus {
+    temperature: bool,
+    sensor_fault: bool,
+    clipping: bool,
+    dac_feedback: bool,
+}
+
+#[derive(Serialize)]
+struct AlarmStatus {
+    config: AlarmConfig,
+    dac_feedback_max: Option<f32>,
+    channels: [ChannelStatus; CHANNELS],
+    fan: bool,
+    supply: bool,
+    watchdog_reset: bool,
+}
+
+/// Read the settings from flash at boot
+pub fn load(store: &mut FlashStore) {
+    match store.read_value::<AlarmConfig>(FLASH_KEY) {
+        Ok(Some(config)) => interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().config = config),
+        Ok(None) => {}
+        Err(e) => error!("unable to load alarm config from flash: {:?}", e),
+    }
+    match store.read_value::<Option<f32>>(DAC_FEEDBACK_KEY) {
+        Ok(Some(max)) => {
+            interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().dac_feedback_max = max)
+        }
+        Ok(None) => {}
+        Err(e) => error!("unable to load dac_feedback alarm from flash: {:?}", e),
+    }
+}
+
+pub fn config() -> AlarmConfig {
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow().config.clone())
+}
+
+/// Apply new settings, and save them to flash
+pub fn set_config(store: &mut impl FlashInterface, config: AlarmConfig) -> Result<(), StoreError> {
+    store.write_value(FLASH_KEY, &config, [0; 64])?;
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().config = config);
+    Ok(())
+}
+
+/// Set the deviation of the DAC feedback beyond which the
+/// `dac_feedback` alarm goes off, `None` to disable it, and save it to
+/// flash
+pub fn set_dac_feedback_max(
+    store: &mut impl FlashInterface,
+    max: Option<f32>,
+) -> Result<(), StoreError> {
+    store.write_value(DAC_FEEDBACK_KEY, &max, [0; 16])?;
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().dac_feedback_max = max);
+    Ok(())
+}
+
+/// Note a reset by the watchdog at boot
+pub fn watchdog_reset() {
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().watchdog_reset = true)
+}
+
+/// Whether the watchdog has reset the device, until cleared
+pub fn watchdog_reset_latched() -> bool {
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow().watchdog_reset)
+}
+
+pub fn clear_watchdog_reset() {
+    interrupt::free(|cs| ALARMS.borrow(cs).borrow_mut().watchdog_reset = false)
+}
+
+pub fn clear() {
+    interrupt::free(|cs| {
+        let mut alarms = ALARMS.borrow(cs).borrow_mut();
+        alarms.latched = [0; CHANNELS + 1];
+        alarms.watchdog_reset = false;
+    })
+}
+
+pub fn status_json() -> Result<JsonBuffer, serde_json_core::ser::Error> {
+    let status = interrupt::free(|cs| {
+        let alarms = ALARMS.borrow(cs).borrow();
+        let latched = |index: usize, kind: AlarmKind| alarms.latched[index] & kind.bit() != 0;
+        AlarmStatus {
+            config: alarms.config.clone(),
+            dac_feedback_max: alarms.dac_feedback_max,
+            channels: core::array::from_fn(|channel| ChannelStatus {
+                temperature: latched(channel, AlarmKind::Temperature),
+                sensor_fault: latched(channel, AlarmKind::SensorFault),
+                clipping: latched(channel, AlarmKind::Clipping),
+                dac_feedback: latched(channel, AlarmKind::DacFeedback),
+            }),
+            fan: latched(CHANNELS, AlarmKind::Fan),
+            supply: latched(CHANNELS, AlarmKind::Supply),
+            watchdog_reset: alarms.watchdog_reset,
+        }
+    });
+    serde_json_core::to_vec(&status)
+}
+
+/// Fault to indicate on the LEDs, the most severe first
+pub fn fault_code(channels: &mut Channels, link_up: bool) -> Option<FaultCode> {
+    let any_channel = |channels: &mut Channels, f: fn(&ChannelState) -> bool| {
+        (0..CHANNELS).any(|channel| f(channels.channel_state(channel)))
+    };
+    if any_channel(channels, |state| state.fault.is_some()) {
+        Some(FaultCode::Interlock)
+    } else if any_channel(channels, |state| state.sensor_fault.is_some()) {
+        Some(FaultCode::SensorFault)
+    } else if !link_up {
+        Some(FaultCode::NetworkDown)
+    } else if watchdog_reset_latched() {
+        Some(FaultCode::WatchdogReset)
+    } else {
+        None
+    }
+}
+
+/// Alarms that go off, to be sent to all clients
+pub fn check(channels: &mut Channels, fan_ctrl: &FanCtrl) -> Vec<Alarm, U8> {
+    let mut alarms = Vec::new();
+    let now = timer::now();
+    for channel in 0..CHANNELS {
+        let sensor_fault = channels.channel_state(channel).take_sensor_alarm();
+        let state = channels.channel_state(channel);
+        let temperature = state
+            .get_temperature()
+            .filter(|_| state.sensor_fault.is_none())
+            .map(|temperature| temperature.get::<degree_celsius>());
+        let clipping = channels.clipping(channel);
+        let dac_feedback = channels
+            .dac_feedback_deviation(channel)
+            .map(|deviation| deviation.get::<volt>());
+        interrupt::free(|cs| {
+            let mut state = ALARMS.borrow(cs).borrow_mut();
+            // A new fault replaces an earlier one, so the alarm is
+            // armed again for each
+            state.update(AlarmKind::SensorFault, Some(channel), false);
+            if state.update(
+                AlarmKind::SensorFault,
+                Some(channel),
+                sensor_fault.is_some(),
+            ) {
+                error!("channel {} sensor fault: {:?}", channel, sensor_fault);
+                let _ = alarms.push(Alarm {
+                    sensor_fault,
+                    ..Alarm::new(AlarmKind::SensorFault, Some(channel))
+                });
+            }
+
+            let outside = match (state.config.window[channel], temperature) {
+                (Some([min, max]), Some(temperature)) => {
+                    !(f64::from(min)..=f64::from(max)).contains(&temperature)
+                }
+                _ => false,
+            };
+            if state.update(AlarmKind::Temperature, Some(channel), outside) {
+                error!("channel {} temperature alarm: {:?}", channel, temperature);
+                let _ = alarms.push(Alarm {
+                    temperature,
+                    ..Alarm::new(AlarmKind::Temperature, Some(channel))
+                });
+            }
+
+            if state.update(AlarmKind::Clipping, Some(channel), clipping) {
+                error!("channel {} output clipping", channel);
+                let _ = alarms.push(Alarm::new(AlarmKind::Clipping, Some(channel)));
+            }
+
+            let deviates = state.dac_feedback_deviates(channel, dac_feedback, now);
+            if state.update(AlarmKind::DacFeedback, Some(channel), deviates) {
+                error!(
+                    "channel {} DAC feedback deviates by {:?} V",
+                    channel, dac_feedback
+                );
+                let _ = alarms.push(Alarm {
+                    dac_feedback,
+                    ..Alarm::new(AlarmKind::DacFeedback, Some(channel))
+                });
+            }
+        });
+    }
+    let fan_failed = fan_ctrl.failed();
+    let supply_3v3 = channels.get_supply_3v3().get::<volt>();
+    let supply_5v = channels.get_supply_5v().map(|supply| supply.get::<volt>());
+    let brown_out =
+        supply_3v3 < SUPPLY_3V3_MIN || matches!(supply_5v, Some(supply) if supply < SUPPLY_5V_MIN);
+    interrupt::free(|cs| {
+        let mut state = ALARMS.borrow(cs).borrow_mut();
+        if state.update(AlarmKind::Fan, None, fan_failed) {
+            error!("fan failure");
+            let _ = alarms.push(Alarm::new(AlarmKind::Fan, None));
+        }
+        if state.update(AlarmKind::Supply, None, brown_out) {
+            error!("supply brown-out: {} V, {:?} V", supply_3v3, supply_5v);
+            let _ = alarms.push(Alarm {
+                supply_3v3: Some(supply_3v3),
+                supply_5v,
+                ..Alarm::new(AlarmKind::Supply, None)
+            });
+        }
+    });
+    alarms
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alarm_update() {
+        let mut alarms = Alarms::new();
+        assert!(alarms.update(AlarmKind::Temperature, Some(1), true));
+        assert!(!alarms.update(AlarmKind::Temperature, Some(1), true));
+        assert!(!alarms.update(AlarmKind::Temperature, Some(1), false));
+        assert!(alarms.update(AlarmKind::Temperature, Some(1), true));
+        assert_eq!(alarms.latched, [0, AlarmKind::Temperature.bit(), 0]);
+
+        // Disabled alarms are not latched
+        assert!(!alarms.update(AlarmKind::Clipping, Some(0), true));
+        assert_eq!(alarms.latched[0], 0);
+    }
+
+    #[test]
+    fn test_dac_feedback_deviates() {
+        let mut alarms = Alarms::new();
+        assert!(!alarms.dac_feedback_deviates(0, None, 0));
+        assert!(!alarms.dac_feedback_deviates(0, Some(0.05), 0));
+        assert!(!alarms.dac_feedback_deviates(0, Some(0.2), 100));
+        assert!(!alarms.dac_feedback_deviates(0, Some(0.2), 100 + DAC_FEEDBACK_HOLD - 1));
+        assert!(alarms.dac_feedback_deviates(0, Some(0.2), 100 + DAC_FEEDBACK_HOLD));
+        // Brief deviations restart the hold time
+        assert!(!alarms.dac_feedback_deviates(1, Some(0.2), 0));
+        assert!(!alarms.dac_feedback_deviates(1, Some(0.0), 500));
+        assert!(!alarms.dac_feedback_deviates(1, Some(0.2), DAC_FEEDBACK_HOLD));
+
+        alarms.dac_feedback_max = None;
+        assert!(!alarms.dac_feedback_deviates(0, Some(1.0), 100 + DAC_FEEDBACK_HOLD));
+    }
+}
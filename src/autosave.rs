@@ -0,0 +1,85 @@
+//! Saving the channel configs to flash shortly after a command has
+//! changed them, when enabled with `autosave on`.
+//!
+//! Changes are marked from anywhere by `changed()`, and written to
+//! flash from the main loop by `flush()` once no further changes have
+//! been made for `DEBOUNCE` milliseconds, so that a script setting
+//! many parameters in a row causes only one write.
+
+use crate::{
+    channels::{Channels, CHANNELS},
+    config::ChannelConfig,
+    event_log::{self, Event, EventKind},
+    flash_store::{FlashInterface, FlashStore, StoreError},
+    interrupt::{self, Mutex},
+    timer, CHANNEL_CONFIG_KEY,
+};
+use core::cell::RefCell;
+use log::{error, info};
+
+/// Milliseconds without changes before saving
+const DEBOUNCE: u32 = 2_000;
+const FLASH_KEY: &str = "autosave";
+
+/// Whether autosave is enabled, and the time of the last change that
+/// has not been saved yet
+static STATE: Mutex<RefCell<(bool, Option<u32>)>> = Mutex::new(RefCell::new((false, None)));
+
+/// Read the setting from flash at boot
+pub fn load(store: &mut FlashStore) {
+    match store.read_value::<bool>(FLASH_KEY) {
+        Ok(Some(enabled)) => interrupt::free(|cs| STATE.borrow(cs).borrow_mut().0 = enabled),
+        Ok(None) => {}
+        Err(e) => error!("cannot read autosave: {:?}", e),
+    }
+}
+
+pub fn enabled() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().0)
+}
+
+/// Enable or disable autosave, and save the setting to flash
+pub fn set(store: &mut impl FlashInterface, enabled: bool) -> Result<(), StoreError> {
+    store.write_value(FLASH_KEY, &enabled, [0; 16])?;
+    interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = (enabled, None));
+    Ok(())
+}
+
+/// Mark the channel configs as changed, to be saved by a later
+/// `flush()`
+pub fn changed() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if state.0 {
+            state.1 = Some(timer::now());
+        }
+    })
+}
+
+/// Save the channel configs if they have been changed, and have not
+/// been changed again for `DEBOUNCE`
+pub fn flush(store: &mut FlashStore, channels: &mut Channels) {
+    let due = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        match state.1 {
+            Some(time) if timer::now().wrapping_sub(time) >= DEBOUNCE => {
+                state.1 = None;
+                true
+            }
+            _ => false,
+        }
+    });
+    if !due {
+        return;
+    }
+
+    info!("autosave");
+    for (c, key) in CHANNEL_CONFIG_KEY.iter().enumerate().take(CHANNELS) {
+        let mut store_value_buf = [0u8; 256];
+        let config = ChannelConfig::new(channels, c);
+        match store.write_value(key, &config, &mut store_value_buf) {
+            Ok(()) => event_log::record(Event::new(EventKind::ConfigSaved, Some(c))),
+            Err(e) => error!("unable to autosave channel {} config to flash: {:?}", c, e),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Board support: the MCU-specific services below the control core
+//! (`channels`, `pid`, `command_handler`), so that the core can be
+//! reused on a variant board with another MCU, such as an STM32F7 or
+//! STM32H7.
+//!
+//! A port implements `Board` for its MCU and selects it as `Mcu`. Its
+//! `pins`, `usb` and `net` modules provide the setup that `main` wires
+//! to the core, which itself only uses the `ChannelPins`, `Adc` and
+//! `Dac` traits of the channel hardware, and the MCU ADC and SPI error
+//! types through `PinsAdc` and `SpiError`.
+
+use crate::interrupt::{self, Mutex};
+use core::{cell::RefCell, fmt};
+use cortex_m_rt::exception;
+use stm32f4xx_hal::{
+    adc::{config::SampleTime, Adc, Temperature, Vref},
+    pac::ADC1,
+    rcc::Clocks,
+    signature::{VrefCal, VtempCal110, VtempCal30},
+    spi,
+    stm32::{SCB, SYST},
+    time::U32Ext,
+    timer::{Event as TimerEvent, Timer},
+};
+use uom::si::{electric_potential::volt, f64::ElectricPotential};
+
+pub trait Board {
+    /// ADC of the MCU, converting the analog inputs of the channels
+    type PinsAdc: McuAdc;
+    /// Error of the SPI bus to the sigma-delta ADC
+    type SpiError: fmt::Debug;
+
+    /// Milliseconds since boot, without wrapping around. Read through
+    /// `timer`.
+    fn now_u64() -> u64;
+    /// Restart the MCU
+    fn reset() -> !;
+    /// Start the bootloader for a firmware update at the next
+    /// `reset()`
+    fn set_dfu_trigger();
+}
+
+/// The board the firmware is built for
+#[cfg(not(any(test, feature = "sim")))]
+pub type Mcu = Stm32f4;
+/// The host, with feature `sim`
+#[cfg(all(feature = "sim", not(test)))]
+pub type Mcu = crate::sim::Host;
+#[cfg(test)]
+pub type Mcu = crate::mock::MockBoard;
+
+/// ADC of the MCU of the board
+pub type PinsAdc = <Mcu as Board>::PinsAdc;
+/// SPI error of the sigma-delta ADC of the board
+pub type SpiError = <Mcu as Board>::SpiError;
+
+/// Readings of the MCU ADC besides the channel inputs, which are
+/// converted through `pins::PinsAdcInput`
+pub trait McuAdc {
+    /// Enable the internal reference and temperature sensor
+    fn enable_internal(&mut self);
+    /// Voltage of a conversion of a channel input
+    fn millivolts(&self, sample: u16) -> u16;
+    /// VDDA, averaging `count` conversions of the internal reference
+    fn vdda(&mut self, count: u32) -> ElectricPotential;
+    /// Die temperature in degrees Celsius, averaging `count`
+    /// conversions of the internal sensor
+    fn temperature(&mut self, count: u32) -> f64;
+}
+
+/// Rate in Hz of the SysTick exception
+const TIMER_RATE: u32 = 500;
+/// Interval duration in milliseconds
+const TIMER_DELTA: u32 = 1000 / TIMER_RATE;
+/// Elapsed time in milliseconds
+static TIMER_MS: Mutex<RefCell<u64>> = Mutex::new(RefCell::new(0));
+
+/// STM32F427 of the Thermostat
+pub struct Stm32f4;
+
+impl Stm32f4 {
+    /// Setup SysTick exception
+    pub fn setup_timer(syst: SYST, clocks: Clocks) {
+        let timer = Timer::syst(syst, &clocks);
+        let mut countdown = timer.start_count_down(TIMER_RATE.hz());
+        countdown.listen(TimerEvent::TimeOut);
+    }
+}
+
+/// SysTick exception (Timer)
+#[exception]
+fn SysTick() {
+    interrupt::free(|cs| {
+        *TIMER_MS.borrow(cs).borrow_mut() += u64::from(TIMER_DELTA);
+    });
+}
+
+impl McuAdc for Adc<ADC1> {
+    fn enable_internal(&mut self) {
+        self.enable_temperature_and_vref();
+    }
+
+    fn millivolts(&self, sample: u16) -> u16 {
+        self.sample_to_millivolts(sample)
+    }
+
+    fn vdda(&mut self, count: u32) -> ElectricPotential {
+        let mut sample: u32 = 0;
+        for _ in 0..count {
+            sample += self.convert(&Vref, SampleTime::Cycles_480) as u32;
+        }
+        let sample = (sample / count).max(1);
+        // The calibration value is taken at VDDA = 3.3 V
+        ElectricPotential::new::<volt>(3.3 * f64::from(VrefCal::get().read()) / sample as f64)
+    }
+
+    fn temperature(&mut self, count: u32) -> f64 {
+        let mut sample: u32 = 0;
+        for _ in 0..count {
+            // At least 10 µs of sampling time
+            sample += self.convert(&Temperature, SampleTime::Cycles_480) as u32;
+        }
+        let mv = self.sample_to_millivolts((sample / count) as u16);
+        // The calibration values are taken at VDDA = 3.3 V
+        let code = f64::from(mv) * 4095.0 / 3300.0;
+        let cal30 = f64::from(VtempCal30::get().read());
+        let cal110 = f64::from(VtempCal110::get().read());
+        30.0 + (code - cal30) * (110.0 - 30.0) / (cal110 - cal30)
+    }
+}
+
+impl Board for Stm32f4 {
+    type PinsAdc = Adc<ADC1>;
+    type SpiError = spi::Error;
+
+    fn now_u64() -> u64 {
+        interrupt::free(|cs| *TIMER_MS.borrow(cs).borrow())
+    }
+
+    fn reset() -> ! {
+        SCB::sys_reset()
+    }
+
+    fn set_dfu_trigger() {
+        unsafe {
+            crate::dfu::set_dfu_trigger();
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Capture of consecutive samples of one channel, for step-response
+//! and noise-floor measurements.
+//!
+//! Declared globally, as it is too large for the stack in CCMRAM.
+
+use crate::interrupt::{self, Mutex};
+use core::cell::RefCell;
+
+/// Maximum number of samples of a capture
+pub const CAPTURE_MAX: usize = 4096;
+
+static CAPTURE: Mutex<RefCell<Capture>> = Mutex::new(RefCell::new(Capture::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// Every raw ADC code
+    Adc,
+    /// Temperatures in degrees Celsius, at least `interval`
+    /// milliseconds apart
+    Temperature { interval: u32 },
+}
+
+struct Capture {
+    channel: Option<usize>,
+    source: Source,
+    /// Raw ADC codes fit into the 24 bit mantissa
+    samples: [f32; CAPTURE_MAX],
+    len: usize,
+    filled: usize,
+    /// Time of the first and the last sample in milliseconds
+    start: u32,
+    end: u32,
+}
+
+impl Capture {
+    const fn new() -> Self {
+        Capture {
+            channel: None,
+            source: Source::Adc,
+            samples: [0.0; CAPTURE_MAX],
+            len: 0,
+            filled: 0,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn push(&mut self, channel: usize, time: u32, value: f32) {
+        if self.channel != Some(channel) || self.filled >= self.len {
+            return;
+        }
+        if self.filled == 0 {
+            self.start = time;
+        }
+        self.end = time;
+        self.samples[self.filled] = value;
+        self.filled += 1;
+    }
+}
+
+/// Start capturing `len` samples of `channel`, replacing any previous
+/// capture.
+pub fn start(channel: usize, len: usize, source: Source) {
+    interrupt::free(|cs| {
+        let mut capture = CAPTURE.borrow(cs).borrow_mut();
+        capture.channel = Some(channel);
+        capture.source = source;
+        capture.len = len.min(CAPTURE_MAX);
+        capture.filled = 0;
+    })
+}
+
+/// Add a raw ADC sample of `channel`
+pub fn update_adc(channel: usize, time: u32, data: u32) {
+    interrupt::free(|cs| {
+        let mut capture = CAPTURE.borrow(cs).borrow_mut();
+        if capture.source == Source::Adc {
+            capture.push(channel, time, data as f32);
+        }
+    })
+}
+
+/// Add a temperature sample of `channel`
+pub fn update_temperature(channel: usize, time: u32, temperature: f64) {
+    interrupt::free(|cs| {
+        let mut capture = CAPTURE.borrow(cs).borrow_mut();
+        if let Source::Temperature { interval } = capture.source {
+            if capture.filled == 0 || time.wrapping_sub(capture.end) >= interval {
+                capture.push(channel, time, temperature as f32);
+            }
+        }
+    })
+}
+
+/// Complete the capture with the samples taken so far
+pub fn stop() {
+    interrupt::free(|cs| {
+        let mut capture = CAPTURE.borrow(cs).borrow_mut();
+        capture.len = capture.filled;
+    })
+}
+
+/// Channel, number of samples and duration in milliseconds of a
+/// complete capture
+pub fn result() -> Option<(usize, usize, u32)> {
+    interrupt::free(|cs| {
+        let capture = CAPTURE.borrow(cs).borrow();
+        match capture.channel {
+            Some(channel) if capture.filled >= capture.len => Some((
+                channel,
+                capture.len,
+                capture.end.wrapping_sub(capture.start),
+            )),
+            _ => None,
+        }
+    })
+}
+
+/// Sample `index` of a complete capture
+pub fn get(index: usize) -> Option<f32> {
+    interrupt::free(|cs| {
+        let capture = CAPTURE.borrow(cs).borrow();
+        if index < capture.filled {
+            Some(capture.samples[index])
+        } else {
+            None
+        }
+    })
+}
+
+/// Evaluate `f` with the samples taken
+pub fn with_samples<F: FnOnce(&[f32]) -> R, R>(f: F) -> R {
+    interrupt::free(|cs| {
+        let capture = CAPTURE.borrow(cs).borrow();
+        f(&capture.samples[..capture.filled])
+    })
+}
@@ -1,9 +1,14 @@
 use crate::{
-    ad5680, ad7172,
+    ad7172,
+    board::PinsAdc,
     channel_state::ChannelState,
-    pins::{ChannelPinSet, ChannelPins},
+    channels::PinsAdcReadTarget,
+    command_parser::PwmPin,
+    dac::{BoardDac, Dac, DacChip},
+    pins::{ChannelPinSet, ChannelPins, PinsAdcInput},
 };
-use stm32f4xx_hal::hal::digital::v2::OutputPin;
+use core::fmt;
+use stm32f4xx_hal::hal::{self, blocking::spi::Transfer, digital::v2::OutputPin};
 use uom::si::{electric_potential::volt, f64::ElectricPotential};
 
 /// Marker type for the first channel
@@ -12,10 +17,33 @@ pub struct Channel0;
 /// Marker type for the second channel
 pub struct Channel1;
 
+/// Hardware of one channel, whatever the types of its pins, so that
+/// `Channels` can hold the channels of a board in an array
+pub trait ChannelHardware {
+    fn state(&mut self) -> &mut ChannelState;
+    /// Largest code of the DAC
+    fn dac_max_value(&self) -> u32;
+    fn dac_chip(&self) -> DacChip;
+    fn set_dac(&mut self, value: u32);
+    /// Measured vref of MAX driver chip
+    fn vref_meas(&self) -> ElectricPotential;
+    fn set_vref_meas(&mut self, vref_meas: ElectricPotential);
+    fn power_up(&mut self);
+    fn power_down(&mut self);
+    fn is_powered(&self) -> bool;
+    /// Convert an input with the STM32 ADC, `None` if the board does
+    /// not connect it
+    fn convert(&self, pins_adc: &mut PinsAdc, target: PinsAdcReadTarget) -> Option<u16>;
+    /// Whether VREF of the MAX driver is connected to the STM32 ADC
+    fn vref_connected(&self) -> bool;
+    /// Set the duty cycle of a limit, returns the one applied
+    fn set_pwm(&mut self, pin: PwmPin, duty: f64) -> f64;
+}
+
 pub struct Channel<C: ChannelPins> {
     pub state: ChannelState,
     /// for `i_set`
-    pub dac: ad5680::Dac<C::DacSpi, C::DacSync>,
+    pub dac: BoardDac<C::DacSpi, C::DacSync>,
     /// Measured vref of MAX driver chip
     pub vref_meas: ElectricPotential,
     pub shdn: C::Shdn,
@@ -24,12 +52,21 @@ pub struct Channel<C: ChannelPins> {
     /// feedback from `dac` output
     pub dac_feedback_pin: C::DacFeedbackPin,
     pub tec_u_meas_pin: C::TecUMeasPin,
+    pub max_v: C::MaxV,
+    pub max_i_pos: C::MaxIPos,
+    pub max_i_neg: C::MaxINeg,
+    /// State of `shdn`
+    powered: bool,
 }
 
 impl<C: ChannelPins> Channel<C> {
-    pub fn new(pins: ChannelPinSet<C>, adc_calibration: ad7172::ChannelCalibration) -> Self {
+    pub fn new(
+        pins: ChannelPinSet<C>,
+        dac_chip: DacChip,
+        adc_calibration: ad7172::ChannelCalibration,
+    ) -> Self {
         let state = ChannelState::new(adc_calibration);
-        let mut dac = ad5680::Dac::new(pins.dac_spi, pins.dac_sync);
+        let mut dac = BoardDac::new(dac_chip, pins.dac_spi, pins.dac_sync);
         let _ = dac.set(0);
         // sensible dummy preset taken from datasheet. calibrate_dac_value() should be used to override this value.
         let vref_meas = ElectricPotential::new::<volt>(1.5);
@@ -43,16 +80,83 @@ impl<C: ChannelPins> Channel<C> {
             itec_pin: pins.itec_pin,
             dac_feedback_pin: pins.dac_feedback_pin,
             tec_u_meas_pin: pins.tec_u_meas_pin,
+            max_v: pins.max_v,
+            max_i_pos: pins.max_i_pos,
+            max_i_neg: pins.max_i_neg,
+            powered: false,
         }
     }
+}
+
+impl<C: ChannelPins> ChannelHardware for Channel<C>
+where
+    <C::DacSpi as Transfer<u8>>::Error: fmt::Debug,
+{
+    fn state(&mut self) -> &mut ChannelState {
+        &mut self.state
+    }
+
+    fn dac_max_value(&self) -> u32 {
+        self.dac.max_value()
+    }
+
+    fn dac_chip(&self) -> DacChip {
+        self.dac.chip()
+    }
+
+    fn set_dac(&mut self, value: u32) {
+        self.dac.set(value).unwrap();
+    }
+
+    fn vref_meas(&self) -> ElectricPotential {
+        self.vref_meas
+    }
+
+    fn set_vref_meas(&mut self, vref_meas: ElectricPotential) {
+        self.vref_meas = vref_meas;
+    }
 
     // power up TEC
-    pub fn power_up(&mut self) {
+    fn power_up(&mut self) {
         let _ = self.shdn.set_high();
+        self.powered = true;
     }
 
     // power down TEC
-    pub fn power_down(&mut self) {
+    fn power_down(&mut self) {
         let _ = self.shdn.set_low();
+        self.powered = false;
+    }
+
+    fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    fn convert(&self, pins_adc: &mut PinsAdc, target: PinsAdcReadTarget) -> Option<u16> {
+        match target {
+            PinsAdcReadTarget::VRef => self.vref_pin.convert(pins_adc),
+            PinsAdcReadTarget::DacVfb => self.dac_feedback_pin.convert(pins_adc),
+            PinsAdcReadTarget::ITec => self.itec_pin.convert(pins_adc),
+            PinsAdcReadTarget::VTec => self.tec_u_meas_pin.convert(pins_adc),
+        }
+    }
+
+    fn vref_connected(&self) -> bool {
+        self.vref_pin.connected()
+    }
+
+    fn set_pwm(&mut self, pin: PwmPin, duty: f64) -> f64 {
+        fn set<P: hal::PwmPin<Duty = u16>>(pin: &mut P, duty: f64) -> f64 {
+            let max = pin.get_max_duty();
+            let value = ((duty * (max as f64)) as u16).min(max);
+            pin.set_duty(value);
+            value as f64 / (max as f64)
+        }
+        match pin {
+            PwmPin::ISet => panic!("i_set is no pwm pin"),
+            PwmPin::MaxIPos => set(&mut self.max_i_pos, duty),
+            PwmPin::MaxINeg => set(&mut self.max_i_neg, duty),
+            PwmPin::MaxV => set(&mut self.max_v, duty),
+        }
     }
 }
@@ -1,60 +1,219 @@
 use crate::{
     ad7172, b_parameter as bp,
-    command_parser::{CenterPoint, Polarity},
+    command_parser::{CenterPoint, Polarity, Sensor},
     config::OutputLimits,
-    pid,
+    pid, rtd, stats, steinhart_hart as sh,
+    step_response::StepTest,
 };
 use core::marker::PhantomData;
+use serde::{Deserialize, Serialize};
 use smoltcp::time::{Duration, Instant};
 use uom::{
     si::{
         f64::{
-            ElectricCurrent, ElectricPotential, ElectricalResistance, ThermodynamicTemperature,
-            Time,
+            ElectricCurrent, ElectricPotential, ElectricalResistance, Energy, TemperatureInterval,
+            ThermodynamicTemperature, Time,
         },
+        temperature_interval::kelvin,
         thermodynamic_temperature::degree_celsius,
-        time::millisecond,
+        time::{millisecond, second},
     },
     ConstZero,
 };
 
-const R_INNER: ElectricalResistance = ElectricalResistance {
+pub const R_INNER: ElectricalResistance = ElectricalResistance {
     dimension: PhantomData,
     units: PhantomData,
     value: 2.0 * 5100.0,
 };
-const VREF_SENS: ElectricPotential = ElectricPotential {
+pub const VREF_SENS: ElectricPotential = ElectricPotential {
     dimension: PhantomData,
     units: PhantomData,
     value: 3.3 / 2.0,
 };
 
+/// Maximum number of raw ADC samples of the median filter
+const MEDIAN_MAX: usize = 5;
+
+/// Sensor resistance below which the sensor is considered shorted
+const SENS_SHORT: ElectricalResistance = ElectricalResistance {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 10.0,
+};
+/// Plausible range of sensor temperatures in degrees Celsius
+const SENS_T_MIN: f64 = -100.0;
+const SENS_T_MAX: f64 = 200.0;
+
+/// Sensor reading that cannot be used for control
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorFault {
+    /// ADC input at full scale
+    Open,
+    /// Resistance below `SENS_SHORT`
+    Short,
+    /// Temperature outside of `SENS_T_MIN..=SENS_T_MAX`
+    OutOfRange,
+}
+
+/// Two-point calibration cannot be completed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalError {
+    NoTemperature,
+    NoPoint1,
+    SameTemperature,
+}
+
+/// Trim of the TEC current and voltage readback for the tolerances of
+/// each board, applied as `gain * x + offset`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TecCalibration {
+    pub i_gain: f64,
+    /// Amperes
+    pub i_offset: f64,
+    pub v_gain: f64,
+    /// Volts
+    pub v_offset: f64,
+}
+
+impl Default for TecCalibration {
+    fn default() -> Self {
+        TecCalibration {
+            i_gain: 1.0,
+            i_offset: 0.0,
+            v_gain: 1.0,
+            v_offset: 0.0,
+        }
+    }
+}
+
+/// Condition that latches the output off until cleared
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    UnderTemperature,
+    OverTemperature,
+    /// No data from the ADC for several sample intervals
+    AdcSilent,
+    /// TEC current at its limit for longer than `overcurrent_time`
+    OverCurrent,
+}
+
 pub struct ChannelState {
     pub adc_data: Option<u32>,
+    /// Last ADC code as read, before any filtering
+    pub adc_raw: u32,
+    /// Number of raw ADC samples to take the median of, `0` for no
+    /// filtering
+    pub median_filter: u8,
+    /// Most recent raw ADC samples, newest last
+    adc_history: [u32; MEDIAN_MAX],
+    adc_history_len: usize,
     pub adc_calibration: ad7172::ChannelCalibration,
     pub adc_time: Instant,
     pub adc_interval: Duration,
+    /// Number of ADC samples since boot, wrapping around, for clients
+    /// to tell missed samples by
+    pub adc_seq: u32,
     /// i_set 0A center point
     pub center: CenterPoint,
+    /// Drift of VREF from `vref_meas` since the DAC calibration,
+    /// smoothed. `None` unless the center point is VREF.
+    pub vref_drift: Option<ElectricPotential>,
     pub dac_value: ElectricPotential,
+    /// Raw code written by `dac`, until the DAC is set from i_set again
+    pub dac_code: Option<u32>,
     pub i_set: ElectricCurrent,
     pub output_limits: OutputLimits,
     pub pid_engaged: bool,
     pub pid: pid::Controller,
     pub bp: bp::Parameters,
+    pub sh: sh::Parameters,
+    pub sensor: Sensor,
     pub polarity: Polarity,
+    /// User calibration of the temperature in degrees Celsius,
+    /// applied after the sensor conversion
+    pub cal_offset: f64,
+    pub cal_gain: f64,
+    /// Measured and reference temperature of the first point of a
+    /// two-point calibration
+    cal_point1: Option<(f64, f64)>,
+    pub tec_cal: TecCalibration,
+    /// Correction in Amperes added to `i_set` on the DAC by the slow
+    /// trim loop, `None` when the loop is disabled
+    pub i_trim: Option<f64>,
+    /// TEC voltage that i_set is adjusted to in voltage-control mode
+    pub v_set: Option<ElectricPotential>,
+    /// Target is set by the PID of the other channel, as the inner
+    /// loop of a cascade
+    pub cascade: bool,
+    /// Time constant of the exponential temperature filter in
+    /// samples, `0` or `1` for no filtering
+    pub sw_filter: u32,
+    /// Filtered temperature in degrees Celsius
+    sw_filtered: Option<f64>,
+    /// Samples accumulated for the next PID update
+    pid_samples: u32,
+    pid_temperature_sum: f64,
+    pid_interval_sum: f64,
+    /// Last PID update result, held between updates
+    pid_output: f64,
+    /// Window around the PID target to consider the temperature stable
+    pub stable_window: TemperatureInterval,
+    /// Duration for the temperature to stay within `stable_window`
+    pub stable_time: Time,
+    /// Time constant of the exponential filter of the temperature
+    /// derivative
+    pub dtdt_window: Time,
+    /// Filtered temperature derivative in K/s, and the temperature
+    /// in degrees Celsius it has last been updated with
+    dtdt: Option<f64>,
+    dtdt_temperature: Option<f64>,
+    /// Time since when the temperature is within `stable_window`
+    stable_since: Option<Instant>,
+    /// Interlock limits in degrees Celsius
+    pub t_min: Option<f64>,
+    pub t_max: Option<f64>,
+    /// Time the TEC current may stay at its limit before latching a
+    /// fault
+    pub overcurrent_time: Option<Time>,
+    /// Time since when the TEC current is at its limit
+    overcurrent_since: Option<Instant>,
+    pub fault: Option<Fault>,
+    pub sensor_fault: Option<SensorFault>,
+    /// Output held in shutdown, only the temperature is measured
+    pub sensor_only: bool,
+    /// Output limited to positive currents, for resistive heaters
+    pub heater_only: bool,
+    /// Temperature in degrees Celsius since the last reset
+    pub temperature_stats: stats::Statistics,
+    /// TEC current in Amperes since the last reset
+    pub tec_i_stats: stats::Statistics,
+    /// Electrical energy into the TEC since the last reset
+    pub tec_energy: Energy,
+    pub step_test: Option<StepTest>,
+    /// A new sensor fault is yet to be announced to clients
+    sensor_alarm: bool,
 }
 
 impl ChannelState {
     pub fn new(adc_calibration: ad7172::ChannelCalibration) -> Self {
         ChannelState {
             adc_data: None,
+            adc_raw: 0,
+            median_filter: 0,
+            adc_history: [0; MEDIAN_MAX],
+            adc_history_len: 0,
             adc_calibration,
             adc_time: Instant::from_secs(0),
             // default: 10 Hz
             adc_interval: Duration::from_millis(100),
+            adc_seq: 0,
             center: CenterPoint::VRef,
+            vref_drift: None,
             dac_value: ElectricPotential::ZERO,
+            dac_code: None,
             i_set: ElectricCurrent::ZERO,
             output_limits: OutputLimits {
                 max_v: ElectricPotential::ZERO,
@@ -64,11 +223,47 @@ impl ChannelState {
             pid_engaged: false,
             pid: pid::Controller::new(pid::Parameters::default()),
             bp: bp::Parameters::default(),
+            sh: sh::Parameters::default(),
+            sensor: Sensor::BParameter,
             polarity: Polarity::Normal,
+            cal_offset: 0.0,
+            cal_gain: 1.0,
+            cal_point1: None,
+            tec_cal: TecCalibration::default(),
+            i_trim: None,
+            v_set: None,
+            cascade: false,
+            sw_filter: 0,
+            sw_filtered: None,
+            pid_samples: 0,
+            pid_temperature_sum: 0.0,
+            pid_interval_sum: 0.0,
+            pid_output: 0.0,
+            stable_window: TemperatureInterval::new::<kelvin>(0.1),
+            stable_time: Time::new::<second>(10.0),
+            stable_since: None,
+            dtdt_window: Time::new::<second>(10.0),
+            dtdt: None,
+            dtdt_temperature: None,
+            t_min: None,
+            t_max: None,
+            overcurrent_time: None,
+            overcurrent_since: None,
+            fault: None,
+            sensor_fault: None,
+            sensor_only: false,
+            heater_only: false,
+            temperature_stats: stats::Statistics::default(),
+            tec_i_stats: stats::Statistics::default(),
+            tec_energy: Energy::ZERO,
+            step_test: None,
+            sensor_alarm: false,
         }
     }
 
     pub fn update(&mut self, now: Instant, adc_data: u32) {
+        self.adc_raw = adc_data;
+        let adc_data = self.median(adc_data);
         self.adc_data = if adc_data == ad7172::MAX_VALUE {
             // this means there is no thermistor plugged into the ADC.
             None
@@ -77,13 +272,203 @@ impl ChannelState {
         };
         self.adc_interval = now - self.adc_time;
         self.adc_time = now;
+        self.adc_seq = self.adc_seq.wrapping_add(1);
+        self.update_sensor_fault();
+        self.update_stability(now);
+        self.update_dtdt();
+    }
+
+    /// Median of the last `median_filter` raw ADC samples, to reject
+    /// single spikes
+    fn median(&mut self, adc_data: u32) -> u32 {
+        let points = usize::from(self.median_filter).min(MEDIAN_MAX);
+        if points < 3 {
+            self.adc_history_len = 0;
+            return adc_data;
+        }
+        self.adc_history.rotate_left(1);
+        self.adc_history[MEDIAN_MAX - 1] = adc_data;
+        self.adc_history_len = (self.adc_history_len + 1).min(points);
+
+        let mut samples = [0; MEDIAN_MAX];
+        let samples = &mut samples[..self.adc_history_len];
+        samples.copy_from_slice(&self.adc_history[MEDIAN_MAX - self.adc_history_len..]);
+        samples.sort_unstable();
+        samples[samples.len() / 2]
+    }
+
+    fn update_sensor_fault(&mut self) {
+        let sensor_fault = self.check_sensor();
+        if sensor_fault.is_some() && sensor_fault != self.sensor_fault {
+            self.sensor_alarm = true;
+        }
+        self.sensor_fault = sensor_fault;
+    }
+
+    fn check_sensor(&self) -> Option<SensorFault> {
+        if self.adc_data.is_none() {
+            return Some(SensorFault::Open);
+        }
+        if self.get_sens()? < SENS_SHORT {
+            return Some(SensorFault::Short);
+        }
+        let temperature = self.get_temperature()?.get::<degree_celsius>();
+        // NaN is out of range, too
+        if !(SENS_T_MIN..=SENS_T_MAX).contains(&temperature) {
+            return Some(SensorFault::OutOfRange);
+        }
+        None
+    }
+
+    /// Returns a new sensor fault once, for announcing it
+    pub fn take_sensor_alarm(&mut self) -> Option<SensorFault> {
+        if self.sensor_alarm {
+            self.sensor_alarm = false;
+            self.sensor_fault
+        } else {
+            None
+        }
+    }
+
+    fn update_dtdt(&mut self) {
+        let temperature = match self.get_temperature() {
+            Some(temperature) if self.sensor_fault.is_none() => temperature.get::<degree_celsius>(),
+            _ => {
+                self.dtdt = None;
+                self.dtdt_temperature = None;
+                return;
+            }
+        };
+        let interval = self.adc_interval.total_millis() as f64 / 1000.0;
+        if let Some(last) = self.dtdt_temperature {
+            if interval > 0.0 {
+                let dtdt = (temperature - last) / interval;
+                let alpha = (interval / self.dtdt_window.get::<second>()).min(1.0);
+                self.dtdt = Some(match self.dtdt {
+                    Some(filtered) => filtered + alpha * (dtdt - filtered),
+                    None => dtdt,
+                });
+            }
+        }
+        self.dtdt_temperature = Some(temperature);
+    }
+
+    /// Temperature derivative in K/s
+    pub fn get_dtdt(&self) -> Option<f64> {
+        self.dtdt
+    }
+
+    fn update_stability(&mut self, now: Instant) {
+        let window = self.stable_window.get::<kelvin>();
+        let within_window = match self.get_temperature() {
+            Some(temperature) if self.pid_engaged => {
+                (temperature.get::<degree_celsius>() - self.pid.target).abs() <= window
+            }
+            _ => false,
+        };
+        if !within_window {
+            self.stable_since = None;
+        } else if self.stable_since.is_none() {
+            self.stable_since = Some(now);
+        }
+    }
+
+    /// Latch a fault if the temperature is beyond the interlock
+    /// limits. Returns the fault if it is new.
+    pub fn check_interlock(&mut self) -> Option<Fault> {
+        if self.fault.is_some() || self.sensor_fault.is_some() {
+            return None;
+        }
+        let temperature = self.get_temperature()?.get::<degree_celsius>();
+        let fault = match (self.t_min, self.t_max) {
+            (Some(t_min), _) if temperature < t_min => Fault::UnderTemperature,
+            (_, Some(t_max)) if temperature > t_max => Fault::OverTemperature,
+            _ => return None,
+        };
+        self.fault = Some(fault);
+        Some(fault)
+    }
+
+    /// Latch a fault if the TEC current has been at its limit for
+    /// longer than `overcurrent_time`. Returns the fault if it is new.
+    pub fn check_overcurrent(&mut self, now: Instant, at_limit: bool) -> Option<Fault> {
+        let overcurrent_time = match self.overcurrent_time {
+            Some(overcurrent_time) if at_limit && self.fault.is_none() => overcurrent_time,
+            _ => {
+                self.overcurrent_since = None;
+                return None;
+            }
+        };
+        let since = *self.overcurrent_since.get_or_insert(now);
+        if Time::new::<millisecond>((now - since).total_millis() as f64) < overcurrent_time {
+            return None;
+        }
+        self.overcurrent_since = None;
+        self.fault = Some(Fault::OverCurrent);
+        Some(Fault::OverCurrent)
+    }
+
+    /// Temperature has been within `stable_window` of the PID target
+    /// for at least `stable_time`
+    pub fn is_stable(&self) -> bool {
+        match self.stable_since {
+            Some(since) => {
+                let duration =
+                    Time::new::<millisecond>((self.adc_time - since).total_millis() as f64);
+                duration >= self.stable_time
+            }
+            None => false,
+        }
     }
 
     /// Update PID state on ADC input, calculate new DAC output
+    ///
+    /// The PID is updated with the average of every
+    /// `pid.parameters.divisor` samples. The previous output is held
+    /// in between.
     pub fn update_pid(&mut self) -> Option<f64> {
-        let temperature = self.get_temperature()?.get::<degree_celsius>();
-        let pid_output = self.pid.update(temperature);
-        Some(pid_output)
+        let temperature = match self.get_temperature() {
+            Some(temperature) if self.sensor_fault.is_none() => temperature.get::<degree_celsius>(),
+            _ => {
+                self.sw_filtered = None;
+                self.pid_samples = 0;
+                self.pid_temperature_sum = 0.0;
+                self.pid_interval_sum = 0.0;
+                return None;
+            }
+        };
+        let temperature = self.filter_temperature(temperature);
+        self.pid_samples += 1;
+        self.pid_temperature_sum += temperature;
+        self.pid_interval_sum += self.adc_interval.total_millis() as f64 / 1000.0;
+        if self.pid_samples < self.pid.parameters.divisor.max(1) {
+            return Some(self.pid_output);
+        }
+
+        let temperature = self.pid_temperature_sum / self.pid_samples as f64;
+        let dt = self.pid_interval_sum;
+        self.pid_samples = 0;
+        self.pid_temperature_sum = 0.0;
+        self.pid_interval_sum = 0.0;
+
+        if !self.pid_engaged {
+            // Ramp from the current temperature once engaged
+            self.pid.reset_ramp();
+        }
+        self.pid_output = self.pid.update(temperature, dt);
+        Some(self.pid_output)
+    }
+
+    /// Exponential moving average over `sw_filter` samples
+    fn filter_temperature(&mut self, temperature: f64) -> f64 {
+        let filtered = match self.sw_filtered {
+            Some(filtered) if self.sw_filter > 1 => {
+                filtered + (temperature - filtered) / self.sw_filter as f64
+            }
+            _ => temperature,
+        };
+        self.sw_filtered = Some(filtered);
+        filtered
     }
 
     pub fn get_adc_time(&self) -> Time {
@@ -105,9 +490,81 @@ impl ChannelState {
         Some(r)
     }
 
+    /// Record the first point of a two-point calibration, with the
+    /// sensor at the `reference` temperature
+    pub fn cal_point1(&mut self, reference: f64) -> Result<(), CalError> {
+        let measured = self
+            .get_sensor_temperature()
+            .ok_or(CalError::NoTemperature)?
+            .get::<degree_celsius>();
+        self.cal_point1 = Some((measured, reference));
+        Ok(())
+    }
+
+    /// Complete a two-point calibration with the sensor at the second
+    /// `reference` temperature, and set `cal_gain` and `cal_offset`
+    pub fn cal_point2(&mut self, reference: f64) -> Result<(), CalError> {
+        let (measured1, reference1) = self.cal_point1.ok_or(CalError::NoPoint1)?;
+        let measured = self
+            .get_sensor_temperature()
+            .ok_or(CalError::NoTemperature)?
+            .get::<degree_celsius>();
+        if measured == measured1 {
+            return Err(CalError::SameTemperature);
+        }
+        self.cal_gain = (reference - reference1) / (measured - measured1);
+        self.cal_offset = reference1 - self.cal_gain * measured1;
+        self.cal_point1 = None;
+        Ok(())
+    }
+
     pub fn get_temperature(&self) -> Option<ThermodynamicTemperature> {
+        let temperature = self.get_sensor_temperature()?.get::<degree_celsius>();
+        let temperature = temperature * self.cal_gain + self.cal_offset;
+        Some(ThermodynamicTemperature::new::<degree_celsius>(temperature))
+    }
+
+    /// Temperature from the sensor conversion, without user calibration
+    fn get_sensor_temperature(&self) -> Option<ThermodynamicTemperature> {
         let r = self.get_sens()?;
-        let temperature = self.bp.get_temperature(r);
+        let temperature = match self.sensor {
+            Sensor::BParameter => self.bp.get_temperature(r),
+            Sensor::SteinhartHart => self.sh.get_temperature(r),
+            Sensor::Pt100 => rtd::get_temperature(rtd::PT100, r),
+            Sensor::Pt1000 => rtd::get_temperature(rtd::PT1000, r),
+        };
         Some(temperature)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pid_divisor() {
+        let calibration = ad7172::ChannelCalibration::new(0x80_0000, 0x55_5550, false);
+        let mut state = ChannelState::new(calibration);
+        state.pid.parameters.ki = 1.0;
+        state.pid.parameters.divisor = 3;
+        state.pid.target = 25.0;
+
+        let mut sum = 0.0;
+        for (i, data) in [0x80_0000, 0x81_0000, 0x82_0000].into_iter().enumerate() {
+            state.update(Instant::from_millis(100 * (i as i64 + 1)), data);
+            assert_eq!(state.sensor_fault, None);
+            sum += state.get_temperature().unwrap().get::<degree_celsius>();
+            let output = state.update_pid().unwrap();
+            if i < 2 {
+                // Held until the third sample
+                assert_eq!(output, 0.0);
+            } else {
+                // One update, with the average of the three
+                assert!((output - (sum / 3.0 - 25.0)).abs() < 1e-9);
+            }
+        }
+        let output = state.pid_output;
+        state.update(Instant::from_millis(400), 0x83_0000);
+        assert_eq!(state.update_pid(), Some(output));
+    }
+}
@@ -1,31 +1,51 @@
-use crate::timer::sleep;
+use crate::timer::{self, sleep};
 use crate::{
-    ad5680,
-    ad7172::{self, PostFilter},
+    ad7172::{self, ChannelCalibration, DataRate, DigitalFilterOrder, PostFilter},
+    adc::{self, Adc, BoardAdc},
     b_parameter,
-    channel::{Channel, Channel0, Channel1},
-    channel_state::ChannelState,
+    board::{self, McuAdc},
+    capture,
+    channel::{Channel, Channel0, Channel1, ChannelHardware},
+    channel_state::{ChannelState, Fault, SensorFault},
     command_handler::JsonBuffer,
-    command_parser::{CenterPoint, Polarity, PwmPin},
-    pins::{self, Channel0VRef, Channel1VRef},
+    command_parser::{CenterPoint, ChannelAlias, DeviceName, Polarity, PwmPin, Sensor},
+    config,
+    dac::DacChip,
+    event_log::{self, Event},
+    history,
+    interrupt::singleton,
+    pins, rtc, stats, steinhart_hart,
+    step_response::StepTest,
 };
-use core::marker::PhantomData;
-use heapless::{consts::U2, Vec};
+use core::{
+    fmt::{self, Write},
+    marker::PhantomData,
+};
+use heapless::{
+    consts::{U2, U512},
+    String, Vec,
+};
+use log::error;
+use num_traits::float::Float;
 use serde::{Serialize, Serializer};
 use smoltcp::time::Instant;
-use stm32f4xx_hal::hal;
 use uom::{
     si::{
         electric_current::ampere,
         electric_potential::{millivolt, volt},
         electrical_resistance::ohm,
-        f64::{ElectricCurrent, ElectricPotential, ElectricalResistance, Time},
+        f64::{
+            ElectricCurrent, ElectricPotential, ElectricalResistance, Energy, Power,
+            TemperatureInterval, Time,
+        },
         ratio::ratio,
         thermodynamic_temperature::degree_celsius,
+        time::{millisecond, second},
     },
     ConstZero,
 };
 
+#[derive(Clone, Copy)]
 pub enum PinsAdcReadTarget {
     VRef,
     DacVfb,
@@ -33,7 +53,157 @@ pub enum PinsAdcReadTarget {
     VTec,
 }
 
+impl PinsAdcReadTarget {
+    const ALL: [Self; 4] = [Self::VRef, Self::DacVfb, Self::ITec, Self::VTec];
+}
+
+/// Averages of VREF over which its drift is smoothed
+const VREF_DRIFT_SMOOTHING: f64 = 16.0;
+/// Reading of STM32 ADC inputs that the board does not connect
+const PINS_ADC_MIDSCALE: u16 = 2048;
+/// Conversions of the STM32 ADC inputs averaged by `poll_pins_adc()`
+const PINS_ADC_AVERAGE: u16 = 16;
+/// Conversions made by each `poll_pins_adc()`, about 25 us each at the
+/// 480 cycle sample time
+const PINS_ADC_BURST: usize = 2;
+
+/// Average of an STM32 ADC input, accumulated over main loop cycles
+/// rather than in one blocking burst
+#[derive(Clone, Copy)]
+struct PinsAdcAverage {
+    sum: ElectricPotential,
+    count: u16,
+    /// Last complete average
+    value: Option<ElectricPotential>,
+}
+
+impl PinsAdcAverage {
+    const fn new() -> Self {
+        PinsAdcAverage {
+            sum: ElectricPotential::ZERO,
+            count: 0,
+            value: None,
+        }
+    }
+
+    /// Returns whether an average has been completed
+    fn update(&mut self, sample: ElectricPotential) -> bool {
+        self.sum += sample;
+        self.count += 1;
+        if self.count >= PINS_ADC_AVERAGE {
+            self.value = Some(self.sum / f64::from(self.count));
+            self.sum = ElectricPotential::ZERO;
+            self.count = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Filter settings of an AD7172 channel, kept to restore them once the
+/// ADC has been reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AdcFilter {
+    data_rate: DataRate,
+    order: DigitalFilterOrder,
+    postfilter: Option<PostFilter>,
+}
+
+impl AdcFilter {
+    /// As set up by `Adc::setup_channel()`
+    const fn new() -> Self {
+        AdcFilter {
+            data_rate: DataRate::DEFAULT,
+            order: DigitalFilterOrder::Sinc5Sinc1,
+            postfilter: Some(PostFilter::F16SPS),
+        }
+    }
+
+    // The setters change the other settings as the ADC does
+
+    fn set_data_rate(&mut self, rate: DataRate) {
+        self.data_rate = rate;
+        self.postfilter = None;
+    }
+
+    fn set_order(&mut self, order: DigitalFilterOrder) {
+        if order != DigitalFilterOrder::Sinc5Sinc1 {
+            self.postfilter = None;
+        }
+        self.order = order;
+    }
+
+    fn set_postfilter(&mut self, filter: Option<PostFilter>) {
+        if filter.is_some() {
+            self.order = DigitalFilterOrder::Sinc5Sinc1;
+        }
+        self.postfilter = filter;
+    }
+}
+
 pub const CHANNELS: usize = 2;
+/// `CHANNELS` as a capacity of `heapless::Vec`
+type ChannelCapacity = U2;
+/// Inputs of the sensor of each channel to the ADC, positive and
+/// negative
+const ADC_INPUTS: [(ad7172::Input, ad7172::Input); CHANNELS] = [
+    (ad7172::Input::Ain2, ad7172::Input::Ain3),
+    (ad7172::Input::Ain0, ad7172::Input::Ain1),
+];
+pub type AdcError = adc::Error;
+
+/// Current to set the DAC to for `i_set` corrected by the trim loop,
+/// within the limits of the TEC driver, and never cooling on a
+/// heater-only channel
+fn dac_current(
+    i_set: ElectricCurrent,
+    i_trim: ElectricCurrent,
+    heater_only: bool,
+) -> ElectricCurrent {
+    let i_out = (i_set + i_trim).min(MAX_TEC_I).max(-MAX_TEC_I);
+    if heater_only {
+        i_out.max(ElectricCurrent::ZERO)
+    } else {
+        i_out
+    }
+}
+
+/// The other channel of the pair that `channel` can form a cascade
+/// with
+pub fn cascade_partner(channel: usize) -> usize {
+    channel ^ 1
+}
+
+/// Forward the PID output of `channel` to its i_set DAC, or to the
+/// target of the inner loop if `channel` is the outer loop of a cascade.
+///
+/// A positive output cools like a positive TEC current, so the outer
+/// output is the offset of the plate target below the outer target.
+pub fn forward_pid_output(
+    channels: &mut dyn ChannelsInterface,
+    channel: usize,
+    pid_output: Option<f64>,
+) {
+    let inner = cascade_partner(channel);
+    if channels.channel_state(inner).cascade {
+        if let Some(pid_output) = pid_output {
+            let target = channels.channel_state(channel).pid.setpoint();
+            channels.channel_state(inner).pid.target = target - pid_output;
+        }
+        return;
+    }
+    match pid_output {
+        Some(pid_output) if channels.channel_state(channel).pid_engaged => {
+            channels.set_i(channel, ElectricCurrent::new::<ampere>(pid_output));
+            channels.power_up(channel);
+        }
+        None if channels.channel_state(channel).pid_engaged => {
+            channels.power_down(channel);
+        }
+        _ => {}
+    }
+}
 const R_SENSE: ElectricalResistance = ElectricalResistance {
     dimension: PhantomData,
     units: PhantomData,
@@ -64,76 +234,520 @@ const DAC_OUT_V_MAX: ElectricPotential = ElectricPotential {
     value: 3.0,
 };
 
+/// Fraction of the i_set error corrected per ADC sample by the trim
+/// loop, about 10 s time constant at the default 10 Hz
+const I_TRIM_GAIN: f64 = 0.01;
+/// Largest correction of the trim loop in Amperes
+const I_TRIM_MAX: f64 = 0.1;
+/// Change of i_set in Amperes per Volt of TEC voltage error, for each
+/// new average of the voltage readback. Stable for loads below 40 Ω.
+const V_SET_GAIN: f64 = 0.05;
+
+/// DAC outputs checked against their feedback by `dac_self_test()`
+const DAC_SELF_TEST_V: [ElectricPotential; 2] = [
+    ElectricPotential {
+        dimension: PhantomData,
+        units: PhantomData,
+        value: 0.5,
+    },
+    ElectricPotential {
+        dimension: PhantomData,
+        units: PhantomData,
+        value: 2.5,
+    },
+];
+const DAC_SELF_TEST_TOLERANCE: ElectricPotential = ElectricPotential {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 0.1,
+};
+
+/// Current applied in both directions by `tec_check()`
+const TEC_CHECK_I: ElectricCurrent = ElectricCurrent {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 0.1,
+};
+/// Samples of the MCU temperature sensor to average
+const MCU_TEMPERATURE_AVG: u32 = 4;
+/// Samples of the MCU internal reference to average
+const VREF_AVG: u32 = 4;
+/// ADC channel sampling its analog supply, divided by
+/// `supply_divider()`
+const SUPPLY_CHANNEL: u8 = CHANNELS as u8;
+/// Output data rate of `SUPPLY_CHANNEL`, fast to keep the temperature
+/// channels from slowing down
+const SUPPLY_DATA_RATE: f32 = 1000.0;
+/// Settling time for each `tec_check()` measurement in milliseconds
+const TEC_CHECK_SETTLE: u32 = 50;
+/// Sample intervals without any ADC data before it is considered
+/// silent
+const ADC_SILENCE_PERIODS: u64 = 5;
+/// Shortest time in milliseconds without ADC data before it is
+/// considered silent, longer than the polls while idle
+const ADC_SILENCE_MIN: u64 = 2_000;
+/// Fraction of `max_i_pos` or `max_i_neg` from which the TEC current
+/// is considered clipped by the analog limit
+const OVERCURRENT_FRACTION: f64 = 0.95;
+/// Smallest TEC current considered clipped, so that limits set close
+/// to zero do not trip on noise
+const OVERCURRENT_MIN: ElectricCurrent = ElectricCurrent {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 0.05,
+};
+/// Below this resistance, a TEC is considered shorted
+const TEC_CHECK_SHORT_R: ElectricalResistance = ElectricalResistance {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 0.1,
+};
+
+/// Settling time in milliseconds of the DAC feedback after each setting
+/// tried by the DAC calibration
+const DAC_CALIBRATION_SETTLE: u32 = 10;
+/// Smallest step of the DAC calibration search, as a power of 2. The
+/// largest is half the range of the DAC.
+const DAC_SEARCH_STEP_MIN: u8 = 5;
+
+/// Search for the DAC setting whose feedback comes closest to `target`
+/// from below, in steps of decreasing powers of 2
+struct DacSearch {
+    target: ElectricPotential,
+    /// Largest code of the DAC
+    max_value: u32,
+    step: u8,
+    /// Setting to measure next, `None` once done
+    value: Option<u32>,
+    /// Closest setting so far, and its error
+    best: Option<(u32, ElectricPotential)>,
+}
+
+impl DacSearch {
+    fn new(target: ElectricPotential, max_value: u32) -> Self {
+        DacSearch {
+            target,
+            max_value,
+            step: (31 - max_value.leading_zeros()) as u8,
+            value: Some(1),
+            best: None,
+        }
+    }
+
+    /// Take the feedback measured for `value`
+    fn feedback(&mut self, dac_feedback: ElectricPotential) {
+        let value = match self.value {
+            Some(value) => value,
+            None => return,
+        };
+        let error = self.target - dac_feedback;
+        let next = if error < ElectricPotential::ZERO {
+            // Gone past the target, go on in smaller steps
+            None
+        } else {
+            if !matches!(self.best, Some((_, best_error)) if error >= best_error) {
+                self.best = Some((value, error));
+            }
+            Some(value + (1 << self.step)).filter(|&value| value <= self.max_value)
+        };
+        self.value = next.or_else(|| {
+            if self.step == DAC_SEARCH_STEP_MIN {
+                None
+            } else {
+                self.step -= 1;
+                Some(self.best.map_or(1, |(value, _)| value))
+            }
+        });
+    }
+}
+
+/// DAC calibration in progress, advanced by `poll_dac_calibration()`
+struct DacCalibration {
+    channel: usize,
+    search: DacSearch,
+    /// Time the DAC has been set to the value of `search`
+    set_time: u32,
+}
+
 pub struct Channels {
-    channel0: Channel<Channel0>,
-    channel1: Channel<Channel1>,
-    adc: ad7172::Adc<pins::AdcSpi, pins::AdcNss>,
-    /// stm32f4 integrated adc
-    pins_adc: pins::PinsAdc,
-    pwm: pins::PwmPins,
+    hardware: [&'static mut dyn ChannelHardware; CHANNELS],
+    adc: BoardAdc,
+    /// ADC of the MCU
+    pins_adc: board::PinsAdc,
+    supply_calibration: ChannelCalibration,
+    /// Last measurement of the 5 V rail
+    supply_5v: Option<ElectricPotential>,
+    /// Reports not sent for lack of room in the send buffer
+    reports_dropped: u32,
+    /// Indexed by channel and `PinsAdcReadTarget`
+    pins_adc_average: [[PinsAdcAverage; 4]; CHANNELS],
+    /// Input to convert next in `poll_pins_adc()`
+    pins_adc_next: usize,
+    dac_calibration: Option<DacCalibration>,
+    adc_filters: [AdcFilter; CHANNELS],
+    /// Time of the last sample from any ADC channel
+    adc_last_sample: Option<Instant>,
+    /// Whether the ADC has been set up since boot, and the calibrations
+    /// read from it
+    adc_setup: bool,
 }
 
 impl Channels {
-    pub fn new(pins: pins::Pins) -> Self {
-        let mut adc = ad7172::Adc::new(pins.adc_spi, pins.adc_nss).unwrap();
-        // Feature not used
-        adc.set_sync_enable(false).unwrap();
-
-        // Setup channels and start ADC
-        adc.setup_channel(0, ad7172::Input::Ain2, ad7172::Input::Ain3)
-            .unwrap();
-        let adc_calibration0 = adc.get_calibration(0).expect("adc_calibration0");
-        adc.setup_channel(1, ad7172::Input::Ain0, ad7172::Input::Ain1)
-            .unwrap();
-        let adc_calibration1 = adc.get_calibration(1).expect("adc_calibration1");
-        adc.start_continuous_conversion().unwrap();
-
-        let channel0 = Channel::new(pins.channel0, adc_calibration0);
-        let channel1 = Channel::new(pins.channel1, adc_calibration1);
-        let pins_adc = pins.pins_adc;
-        let pwm = pins.pwm;
+    /// Calibrate the DAC of each channel for which no `vref_meas` has
+    /// been saved
+    pub fn new(
+        pins: pins::Pins,
+        dac_chip: DacChip,
+        vref_meas: [Option<ElectricPotential>; CHANNELS],
+    ) -> Self {
+        let mut adc = BoardAdc::detect(pins.adc_spi, pins.adc_nss).unwrap_or_else(|e| {
+            error!("no ADC found: {:?}", e);
+            BoardAdc::Missing
+        });
+        // Without the ADC, the channels start with a latched fault, and
+        // check_adc_silence() keeps trying to set it up
+        let (adc_calibrations, supply_calibration, adc_setup) = match Self::start_adc(&mut adc) {
+            Ok((adc_calibrations, supply_calibration)) => {
+                (adc_calibrations, supply_calibration, true)
+            }
+            Err(e) => {
+                error!("unable to set up the ADC: {:?}", e);
+                Default::default()
+            }
+        };
+        let [adc_calibration0, adc_calibration1] = adc_calibrations;
+        // Statics for the channels of different pin types to share an
+        // array
+        let hardware: [&'static mut dyn ChannelHardware; CHANNELS] = [
+            singleton!(: Channel<Channel0> =
+                Channel::new(pins.channel0, dac_chip, adc_calibration0))
+            .unwrap(),
+            singleton!(: Channel<Channel1> =
+                Channel::new(pins.channel1, dac_chip, adc_calibration1))
+            .unwrap(),
+        ];
+
+        let mut pins_adc = pins.pins_adc;
+        pins_adc.enable_internal();
         let mut channels = Channels {
-            channel0,
-            channel1,
+            hardware,
             adc,
             pins_adc,
-            pwm,
+            supply_calibration,
+            supply_5v: None,
+            reports_dropped: 0,
+            pins_adc_average: [[PinsAdcAverage::new(); 4]; CHANNELS],
+            pins_adc_next: 0,
+            dac_calibration: None,
+            adc_filters: [AdcFilter::new(); CHANNELS],
+            adc_last_sample: None,
+            adc_setup,
         };
-        for channel in 0..CHANNELS {
-            channels.calibrate_dac_value(channel);
+        for (channel, vref_meas) in vref_meas.iter().enumerate() {
+            if !adc_setup {
+                event_log::record(Event::fault(channel, Fault::AdcSilent));
+                channels.channel_state(channel).fault = Some(Fault::AdcSilent);
+            }
+            match vref_meas {
+                Some(vref_meas) => channels.set_vref_meas(channel, *vref_meas),
+                None => channels.calibrate_dac_value(channel),
+            }
             channels.set_i(channel, ElectricCurrent::ZERO);
         }
         channels
     }
 
-    pub fn channel_state<I: Into<usize>>(&mut self, channel: I) -> &mut ChannelState {
-        match channel.into() {
-            0 => &mut self.channel0.state,
-            1 => &mut self.channel1.state,
-            _ => unreachable!(),
+    /// Set up the ADC channels, before starting conversion
+    fn setup_adc(adc: &mut BoardAdc) -> Result<(), AdcError> {
+        // Feature not used
+        adc.set_sync_enable(false)?;
+        for (channel, (in_pos, in_neg)) in ADC_INPUTS.iter().enumerate() {
+            adc.setup_channel(channel as u8, *in_pos, *in_neg)?;
+        }
+        adc.setup_channel(
+            SUPPLY_CHANNEL,
+            ad7172::Input::AnalogSupplyPos,
+            ad7172::Input::AnalogSupplyNeg,
+        )?;
+        adc.set_data_rate(
+            SUPPLY_CHANNEL,
+            DataRate::closest(SUPPLY_DATA_RATE, DigitalFilterOrder::Sinc5Sinc1),
+        )
+    }
+
+    /// Set up the ADC and start conversion. Returns the calibrations of
+    /// the channels and of the supply.
+    fn start_adc(
+        adc: &mut BoardAdc,
+    ) -> Result<([ChannelCalibration; CHANNELS], ChannelCalibration), AdcError> {
+        Self::setup_adc(adc)?;
+        let adc_calibrations = [adc.get_calibration(0)?, adc.get_calibration(1)?];
+        let supply_calibration = adc.get_calibration(SUPPLY_CHANNEL)?;
+        adc.start_continuous_conversion()?;
+        Ok((adc_calibrations, supply_calibration))
+    }
+
+    /// Reset the ADC and set it up again, with the calibrations and
+    /// filter settings in use
+    fn reinit_adc(&mut self) -> Result<(), AdcError> {
+        self.adc.reinit()?;
+        Self::setup_adc(&mut self.adc)?;
+        if !self.adc_setup {
+            // Not set up at boot, so use the calibrations of the ADC
+            for channel in 0..CHANNELS {
+                self.channel_state(channel).adc_calibration =
+                    self.adc.get_calibration(channel as u8)?;
+            }
+            self.supply_calibration = self.adc.get_calibration(SUPPLY_CHANNEL)?;
+            self.adc_setup = true;
+        }
+        for channel in 0..CHANNELS {
+            let index = channel as u8;
+            let calibration = self.channel_state(channel).adc_calibration.clone();
+            self.adc.set_calibration(index, &calibration)?;
+            let filter = self.adc_filters[channel];
+            self.adc.set_data_rate(index, filter.data_rate)?;
+            self.adc.set_filter_order(index, filter.order)?;
+            if filter.postfilter.is_some() {
+                self.adc.set_postfilter(index, filter.postfilter)?;
+            }
+        }
+        let supply_calibration = self.supply_calibration.clone();
+        self.adc
+            .set_calibration(SUPPLY_CHANNEL, &supply_calibration)?;
+        self.adc.start_continuous_conversion()
+    }
+
+    /// Check that the ADC has delivered samples within the last few
+    /// sample intervals. Otherwise the outputs are shut down with a
+    /// latched fault, and the ADC is set up again.
+    pub fn check_adc_silence(&mut self, instant: Instant) {
+        let last_sample = *self.adc_last_sample.get_or_insert(instant);
+        let interval = (0..CHANNELS)
+            .map(|channel| self.channel_state(channel).adc_interval.total_millis())
+            .max()
+            .unwrap_or(0);
+        let silence = (instant - last_sample).total_millis();
+        if silence <= (ADC_SILENCE_PERIODS * interval).max(ADC_SILENCE_MIN) {
+            return;
+        }
+
+        error!("no ADC data for {} ms, resetting it", silence);
+        for channel in 0..CHANNELS {
+            if self.channel_state(channel).fault != Some(Fault::AdcSilent) {
+                event_log::record(Event::fault(channel, Fault::AdcSilent));
+            }
+            self.shut_down(channel);
+            self.channel_state(channel).fault = Some(Fault::AdcSilent);
         }
+        if let Err(e) = self.reinit_adc() {
+            error!("unable to set up the ADC again: {:?}", e);
+        }
+        // As long again before the next attempt
+        self.adc_last_sample = Some(instant);
+    }
+
+    /// Whether the fault of a silent ADC is latched on any channel
+    pub fn adc_silent(&mut self) -> bool {
+        (0..CHANNELS).any(|channel| self.channel_state(channel).fault == Some(Fault::AdcSilent))
+    }
+
+    pub fn channel_state<I: Into<usize>>(&mut self, channel: I) -> &mut ChannelState {
+        self.hardware[channel.into()].state()
     }
 
     /// ADC input + PID processing
     pub fn poll_adc(&mut self, instant: Instant) -> Option<u8> {
-        self.adc.data_ready().unwrap().map(|channel| {
-            let data = self.adc.read_data().unwrap();
+        let channel = match self.adc.data_ready() {
+            Ok(channel) => channel,
+            Err(e) => {
+                error!("ADC status: {:?}", e);
+                None
+            }
+        };
+        channel.and_then(|channel| {
+            // Skip corrupted samples rather than using them
+            let data = match self.adc.read_data() {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("channel {} ADC data: {:?}", channel, e);
+                    return None;
+                }
+            };
+            self.adc_last_sample = Some(instant);
+            if channel == SUPPLY_CHANNEL {
+                self.supply_5v =
+                    Some(self.adc.supply_divider() * self.supply_calibration.convert_data(data));
+                return None;
+            }
+            capture::update_adc(channel.into(), instant.total_millis() as u32, data);
             let state = self.channel_state(channel);
+            let sensor_fault = state.sensor_fault;
             state.update(instant, data);
-            match state.update_pid() {
-                Some(pid_output) if state.pid_engaged => {
-                    // Forward PID output to i_set DAC
-                    self.set_i(channel.into(), ElectricCurrent::new::<ampere>(pid_output));
-                    self.power_up(channel);
+            match state.sensor_fault {
+                Some(fault) if state.sensor_fault != sensor_fault => {
+                    event_log::record(Event::sensor_fault(channel.into(), fault))
+                }
+                _ => {}
+            }
+            if let Some(fault) = state.check_interlock() {
+                error!("channel {} interlock: {:?}", channel, fault);
+                event_log::record(Event::fault(channel.into(), fault));
+                self.shut_down(channel.into());
+            }
+            self.check_overcurrent(channel.into(), instant);
+            self.update_stats(channel.into(), instant);
+            self.update_i_trim(channel.into());
+            self.update_step_test(channel.into(), instant);
+            let pid_output = self.channel_state(channel).update_pid();
+            forward_pid_output(self, channel.into(), pid_output);
+
+            Some(channel)
+        })
+    }
+
+    /// Step the i_set trim loop towards the commanded current, while
+    /// the output is driven by the PID and not clipping. Held otherwise,
+    /// not to wind up.
+    fn update_i_trim(&mut self, channel: usize) {
+        let state = self.channel_state(channel);
+        let i_trim = match state.i_trim {
+            Some(i_trim) => i_trim,
+            None => return,
+        };
+        if !state.pid_engaged
+            || state.fault.is_some()
+            || !self.is_powered(channel)
+            || self.clipping(channel)
+        {
+            return;
+        }
+        let error = (self.get_i_set(channel) - self.get_tec_i(channel)).get::<ampere>();
+        let i_trim = (i_trim + I_TRIM_GAIN * error).clamp(-I_TRIM_MAX, I_TRIM_MAX);
+        self.channel_state(channel).i_trim = Some(i_trim);
+        let i_set = self.get_i_set(channel);
+        self.set_i(channel, i_set);
+    }
+
+    /// Step i_set towards the commanded TEC voltage in voltage-control
+    /// mode, on each new average of the voltage readback
+    fn update_v_set(&mut self, channel: usize) {
+        let v_set = match self.channel_state(channel).v_set {
+            Some(v_set) => v_set,
+            None => return,
+        };
+        if !self.is_powered(channel) || self.channel_state(channel).fault.is_some() {
+            return;
+        }
+        let error = (v_set - self.get_tec_v(channel)).get::<volt>();
+        let i_set = self.get_i_set(channel) + ElectricCurrent::new::<ampere>(V_SET_GAIN * error);
+        // Not to wind up beyond what the output limits let through
+        let i_set = i_set
+            .min(self.get_max_i_pos(channel))
+            .max(-self.get_max_i_neg(channel));
+        self.set_i(channel, i_set);
+    }
+
+    /// Enable or disable the i_set trim loop, starting from no
+    /// correction
+    pub fn set_i_trim(&mut self, channel: usize, enabled: bool) {
+        self.channel_state(channel).i_trim = if enabled { Some(0.0) } else { None };
+        let i_set = self.get_i_set(channel);
+        self.set_i(channel, i_set);
+    }
+
+    /// Latch `Fault::OverCurrent` once the TEC current has been at
+    /// its limit for longer than `overcurrent_time`
+    fn check_overcurrent(&mut self, channel: usize, instant: Instant) {
+        // Spare the pins ADC readings while disabled
+        let at_limit = self.channel_state(channel).overcurrent_time.is_some()
+            && self.is_powered(channel)
+            && self.tec_i_at_limit(channel);
+        if let Some(fault) = self
+            .channel_state(channel)
+            .check_overcurrent(instant, at_limit)
+        {
+            error!("channel {} interlock: {:?}", channel, fault);
+            event_log::record(Event::fault(channel, fault));
+            self.shut_down(channel);
+        }
+    }
+
+    fn tec_i_at_limit(&mut self, channel: usize) -> bool {
+        let tec_i = self.get_tec_i(channel);
+        let limit = if tec_i > ElectricCurrent::ZERO {
+            self.get_max_i_pos(channel)
+        } else {
+            self.get_max_i_neg(channel)
+        };
+        tec_i.abs() >= OVERCURRENT_MIN && tec_i.abs() >= limit * OVERCURRENT_FRACTION
+    }
+
+    fn update_stats(&mut self, channel: usize, instant: Instant) {
+        let tec_i = self.get_tec_i(channel);
+        let tec_power = tec_i * self.get_tec_v(channel);
+        let tec_i = tec_i.get::<ampere>();
+        let state = self.channel_state(channel);
+        state.tec_energy += tec_power * state.get_adc_interval();
+        match state.get_temperature() {
+            Some(temperature) if state.sensor_fault.is_none() => {
+                let temperature = temperature.get::<degree_celsius>();
+                state.temperature_stats.update(temperature);
+                history::update(channel, instant.total_millis() as u32, temperature);
+                capture::update_temperature(channel, instant.total_millis() as u32, temperature);
+            }
+            _ => {}
+        }
+        state.tec_i_stats.update(tec_i);
+    }
+
+    pub fn reset_stats(&mut self) {
+        for channel in 0..CHANNELS {
+            let state = self.channel_state(channel);
+            state.temperature_stats = stats::Statistics::default();
+            state.tec_i_stats = stats::Statistics::default();
+        }
+    }
+
+    pub fn step_test_running(&mut self) -> bool {
+        (0..CHANNELS).any(|channel| self.channel_state(channel).step_test.is_some())
+    }
+
+    fn update_step_test(&mut self, channel: usize, instant: Instant) {
+        let now = instant.total_millis() as u32;
+        let test = match self.channel_state(channel).step_test.as_mut() {
+            Some(test) => test,
+            None => return,
+        };
+        match test.start {
+            None => {
+                // The first sample has been captured before the step
+                test.start = Some(now);
+                let i_set = test.i_set + test.delta_i;
+                self.set_i(channel, i_set);
+                self.power_up(channel);
+            }
+            Some(start) if now.wrapping_sub(start) >= test.duration => {
+                let test = self.channel_state(channel).step_test.take().unwrap();
+                capture::stop();
+                if self.channel_state(channel).fault.is_some() {
+                    // Shut down meanwhile
+                    return;
                 }
-                None if state.pid_engaged => {
+                self.set_i(channel, test.i_set);
+                if !test.powered {
                     self.power_down(channel);
                 }
-                _ => {}
+                self.channel_state(channel).pid_engaged = test.pid_engaged;
             }
+            Some(_) => {}
+        }
+    }
 
-            channel
-        })
+    pub fn reset_energy(&mut self) {
+        for channel in 0..CHANNELS {
+            self.channel_state(channel).tec_energy = Energy::ZERO;
+        }
     }
 
     /// calculate the TEC i_set centerpoint
@@ -159,33 +773,53 @@ impl Channels {
 
     /// i_set DAC
     fn set_dac(&mut self, channel: usize, voltage: ElectricPotential) -> ElectricPotential {
-        let value = ((voltage / DAC_OUT_V_MAX).get::<ratio>() * (ad5680::MAX_VALUE as f64)) as u32;
-        match channel {
-            0 => self.channel0.dac.set(value).unwrap(),
-            1 => self.channel1.dac.set(value).unwrap(),
-            _ => unreachable!(),
-        };
-        self.channel_state(channel).dac_value = voltage;
+        // The DAC calibration drives the DAC until it is done, and then
+        // applies i_set again
+        if !self.dac_calibration_running(channel) {
+            let max_value = self.dac_max_value(channel);
+            let value = ((voltage / DAC_OUT_V_MAX).get::<ratio>() * (max_value as f64)) as u32;
+            self.set_dac_value(channel, value);
+        }
+        let state = self.channel_state(channel);
+        state.dac_value = voltage;
+        state.dac_code = None;
         voltage
     }
 
+    /// Largest code of the DAC of `channel`
+    pub fn dac_max_value(&self, channel: usize) -> u32 {
+        self.hardware[channel].dac_max_value()
+    }
+
+    /// DAC fitted for `channel`, as set up at boot
+    pub fn dac_chip(&self, channel: usize) -> DacChip {
+        self.hardware[channel].dac_chip()
+    }
+
+    fn set_dac_value(&mut self, channel: usize, value: u32) {
+        self.hardware[channel].set_dac(value);
+    }
+
     pub fn set_i(&mut self, channel: usize, i_set: ElectricCurrent) -> ElectricCurrent {
         let i_set = i_set.min(MAX_TEC_I).max(-MAX_TEC_I);
+        let i_set = if self.channel_state(channel).heater_only {
+            i_set.max(ElectricCurrent::ZERO)
+        } else {
+            i_set
+        };
         self.channel_state(channel).i_set = i_set;
         let negate = match self.channel_state(channel).polarity {
             Polarity::Normal => 1.0,
             Polarity::Reversed => -1.0,
         };
-        let vref_meas = match channel {
-            0 => self.channel0.vref_meas,
-            1 => self.channel1.vref_meas,
-            _ => unreachable!(),
-        };
-        let center_point = vref_meas;
-        let voltage = negate * i_set * 10.0 * R_SENSE + center_point;
+        let center_point = self.get_vref_meas(channel);
+        let state = self.channel_state(channel);
+        let i_trim = ElectricCurrent::new::<ampere>(state.i_trim.unwrap_or(0.0));
+        let i_out = dac_current(i_set, i_trim, state.heater_only);
+        let voltage = negate * i_out * 10.0 * R_SENSE + center_point;
         let voltage = self.set_dac(channel, voltage);
 
-        negate * (voltage - center_point) / (10.0 * R_SENSE)
+        negate * (voltage - center_point) / (10.0 * R_SENSE) - i_trim
     }
 
     /// AN4073: ADC Reading Dispersion can be reduced through Averaging
@@ -195,108 +829,90 @@ impl Channels {
         adc_read_target: PinsAdcReadTarget,
         avg_pt: u16,
     ) -> ElectricPotential {
+        let hardware = &self.hardware[channel];
         let mut sample: u32 = 0;
-        match channel {
-            0 => {
-                sample = match adc_read_target {
-                    PinsAdcReadTarget::VRef => match &self.channel0.vref_pin {
-                        Channel0VRef::Analog(vref_pin) => {
-                            for _ in (0..avg_pt).rev() {
-                                sample += self.pins_adc.convert(
-                                    vref_pin,
-                                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                                ) as u32;
-                            }
-                            sample / avg_pt as u32
-                        }
-                        Channel0VRef::Disabled(_) => 2048_u32,
-                    },
-                    PinsAdcReadTarget::DacVfb => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel0.dac_feedback_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                    PinsAdcReadTarget::ITec => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel0.itec_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                    PinsAdcReadTarget::VTec => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel0.tec_u_meas_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                };
-                let mv = self.pins_adc.sample_to_millivolts(sample as u16);
-                ElectricPotential::new::<millivolt>(mv as f64)
-            }
-            1 => {
-                sample = match adc_read_target {
-                    PinsAdcReadTarget::VRef => match &self.channel1.vref_pin {
-                        Channel1VRef::Analog(vref_pin) => {
-                            for _ in (0..avg_pt).rev() {
-                                sample += self.pins_adc.convert(
-                                    vref_pin,
-                                    stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                                ) as u32;
-                            }
-                            sample / avg_pt as u32
-                        }
-                        Channel1VRef::Disabled(_) => 2048_u32,
-                    },
-                    PinsAdcReadTarget::DacVfb => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel1.dac_feedback_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                    PinsAdcReadTarget::ITec => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel1.itec_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                    PinsAdcReadTarget::VTec => {
-                        for _ in (0..avg_pt).rev() {
-                            sample += self.pins_adc.convert(
-                                &self.channel1.tec_u_meas_pin,
-                                stm32f4xx_hal::adc::config::SampleTime::Cycles_480,
-                            ) as u32;
-                        }
-                        sample / avg_pt as u32
-                    }
-                };
-                let mv = self.pins_adc.sample_to_millivolts(sample as u16);
-                ElectricPotential::new::<millivolt>(mv as f64)
+        for _ in 0..avg_pt {
+            // Inputs not connected read as mid-scale
+            sample += hardware
+                .convert(&mut self.pins_adc, adc_read_target)
+                .unwrap_or(PINS_ADC_MIDSCALE) as u32;
+        }
+        let sample = sample / avg_pt as u32;
+        let mv = self.pins_adc.millivolts(sample as u16);
+        ElectricPotential::new::<millivolt>(mv as f64)
+    }
+
+    /// Convert the next few STM32 ADC inputs into their averages, to
+    /// be called from the main loop. Readings in reports and stats are
+    /// taken from these averages, so that a `report` does not block
+    /// the loop for the conversions.
+    pub fn poll_pins_adc(&mut self) {
+        let targets = PinsAdcReadTarget::ALL.len();
+        for _ in 0..PINS_ADC_BURST {
+            let channel = self.pins_adc_next / targets;
+            let target = self.pins_adc_next % targets;
+            let target = PinsAdcReadTarget::ALL[target];
+            let sample = self.adc_read(channel, target, 1);
+            let averaged = self.pins_adc_average[channel][target as usize].update(sample);
+            self.pins_adc_next = (self.pins_adc_next + 1) % (CHANNELS * targets);
+            match target {
+                PinsAdcReadTarget::VTec if averaged => self.update_v_set(channel),
+                PinsAdcReadTarget::VRef if averaged => self.update_vref_drift(channel),
+                _ => {}
             }
-            _ => unreachable!(),
         }
     }
 
+    /// Compare the VREF average with `vref_meas` without applying it,
+    /// as the DAC calibration would. The DAC setting in use is taken
+    /// off the DAC feedback, which leaves `vref_meas` as measured by
+    /// the same ADC, so that its offset cancels out.
+    fn update_vref_drift(&mut self, channel: usize) {
+        let averages = &self.pins_adc_average[channel];
+        let vref = averages[PinsAdcReadTarget::VRef as usize].value;
+        let dac_feedback = averages[PinsAdcReadTarget::DacVfb as usize].value;
+        let measurable =
+            self.hardware[channel].vref_connected() && !self.dac_calibration_running(channel);
+        let vref_meas = self.get_vref_meas(channel);
+        let state = self.channel_state(channel);
+        if !measurable || state.center != CenterPoint::VRef {
+            state.vref_drift = None;
+            return;
+        }
+        if let (Some(vref), Some(dac_feedback)) = (vref, dac_feedback) {
+            let drift = vref_drift(vref, dac_feedback, state.dac_value, vref_meas);
+            state.vref_drift = Some(match state.vref_drift {
+                Some(smoothed) => smoothed + (drift - smoothed) / VREF_DRIFT_SMOOTHING,
+                None => drift,
+            });
+        }
+    }
+
+    /// Averaged STM32 ADC input, measured on the spot until
+    /// `poll_pins_adc()` has completed an average
+    fn pins_adc_average(
+        &mut self,
+        channel: usize,
+        adc_read_target: PinsAdcReadTarget,
+    ) -> ElectricPotential {
+        match self.pins_adc_average[channel][adc_read_target as usize].value {
+            Some(value) => value,
+            None => self.adc_read(channel, adc_read_target, PINS_ADC_AVERAGE),
+        }
+    }
+
+    /// Discard the averages of `channel` after a change of its output,
+    /// so that the next readings are measured on the spot
+    fn reset_pins_adc_average(&mut self, channel: usize) {
+        self.pins_adc_average[channel] = [PinsAdcAverage::new(); 4];
+    }
+
     /// Calibrates the DAC output to match vref of the MAX driver to reduce zero-current offset of the MAX driver output.
     ///
     /// The thermostat DAC applies a control voltage signal to the CTLI pin of MAX driver chip to control its output current.
     /// The CTLI input signal is centered around VREF of the MAX chip. Applying VREF to CTLI sets the output current to 0.
     ///
-    /// This calibration routine measures the VREF voltage and the DAC output with the STM32 ADC, and uses a breadth-first     
+    /// This calibration routine measures the VREF voltage and the DAC output with the STM32 ADC, and uses a breadth-first
     /// search to find the DAC setting that will produce a DAC output voltage closest to VREF. This DAC output voltage will
     /// be stored and used in subsequent i_set routines to bias the current control signal to the measured VREF, reducing
     /// the offset error of the current control signal.
@@ -304,70 +920,212 @@ impl Channels {
     /// The input offset of the STM32 ADC is eliminated by using the same ADC for the measurements, and by only using the
     /// difference in VREF and DAC output for the calibration.
     ///
-    /// This routine should be called only once after boot, repeated reading of the vref signal and changing of the stored
-    /// VREF measurement can introduce significant noise at the current output, degrading the stabilily performance of the
-    /// thermostat.
+    /// This routine should be called only once after boot, or with the output powered down, repeated reading of the
+    /// vref signal and changing of the stored VREF measurement can introduce significant noise at the current output,
+    /// degrading the stabilily performance of the thermostat.
+    ///
+    /// Blocks until done, for use at boot. At runtime, the search is run
+    /// from the main loop by `start_dac_calibration()`.
     pub fn calibrate_dac_value(&mut self, channel: usize) {
+        self.start_dac_calibration(channel);
+        while self.poll_dac_calibration().is_none() {}
+    }
+
+    /// Start the search of `calibrate_dac_value()` on `channel`, to be
+    /// advanced by `poll_dac_calibration()`. The output stays off until
+    /// it is done.
+    pub fn start_dac_calibration(&mut self, channel: usize) {
         let samples = 50;
         let mut target_voltage = ElectricPotential::ZERO;
         for _ in 0..samples {
             target_voltage += self.get_center(channel);
         }
         target_voltage /= samples as f64;
-        let mut start_value = 1;
-        let mut best_error = ElectricPotential::new::<volt>(100.0);
-
-        for step in (5..18).rev() {
-            for value in (start_value..=ad5680::MAX_VALUE).step_by(1 << step) {
-                match channel {
-                    0 => {
-                        self.channel0.dac.set(value).unwrap();
-                    }
-                    1 => {
-                        self.channel1.dac.set(value).unwrap();
-                    }
-                    _ => unreachable!(),
-                }
-                sleep(10);
-
-                let dac_feedback = self.adc_read(channel, PinsAdcReadTarget::DacVfb, 64);
-                let error = target_voltage - dac_feedback;
-                if error < ElectricPotential::ZERO {
-                    break;
-                } else if error < best_error {
-                    best_error = error;
-                    start_value = value;
-
-                    let vref = (value as f64 / ad5680::MAX_VALUE as f64) * DAC_OUT_V_MAX;
-                    match channel {
-                        0 => self.channel0.vref_meas = vref,
-                        1 => self.channel1.vref_meas = vref,
-                        _ => unreachable!(),
-                    }
-                }
-            }
+        let search = DacSearch::new(target_voltage, self.dac_max_value(channel));
+        if let Some(value) = search.value {
+            self.set_dac_value(channel, value);
         }
+        self.dac_calibration = Some(DacCalibration {
+            channel,
+            search,
+            set_time: timer::now(),
+        });
+    }
 
-        // Reset
-        self.set_dac(channel, ElectricPotential::ZERO);
+    /// Whether a DAC calibration is running on `channel`
+    pub fn dac_calibration_running(&self, channel: usize) -> bool {
+        matches!(&self.dac_calibration, Some(calibration) if calibration.channel == channel)
     }
 
-    // power up TEC
-    pub fn power_up<I: Into<usize>>(&mut self, channel: I) {
-        match channel.into() {
-            0 => self.channel0.power_up(),
-            1 => self.channel1.power_up(),
-            _ => unreachable!(),
+    pub fn any_dac_calibration_running(&self) -> bool {
+        self.dac_calibration.is_some()
+    }
+
+    /// Measure the DAC setting of a running calibration once it has
+    /// settled, and try the next one. Returns the channel and its new
+    /// `vref_meas` when the search is done.
+    pub fn poll_dac_calibration(&mut self) -> Option<(usize, ElectricPotential)> {
+        let mut calibration = self.dac_calibration.take()?;
+        let channel = calibration.channel;
+        if timer::now().wrapping_sub(calibration.set_time) <= DAC_CALIBRATION_SETTLE {
+            self.dac_calibration = Some(calibration);
+            return None;
+        }
+
+        let dac_feedback = self.adc_read(channel, PinsAdcReadTarget::DacVfb, 64);
+        calibration.search.feedback(dac_feedback);
+        if let Some(value) = calibration.search.value {
+            self.set_dac_value(channel, value);
+            calibration.set_time = timer::now();
+            self.dac_calibration = Some(calibration);
+            return None;
+        }
+
+        if let Some((value, _)) = calibration.search.best {
+            let vref = (value as f64 / self.dac_max_value(channel) as f64) * DAC_OUT_V_MAX;
+            self.set_vref_meas(channel, vref);
+        }
+        let i_set = self.get_i_set(channel);
+        self.set_i(channel, i_set);
+        self.reset_pins_adc_average(channel);
+        Some((channel, self.get_vref_meas(channel)))
+    }
+
+    /// DAC setting found for the i_set 0A center point by
+    /// `calibrate_dac_value()`
+    pub fn get_vref_meas(&self, channel: usize) -> ElectricPotential {
+        self.hardware[channel].vref_meas()
+    }
+
+    /// Use a `vref_meas` saved from an earlier calibration
+    pub fn set_vref_meas(&mut self, channel: usize, vref_meas: ElectricPotential) {
+        self.hardware[channel].set_vref_meas(vref_meas);
+        self.channel_state(channel).vref_drift = None;
+    }
+
+    /// Whether the ADC identifies itself over SPI
+    pub fn adc_self_test(&mut self) -> bool {
+        self.adc.check_id()
+    }
+
+    /// Whether the DAC feedback follows `DAC_SELF_TEST_V`. Skipped
+    /// with `None` while the output is powered. The DAC setting is
+    /// restored afterwards.
+    pub fn dac_self_test(&mut self, channel: usize) -> Option<bool> {
+        if self.is_powered(channel) {
+            return None;
+        }
+        let dac_value = self.channel_state(channel).dac_value;
+        let pass = DAC_SELF_TEST_V.iter().all(|&voltage| {
+            let voltage = self.set_dac(channel, voltage);
+            sleep(10);
+            let dac_feedback = self.adc_read(channel, PinsAdcReadTarget::DacVfb, 16);
+            (dac_feedback - voltage).abs() < DAC_SELF_TEST_TOLERANCE
+        });
+        self.set_dac(channel, dac_value);
+        Some(pass)
+    }
+
+    /// Deviation of the averaged DAC feedback from the DAC setting.
+    /// `None` until an average has been taken, and while a DAC
+    /// calibration drives the DAC.
+    pub fn dac_feedback_deviation(&mut self, channel: usize) -> Option<ElectricPotential> {
+        if self.dac_calibration_running(channel) {
+            return None;
+        }
+        let dac_feedback =
+            self.pins_adc_average[channel][PinsAdcReadTarget::DacVfb as usize].value?;
+        Some((dac_feedback - self.channel_state(channel).dac_value).abs())
+    }
+
+    /// Write a raw code to the DAC of a channel, and measure the DAC
+    /// output after `DAC_CALIBRATION_SETTLE`. The code stays until the
+    /// output is set again, or powered up.
+    pub fn set_dac_code(&mut self, channel: usize, code: u32) -> ElectricPotential {
+        let max_value = self.dac_max_value(channel);
+        let code = code.min(max_value);
+        self.set_dac_value(channel, code);
+        let state = self.channel_state(channel);
+        state.dac_value = DAC_OUT_V_MAX * (code as f64 / max_value as f64);
+        state.dac_code = Some(code);
+        self.reset_pins_adc_average(channel);
+        sleep(DAC_CALIBRATION_SETTLE);
+        self.adc_read(channel, PinsAdcReadTarget::DacVfb, PINS_ADC_AVERAGE)
+    }
+
+    /// Measure the TEC resistance with a small current applied in
+    /// both directions, which cancels out its Seebeck voltage. Blocks
+    /// for about `2 * TEC_CHECK_SETTLE` ms. The previous output
+    /// settings are restored afterwards.
+    fn tec_check(&mut self, channel: usize) -> TecCheck {
+        let i_set = self.get_i_set(channel);
+        let pid_engaged = self.channel_state(channel).pid_engaged;
+        let powered = self.is_powered(channel);
+        self.channel_state(channel).pid_engaged = false;
+
+        self.set_i(channel, TEC_CHECK_I);
+        self.power_up(channel);
+        sleep(TEC_CHECK_SETTLE);
+        self.reset_pins_adc_average(channel);
+        let i_pos = self.get_tec_i(channel);
+        let v_pos = self.get_tec_v(channel);
+        self.set_i(channel, -TEC_CHECK_I);
+        sleep(TEC_CHECK_SETTLE);
+        self.reset_pins_adc_average(channel);
+        let i_neg = self.get_tec_i(channel);
+        let v_neg = self.get_tec_v(channel);
+
+        self.set_i(channel, i_set);
+        if !powered {
+            self.power_down(channel);
+        }
+        self.channel_state(channel).pid_engaged = pid_engaged;
+        self.reset_pins_adc_average(channel);
+
+        let delta_i = i_pos - i_neg;
+        let (resistance, status) = if delta_i.abs() < TEC_CHECK_I {
+            // Less than half of the test current flows
+            (None, TecStatus::Open)
+        } else {
+            // Sign depends on polarity
+            let resistance = ((v_pos - v_neg) / delta_i).abs();
+            let status = if resistance < TEC_CHECK_SHORT_R {
+                TecStatus::Short
+            } else {
+                TecStatus::Ok
+            };
+            (Some(resistance), status)
+        };
+        TecCheck {
+            channel,
+            i_pos,
+            i_neg,
+            v_pos,
+            v_neg,
+            resistance,
+            status,
         }
     }
 
+    pub fn tec_check_json(
+        &mut self,
+        channel: usize,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let check = self.tec_check(channel);
+        serde_json_core::to_vec(&check)
+    }
+
     // power down TEC
     pub fn power_down<I: Into<usize>>(&mut self, channel: I) {
-        match channel.into() {
-            0 => self.channel0.power_down(),
-            1 => self.channel1.power_down(),
-            _ => unreachable!(),
-        }
+        self.hardware[channel.into()].power_down();
+    }
+
+    pub fn is_powered(&self, channel: usize) -> bool {
+        self.hardware[channel].is_powered()
+    }
+
+    pub fn any_powered(&self) -> bool {
+        (0..CHANNELS).any(|channel| self.is_powered(channel))
     }
 
     pub fn get_max_v(&mut self, channel: usize) -> ElectricPotential {
@@ -382,15 +1140,31 @@ impl Channels {
         self.channel_state(channel).output_limits.max_i_neg
     }
 
+    // While the ADC does not respond, the getters show the filter
+    // settings kept to restore
+
     pub fn get_postfilter(&mut self, index: u8) -> Option<PostFilter> {
-        self.adc.get_postfilter(index).unwrap()
+        let filter = self.adc_filters[usize::from(index)];
+        self.adc.get_postfilter(index).unwrap_or(filter.postfilter)
+    }
+
+    pub fn get_data_rate(&mut self, index: u8) -> DataRate {
+        let filter = self.adc_filters[usize::from(index)];
+        self.adc.get_data_rate(index).unwrap_or(filter.data_rate)
+    }
+
+    pub fn get_filter_order(&mut self, index: u8) -> DigitalFilterOrder {
+        let filter = self.adc_filters[usize::from(index)];
+        self.adc.get_filter_order(index).unwrap_or(filter.order)
     }
 
     // Get current passing through TEC
     pub fn get_tec_i(&mut self, channel: usize) -> ElectricCurrent {
-        let tec_i = (self.adc_read(channel, PinsAdcReadTarget::ITec, 16)
-            - self.adc_read(channel, PinsAdcReadTarget::VRef, 16))
+        let tec_i = (self.pins_adc_average(channel, PinsAdcReadTarget::ITec)
+            - self.pins_adc_average(channel, PinsAdcReadTarget::VRef))
             / ElectricalResistance::new::<ohm>(0.4);
+        let cal = &self.channel_state(channel).tec_cal;
+        let tec_i = tec_i * cal.i_gain + ElectricCurrent::new::<ampere>(cal.i_offset);
         match self.channel_state(channel).polarity {
             Polarity::Normal => tec_i,
             Polarity::Reversed => -tec_i,
@@ -399,27 +1173,32 @@ impl Channels {
 
     // Get voltage across TEC
     pub fn get_tec_v(&mut self, channel: usize) -> ElectricPotential {
-        (self.adc_read(channel, PinsAdcReadTarget::VTec, 16) - ElectricPotential::new::<volt>(1.5))
-            * 4.0
+        let tec_v = (self.pins_adc_average(channel, PinsAdcReadTarget::VTec)
+            - ElectricPotential::new::<volt>(1.5))
+            * 4.0;
+        let cal = &self.channel_state(channel).tec_cal;
+        tec_v * cal.v_gain + ElectricPotential::new::<volt>(cal.v_offset)
+    }
+
+    /// The 3.3 V rail, as VDDA of the MCU measured against its internal
+    /// reference
+    pub fn get_supply_3v3(&mut self) -> ElectricPotential {
+        self.pins_adc.vdda(VREF_AVG)
+    }
+
+    /// The 5 V rail, as AVDD1 of the AD7172, once it has been sampled
+    pub fn get_supply_5v(&self) -> Option<ElectricPotential> {
+        self.supply_5v
+    }
+
+    /// Die temperature of the MCU in degrees Celsius, from its internal
+    /// sensor and the factory calibration at 30 °C and 110 °C
+    pub fn get_mcu_temperature(&mut self) -> f64 {
+        self.pins_adc.temperature(MCU_TEMPERATURE_AVG)
     }
 
     fn set_pwm(&mut self, channel: usize, pin: PwmPin, duty: f64) -> f64 {
-        fn set<P: hal::PwmPin<Duty = u16>>(pin: &mut P, duty: f64) -> f64 {
-            let max = pin.get_max_duty();
-            let value = ((duty * (max as f64)) as u16).min(max);
-            pin.set_duty(value);
-            value as f64 / (max as f64)
-        }
-        match (channel, pin) {
-            (_, PwmPin::ISet) => panic!("i_set is no pwm pin"),
-            (0, PwmPin::MaxIPos) => set(&mut self.pwm.max_i_pos0, duty),
-            (0, PwmPin::MaxINeg) => set(&mut self.pwm.max_i_neg0, duty),
-            (0, PwmPin::MaxV) => set(&mut self.pwm.max_v0, duty),
-            (1, PwmPin::MaxIPos) => set(&mut self.pwm.max_i_pos1, duty),
-            (1, PwmPin::MaxINeg) => set(&mut self.pwm.max_i_neg1, duty),
-            (1, PwmPin::MaxV) => set(&mut self.pwm.max_v1, duty),
-            _ => unreachable!(),
-        }
+        self.hardware[channel].set_pwm(pin, duty)
     }
 
     pub fn set_max_v(
@@ -471,7 +1250,11 @@ impl Channels {
             Polarity::Reversed => PwmPin::MaxIPos,
         };
 
-        let max_i_neg = max_i_neg.min(MAX_TEC_I).max(ElectricCurrent::ZERO);
+        let max_i_neg = if self.channel_state(channel).heater_only {
+            ElectricCurrent::ZERO
+        } else {
+            max_i_neg.min(MAX_TEC_I).max(ElectricCurrent::ZERO)
+        };
         self.channel_state(channel).output_limits.max_i_neg = max_i_neg;
         let v_maxin = 10.0 * (max_i_neg * R_SENSE);
         let duty = (v_maxin / CPU_ADC_VREF).get::<ratio>();
@@ -483,14 +1266,65 @@ impl Channels {
         (max_i_neg, MAX_TEC_I)
     }
 
+    // The filter settings are kept even if the ADC does not take them,
+    // for reinit_adc() to apply
+
     pub fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) {
-        self.adc.set_postfilter(index, filter).unwrap()
+        if let Err(e) = self.adc.set_postfilter(index, filter) {
+            error!("unable to set the ADC postfilter: {:?}", e);
+        }
+        if let Some(adc_filter) = self.adc_filters.get_mut(usize::from(index)) {
+            adc_filter.set_postfilter(filter);
+        }
     }
 
-    pub fn set_polarity(&mut self, channel: usize, polarity: Polarity) {
-        if self.channel_state(channel).polarity != polarity {
-            let i_set = self.channel_state(channel).i_set;
-            let max_i_pos = self.get_max_i_pos(channel);
+    /// Run a system calibration of the ADC on `channel`, and use the
+    /// result for the conversion
+    pub fn calibrate_adc(
+        &mut self,
+        channel: usize,
+        mode: ad7172::Mode,
+    ) -> Result<ChannelCalibration, AdcError> {
+        self.adc.calibrate(channel as u8, mode)?;
+        let calibration = self.adc.get_calibration(channel as u8)?;
+        self.channel_state(channel).adc_calibration = calibration.clone();
+        Ok(calibration)
+    }
+
+    pub fn set_adc_calibration(&mut self, channel: usize, calibration: &ChannelCalibration) {
+        let index = channel as u8;
+        match self
+            .adc
+            .set_calibration(index, calibration)
+            .and_then(|()| self.adc.get_calibration(index))
+        {
+            Ok(calibration) => self.channel_state(channel).adc_calibration = calibration,
+            Err(e) => error!("unable to set the ADC calibration: {:?}", e),
+        }
+    }
+
+    pub fn set_data_rate(&mut self, index: u8, rate: DataRate) {
+        if let Err(e) = self.adc.set_data_rate(index, rate) {
+            error!("unable to set the ADC data rate: {:?}", e);
+        }
+        if let Some(adc_filter) = self.adc_filters.get_mut(usize::from(index)) {
+            adc_filter.set_data_rate(rate);
+        }
+    }
+
+    pub fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder) {
+        if let Err(e) = self.adc.set_filter_order(index, order) {
+            error!("unable to set the ADC filter order: {:?}", e);
+        }
+        if let Some(adc_filter) = self.adc_filters.get_mut(usize::from(index)) {
+            adc_filter.set_order(order);
+        }
+    }
+
+    pub fn set_polarity(&mut self, channel: usize, polarity: Polarity) {
+        if self.channel_state(channel).polarity != polarity {
+            let i_set = self.channel_state(channel).i_set;
+            let max_i_pos = self.get_max_i_pos(channel);
             let max_i_neg = self.get_max_i_neg(channel);
             self.channel_state(channel).polarity = polarity;
 
@@ -502,41 +1336,126 @@ impl Channels {
 
     fn report(&mut self, channel: usize) -> Report {
         let i_set = self.get_i_set(channel);
-        let i_tec = self.adc_read(channel, PinsAdcReadTarget::ITec, 16);
+        let i_tec = self.pins_adc_average(channel, PinsAdcReadTarget::ITec);
         let tec_i = self.get_tec_i(channel);
+        let tec_u_meas = self.get_tec_v(channel);
         let dac_value = self.get_dac(channel);
+        let dac_feedback = self.pins_adc_average(channel, PinsAdcReadTarget::DacVfb);
         let state = self.channel_state(channel);
         let pid_output = ElectricCurrent::new::<ampere>(state.pid.y1);
         Report {
+            name: config::name(),
             channel,
+            alias: config::aliases()[channel].clone(),
+            seq: state.adc_seq,
             time: state.get_adc_time(),
+            epoch: rtc::epoch_at(state.adc_time.total_millis() as u64),
             interval: state.get_adc_interval(),
             adc: state.get_adc(),
             sens: state.get_sens(),
             temperature: state
                 .get_temperature()
                 .map(|temperature| temperature.get::<degree_celsius>()),
+            dtdt: state.get_dtdt(),
             pid_engaged: state.pid_engaged,
+            stable: state.is_stable(),
+            fault: state.fault,
+            sensor_fault: state.sensor_fault,
             i_set,
             dac_value,
-            dac_feedback: self.adc_read(channel, PinsAdcReadTarget::DacVfb, 1),
+            dac_feedback,
             i_tec,
             tec_i,
-            tec_u_meas: self.get_tec_v(channel),
+            tec_u_meas,
+            tec_power: tec_i * tec_u_meas,
+            tec_energy: state.tec_energy,
             pid_output,
+            vref_drift: state.vref_drift,
+            adc_errors: self.adc.checksum_errors(),
+            dropped: self.reports_dropped,
+            mcu_temperature: self.get_mcu_temperature(),
+            supply_3v3: self.get_supply_3v3(),
+            supply_5v: self.get_supply_5v(),
         }
     }
 
-    pub fn reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
-        let mut reports = Vec::<_, U2>::new();
+    /// Count a report that could not be sent
+    pub fn report_dropped(&mut self) {
+        self.reports_dropped = self.reports_dropped.wrapping_add(1);
+    }
+
+    pub fn reports_milli_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut reports = Vec::<MilliReport, ChannelCapacity>::new();
         for channel in 0..CHANNELS {
-            let _ = reports.push(self.report(channel));
+            let _ = reports.push(self.report(channel).into());
+        }
+        serde_json_core::to_vec(&reports)
+    }
+
+    /// Report with the measurements rounded to `precision`
+    /// significant digits
+    fn report_rounded(&mut self, channel: usize, precision: Option<u8>) -> Report {
+        let mut report = self.report(channel);
+        if let Some(digits) = precision {
+            report.round(digits);
+        }
+        report
+    }
+
+    pub fn report_csv(
+        &mut self,
+        channel: usize,
+        precision: Option<u8>,
+    ) -> Result<String<U512>, fmt::Error> {
+        self.report_rounded(channel, precision).csv()
+    }
+
+    pub fn reports_json(
+        &mut self,
+        precision: Option<u8>,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut reports = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = reports.push(self.report_rounded(channel, precision));
+        }
+        serde_json_core::to_vec(&reports)
+    }
+
+    /// Whether the PID asks for more current than the PID output
+    /// clamp or the output limits let through
+    pub fn clipping(&mut self, channel: usize) -> bool {
+        let max_i_pos = self.get_max_i_pos(channel);
+        let max_i_neg = self.get_max_i_neg(channel);
+        let state = self.channel_state(channel);
+        if !state.pid_engaged {
+            return false;
+        }
+        let i_set = state.i_set;
+        let output_min = ElectricCurrent::new::<ampere>(state.pid.parameters.output_min.into());
+        let output_max = ElectricCurrent::new::<ampere>(state.pid.parameters.output_max.into());
+        i_set <= output_min || i_set >= output_max || i_set >= max_i_pos || i_set <= -max_i_neg
+    }
+
+    fn raw_report(&mut self, channel: usize) -> RawReport {
+        let state = self.channel_state(channel);
+        RawReport {
+            channel,
+            time: state.get_adc_time(),
+            adc_code: state.adc_raw,
+            calibration: state.adc_calibration.clone(),
+        }
+    }
+
+    pub fn raw_reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut reports = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = reports.push(self.raw_report(channel));
         }
         serde_json_core::to_vec(&reports)
     }
 
     pub fn pid_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
-        let mut summaries = Vec::<_, U2>::new();
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
         for channel in 0..CHANNELS {
             let _ = summaries.push(self.channel_state(channel).pid.summary(channel));
         }
@@ -556,16 +1475,25 @@ impl Channels {
         OutputSummary {
             channel,
             center: CenterPointJson(self.channel_state(channel).center.clone()),
+            powered: self.is_powered(channel),
+            sensor_only: self.channel_state(channel).sensor_only,
+            heater_only: self.channel_state(channel).heater_only,
             i_set: self.get_i_set(channel),
+            v_set: self.channel_state(channel).v_set,
+            vref_meas: self.get_vref_meas(channel),
             max_v: self.get_max_v(channel),
             max_i_pos: self.get_max_i_pos(channel),
             max_i_neg: self.get_max_i_neg(channel),
             polarity: PolarityJson(self.channel_state(channel).polarity.clone()),
+            i_trim: self
+                .channel_state(channel)
+                .i_trim
+                .map(ElectricCurrent::new::<ampere>),
         }
     }
 
     pub fn output_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
-        let mut summaries = Vec::<_, U2>::new();
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
         for channel in 0..CHANNELS {
             let _ = summaries.push(self.output_summary(channel));
         }
@@ -576,11 +1504,37 @@ impl Channels {
         let rate = self
             .get_postfilter(channel as u8)
             .and_then(|filter| filter.output_rate());
-        PostFilterSummary { channel, rate }
+        let state = self.channel_state(channel);
+        PostFilterSummary {
+            channel,
+            rate,
+            sw: state.sw_filter,
+            median: state.median_filter,
+        }
+    }
+
+    fn data_rate_summary(&mut self, channel: usize) -> DataRateSummary {
+        let postfilter = self.get_postfilter(channel as u8).is_some();
+        let order = self.get_filter_order(channel as u8);
+        let rate = self.get_data_rate(channel as u8).output_rate(order);
+        DataRateSummary {
+            channel,
+            rate,
+            order,
+            postfilter,
+        }
+    }
+
+    pub fn data_rate_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.data_rate_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
     }
 
     pub fn postfilter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
-        let mut summaries = Vec::<_, U2>::new();
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
         for channel in 0..CHANNELS {
             let _ = summaries.push(self.postfilter_summary(channel));
         }
@@ -595,37 +1549,421 @@ impl Channels {
     pub fn b_parameter_summaries_json(
         &mut self,
     ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
-        let mut summaries = Vec::<_, U2>::new();
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
         for channel in 0..CHANNELS {
             let _ = summaries.push(self.b_parameter_summary(channel));
         }
         serde_json_core::to_vec(&summaries)
     }
 
+    fn cascade_summary(&mut self, channel: usize) -> CascadeSummary {
+        let cascade = self.channel_state(channel).cascade;
+        CascadeSummary { channel, cascade }
+    }
+
+    pub fn cascade_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.cascade_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn interlock_summary(&mut self, channel: usize) -> InterlockSummary {
+        let state = self.channel_state(channel);
+        InterlockSummary {
+            channel,
+            t_min: state.t_min,
+            t_max: state.t_max,
+            overcurrent_time: state
+                .overcurrent_time
+                .map(|overcurrent_time| overcurrent_time.get::<second>()),
+            fault: state.fault,
+        }
+    }
+
+    pub fn interlock_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.interlock_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn stats_summary(&mut self, channel: usize) -> StatsSummary {
+        let state = self.channel_state(channel);
+        StatsSummary {
+            channel,
+            temperature: state.temperature_stats.summary(),
+            tec_i: state.tec_i_stats.summary(),
+        }
+    }
+
+    pub fn stats_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.stats_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn cal_summary(&mut self, channel: usize) -> CalSummary {
+        let state = self.channel_state(channel);
+        CalSummary {
+            channel,
+            offset: state.cal_offset,
+            gain: state.cal_gain,
+        }
+    }
+
+    pub fn cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.cal_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn tec_cal_summary(&mut self, channel: usize) -> TecCalSummary {
+        let cal = &self.channel_state(channel).tec_cal;
+        TecCalSummary {
+            channel,
+            i_gain: cal.i_gain,
+            i_offset: cal.i_offset,
+            v_gain: cal.v_gain,
+            v_offset: cal.v_offset,
+        }
+    }
+
+    pub fn tec_cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.tec_cal_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn stable_summary(&mut self, channel: usize) -> StableSummary {
+        let state = self.channel_state(channel);
+        StableSummary {
+            channel,
+            window: state.stable_window,
+            time: state.stable_time,
+            stable: state.is_stable(),
+        }
+    }
+
+    pub fn stable_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.stable_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn dtdt_summary(&mut self, channel: usize) -> DtDtSummary {
+        let state = self.channel_state(channel);
+        DtDtSummary {
+            channel,
+            window: state.dtdt_window,
+            dtdt: state.get_dtdt(),
+        }
+    }
+
+    pub fn dtdt_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.dtdt_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    pub fn locked_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut locked = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = locked.push(LockedSummary {
+                channel,
+                locked: self.channel_state(channel).is_stable(),
+            });
+        }
+        serde_json_core::to_vec(&locked)
+    }
+
+    fn steinhart_hart_summary(&mut self, channel: usize) -> SteinhartHartSummary {
+        let params = self.channel_state(channel).sh.clone();
+        SteinhartHartSummary { channel, params }
+    }
+
+    pub fn steinhart_hart_summaries_json(
+        &mut self,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.steinhart_hart_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    fn sensor_summary(&mut self, channel: usize) -> SensorSummary {
+        let sensor = SensorJson(self.channel_state(channel).sensor);
+        SensorSummary { channel, sensor }
+    }
+
+    pub fn sensor_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut summaries = Vec::<_, ChannelCapacity>::new();
+        for channel in 0..CHANNELS {
+            let _ = summaries.push(self.sensor_summary(channel));
+        }
+        serde_json_core::to_vec(&summaries)
+    }
+
+    /// Largest TEC current of the channels that drive an output, for
+    /// the fan
     pub fn current_abs_max_tec_i(&mut self) -> ElectricCurrent {
-        (0..CHANNELS)
-            .map(|channel| self.get_tec_i(channel).abs())
-            .max_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal))
-            .unwrap()
+        let mut max = ElectricCurrent::ZERO;
+        for channel in 0..CHANNELS {
+            if !self.channel_state(channel).sensor_only {
+                max = max.max(self.get_tec_i(channel).abs());
+            }
+        }
+        max
     }
 }
 
 #[derive(Serialize)]
 pub struct Report {
+    name: DeviceName,
     channel: usize,
+    alias: Option<ChannelAlias>,
+    seq: u32,
     time: Time,
+    epoch: Option<f64>,
     interval: Time,
     adc: Option<ElectricPotential>,
     sens: Option<ElectricalResistance>,
     temperature: Option<f64>,
+    dtdt: Option<f64>,
     pid_engaged: bool,
+    stable: bool,
+    fault: Option<Fault>,
+    sensor_fault: Option<SensorFault>,
     i_set: ElectricCurrent,
     dac_value: ElectricPotential,
     dac_feedback: ElectricPotential,
     i_tec: ElectricPotential,
     tec_i: ElectricCurrent,
     tec_u_meas: ElectricPotential,
+    tec_power: Power,
+    tec_energy: Energy,
     pid_output: ElectricCurrent,
+    adc_errors: u32,
+    dropped: u32,
+    /// Die temperature of the MCU in degrees Celsius
+    mcu_temperature: f64,
+    supply_3v3: ElectricPotential,
+    supply_5v: Option<ElectricPotential>,
+    /// Drift of VREF from `vref_meas`
+    vref_drift: Option<ElectricPotential>,
+}
+
+/// `Report` with integers in milli-units: ms, mV, mΩ, m°C, mK/s, mA,
+/// mW and mJ
+#[derive(Serialize)]
+pub struct MilliReport {
+    name: DeviceName,
+    channel: usize,
+    alias: Option<ChannelAlias>,
+    seq: u32,
+    time: i64,
+    epoch: Option<i64>,
+    interval: i64,
+    adc: Option<i32>,
+    sens: Option<i64>,
+    temperature: Option<i32>,
+    dtdt: Option<i32>,
+    pid_engaged: bool,
+    stable: bool,
+    fault: Option<Fault>,
+    sensor_fault: Option<SensorFault>,
+    i_set: i32,
+    dac_value: i32,
+    dac_feedback: i32,
+    i_tec: i32,
+    tec_i: i32,
+    tec_u_meas: i32,
+    tec_power: i32,
+    tec_energy: i64,
+    pid_output: i32,
+    adc_errors: u32,
+    dropped: u32,
+    mcu_temperature: i32,
+    supply_3v3: i32,
+    supply_5v: Option<i32>,
+    vref_drift: Option<i32>,
+}
+
+/// Drift of the measured `vref` from `vref_meas`. The DAC feedback
+/// minus the DAC setting is the offset of the ADC, as for `vref`.
+fn vref_drift(
+    vref: ElectricPotential,
+    dac_feedback: ElectricPotential,
+    dac_value: ElectricPotential,
+    vref_meas: ElectricPotential,
+) -> ElectricPotential {
+    vref - (dac_feedback - dac_value) - vref_meas
+}
+
+/// `value` rounded to `digits` significant digits, so that it is
+/// serialized with no more
+fn round_significant(value: f64, digits: u8) -> f64 {
+    if value == 0.0 || !value.is_finite() {
+        return value;
+    }
+    let exponent = Float::floor(Float::log10(Float::abs(value))) as i32 + 1 - i32::from(digits);
+    // Dividing by an exact power of ten rounds correctly, unlike
+    // multiplying by its inexact inverse
+    let scale: f64 = Float::powi(10.0, exponent.abs());
+    if exponent < 0 {
+        Float::round(value * scale) / scale
+    } else {
+        Float::round(value / scale) * scale
+    }
+}
+
+fn milli(value: f64) -> i64 {
+    Float::round(value * 1000.0) as i64
+}
+
+impl From<Report> for MilliReport {
+    fn from(report: Report) -> Self {
+        let milli32 = |value: f64| milli(value) as i32;
+        MilliReport {
+            name: report.name,
+            channel: report.channel,
+            alias: report.alias,
+            seq: report.seq,
+            time: milli(report.time.value),
+            epoch: report.epoch.map(milli),
+            interval: milli(report.interval.value),
+            adc: report.adc.map(|adc| milli32(adc.value)),
+            sens: report.sens.map(|sens| milli(sens.value)),
+            temperature: report.temperature.map(milli32),
+            dtdt: report.dtdt.map(milli32),
+            pid_engaged: report.pid_engaged,
+            stable: report.stable,
+            fault: report.fault,
+            sensor_fault: report.sensor_fault,
+            i_set: milli32(report.i_set.value),
+            dac_value: milli32(report.dac_value.value),
+            dac_feedback: milli32(report.dac_feedback.value),
+            i_tec: milli32(report.i_tec.value),
+            tec_i: milli32(report.tec_i.value),
+            tec_u_meas: milli32(report.tec_u_meas.value),
+            tec_power: milli32(report.tec_power.value),
+            tec_energy: milli(report.tec_energy.value),
+            pid_output: milli32(report.pid_output.value),
+            adc_errors: report.adc_errors,
+            dropped: report.dropped,
+            mcu_temperature: milli32(report.mcu_temperature),
+            supply_3v3: milli32(report.supply_3v3.value),
+            supply_5v: report.supply_5v.map(|supply| milli32(supply.value)),
+            vref_drift: report.vref_drift.map(|drift| milli32(drift.value)),
+        }
+    }
+}
+
+/// Columns of `Report::csv()`, the numbers of a report in the same
+/// units
+pub const REPORT_CSV_HEADER: &[u8] = b"channel,seq,time,epoch,interval,adc,sens,temperature,dtdt,\
+pid_engaged,stable,i_set,dac_value,dac_feedback,i_tec,tec_i,tec_u_meas,tec_power,tec_energy,\
+pid_output";
+
+/// Empty CSV field for `None`
+struct CsvField<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for CsvField<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(fmt),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Report {
+    /// Round the measurements to `digits` significant digits, but not
+    /// the times, which would lose their resolution
+    fn round(&mut self, digits: u8) {
+        let values = [
+            &mut self.i_set.value,
+            &mut self.dac_value.value,
+            &mut self.dac_feedback.value,
+            &mut self.i_tec.value,
+            &mut self.tec_i.value,
+            &mut self.tec_u_meas.value,
+            &mut self.tec_power.value,
+            &mut self.tec_energy.value,
+            &mut self.pid_output.value,
+            &mut self.mcu_temperature,
+            &mut self.supply_3v3.value,
+        ];
+        for value in values {
+            *value = round_significant(*value, digits);
+        }
+        let optional = [
+            self.adc.as_mut().map(|adc| &mut adc.value),
+            self.sens.as_mut().map(|sens| &mut sens.value),
+            self.temperature.as_mut(),
+            self.dtdt.as_mut(),
+            self.supply_5v.as_mut().map(|supply| &mut supply.value),
+            self.vref_drift.as_mut().map(|drift| &mut drift.value),
+        ];
+        for value in optional.into_iter().flatten() {
+            *value = round_significant(*value, digits);
+        }
+    }
+
+    /// Row of the columns in `REPORT_CSV_HEADER`
+    pub fn csv(&self) -> Result<String<U512>, fmt::Error> {
+        let mut row = String::new();
+        write!(
+            row,
+            "{},{},{},{},{},{},{},{},{},{},{},",
+            self.channel,
+            self.seq,
+            self.time.value,
+            CsvField(self.epoch),
+            self.interval.value,
+            CsvField(self.adc.map(|adc| adc.value)),
+            CsvField(self.sens.map(|sens| sens.value)),
+            CsvField(self.temperature),
+            CsvField(self.dtdt),
+            u8::from(self.pid_engaged),
+            u8::from(self.stable),
+        )?;
+        write!(
+            row,
+            "{},{},{},{},{},{},{},{},{}",
+            self.i_set.value,
+            self.dac_value.value,
+            self.dac_feedback.value,
+            self.i_tec.value,
+            self.tec_i.value,
+            self.tec_u_meas.value,
+            self.tec_power.value,
+            self.tec_energy.value,
+            self.pid_output.value,
+        )?;
+        Ok(row)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RawReport {
+    channel: usize,
+    time: Time,
+    adc_code: u32,
+    calibration: ChannelCalibration,
 }
 
 pub struct CenterPointJson(CenterPoint);
@@ -658,21 +1996,56 @@ impl Serialize for PolarityJson {
     }
 }
 
+pub struct SensorJson(Sensor);
+
+// used in JSON encoding, not for config
+impl Serialize for SensorJson {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(match self.0 {
+            Sensor::BParameter => "b-p",
+            Sensor::SteinhartHart => "s-h",
+            Sensor::Pt100 => "pt100",
+            Sensor::Pt1000 => "pt1000",
+        })
+    }
+}
+
 #[derive(Serialize)]
 pub struct OutputSummary {
     channel: usize,
     center: CenterPointJson,
+    powered: bool,
+    sensor_only: bool,
+    heater_only: bool,
     i_set: ElectricCurrent,
+    /// TEC voltage commanded in voltage-control mode
+    v_set: Option<ElectricPotential>,
+    vref_meas: ElectricPotential,
     max_v: ElectricPotential,
     max_i_pos: ElectricCurrent,
     max_i_neg: ElectricCurrent,
     polarity: PolarityJson,
+    i_trim: Option<ElectricCurrent>,
 }
 
 #[derive(Serialize)]
 pub struct PostFilterSummary {
     channel: usize,
     rate: Option<f32>,
+    sw: u32,
+    median: u8,
+}
+
+#[derive(Serialize)]
+pub struct DataRateSummary {
+    channel: usize,
+    rate: f32,
+    order: DigitalFilterOrder,
+    /// Postfilter overrides `rate`
+    postfilter: bool,
 }
 
 #[derive(Serialize)]
@@ -680,3 +2053,627 @@ pub struct BParameterSummary {
     channel: usize,
     params: b_parameter::Parameters,
 }
+
+#[derive(Serialize)]
+pub struct CascadeSummary {
+    channel: usize,
+    cascade: bool,
+}
+
+#[derive(Serialize)]
+pub struct StatsSummary {
+    channel: usize,
+    temperature: stats::Summary,
+    tec_i: stats::Summary,
+}
+
+#[derive(Serialize)]
+pub struct CalSummary {
+    channel: usize,
+    offset: f64,
+    gain: f64,
+}
+
+#[derive(Serialize)]
+pub struct TecCalSummary {
+    channel: usize,
+    i_gain: f64,
+    i_offset: f64,
+    v_gain: f64,
+    v_offset: f64,
+}
+
+#[derive(Serialize)]
+pub struct StableSummary {
+    channel: usize,
+    window: TemperatureInterval,
+    time: Time,
+    stable: bool,
+}
+
+#[derive(Serialize)]
+pub struct DtDtSummary {
+    channel: usize,
+    window: Time,
+    dtdt: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct LockedSummary {
+    channel: usize,
+    locked: bool,
+}
+
+#[derive(Serialize)]
+pub struct InterlockSummary {
+    channel: usize,
+    t_min: Option<f64>,
+    t_max: Option<f64>,
+    /// Seconds
+    overcurrent_time: Option<f64>,
+    fault: Option<Fault>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TecStatus {
+    Ok,
+    Open,
+    Short,
+}
+
+#[derive(Serialize)]
+pub struct TecCheck {
+    channel: usize,
+    i_pos: ElectricCurrent,
+    i_neg: ElectricCurrent,
+    v_pos: ElectricPotential,
+    v_neg: ElectricPotential,
+    resistance: Option<ElectricalResistance>,
+    status: TecStatus,
+}
+
+#[derive(Serialize)]
+pub struct SteinhartHartSummary {
+    channel: usize,
+    params: steinhart_hart::Parameters,
+}
+
+#[derive(Serialize)]
+pub struct SensorSummary {
+    channel: usize,
+    sensor: SensorJson,
+}
+
+/// Operations of the command handler on the channels, implemented by
+/// `Channels` on the hardware and by a mock in the host tests
+///
+/// The rules for driving the outputs are default methods, so that the
+/// tests on the mock run them as the firmware does, while the
+/// implementations only carry out their effects on the hardware.
+pub trait ChannelsInterface {
+    fn channel_state(&mut self, channel: usize) -> &mut ChannelState;
+    fn is_powered(&self, channel: usize) -> bool;
+    /// Switch the TEC driver on, unlike `power_up()` without checking
+    /// whether the output may be driven
+    fn enable_output(&mut self, channel: usize);
+    fn power_down(&mut self, channel: usize);
+
+    // power up TEC
+    fn power_up(&mut self, channel: usize) {
+        let state = self.channel_state(channel);
+        if state.fault.is_some() || state.sensor_only || self.dac_calibration_running(channel) {
+            // Output is latched off, held off while sensor-only, or
+            // kept off until the DAC is calibrated
+            return;
+        }
+        if self.channel_state(channel).dac_code.is_some() {
+            // Not to drive the TEC with a raw code from `dac`
+            let i_set = self.get_i_set(channel);
+            self.set_i(channel, i_set);
+        }
+        self.enable_output(channel);
+    }
+
+    /// Disengage PID, and zero and power down the output
+    fn shut_down(&mut self, channel: usize) {
+        self.channel_state(channel).pid_engaged = false;
+        self.channel_state(channel).v_set = None;
+        self.set_i(channel, ElectricCurrent::ZERO);
+        self.power_down(channel);
+    }
+
+    fn get_i_set(&mut self, channel: usize) -> ElectricCurrent;
+    fn set_i(&mut self, channel: usize, i_set: ElectricCurrent) -> ElectricCurrent;
+    fn set_i_trim(&mut self, channel: usize, enabled: bool);
+    fn set_polarity(&mut self, channel: usize, polarity: Polarity);
+
+    /// Make `channel` the inner loop of a cascade, with the other
+    /// channel's sensor and PID as the outer loop. Returns the outer
+    /// channel, whose own output is shut down.
+    fn set_cascade(&mut self, channel: usize, enabled: bool) -> Option<usize> {
+        self.channel_state(channel).cascade = enabled;
+        if enabled {
+            let outer = cascade_partner(channel);
+            self.channel_state(outer).cascade = false;
+            // The outer loop does not drive its own output
+            self.shut_down(outer);
+            Some(outer)
+        } else {
+            None
+        }
+    }
+
+    fn get_max_v(&mut self, channel: usize) -> ElectricPotential;
+    fn set_max_v(
+        &mut self,
+        channel: usize,
+        max_v: ElectricPotential,
+    ) -> (ElectricPotential, ElectricPotential);
+    fn get_max_i_pos(&mut self, channel: usize) -> ElectricCurrent;
+    fn set_max_i_pos(
+        &mut self,
+        channel: usize,
+        max_i_pos: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent);
+    fn get_max_i_neg(&mut self, channel: usize) -> ElectricCurrent;
+    fn set_max_i_neg(
+        &mut self,
+        channel: usize,
+        max_i_neg: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent);
+    fn start_dac_calibration(&mut self, channel: usize);
+    fn dac_calibration_running(&self, channel: usize) -> bool;
+    fn any_dac_calibration_running(&self) -> bool;
+    fn set_dac_code(&mut self, channel: usize, code: u32) -> ElectricPotential;
+    fn dac_max_value(&self, channel: usize) -> u32;
+    fn dac_chip(&self, channel: usize) -> DacChip;
+
+    fn get_data_rate(&mut self, index: u8) -> DataRate;
+    fn set_data_rate(&mut self, index: u8, rate: DataRate);
+    fn get_filter_order(&mut self, index: u8) -> DigitalFilterOrder;
+    fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder);
+    fn get_postfilter(&mut self, index: u8) -> Option<PostFilter>;
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>);
+    fn calibrate_adc(
+        &mut self,
+        channel: usize,
+        mode: ad7172::Mode,
+    ) -> Result<ChannelCalibration, AdcError>;
+
+    fn reset_stats(&mut self);
+    fn reset_energy(&mut self);
+    fn step_test_running(&mut self) -> bool;
+
+    /// Step the output current of `channel` by `delta_i` from its
+    /// present setting with the next ADC sample, and capture the
+    /// temperature response for `duration`. The PID is disengaged
+    /// meanwhile.
+    fn start_step_test(&mut self, channel: usize, delta_i: ElectricCurrent, duration: Time) {
+        let duration = duration.get::<millisecond>() as u32;
+        capture::start(
+            channel,
+            capture::CAPTURE_MAX,
+            capture::Source::Temperature {
+                interval: duration / capture::CAPTURE_MAX as u32,
+            },
+        );
+        let i_set = self.get_i_set(channel);
+        let powered = self.is_powered(channel);
+        let state = self.channel_state(channel);
+        state.step_test = Some(StepTest {
+            delta_i,
+            duration,
+            start: None,
+            i_set,
+            pid_engaged: state.pid_engaged,
+            powered,
+        });
+        state.pid_engaged = false;
+    }
+
+    fn report_dropped(&mut self);
+
+    fn report_csv(
+        &mut self,
+        channel: usize,
+        precision: Option<u8>,
+    ) -> Result<String<U512>, fmt::Error>;
+    fn reports_json(
+        &mut self,
+        precision: Option<u8>,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn reports_milli_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn raw_reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn tec_check_json(&mut self, channel: usize)
+        -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn pid_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn output_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn data_rate_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn postfilter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn b_parameter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn cascade_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn interlock_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn stats_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn tec_cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn stable_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn dtdt_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn locked_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn steinhart_hart_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+    fn sensor_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error>;
+}
+
+impl ChannelsInterface for Channels {
+    fn channel_state(&mut self, channel: usize) -> &mut ChannelState {
+        Channels::channel_state(self, channel)
+    }
+
+    fn is_powered(&self, channel: usize) -> bool {
+        Channels::is_powered(self, channel)
+    }
+
+    fn enable_output(&mut self, channel: usize) {
+        self.hardware[channel].power_up();
+    }
+
+    fn power_down(&mut self, channel: usize) {
+        Channels::power_down(self, channel)
+    }
+
+    fn get_i_set(&mut self, channel: usize) -> ElectricCurrent {
+        Channels::get_i_set(self, channel)
+    }
+
+    fn set_i(&mut self, channel: usize, i_set: ElectricCurrent) -> ElectricCurrent {
+        Channels::set_i(self, channel, i_set)
+    }
+
+    fn set_i_trim(&mut self, channel: usize, enabled: bool) {
+        Channels::set_i_trim(self, channel, enabled)
+    }
+
+    fn set_polarity(&mut self, channel: usize, polarity: Polarity) {
+        Channels::set_polarity(self, channel, polarity)
+    }
+
+    fn get_max_v(&mut self, channel: usize) -> ElectricPotential {
+        Channels::get_max_v(self, channel)
+    }
+
+    fn set_max_v(
+        &mut self,
+        channel: usize,
+        max_v: ElectricPotential,
+    ) -> (ElectricPotential, ElectricPotential) {
+        Channels::set_max_v(self, channel, max_v)
+    }
+
+    fn get_max_i_pos(&mut self, channel: usize) -> ElectricCurrent {
+        Channels::get_max_i_pos(self, channel)
+    }
+
+    fn set_max_i_pos(
+        &mut self,
+        channel: usize,
+        max_i_pos: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent) {
+        Channels::set_max_i_pos(self, channel, max_i_pos)
+    }
+
+    fn get_max_i_neg(&mut self, channel: usize) -> ElectricCurrent {
+        Channels::get_max_i_neg(self, channel)
+    }
+
+    fn set_max_i_neg(
+        &mut self,
+        channel: usize,
+        max_i_neg: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent) {
+        Channels::set_max_i_neg(self, channel, max_i_neg)
+    }
+
+    fn start_dac_calibration(&mut self, channel: usize) {
+        Channels::start_dac_calibration(self, channel)
+    }
+
+    fn set_dac_code(&mut self, channel: usize, code: u32) -> ElectricPotential {
+        Channels::set_dac_code(self, channel, code)
+    }
+
+    fn dac_max_value(&self, channel: usize) -> u32 {
+        Channels::dac_max_value(self, channel)
+    }
+
+    fn dac_chip(&self, channel: usize) -> DacChip {
+        Channels::dac_chip(self, channel)
+    }
+
+    fn dac_calibration_running(&self, channel: usize) -> bool {
+        Channels::dac_calibration_running(self, channel)
+    }
+
+    fn any_dac_calibration_running(&self) -> bool {
+        Channels::any_dac_calibration_running(self)
+    }
+
+    fn get_data_rate(&mut self, index: u8) -> DataRate {
+        Channels::get_data_rate(self, index)
+    }
+
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) {
+        Channels::set_data_rate(self, index, rate)
+    }
+
+    fn get_filter_order(&mut self, index: u8) -> DigitalFilterOrder {
+        Channels::get_filter_order(self, index)
+    }
+
+    fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder) {
+        Channels::set_filter_order(self, index, order)
+    }
+
+    fn get_postfilter(&mut self, index: u8) -> Option<PostFilter> {
+        Channels::get_postfilter(self, index)
+    }
+
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) {
+        Channels::set_postfilter(self, index, filter)
+    }
+
+    fn calibrate_adc(
+        &mut self,
+        channel: usize,
+        mode: ad7172::Mode,
+    ) -> Result<ChannelCalibration, AdcError> {
+        Channels::calibrate_adc(self, channel, mode)
+    }
+
+    fn reset_stats(&mut self) {
+        Channels::reset_stats(self)
+    }
+
+    fn reset_energy(&mut self) {
+        Channels::reset_energy(self)
+    }
+
+    fn step_test_running(&mut self) -> bool {
+        Channels::step_test_running(self)
+    }
+
+    fn report_dropped(&mut self) {
+        Channels::report_dropped(self)
+    }
+
+    fn report_csv(
+        &mut self,
+        channel: usize,
+        precision: Option<u8>,
+    ) -> Result<String<U512>, fmt::Error> {
+        Channels::report_csv(self, channel, precision)
+    }
+
+    fn reports_json(
+        &mut self,
+        precision: Option<u8>,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::reports_json(self, precision)
+    }
+
+    fn reports_milli_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::reports_milli_json(self)
+    }
+
+    fn raw_reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::raw_reports_json(self)
+    }
+
+    fn tec_check_json(
+        &mut self,
+        channel: usize,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::tec_check_json(self, channel)
+    }
+
+    fn pid_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::pid_summaries_json(self)
+    }
+
+    fn output_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::output_summaries_json(self)
+    }
+
+    fn data_rate_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::data_rate_summaries_json(self)
+    }
+
+    fn postfilter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::postfilter_summaries_json(self)
+    }
+
+    fn b_parameter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::b_parameter_summaries_json(self)
+    }
+
+    fn cascade_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::cascade_summaries_json(self)
+    }
+
+    fn interlock_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::interlock_summaries_json(self)
+    }
+
+    fn stats_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::stats_summaries_json(self)
+    }
+
+    fn cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::cal_summaries_json(self)
+    }
+
+    fn tec_cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::tec_cal_summaries_json(self)
+    }
+
+    fn stable_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::stable_summaries_json(self)
+    }
+
+    fn dtdt_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::dtdt_summaries_json(self)
+    }
+
+    fn locked_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::locked_json(self)
+    }
+
+    fn steinhart_hart_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::steinhart_hart_summaries_json(self)
+    }
+
+    fn sensor_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        Channels::sensor_summaries_json(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ad5680, dac8560, mock::MockChannels, pid};
+
+    #[test]
+    fn test_dac_current() {
+        let amps = ElectricCurrent::new::<ampere>;
+        let i_out = |i_set, i_trim, heater_only| {
+            dac_current(amps(i_set), amps(i_trim), heater_only).get::<ampere>()
+        };
+        assert_eq!(i_out(1.0, 0.05, false), 1.05);
+        // The trim cannot push the output beyond the limits
+        assert_eq!(i_out(2.0, 0.1, false), 2.0);
+        assert_eq!(i_out(-2.0, -0.1, false), -2.0);
+        // Nor make a heater-only channel cool
+        assert_eq!(i_out(0.0, -0.05, true), 0.0);
+        assert_eq!(i_out(0.5, -0.05, true), 0.45);
+    }
+
+    #[test]
+    fn test_round_significant() {
+        assert_eq!(round_significant(24.973456789, 4), 24.97);
+        assert_eq!(round_significant(-0.00123456, 3), -0.00123);
+        assert_eq!(round_significant(10012.4, 3), 10000.0);
+        assert_eq!(round_significant(0.0, 3), 0.0);
+    }
+
+    #[test]
+    fn test_adc_filter() {
+        let mut filter = AdcFilter::new();
+        filter.set_order(DigitalFilterOrder::Sinc3);
+        assert_eq!(filter.postfilter, None);
+        filter.set_postfilter(Some(PostFilter::F20SPS));
+        assert_eq!(filter.order, DigitalFilterOrder::Sinc5Sinc1);
+        filter.set_data_rate(DataRate::DEFAULT);
+        assert_eq!(filter.postfilter, None);
+    }
+
+    #[test]
+    fn test_vref_drift() {
+        let volts = ElectricPotential::new::<volt>;
+        // ADC offset of 2 mV, with 0.2 V of i_set on the DAC
+        let drift = vref_drift(volts(1.502), volts(1.702), volts(1.7), volts(1.5));
+        assert!(drift.get::<volt>().abs() < 1e-9);
+        let drift = vref_drift(volts(1.507), volts(1.702), volts(1.7), volts(1.5));
+        assert!((drift.get::<volt>() - 0.005).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_channel_count() {
+        assert_eq!(Vec::<u8, ChannelCapacity>::new().capacity(), CHANNELS);
+        for channel in 0..CHANNELS {
+            let partner = cascade_partner(channel);
+            assert!(partner < CHANNELS);
+            assert_ne!(partner, channel);
+            assert_eq!(cascade_partner(partner), channel);
+        }
+    }
+
+    #[test]
+    fn test_cascade_convergence() {
+        // Initial and ambient temperature
+        const AMBIENT: f64 = 20.0;
+        // Object target temperature
+        const TARGET: f64 = 30.0;
+        // Control tolerance
+        const ERROR: f64 = 0.01;
+        const CYCLES: u32 = 3000;
+
+        let mut channels = MockChannels::new();
+        channels.set_cascade(0, true);
+        channels.states[0].pid.parameters = pid::Parameters {
+            kp: 0.03,
+            ki: 0.002,
+            kd: 0.15,
+            output_min: -10.0,
+            output_max: 10.0,
+            ..pid::Parameters::default()
+        };
+        channels.states[0].pid_engaged = true;
+        // Plate target within 15 K of the object target
+        channels.states[1].pid.parameters = pid::Parameters {
+            kp: 0.05,
+            ki: 0.005,
+            output_min: -15.0,
+            output_max: 15.0,
+            ..pid::Parameters::default()
+        };
+        channels.states[1].pid.target = TARGET;
+
+        let (mut plate, mut object) = (AMBIENT, AMBIENT);
+        for _ in 0..CYCLES {
+            let output = channels.states[1].pid.update(object, 1.0);
+            forward_pid_output(&mut channels, 1, Some(output));
+            let output = channels.states[0].pid.update(plate, 1.0);
+            forward_pid_output(&mut channels, 0, Some(output));
+            // Positive TEC current cools the plate
+            let i_set = channels.states[0].i_set.get::<ampere>();
+            plate += -i_set - (plate - AMBIENT) * 0.05;
+            object += (plate - object) * 0.02 - (object - AMBIENT) * 0.01;
+        }
+        assert!((object - TARGET).abs() < ERROR);
+        // The plate makes up for the heat the object loses
+        assert!(plate > TARGET);
+        assert!(channels.powered[0]);
+        assert!(!channels.powered[1]);
+    }
+
+    #[test]
+    fn test_dac_search() {
+        for max_value in [ad5680::MAX_VALUE, dac8560::MAX_VALUE] {
+            // DAC feedback of an ideal DAC, against a centerpoint of 1.5 V
+            let feedback = |value: u32| DAC_OUT_V_MAX * (value as f64 / max_value as f64);
+            let target = ElectricPotential::new::<volt>(1.5);
+            let mut search = DacSearch::new(target, max_value);
+            let mut settings = 0;
+            while let Some(value) = search.value {
+                search.feedback(feedback(value));
+                settings += 1;
+            }
+            let (value, error) = search.best.unwrap();
+            assert!(feedback(value) <= target);
+            assert!(error < feedback(1 << DAC_SEARCH_STEP_MIN));
+            assert!(settings < 64);
+        }
+    }
+
+    #[test]
+    fn test_pins_adc_average() {
+        let mut average = PinsAdcAverage::new();
+        for i in 0..PINS_ADC_AVERAGE {
+            assert_eq!(average.value, None);
+            average.update(ElectricPotential::new::<volt>(f64::from(i % 2)));
+        }
+        assert_eq!(average.value, Some(ElectricPotential::new::<volt>(0.5)));
+        // Kept until the next average is complete
+        average.update(ElectricPotential::new::<volt>(3.0));
+        assert_eq!(average.value, Some(ElectricPotential::new::<volt>(0.5)));
+    }
+}
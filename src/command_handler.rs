@@ -1,18 +1,44 @@
 use super::{
     ad7172,
-    channels::{Channels, CHANNELS},
+    alarms::{self, AlarmKind},
+    autosave,
+    board::{Board, Mcu},
+    capture,
+    channels::{ChannelsInterface, CHANNELS, MAX_TEC_I, MAX_TEC_V, REPORT_CSV_HEADER},
     command_parser::{
-        BpParameter, CenterPoint, Command, Ipv4Config, PidParameter, Polarity, PwmPin, ShowCommand,
+        AdcCalibration, BpParameter, CalParameter, CenterPoint, ChannelAlias, Command, DeviceName,
+        EepromAccess, Framing, InterlockLimit, Ipv4Config, LogLevel, PidParameter, Polarity,
+        PowerOn, ProfileName, PwmPin, ReportFormat, RequestTag, ScheduledLine, Sensor, ShParameter,
+        ShowCommand, StableParameter, TecCalParameter, ALL_CHANNELS,
     },
-    config::ChannelConfig,
-    dfu,
-    flash_store::FlashStore,
+    config::{
+        self, ChannelConfig, ConfigDiff, ConfigDocument, DeviceConfig, DocumentMode, Problem,
+        Problems,
+    },
+    dac::{self, DacChip},
+    deadman,
+    event_log::{self, Event, EventKind},
+    faults::{self, FaultStatus},
+    flash_store::{self, FlashInterface, ProfileError},
+    history,
     hw_rev::HWRev,
-    net, FanCtrl, CHANNEL_CONFIG_KEY,
+    leds, log_buffer, net,
+    pins::Eeprom,
+    rtc, scheduler,
+    selftest::SelfTest,
+    server::{self, SOCKET_COUNT},
+    session::ClientSummary,
+    stable_output, step_response, timer, FanCtrl, ADC_CALIBRATION_KEY, CHANNEL_ALIAS_KEY,
+    CHANNEL_CONFIG_KEY, DEVICE_CONFIG_KEY, DEVICE_NAME_KEY, TEC_CALIBRATION_KEY,
+};
+use byteorder::{BigEndian, ByteOrder};
+use core::fmt::{self, Write};
+use heapless::{
+    consts::{U2048, U256, U48},
+    String, Vec,
 };
-use core::fmt::Write;
-use heapless::{consts::U1024, Vec};
 use log::{error, warn};
+use serde::Serialize;
 use smoltcp::socket::TcpSocket;
 
 use uom::si::{
@@ -21,10 +47,11 @@ use uom::si::{
     electrical_resistance::ohm,
     f64::{
         ElectricCurrent, ElectricPotential, ElectricalResistance, TemperatureInterval,
-        ThermodynamicTemperature,
+        ThermodynamicTemperature, Time,
     },
     temperature_interval::kelvin,
     thermodynamic_temperature::degree_celsius,
+    time::second,
 };
 
 #[derive(Debug, Clone, PartialEq)]
@@ -32,199 +59,1205 @@ pub enum Handler {
     Handled,
     CloseSocket,
     NewIPV4(Ipv4Config),
+    NewFraming(Framing),
+    NewReportFormat(ReportFormat),
+    NewPrecision(Option<u8>),
+    /// Enable or disable echo and prompt of the session
+    Interactive(bool),
+    NewDump(Dump),
+    /// Run the self-test, which needs peripherals beyond the handler's
+    SelfTest,
+    /// List the clients, which are only known to the server
+    Clients,
+    /// Close the session of client `index`
+    Kick(usize),
+    /// Read the next line as a config document for `config load`
+    ReceiveConfig,
+    /// Access the EEPROM, which is only known to the main loop
+    Eeprom(EepromAccess),
     Reset,
 }
 
+/// Samples that remain to be sent to a session, before further
+/// commands are read
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dump {
+    /// Samples of the history, by sequence number
+    History { channel: usize, next: u32, end: u32 },
+    /// Samples of the capture, once it is complete
+    Capture { started: bool, next: usize },
+    /// Step response estimates, once the step test is complete,
+    /// followed by the captured samples
+    StepTest { delta_i: f64 },
+    /// Events of the event log, from the oldest
+    EventLog { next: usize, end: usize },
+    /// Lines of the log buffer by byte offset, from the oldest
+    Log { next: u32, end: u32, remaining: u32 },
+    /// Config documents of the channels
+    Config { next: usize },
+}
+
+/// Room to leave in the TCP send buffer for each sample
+const DUMP_LINE_MAX: usize = 64;
+/// Room to leave in the TCP send buffer for each event
+const EVENT_LINE_MAX: usize = 160;
+/// Room to leave in the TCP send buffer for each log line
+const LOG_LINE_MAX: usize = 260;
+/// Longest `deadman` timeout in seconds
+const DEADMAN_MAX: u32 = 86_400;
+/// Stack of a session besides its send queue
+pub const SESSION_BASE_SIZE: usize = 512;
+/// Room for a report of both channels, or for what is left of the
+/// share of each session in `server::STACK_BUDGET`, with many sockets
+/// or large socket buffers
+pub const SEND_QUEUE_LEN: usize = {
+    let share = server::STACK_BUDGET.saturating_sub(server::SOCKET_BUFFERS_SIZE) / SOCKET_COUNT;
+    let len = share.saturating_sub(SESSION_BASE_SIZE);
+    if len < 2048 {
+        len
+    } else {
+        2048
+    }
+};
+
+fn dump_line_fits(socket: &FramedSocket, line_max: usize) -> bool {
+    // `{"id":"<tag>","data":}` added by `send_line()`
+    let tag_len = socket.tag.as_ref().map_or(0, |tag| tag.len() + 17);
+    socket.socket.queued() == 0 && socket.send_free() >= line_max + tag_len
+}
+
+/// Send as many samples as fit into the TCP send buffer.
+/// Returns `true` once all have been sent.
+pub fn send_dump(
+    socket: &mut FramedSocket,
+    dump: &mut Dump,
+    channels: &mut dyn ChannelsInterface,
+) -> bool {
+    match dump {
+        Dump::History { channel, next, end } => {
+            while *next < *end {
+                if !dump_line_fits(socket, DUMP_LINE_MAX) {
+                    return false;
+                }
+                match history::get(*channel, *next) {
+                    Some(sample) => send_fmt(
+                        socket,
+                        format_args!(
+                            "{{\"time\":{:.3},\"temperature\":{}}}",
+                            sample.time as f64 / 1000.0,
+                            sample.temperature
+                        ),
+                    ),
+                    // Overwritten in the meantime
+                    None => send_line(socket, b"null"),
+                };
+                *next += 1;
+            }
+        }
+        Dump::Capture { started, next } => {
+            let (channel, samples, duration) = match capture::result() {
+                Some(result) => result,
+                // Still capturing
+                None => return false,
+            };
+            if !*started {
+                send_fmt(
+                    socket,
+                    format_args!(
+                        "{{\"channel\":{},\"samples\":{},\"duration\":{:.3}}}",
+                        channel,
+                        samples,
+                        duration as f64 / 1000.0
+                    ),
+                );
+                *started = true;
+            }
+            while *next < samples {
+                if !dump_line_fits(socket, DUMP_LINE_MAX) {
+                    return false;
+                }
+                match capture::get(*next) {
+                    Some(data) => send_fmt(socket, format_args!("{}", data)),
+                    // Restarted by another session in the meantime
+                    None => send_line(socket, b"null"),
+                };
+                *next += 1;
+            }
+        }
+        Dump::StepTest { delta_i } => {
+            let (channel, samples, duration) = match capture::result() {
+                Some(result) => result,
+                // Still stepping
+                None => return false,
+            };
+            let interval = duration as f64 / 1000.0 / (samples.max(2) - 1) as f64;
+            match capture::with_samples(|samples| {
+                step_response::analyze(samples, interval, *delta_i)
+            }) {
+                Some(response) => {
+                    send_fmt(
+                        socket,
+                        format_args!(
+                            "{{\"channel\":{},\"gain\":{},\"rise_time\":{},\"dead_time\":{},\"time_constant\":{}}}",
+                            channel,
+                            response.gain,
+                            response.rise_time,
+                            response.dead_time,
+                            response.time_constant
+                        ),
+                    );
+                }
+                None => {
+                    send_error(
+                        socket,
+                        Error::StepTest,
+                        format_args!("no step response"),
+                        None,
+                    );
+                }
+            };
+            *dump = Dump::Capture {
+                started: false,
+                next: 0,
+            };
+            return send_dump(socket, dump, channels);
+        }
+        Dump::EventLog { next, end } => {
+            while *next < *end {
+                if !dump_line_fits(socket, EVENT_LINE_MAX) {
+                    return false;
+                }
+                match event_log::get(*next).map(|event| event.json()) {
+                    Some(Ok(buf)) => send_line(socket, &buf),
+                    Some(Err(e)) => {
+                        error!("unable to serialize event: {:?}", e);
+                        send_line(socket, b"null")
+                    }
+                    // Cleared in the meantime
+                    None => send_line(socket, b"null"),
+                };
+                *next += 1;
+            }
+        }
+        Dump::Log {
+            next,
+            end,
+            remaining,
+        } => {
+            while *remaining > 0 {
+                if !dump_line_fits(socket, LOG_LINE_MAX) {
+                    return false;
+                }
+                match log_buffer::line(*next, *end) {
+                    Some((line, line_end)) => {
+                        send_line(socket, line.as_bytes());
+                        *next = line_end;
+                    }
+                    // Overwritten in the meantime
+                    None => {
+                        send_line(socket, b"null");
+                    }
+                }
+                *remaining -= 1;
+            }
+        }
+        Dump::Config { next } => {
+            while *next < CHANNELS {
+                match serde_json_core::to_vec::<U2048, _>(&ConfigDocument::channel(channels, *next))
+                {
+                    // With the length prefix of its framing
+                    Ok(buf) if !dump_line_fits(socket, buf.len() + 4) => {
+                        if buf.len() + 4 < socket.send_capacity() {
+                            return false;
+                        }
+                        send_error(
+                            socket,
+                            Error::Report,
+                            format_args!("config longer than the send buffer"),
+                            None,
+                        );
+                    }
+                    Ok(buf) => {
+                        send_line(socket, &buf);
+                    }
+                    Err(e) => {
+                        error!("unable to serialize config: {:?}", e);
+                        send_error(socket, Error::Report, format_args!("{:?}", e), None);
+                    }
+                }
+                *next += 1;
+            }
+        }
+    }
+    true
+}
+
+pub fn send_self_test(socket: &mut FramedSocket, selftest: &SelfTest) {
+    match selftest.json() {
+        Ok(buf) => {
+            send_line(socket, &buf);
+        }
+        Err(e) => {
+            error!("unable to serialize self-test: {:?}", e);
+            send_error(socket, Error::Report, format_args!("{:?}", e), None);
+        }
+    }
+}
+
+/// EEPROM size of the 24x02
+const EEPROM_SIZE: u32 = 256;
+/// End of the user area. The upper half of the 24AA02E48 is
+/// write-protected, and holds the EUI-48 at 0xFA.
+const EEPROM_USER_END: u32 = 0x80;
+const EEPROM_PAGE_SIZE: u32 = 8;
+/// Milliseconds for the EEPROM to write a page
+const EEPROM_WRITE_TIME: u32 = 5;
+
+/// Bytes formatted as hex digits
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(fmt, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Carry out an `eeprom` command that has been checked by
+/// `Handler::eeprom()`
+pub fn send_eeprom(socket: &mut FramedSocket, eeprom: &mut Eeprom, access: &EepromAccess) {
+    match access {
+        EepromAccess::Read { offset, len } => {
+            let mut buf = [0; EEPROM_SIZE as usize];
+            let data = &mut buf[..(*len as usize)];
+            match eeprom.read_data(*offset, data) {
+                Ok(()) => {
+                    send_fmt(
+                        socket,
+                        format_args!("{{\"offset\":{},\"data\":\"{}\"}}", offset, Hex(data)),
+                    );
+                }
+                Err(e) => {
+                    error!("unable to read EEPROM: {:?}", e);
+                    send_error(socket, Error::Eeprom, format_args!("{:?}", e), None);
+                }
+            }
+        }
+        EepromAccess::Write { offset, data } => {
+            let mut offset = *offset;
+            let mut data = &data[..];
+            while !data.is_empty() {
+                // A write must not cross a page boundary
+                let len = ((EEPROM_PAGE_SIZE - offset % EEPROM_PAGE_SIZE) as usize).min(data.len());
+                if let Err(e) = eeprom.write_page(offset, &data[..len]) {
+                    error!("unable to write EEPROM: {:?}", e);
+                    send_error(socket, Error::Eeprom, format_args!("{:?}", e), None);
+                    return;
+                }
+                timer::sleep(EEPROM_WRITE_TIME);
+                offset += len as u32;
+                data = &data[len..];
+            }
+            send_line(socket, b"{}");
+        }
+    }
+}
+
+pub fn send_clients(socket: &mut FramedSocket, clients: &[ClientSummary]) {
+    match serde_json_core::to_vec::<U2048, _>(&clients) {
+        Ok(buf) => {
+            send_line(socket, &buf);
+        }
+        Err(e) => {
+            error!("unable to serialize clients: {:?}", e);
+            send_error(socket, Error::Report, format_args!("{:?}", e), None);
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
-    Report,
-    PostFilterRate,
+    /// Command line that cannot be parsed
+    Parse,
+    /// Value outside of the range that a setting accepts
+    Range,
+    /// Config document that cannot be applied
+    Config,
+    Alias,
+    Fault,
+    /// Output powered during a calibration
+    Powered,
+    StepTest,
+    ConfigBusy,
+    DacCalibration,
+    /// Output of a sensor-only channel
+    SensorOnly,
+    Calibration,
+    Eeprom,
     Flash,
+    /// Response that cannot be serialized
+    Report,
+}
+
+impl Error {
+    /// Code of the error in responses, which stays the same across
+    /// firmware versions. The hundreds are the category.
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Parse => 100,
+            Error::Range => 101,
+            Error::Config => 102,
+            Error::Alias => 103,
+            Error::Fault => 200,
+            Error::Powered => 201,
+            Error::StepTest => 202,
+            Error::ConfigBusy => 203,
+            Error::DacCalibration => 204,
+            Error::SensorOnly => 205,
+            Error::Calibration => 300,
+            Error::Eeprom => 301,
+            Error::Flash => 400,
+            Error::Report => 500,
+        }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self.code() / 100 {
+            1 => "input",
+            2 => "state",
+            3 => "hardware",
+            4 => "flash",
+            _ => "internal",
+        }
+    }
+}
+
+pub type JsonBuffer = Vec<u8, U2048>;
+
+/// Transport that responses are written to, so that commands are
+/// handled the same way whichever one they have come from
+pub trait Response {
+    /// Size of the send buffer
+    fn send_capacity(&self) -> usize;
+    /// Bytes in the send buffer that have not been sent yet
+    fn send_queue(&self) -> usize;
+    /// Queue as many bytes of `data` as fit, returns how many
+    fn send_slice(&mut self, data: &[u8]) -> usize;
+    /// Bytes of earlier lines waiting for room in the send buffer,
+    /// which further lines must go after
+    fn queued(&self) -> usize {
+        0
+    }
+    /// Keep a whole line, made of `parts`, until the send buffer has
+    /// room for it. Returns `false` if there is no room to keep it.
+    fn queue_line(&mut self, _parts: &[&[u8]]) -> bool {
+        false
+    }
+}
+
+impl Response for TcpSocket<'_> {
+    fn send_capacity(&self) -> usize {
+        TcpSocket::send_capacity(self)
+    }
+
+    fn send_queue(&self) -> usize {
+        TcpSocket::send_queue(self)
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        TcpSocket::send_slice(self, data).unwrap_or_else(|e| {
+            error!("error sending: {:?}", e);
+            0
+        })
+    }
+}
+
+/// Lines that did not fit the send buffer of a TCP client, sent as
+/// it drains instead of being dropped for a slow client
+pub struct SendQueue {
+    buf: [u8; SEND_QUEUE_LEN],
+    len: usize,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        SendQueue {
+            buf: [0; SEND_QUEUE_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Send as much of the queue as the send buffer takes. Returns
+    /// `true` once it is empty.
+    pub fn flush(&mut self, socket: &mut dyn Response) -> bool {
+        if self.len == 0 {
+            return true;
+        }
+        let sent = socket.send_slice(&self.buf[..self.len]);
+        self.buf.copy_within(sent..self.len, 0);
+        self.len -= sent;
+        self.len == 0
+    }
+
+    fn push(&mut self, parts: &[&[u8]]) -> bool {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if self.len + len > SEND_QUEUE_LEN {
+            return false;
+        }
+        for part in parts {
+            self.buf[self.len..self.len + part.len()].copy_from_slice(part);
+            self.len += part.len();
+        }
+        true
+    }
+}
+
+/// Transport that keeps lines in a `SendQueue` when they do not fit
+pub struct QueuedResponse<'a> {
+    socket: &'a mut dyn Response,
+    queue: &'a mut SendQueue,
+}
+
+impl<'a> QueuedResponse<'a> {
+    pub fn new(socket: &'a mut dyn Response, queue: &'a mut SendQueue) -> Self {
+        QueuedResponse { socket, queue }
+    }
+}
+
+impl Response for QueuedResponse<'_> {
+    fn send_capacity(&self) -> usize {
+        self.socket.send_capacity()
+    }
+
+    fn send_queue(&self) -> usize {
+        self.socket.send_queue()
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        self.socket.send_slice(data)
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.len
+    }
+
+    fn queue_line(&mut self, parts: &[&[u8]]) -> bool {
+        self.queue.push(parts)
+    }
+}
+
+/// Transport of a session, with the response framing negotiated for
+/// it
+pub struct FramedSocket<'s> {
+    socket: &'s mut dyn Response,
+    framing: Framing,
+    /// Send the `{}` acknowledgement of commands, unless it is sent
+    /// once for a command applied to all channels
+    acknowledge: bool,
+    /// Tag of the command, added to responses that are objects
+    tag: Option<RequestTag>,
+    report_format: ReportFormat,
+    /// Significant digits of the measurements in reports
+    precision: Option<u8>,
+}
+
+impl<'s> FramedSocket<'s> {
+    pub fn new(socket: &'s mut dyn Response, framing: Framing) -> Self {
+        FramedSocket {
+            socket,
+            framing,
+            acknowledge: true,
+            tag: None,
+            report_format: ReportFormat::Json,
+            precision: None,
+        }
+    }
+
+    pub fn set_tag(&mut self, tag: Option<RequestTag>) {
+        self.tag = tag;
+    }
+
+    pub fn set_report_format(&mut self, report_format: ReportFormat) {
+        self.report_format = report_format;
+    }
+
+    pub fn set_precision(&mut self, precision: Option<u8>) {
+        self.precision = precision;
+    }
+
+    fn send_capacity(&self) -> usize {
+        self.socket.send_capacity()
+    }
+
+    fn send_free(&self) -> usize {
+        self.socket.send_capacity() - self.socket.send_queue()
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        self.socket.send_slice(data)
+    }
 }
 
-pub type JsonBuffer = Vec<u8, U1024>;
+/// Send the echo of an interactive session as it is, without framing
+pub fn send_echo(socket: &mut FramedSocket, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    if socket.socket.queued() > 0 {
+        if !socket.socket.queue_line(&[data]) {
+            warn!("send queue full, echo dropped");
+        }
+        return;
+    }
+    socket.send_slice(data);
+}
 
-fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
-    let send_free = socket.send_capacity() - socket.send_queue();
-    if data.len() > send_free + 1 {
+pub fn send_line(socket: &mut FramedSocket, data: &[u8]) -> bool {
+    if !socket.acknowledge && data == b"{}" {
+        return true;
+    }
+    // The tag goes first into objects, as in `{"id":"<tag>",...}`,
+    // and other lines are wrapped as `{"id":"<tag>","data":...}`
+    let mut head = String::<U48>::new();
+    let mut body = data;
+    let mut tail: &[u8] = b"";
+    match (&socket.tag, data.split_first()) {
+        (Some(tag), Some((&b'{', rest))) => {
+            let separator = if rest == b"}" { "" } else { "," };
+            let _ = write!(head, "{{\"id\":\"{}\"{}", tag, separator);
+            body = rest;
+        }
+        (Some(tag), _) => {
+            let _ = write!(head, "{{\"id\":\"{}\",\"data\":", tag);
+            tail = b"}";
+        }
+        (None, _) => {}
+    }
+    let len = head.len() + body.len() + tail.len();
+    let framing = socket.framing;
+    let needed = match framing {
+        Framing::Newline => len + 1,
+        Framing::LengthPrefix => 4 + len,
+    };
+    let mut header = [0; 4];
+    BigEndian::write_u32(&mut header, len as u32);
+    let send_free = socket.send_free();
+    if socket.socket.queued() > 0 || needed > send_free {
+        // Keep the line until the client has taken the ones before
+        let header: &[u8] = match framing {
+            Framing::Newline => b"",
+            Framing::LengthPrefix => &header,
+        };
+        let newline: &[u8] = match framing {
+            Framing::Newline => b"\n",
+            Framing::LengthPrefix => b"",
+        };
+        if socket
+            .socket
+            .queue_line(&[header, head.as_bytes(), body, tail, newline])
+        {
+            return true;
+        }
         // Not enough buffer space, skip report for now,
         // instead of sending incomplete line
         warn!(
-            "TCP socket has only {}/{} needed {}",
-            send_free + 1,
+            "send buffer has only {}/{} needed {}",
+            send_free,
             socket.send_capacity(),
-            data.len(),
+            needed,
         );
     } else {
-        match socket.send_slice(data) {
-            Ok(sent) if sent == data.len() => {
-                let _ = socket.send_slice(b"\n");
-                // success
-                return true;
+        if framing == Framing::LengthPrefix {
+            socket.send_slice(&header);
+        }
+        socket.send_slice(head.as_bytes());
+        let sent = socket.send_slice(body);
+        if sent == body.len() {
+            socket.send_slice(tail);
+            if framing == Framing::Newline {
+                socket.send_slice(b"\n");
             }
-            Ok(sent) => warn!("sent only {}/{} bytes", sent, data.len()),
-            Err(e) => error!("error sending line: {:?}", e),
+            // success
+            return true;
         }
+        warn!("sent only {}/{} bytes", head.len() + sent, len);
     }
     // not success
     false
 }
 
-impl Handler {
-    fn show_report(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.reports_json() {
-            Ok(buf) => {
-                send_line(socket, &buf[..]);
-            }
-            Err(e) => {
-                error!("unable to serialize report: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::Report);
-            }
+/// Send `json` as a line, or a report error if `what` could not be
+/// serialized
+fn send_serialized(
+    socket: &mut FramedSocket,
+    what: &str,
+    json: Result<JsonBuffer, serde_json_core::ser::Error>,
+) -> Result<Handler, Error> {
+    match json {
+        Ok(buf) => {
+            send_line(socket, &buf);
+            Ok(Handler::Handled)
+        }
+        Err(e) => {
+            error!("unable to serialize {}: {:?}", what, e);
+            Err(send_error(
+                socket,
+                Error::Report,
+                format_args!("{:?}", e),
+                None,
+            ))
         }
-        Ok(Handler::Handled)
     }
+}
 
-    fn show_pid(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.pid_summaries_json() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-            }
-            Err(e) => {
-                error!("unable to serialize pid summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::Report);
-            }
-        }
-        Ok(Handler::Handled)
+/// Serialize `value` and send it as a line
+fn send_json<T: Serialize + ?Sized>(
+    socket: &mut FramedSocket,
+    what: &str,
+    value: &T,
+) -> Result<Handler, Error> {
+    send_serialized(socket, what, serde_json_core::to_vec(value))
+}
+
+/// Format a line before sending it, as the length prefix must be
+/// known up front
+fn send_fmt(socket: &mut FramedSocket, args: fmt::Arguments) -> bool {
+    let mut line = String::<U256>::new();
+    if line.write_fmt(args).is_err() {
+        error!("line too long to format");
+        return false;
     }
+    send_line(socket, line.as_bytes())
+}
 
-    fn show_output(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.output_summaries_json() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-            }
-            Err(e) => {
-                error!("unable to serialize output summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::Report);
+/// Text with `"` and `\` escaped, to be sent within a JSON string
+struct JsonEscape<'a>(fmt::Arguments<'a>);
+
+impl fmt::Display for JsonEscape<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl fmt::Write for Escaper<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    if c == '"' || c == '\\' {
+                        self.0.write_char('\\')?;
+                    }
+                    self.0.write_char(c)?;
+                }
+                Ok(())
             }
         }
-        Ok(Handler::Handled)
+
+        fmt::write(&mut Escaper(fmt), self.0)
     }
+}
 
-    fn show_b_parameter(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.b_parameter_summaries_json() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-            }
-            Err(e) => {
-                error!("unable to serialize b parameter summaries: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::Report);
-            }
-        }
-        Ok(Handler::Handled)
+/// Send an error response, naming the parameter of the command that
+/// caused it if there is one. Returns the error, to be passed on.
+pub fn send_error(
+    socket: &mut FramedSocket,
+    error: Error,
+    message: fmt::Arguments,
+    param: Option<&str>,
+) -> Error {
+    let mut line = String::<U256>::new();
+    let result = write!(
+        line,
+        "{{\"error\":{{\"code\":{},\"category\":\"{}\",\"message\":\"{}\",\"param\":",
+        error.code(),
+        error.category(),
+        JsonEscape(message)
+    )
+    .and_then(|()| match param {
+        Some(param) => write!(line, "\"{}\"}}}}", param),
+        None => line.write_str("null}}"),
+    });
+    if result.is_err() {
+        error!("error response too long to format");
+    } else {
+        send_line(socket, line.as_bytes());
     }
+    error
+}
 
-    fn show_post_filter(socket: &mut TcpSocket, channels: &mut Channels) -> Result<Handler, Error> {
-        match channels.postfilter_summaries_json() {
+impl Handler {
+    fn show_report(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        if socket.report_format == ReportFormat::Csv {
+            // Rows are not JSON, and cannot carry a tag
+            socket.set_tag(None);
+            for channel in 0..CHANNELS {
+                match channels.report_csv(channel, socket.precision) {
+                    Ok(row) => {
+                        if !send_line(socket, row.as_bytes()) {
+                            channels.report_dropped();
+                        }
+                    }
+                    Err(e) => {
+                        error!("unable to format report: {:?}", e);
+                        return Err(send_error(
+                            socket,
+                            Error::Report,
+                            format_args!("{:?}", e),
+                            None,
+                        ));
+                    }
+                }
+            }
+            return Ok(Handler::Handled);
+        }
+        let reports = match socket.report_format {
+            ReportFormat::Milli => channels.reports_milli_json(),
+            _ => channels.reports_json(socket.precision),
+        };
+        match reports {
             Ok(buf) => {
-                send_line(socket, &buf);
+                if !send_line(socket, &buf[..]) {
+                    channels.report_dropped();
+                }
             }
             Err(e) => {
-                error!("unable to serialize postfilter summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                return Err(Error::Report);
+                error!("unable to serialize report: {:?}", e);
+                return Err(send_error(
+                    socket,
+                    Error::Report,
+                    format_args!("{:?}", e),
+                    None,
+                ));
             }
         }
         Ok(Handler::Handled)
     }
 
-    fn show_ipv4(socket: &mut TcpSocket, ipv4_config: &mut Ipv4Config) -> Result<Handler, Error> {
-        let (cidr, gateway) = net::split_ipv4_config(ipv4_config.clone());
-        let _ = write!(socket, "{{\"addr\":\"{}\"", cidr);
-        gateway.map(|gateway| write!(socket, ",\"gateway\":\"{}\"", gateway));
-        let _ = writeln!(socket, "}}");
-        Ok(Handler::Handled)
+    fn show_pid(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "pid summary", channels.pid_summaries_json())
     }
 
-    fn engage_pid(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
+    fn show_output(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
     ) -> Result<Handler, Error> {
-        channels.channel_state(channel).pid_engaged = true;
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+        send_serialized(socket, "output summary", channels.output_summaries_json())
     }
 
-    fn set_polarity(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
-        polarity: Polarity,
+    fn show_b_parameter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
     ) -> Result<Handler, Error> {
-        channels.set_polarity(channel, polarity);
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+        send_serialized(
+            socket,
+            "b parameter summaries",
+            channels.b_parameter_summaries_json(),
+        )
     }
 
-    fn set_output(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
-        pin: PwmPin,
-        value: f64,
+    fn show_steinhart_hart(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
     ) -> Result<Handler, Error> {
-        match pin {
-            PwmPin::ISet => {
-                channels.channel_state(channel).pid_engaged = false;
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_i(channel, current);
-                channels.power_up(channel);
-            }
-            PwmPin::MaxV => {
-                let voltage = ElectricPotential::new::<volt>(value);
-                channels.set_max_v(channel, voltage);
-            }
-            PwmPin::MaxIPos => {
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_max_i_pos(channel, current);
-            }
-            PwmPin::MaxINeg => {
-                let current = ElectricCurrent::new::<ampere>(value);
-                channels.set_max_i_neg(channel, current);
-            }
-        }
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+        send_serialized(
+            socket,
+            "steinhart-hart summaries",
+            channels.steinhart_hart_summaries_json(),
+        )
     }
 
-    fn set_center_point(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
-        center: CenterPoint,
+    fn show_sensor(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
     ) -> Result<Handler, Error> {
-        let i_set = channels.get_i_set(channel);
-        let state = channels.channel_state(channel);
-        state.center = center;
-        if !state.pid_engaged {
-            channels.set_i(channel, i_set);
-        }
-        send_line(socket, b"{}");
-        Ok(Handler::Handled)
+        send_serialized(socket, "sensor summaries", channels.sensor_summaries_json())
     }
 
-    fn set_pid(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
-        parameter: PidParameter,
-        value: f64,
+    fn show_raw_report(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
     ) -> Result<Handler, Error> {
-        let pid = &mut channels.channel_state(channel).pid;
+        send_serialized(socket, "raw report", channels.raw_reports_json())
+    }
+
+    fn show_cal(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "cal summaries", channels.cal_summaries_json())
+    }
+
+    fn show_stats(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "stats summaries", channels.stats_summaries_json())
+    }
+
+    fn show_data_rate(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(
+            socket,
+            "data rate summaries",
+            channels.data_rate_summaries_json(),
+        )
+    }
+
+    fn show_post_filter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(
+            socket,
+            "postfilter summary",
+            channels.postfilter_summaries_json(),
+        )
+    }
+
+    fn show_cascade(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "cascade summary", channels.cascade_summaries_json())
+    }
+
+    fn show_interlock(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(
+            socket,
+            "interlock summary",
+            channels.interlock_summaries_json(),
+        )
+    }
+
+    fn show_stable(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "stable summary", channels.stable_summaries_json())
+    }
+
+    fn show_dtdt(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "dT/dt summary", channels.dtdt_summaries_json())
+    }
+
+    fn show_locked(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(socket, "locked summary", channels.locked_json())
+    }
+
+    fn show_ipv4(
+        socket: &mut FramedSocket,
+        ipv4_config: &mut Ipv4Config,
+    ) -> Result<Handler, Error> {
+        let (cidr, gateway) = net::split_ipv4_config(ipv4_config.clone());
+        match gateway {
+            Some(gateway) => send_fmt(
+                socket,
+                format_args!("{{\"addr\":\"{}\",\"gateway\":\"{}\"}}", cidr, gateway),
+            ),
+            None => send_fmt(socket, format_args!("{{\"addr\":\"{}\"}}", cidr)),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn show_framing(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        let line: &[u8] = match socket.framing {
+            Framing::Newline => b"{\"framing\":\"newline\"}",
+            Framing::LengthPrefix => b"{\"framing\":\"length\"}",
+        };
+        send_line(socket, line);
+        Ok(Handler::Handled)
+    }
+
+    fn set_framing(socket: &mut FramedSocket, framing: Framing) -> Result<Handler, Error> {
+        // Acknowledge with the previous framing, the new one applies
+        // to all subsequent responses
+        send_line(socket, b"{}");
+        Ok(Handler::NewFraming(framing))
+    }
+
+    fn show_report_format(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        let line: &[u8] = match socket.report_format {
+            ReportFormat::Json => b"{\"format\":\"json\"}",
+            ReportFormat::Milli => b"{\"format\":\"milli\"}",
+            ReportFormat::Csv => b"{\"format\":\"csv\"}",
+        };
+        send_line(socket, line);
+        Ok(Handler::Handled)
+    }
+
+    fn set_report_format(
+        socket: &mut FramedSocket,
+        report_format: ReportFormat,
+    ) -> Result<Handler, Error> {
+        match report_format {
+            ReportFormat::Json | ReportFormat::Milli => {
+                send_line(socket, b"{}");
+            }
+            // The header in place of the acknowledgement
+            ReportFormat::Csv => {
+                socket.set_tag(None);
+                send_line(socket, REPORT_CSV_HEADER);
+            }
+        }
+        Ok(Handler::NewReportFormat(report_format))
+    }
+
+    fn show_precision(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        match socket.precision {
+            Some(digits) => send_fmt(socket, format_args!("{{\"precision\":{}}}", digits)),
+            None => send_line(socket, b"{\"precision\":null}"),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn set_precision(socket: &mut FramedSocket, precision: Option<u8>) -> Result<Handler, Error> {
+        send_line(socket, b"{}");
+        Ok(Handler::NewPrecision(precision))
+    }
+
+    fn set_interactive(socket: &mut FramedSocket, enabled: bool) -> Result<Handler, Error> {
+        send_line(socket, b"{}");
+        Ok(Handler::Interactive(enabled))
+    }
+
+    /// Refuse to drive the output of a channel with a latched fault,
+    /// or of a sensor-only channel
+    fn check_output_allowed(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<(), Error> {
+        let state = channels.channel_state(channel);
+        if state.fault.is_some() {
+            return Err(send_error(
+                socket,
+                Error::Fault,
+                format_args!("channel fault, use clear_fault"),
+                None,
+            ));
+        }
+        if state.sensor_only {
+            return Err(send_error(
+                socket,
+                Error::SensorOnly,
+                format_args!("sensor-only channel, use sensor_only off"),
+                None,
+            ));
+        }
+        if channels.dac_calibration_running(channel) {
+            // The output is kept off until the search is done
+            return Err(send_error(
+                socket,
+                Error::DacCalibration,
+                format_args!("DAC calibration running"),
+                None,
+            ));
+        }
+        Ok(())
+    }
+
+    fn engage_pid(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        Handler::check_output_allowed(socket, channels, channel)?;
+        channels.channel_state(channel).v_set = None;
+        channels.channel_state(channel).pid_engaged = true;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn output_off(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        channels.shut_down(channel);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_polarity(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        polarity: Polarity,
+    ) -> Result<Handler, Error> {
+        channels.set_polarity(channel, polarity);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_output_trim(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        enabled: bool,
+    ) -> Result<Handler, Error> {
+        channels.set_i_trim(channel, enabled);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_output_heater_only(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        enabled: bool,
+    ) -> Result<Handler, Error> {
+        let state = channels.channel_state(channel);
+        state.heater_only = enabled;
+        if enabled {
+            // Keep the PID from winding up towards cooling
+            let output_min = &mut state.pid.parameters.output_min;
+            *output_min = output_min.max(0.0);
+            let zero = ElectricCurrent::new::<ampere>(0.0);
+            channels.set_max_i_neg(channel, zero);
+            let i_set = channels.get_i_set(channel);
+            channels.set_i(channel, i_set.max(zero));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_output_voltage(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        voltage: f64,
+    ) -> Result<Handler, Error> {
+        let voltage = ElectricPotential::new::<volt>(voltage);
+        if voltage.abs() > MAX_TEC_V {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("v_set beyond {} V", MAX_TEC_V.get::<volt>()),
+                Some("v_set"),
+            ));
+        }
+        Handler::check_output_allowed(socket, channels, channel)?;
+        let state = channels.channel_state(channel);
+        state.pid_engaged = false;
+        state.v_set = Some(voltage);
+        channels.power_up(channel);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn query_output(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        pin: PwmPin,
+    ) -> Result<Handler, Error> {
+        let (name, value) = match pin {
+            PwmPin::ISet => ("i_set", channels.get_i_set(channel).get::<ampere>()),
+            PwmPin::MaxIPos => ("max_i_pos", channels.get_max_i_pos(channel).get::<ampere>()),
+            PwmPin::MaxINeg => ("max_i_neg", channels.get_max_i_neg(channel).get::<ampere>()),
+            PwmPin::MaxV => ("max_v", channels.get_max_v(channel).get::<volt>()),
+        };
+        send_fmt(socket, format_args!("{{\"{}\":{}}}", name, value));
+        Ok(Handler::Handled)
+    }
+
+    fn set_output(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        pin: PwmPin,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        match pin {
+            PwmPin::ISet => {
+                Handler::check_output_allowed(socket, channels, channel)?;
+                channels.channel_state(channel).pid_engaged = false;
+                channels.channel_state(channel).v_set = None;
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_i(channel, current);
+                channels.power_up(channel);
+            }
+            PwmPin::MaxV => {
+                let voltage = ElectricPotential::new::<volt>(value);
+                channels.set_max_v(channel, voltage);
+            }
+            PwmPin::MaxIPos => {
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_max_i_pos(channel, current);
+            }
+            PwmPin::MaxINeg => {
+                let current = ElectricCurrent::new::<ampere>(value);
+                channels.set_max_i_neg(channel, current);
+            }
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_center_point(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        center: CenterPoint,
+    ) -> Result<Handler, Error> {
+        let i_set = channels.get_i_set(channel);
+        let state = channels.channel_state(channel);
+        state.center = center;
+        if !state.pid_engaged {
+            channels.set_i(channel, i_set);
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_pid(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        parameter: PidParameter,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        let state = channels.channel_state(channel);
+        if matches!(parameter, PidParameter::OutputMin) && state.heater_only && value < 0.0 {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("heater-only channel, output_min must not be negative"),
+                Some("output_min"),
+            ));
+        }
+        if matches!(parameter, PidParameter::Divisor) && !(value >= 1.0 && value.fract() == 0.0) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("divisor must be a whole number of samples, at least 1"),
+                Some("divisor"),
+            ));
+        }
+        let pid = &mut state.pid;
         use super::command_parser::PidParameter::*;
         match parameter {
             Target => pid.target = value,
@@ -233,130 +1266,1593 @@ impl Handler {
             KD => pid.parameters.kd = value as f32,
             OutputMin => pid.parameters.output_min = value as f32,
             OutputMax => pid.parameters.output_max = value as f32,
+            Ramp => pid.parameters.ramp_rate = value as f32,
+            DFilter => pid.parameters.d_filter = value as f32,
+            FfOffset => pid.parameters.ff_offset = value as f32,
+            FfGain => pid.parameters.ff_gain = value as f32,
+            FfReference => pid.parameters.ff_reference = value as f32,
+            Divisor => pid.parameters.divisor = value as u32,
         }
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn set_b_parameter(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
+    fn query_pid(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
         channel: usize,
-        parameter: BpParameter,
+        parameter: PidParameter,
+    ) -> Result<Handler, Error> {
+        let pid = &channels.channel_state(channel).pid;
+        let (name, value): (&str, &dyn fmt::Display) = match parameter {
+            PidParameter::Target => ("target", &pid.target),
+            PidParameter::KP => ("kp", &pid.parameters.kp),
+            PidParameter::KI => ("ki", &pid.parameters.ki),
+            PidParameter::KD => ("kd", &pid.parameters.kd),
+            PidParameter::OutputMin => ("output_min", &pid.parameters.output_min),
+            PidParameter::OutputMax => ("output_max", &pid.parameters.output_max),
+            PidParameter::Ramp => ("ramp", &pid.parameters.ramp_rate),
+            PidParameter::DFilter => ("d_filter", &pid.parameters.d_filter),
+            PidParameter::FfOffset => ("ff_offset", &pid.parameters.ff_offset),
+            PidParameter::FfGain => ("ff_gain", &pid.parameters.ff_gain),
+            PidParameter::FfReference => ("ff_reference", &pid.parameters.ff_reference),
+            PidParameter::Divisor => ("divisor", &pid.parameters.divisor),
+        };
+        send_fmt(socket, format_args!("{{\"{}\":{}}}", name, value));
+        Ok(Handler::Handled)
+    }
+
+    fn set_cascade(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        enabled: bool,
+    ) -> Result<Handler, Error> {
+        match channels.set_cascade(channel, enabled) {
+            Some(outer) => send_fmt(socket, format_args!("{{\"disabled\":{}}}", outer)),
+            None => send_line(socket, b"{}"),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn set_sensor_only(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        enabled: bool,
+    ) -> Result<Handler, Error> {
+        if enabled {
+            channels.shut_down(channel);
+        }
+        channels.channel_state(channel).sensor_only = enabled;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_cal(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        parameter: CalParameter,
         value: f64,
     ) -> Result<Handler, Error> {
-        let bp = &mut channels.channel_state(channel).bp;
-        use super::command_parser::BpParameter::*;
+        let state = channels.channel_state(channel);
+        let result = match parameter {
+            CalParameter::Offset => {
+                state.cal_offset = value;
+                Ok(())
+            }
+            CalParameter::Gain => {
+                state.cal_gain = value;
+                Ok(())
+            }
+            CalParameter::Point1 => state.cal_point1(value),
+            CalParameter::Point2 => state.cal_point2(value),
+        };
+        match result {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to calibrate channel {}: {:?}", channel, e);
+                Err(send_error(
+                    socket,
+                    Error::Calibration,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_tec_cal(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        send_serialized(
+            socket,
+            "TEC cal summaries",
+            channels.tec_cal_summaries_json(),
+        )
+    }
+
+    fn set_tec_cal(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        channel: usize,
+        parameter: TecCalParameter,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        let cal = &mut channels.channel_state(channel).tec_cal;
         match parameter {
-            T0 => bp.t0 = ThermodynamicTemperature::new::<degree_celsius>(value),
-            B => bp.b = TemperatureInterval::new::<kelvin>(value),
-            R0 => bp.r0 = ElectricalResistance::new::<ohm>(value),
+            TecCalParameter::IGain => cal.i_gain = value,
+            TecCalParameter::IOffset => cal.i_offset = value,
+            TecCalParameter::VGain => cal.v_gain = value,
+            TecCalParameter::VOffset => cal.v_offset = value,
+        }
+        match store.write_value(TEC_CALIBRATION_KEY[channel], cal, [0; 64]) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save TEC calibration to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    /// Start the search, which the main loop saves to flash when done
+    fn calibrate_dac(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        if channels.is_powered(channel) {
+            return Err(send_error(
+                socket,
+                Error::Powered,
+                format_args!("output powered, use output off"),
+                None,
+            ));
         }
+        if channels.any_dac_calibration_running() {
+            return Err(send_error(
+                socket,
+                Error::DacCalibration,
+                format_args!("DAC calibration running"),
+                None,
+            ));
+        }
+        channels.start_dac_calibration(channel);
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn reset_post_filter(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
+    /// Write a raw code to the DAC of a channel with its output off,
+    /// and reply with the DAC output measured
+    fn set_dac_code(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
         channel: usize,
+        code: u32,
     ) -> Result<Handler, Error> {
-        channels.set_postfilter(channel as u8, None);
+        let max_value = channels.dac_max_value(channel);
+        if code > max_value {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("code beyond {}", max_value),
+                Some("code"),
+            ));
+        }
+        if channels.is_powered(channel) {
+            return Err(send_error(
+                socket,
+                Error::Powered,
+                format_args!("output powered, use output off"),
+                None,
+            ));
+        }
+        if channels.any_dac_calibration_running() {
+            return Err(send_error(
+                socket,
+                Error::DacCalibration,
+                format_args!("DAC calibration running"),
+                None,
+            ));
+        }
+        // Not to be overwritten by the PID
+        channels.channel_state(channel).pid_engaged = false;
+        let dac_feedback = channels.set_dac_code(channel, code);
+        send_fmt(
+            socket,
+            format_args!(
+                "{{\"dac_code\":{},\"dac_feedback\":{}}}",
+                code,
+                dac_feedback.get::<volt>()
+            ),
+        );
+        Ok(Handler::Handled)
+    }
+
+    fn reset_stats(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        channels.reset_stats();
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn set_post_filter(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: usize,
-        rate: f32,
+    fn show_time(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        match rtc::now() {
+            Some(time) => send_fmt(
+                socket,
+                format_args!(
+                    "{{\"epoch\":{:.3},\"iso\":\"{}\"}}",
+                    time.to_epoch_millis() as f64 / 1000.0,
+                    time
+                ),
+            ),
+            None => send_line(socket, b"{\"epoch\":null,\"iso\":null}"),
+        };
+        Ok(Handler::Handled)
+    }
+
+    fn set_time(socket: &mut FramedSocket, epoch: u32) -> Result<Handler, Error> {
+        if !(rtc::EPOCH_MIN..=rtc::EPOCH_MAX).contains(&epoch) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("time out of range"),
+                Some("epoch"),
+            ));
+        }
+        rtc::set(epoch);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn reset_energy(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        channels.reset_energy();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_history(
+        socket: &mut FramedSocket,
+        channel: usize,
+        samples: Option<u32>,
+    ) -> Result<Handler, Error> {
+        let samples = samples.unwrap_or(history::HISTORY_LEN as u32);
+        let (next, end) = history::last(channel, samples);
+        send_fmt(
+            socket,
+            format_args!("{{\"channel\":{},\"samples\":{}}}", channel, end - next),
+        );
+        Ok(Handler::NewDump(Dump::History { channel, next, end }))
+    }
+
+    /// Send the config document of the device, followed by those of
+    /// the channels as a dump
+    fn show_config(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        ipv4_config: &Ipv4Config,
+        fan_ctrl: &mut FanCtrl,
+    ) -> Result<Handler, Error> {
+        let power_on = store.read_value("power_on").unwrap_or(None);
+        let selftest_boot = store.read_value("selftest_boot").unwrap_or(None);
+        let document = ConfigDocument::device(ipv4_config, fan_ctrl, power_on, selftest_boot);
+        send_json(socket, "config", &document).map(|_| Handler::NewDump(Dump::Config { next: 0 }))
+    }
+
+    fn load_config(socket: &mut FramedSocket, mode: DocumentMode) -> Result<Handler, Error> {
+        if !config::receive_begin(mode) {
+            return Err(send_error(
+                socket,
+                Error::ConfigBusy,
+                format_args!("config load in progress"),
+                None,
+            ));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::ReceiveConfig)
+    }
+
+    fn receive_config(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        fan_ctrl: &mut FanCtrl,
+    ) -> Result<Handler, Error> {
+        match config::receive_end() {
+            Ok((document, DocumentMode::Apply)) => {
+                Handler::apply_config(socket, channels, store, fan_ctrl, document)
+            }
+            Ok((document, DocumentMode::Check)) => {
+                Handler::send_problems(socket, &document.check())
+            }
+            Err(e) => {
+                error!("unable to parse config: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Config,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn apply_config(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        fan_ctrl: &mut FanCtrl,
+        document: ConfigDocument,
+    ) -> Result<Handler, Error> {
+        if let Some(configs) = &document.channels {
+            for (c, config) in configs.iter().enumerate() {
+                if let Some(config) = config {
+                    config.apply(channels, c);
+                    event_log::record(Event::new(EventKind::ConfigLoaded, Some(c)));
+                }
+            }
+        }
+        if let Some(fan) = &document.fan {
+            fan_ctrl.apply_config(fan);
+        }
+        let mut store_result = Ok(());
+        if let Some(power_on) = document.power_on {
+            store_result = store_result.and(store.write_value("power_on", &power_on, [0; 16]));
+        }
+        if let Some(selftest_boot) = document.selftest_boot {
+            store_result =
+                store_result.and(store.write_value("selftest_boot", &selftest_boot, [0; 16]));
+        }
+        if let Some(ipv4) = &document.ipv4 {
+            store_result = store_result.and(store.write_value("ipv4", ipv4, [0; 16]));
+        }
+        if let Err(e) = store_result {
+            error!("unable to save config to flash: {:?}", e);
+            return Err(send_error(
+                socket,
+                Error::Flash,
+                format_args!("{:?}", e),
+                None,
+            ));
+        }
+        send_line(socket, b"{}");
+        match document.ipv4 {
+            Some(ipv4) => {
+                event_log::record(Event::new(EventKind::Ipv4Changed, None));
+                Ok(Handler::NewIPV4(ipv4))
+            }
+            None => Ok(Handler::Handled),
+        }
+    }
+
+    /// Check the channel configs saved in flash, as they would be
+    /// loaded at boot
+    fn check_config(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+    ) -> Result<Handler, Error> {
+        let mut problems = Problems::new();
+        for c in 0..CHANNELS {
+            let message = match config::read_channel_config(store, channels, c) {
+                Ok(Some(config)) => {
+                    config.check(c, &mut problems);
+                    continue;
+                }
+                Ok(None) => "not saved",
+                Err(e) => {
+                    error!("unable to load config from flash: {:?}", e);
+                    "cannot be read"
+                }
+            };
+            let _ = problems.push(Problem {
+                channel: Some(c),
+                field: "config",
+                message,
+            });
+        }
+        Handler::send_problems(socket, &problems)
+    }
+
+    /// Compare the configs that `save` would write with those saved in
+    /// flash
+    fn diff_config(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        fan_ctrl: &FanCtrl,
+    ) -> Result<Handler, Error> {
+        const NO_FIELDS: Option<config::Fields> = None;
+        let mut diff = ConfigDiff {
+            channels: [NO_FIELDS; CHANNELS],
+            device: None,
+        };
+        let mut read_result = config::read_device_config(store)
+            .map(|saved| diff.device = saved.map(|saved| DeviceConfig::new(fan_ctrl).diff(&saved)));
+        for c in 0..CHANNELS {
+            read_result = read_result.and(config::read_channel_config(store, channels, c).map(
+                |saved| {
+                    diff.channels[c] =
+                        saved.map(|saved| ChannelConfig::new(channels, c).diff(&saved))
+                },
+            ));
+        }
+        if let Err(e) = read_result {
+            error!("unable to load config from flash: {:?}", e);
+            return Err(send_error(
+                socket,
+                Error::Flash,
+                format_args!("{:?}", e),
+                None,
+            ));
+        }
+        send_json(socket, "config diff", &diff)
+    }
+
+    fn send_problems(socket: &mut FramedSocket, problems: &Problems) -> Result<Handler, Error> {
+        #[derive(Serialize)]
+        struct Check<'a> {
+            problems: &'a Problems,
+        }
+
+        send_json(socket, "config problems", &Check { problems })
+    }
+
+    fn kick(socket: &mut FramedSocket, index: usize) -> Result<Handler, Error> {
+        if index >= SOCKET_COUNT {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("there are {} clients", SOCKET_COUNT),
+                Some("index"),
+            ));
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Kick(index))
+    }
+
+    fn show_event_log(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        let end = event_log::len();
+        send_fmt(socket, format_args!("{{\"events\":{}}}", end));
+        Ok(Handler::NewDump(Dump::EventLog { next: 0, end }))
+    }
+
+    fn dump_log(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        let (lines, end) = log_buffer::lines();
+        send_fmt(socket, format_args!("{{\"lines\":{}}}", lines));
+        Ok(Handler::NewDump(Dump::Log {
+            next: 0,
+            end,
+            remaining: lines,
+        }))
+    }
+
+    fn clear_event_log(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        event_log::clear();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn start_capture(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        samples: u32,
+    ) -> Result<Handler, Error> {
+        if channels.step_test_running() {
+            return Err(send_error(
+                socket,
+                Error::StepTest,
+                format_args!("step test running"),
+                None,
+            ));
+        }
+        capture::start(channel, samples as usize, capture::Source::Adc);
+        Ok(Handler::NewDump(Dump::Capture {
+            started: false,
+            next: 0,
+        }))
+    }
+
+    fn tune_step(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        delta_i: f64,
+        duration: f64,
+    ) -> Result<Handler, Error> {
+        Handler::check_output_allowed(socket, channels, channel)?;
+        if duration < 0.0 {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("duration must not be negative"),
+                Some("duration"),
+            ));
+        }
+        if ElectricCurrent::new::<ampere>(delta_i).abs() > MAX_TEC_I {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("step beyond the TEC current limit"),
+                Some("delta_i"),
+            ));
+        }
+        if channels.step_test_running() {
+            return Err(send_error(
+                socket,
+                Error::StepTest,
+                format_args!("step test running"),
+                None,
+            ));
+        }
+        channels.start_step_test(
+            channel,
+            ElectricCurrent::new::<ampere>(delta_i),
+            Time::new::<second>(duration),
+        );
+        Ok(Handler::NewDump(Dump::StepTest { delta_i }))
+    }
+
+    fn set_stable(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        parameter: StableParameter,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        if value <= 0.0 {
+            let field = match parameter {
+                StableParameter::Window => "window",
+                StableParameter::Time => "time",
+            };
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("{} must be positive", field),
+                Some(field),
+            ));
+        }
+        let state = channels.channel_state(channel);
+        match parameter {
+            StableParameter::Window => {
+                state.stable_window = TemperatureInterval::new::<kelvin>(value)
+            }
+            StableParameter::Time => state.stable_time = Time::new::<second>(value),
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_dtdt_window(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        window: f64,
+    ) -> Result<Handler, Error> {
+        if window <= 0.0 {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("window must be positive"),
+                Some("window"),
+            ));
+        }
+        channels.channel_state(channel).dtdt_window = Time::new::<second>(window);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_interlock(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        limit: Option<(InterlockLimit, f64)>,
+    ) -> Result<Handler, Error> {
+        let state = channels.channel_state(channel);
+        match limit {
+            Some((InterlockLimit::TMin, value)) => state.t_min = Some(value),
+            Some((InterlockLimit::TMax, value)) => state.t_max = Some(value),
+            Some((InterlockLimit::OverCurrent, value)) if value <= 0.0 => {
+                return Err(send_error(
+                    socket,
+                    Error::Range,
+                    format_args!("overcurrent time must be positive"),
+                    Some("overcurrent"),
+                ));
+            }
+            Some((InterlockLimit::OverCurrent, value)) => {
+                state.overcurrent_time = Some(Time::new::<second>(value))
+            }
+            None => {
+                state.t_min = None;
+                state.t_max = None;
+                state.overcurrent_time = None;
+            }
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn clear_fault(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        channels.channel_state(channel).fault = None;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn tec_check(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        Handler::check_output_allowed(socket, channels, channel)?;
+        send_serialized(socket, "tec check", channels.tec_check_json(channel))
+    }
+
+    fn set_b_parameter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        parameter: BpParameter,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        let bp = &mut channels.channel_state(channel).bp;
+        use super::command_parser::BpParameter::*;
+        match parameter {
+            T0 => bp.t0 = ThermodynamicTemperature::new::<degree_celsius>(value),
+            B => bp.b = TemperatureInterval::new::<kelvin>(value),
+            R0 => bp.r0 = ElectricalResistance::new::<ohm>(value),
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_steinhart_hart(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        parameter: ShParameter,
+        value: f64,
+    ) -> Result<Handler, Error> {
+        let sh = &mut channels.channel_state(channel).sh;
+        match parameter {
+            ShParameter::A => sh.a = value,
+            ShParameter::B => sh.b = value,
+            ShParameter::C => sh.c = value,
+        }
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_sensor(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        sensor: Sensor,
+    ) -> Result<Handler, Error> {
+        channels.channel_state(channel).sensor = sensor;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn reset_post_filter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+    ) -> Result<Handler, Error> {
+        channels.set_postfilter(channel as u8, None);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_post_filter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        rate: f32,
+    ) -> Result<Handler, Error> {
+        let filter = ad7172::PostFilter::closest(rate);
+        match filter {
+            Some(filter) => {
+                channels.set_postfilter(channel as u8, Some(filter));
+                send_line(socket, b"{}");
+            }
+            None => {
+                error!("unable to choose postfilter for rate {:.3}", rate);
+                return Err(send_error(
+                    socket,
+                    Error::Range,
+                    format_args!("unable to choose postfilter rate"),
+                    Some("rate"),
+                ));
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn set_data_rate(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        rate: f32,
+    ) -> Result<Handler, Error> {
+        let order = channels.get_filter_order(channel as u8);
+        let rate = ad7172::DataRate::closest(rate, order);
+        channels.set_data_rate(channel as u8, rate);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_filter_order(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        order: ad7172::DigitalFilterOrder,
+    ) -> Result<Handler, Error> {
+        channels.set_filter_order(channel as u8, order);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_sw_filter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        samples: u32,
+    ) -> Result<Handler, Error> {
+        channels.channel_state(channel).sw_filter = samples;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_median_filter(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: usize,
+        points: u8,
+    ) -> Result<Handler, Error> {
+        channels.channel_state(channel).median_filter = points;
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn load_channel(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        fan_ctrl: &mut FanCtrl,
+        channel: Option<usize>,
+    ) -> Result<Handler, Error> {
+        if channel.is_none() {
+            match config::read_device_config(store) {
+                Ok(Some(config)) => config.apply(fan_ctrl),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("unable to load device config from flash: {:?}", e);
+                    return Err(send_error(
+                        socket,
+                        Error::Flash,
+                        format_args!("{:?}", e),
+                        None,
+                    ));
+                }
+            }
+        }
+        for c in 0..CHANNELS {
+            if channel.is_none() || channel == Some(c) {
+                match config::read_channel_config(store, channels, c) {
+                    Ok(Some(config)) => {
+                        config.apply(channels, c);
+                        event_log::record(Event::new(EventKind::ConfigLoaded, Some(c)));
+                        send_line(socket, b"{}");
+                    }
+                    Ok(None) => {
+                        error!("flash config not found");
+                        send_error(
+                            socket,
+                            Error::Flash,
+                            format_args!("flash config not found"),
+                            None,
+                        );
+                    }
+                    Err(e) => {
+                        error!("unable to load config from flash: {:?}", e);
+                        return Err(send_error(
+                            socket,
+                            Error::Flash,
+                            format_args!("{:?}", e),
+                            None,
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn save_channel(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        channel: Option<usize>,
+        store: &mut impl FlashInterface,
+        fan_ctrl: &mut FanCtrl,
+    ) -> Result<Handler, Error> {
+        if channel.is_none() {
+            let mut store_value_buf = [0u8; 128];
+            let config = DeviceConfig::new(fan_ctrl);
+            if let Err(e) = store.write_value(DEVICE_CONFIG_KEY, &config, &mut store_value_buf) {
+                error!("unable to save device config to flash: {:?}", e);
+                return Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ));
+            }
+        }
+        for (c, key) in CHANNEL_CONFIG_KEY.iter().enumerate().take(CHANNELS) {
+            let mut store_value_buf = [0u8; 256];
+            if channel.is_none() || channel == Some(c) {
+                let config = ChannelConfig::new(channels, c);
+                match store.write_value(key, &config, &mut store_value_buf) {
+                    Ok(()) => {
+                        event_log::record(Event::new(EventKind::ConfigSaved, Some(c)));
+                        send_line(socket, b"{}");
+                    }
+                    Err(e) => {
+                        error!("unable to save channel {} config to flash: {:?}", c, e);
+                        return Err(send_error(
+                            socket,
+                            Error::Flash,
+                            format_args!("{:?}", e),
+                            None,
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(Handler::Handled)
+    }
+
+    fn load_profile(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        name: &ProfileName,
+    ) -> Result<Handler, Error> {
+        match flash_store::load_profile::<[ChannelConfig; CHANNELS]>(store, name) {
+            Ok(configs) => {
+                for (c, config) in configs.iter().enumerate() {
+                    config.apply(channels, c);
+                    event_log::record(Event::new(EventKind::ConfigLoaded, Some(c)));
+                }
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(ProfileError::NotFound) => {
+                error!("profile {} not found", name);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("profile not found"),
+                    Some("name"),
+                ))
+            }
+            Err(e) => {
+                error!("unable to load profile {} from flash: {:?}", name, e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn save_profile(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        name: &ProfileName,
+    ) -> Result<Handler, Error> {
+        let configs: [ChannelConfig; CHANNELS] =
+            core::array::from_fn(|channel| ChannelConfig::new(channels, channel));
+        match flash_store::save_profile(store, name, &configs) {
+            Ok(()) => {
+                for c in 0..CHANNELS {
+                    event_log::record(Event::new(EventKind::ConfigSaved, Some(c)));
+                }
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(ProfileError::Store(e)) => {
+                error!("unable to save profile {} to flash: {:?}", name, e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+            Err(e) => {
+                error!("unable to save profile {}: {:?}", name, e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn remove_profile(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        name: &ProfileName,
+    ) -> Result<Handler, Error> {
+        match flash_store::remove_profile(store, name) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to remove profile {}: {:?}", name, e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_profiles(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+    ) -> Result<Handler, Error> {
+        match flash_store::profiles(store) {
+            Ok(profiles) => {
+                let mut line: String<U256> = String::new();
+                let _ = line.push_str("{\"profiles\":[");
+                for (i, name) in profiles.iter().enumerate() {
+                    let separator = if i > 0 { "," } else { "" };
+                    let _ = write!(line, "{}\"{}\"", separator, name);
+                }
+                let _ = line.push_str("]}");
+                send_line(socket, line.as_bytes());
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to read profiles from flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn set_ipv4(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        config: Ipv4Config,
+    ) -> Result<Handler, Error> {
+        let _ = store
+            .write_value("ipv4", &config, [0; 16])
+            .map_err(|e| error!("unable to save ipv4 config to flash: {:?}", e));
+        event_log::record(Event::new(EventKind::Ipv4Changed, None));
+        let new_ipv4_config = Some(config);
+        send_line(socket, b"{}");
+        Ok(Handler::NewIPV4(new_ipv4_config.unwrap()))
+    }
+
+    fn show_power_on(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+    ) -> Result<Handler, Error> {
+        let power_on = match store.read_value("power_on") {
+            Ok(Some(power_on)) => power_on,
+            Ok(None) => PowerOn::Restore,
+            Err(e) => {
+                error!("unable to read power_on from flash: {:?}", e);
+                return Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ));
+            }
+        };
+        let line: &[u8] = match power_on {
+            PowerOn::Restore => b"{\"power_on\":\"restore\"}",
+            PowerOn::Off => b"{\"power_on\":\"off\"}",
+        };
+        send_line(socket, line);
+        Ok(Handler::Handled)
+    }
+
+    /// The chip in use, and the one saved for the next boot
+    fn show_dac_chip(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+    ) -> Result<Handler, Error> {
+        #[derive(Serialize)]
+        struct DacChipSummary {
+            dac_chip: DacChip,
+            saved: Option<DacChip>,
+        }
+
+        let saved = match store.read_value(dac::FLASH_KEY) {
+            Ok(saved) => saved,
+            Err(e) => {
+                error!("unable to read dac_chip from flash: {:?}", e);
+                return Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ));
+            }
+        };
+        let summary = DacChipSummary {
+            dac_chip: channels.dac_chip(0),
+            saved,
+        };
+        send_json(socket, "dac chip", &summary)
+    }
+
+    /// Takes effect at the next boot, as the DACs are set up once
+    fn set_dac_chip(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        dac_chip: DacChip,
+    ) -> Result<Handler, Error> {
+        match store.write_value(dac::FLASH_KEY, &dac_chip, [0; 16]) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save dac_chip to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn calibrate_adc(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        channel: usize,
+        calibration: AdcCalibration,
+    ) -> Result<Handler, Error> {
+        let mode = match calibration {
+            AdcCalibration::Offset => ad7172::Mode::SystemOffsetCalibration,
+            AdcCalibration::Gain => ad7172::Mode::SystemGainCalibration,
+        };
+        let calibration = match channels.calibrate_adc(channel, mode) {
+            Ok(calibration) => calibration,
+            Err(e) => {
+                error!(
+                    "unable to calibrate the ADC of channel {}: {:?}",
+                    channel, e
+                );
+                return Err(send_error(
+                    socket,
+                    Error::Calibration,
+                    format_args!("{:?}", e),
+                    None,
+                ));
+            }
+        };
+        match store.write_value(ADC_CALIBRATION_KEY[channel], &calibration, [0; 16]) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save ADC calibration to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn set_power_on(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        power_on: PowerOn,
+    ) -> Result<Handler, Error> {
+        match store.write_value("power_on", &power_on, [0; 16]) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save power_on to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn set_self_test_boot(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        enable: bool,
+    ) -> Result<Handler, Error> {
+        match store.write_value("selftest_boot", &enable, [0; 16]) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save selftest_boot to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_log_level(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        #[derive(Serialize)]
+        struct LogLevelSummary {
+            log_level: LogLevel,
+        }
+
+        let summary = LogLevelSummary {
+            log_level: LogLevel::current(),
+        };
+        send_json(socket, "log level", &summary)
+    }
+
+    fn set_log_level(socket: &mut FramedSocket, level: LogLevel) -> Result<Handler, Error> {
+        level.apply();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_safe_start(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_fmt(
+            socket,
+            format_args!("{{\"safe_start\":{}}}", config::safe_start()),
+        );
+        Ok(Handler::Handled)
+    }
+
+    /// Saved with the device config by `save`
+    fn set_safe_start(socket: &mut FramedSocket, enable: bool) -> Result<Handler, Error> {
+        config::set_safe_start(enable);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn eeprom(socket: &mut FramedSocket, access: EepromAccess) -> Result<Handler, Error> {
+        let (end, limit) = match &access {
+            EepromAccess::Read { offset, len } => (offset.saturating_add(*len), EEPROM_SIZE),
+            EepromAccess::Write { offset, data } => {
+                (offset.saturating_add(data.len() as u32), EEPROM_USER_END)
+            }
+        };
+        if end > limit {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("beyond EEPROM address {}", limit),
+                Some("offset"),
+            ));
+        }
+        Ok(Handler::Eeprom(access))
+    }
+
+    fn show_alarms(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_serialized(socket, "alarms", alarms::status_json())
+    }
+
+    fn clear_alarms(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        alarms::clear();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_faults(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        fan_ctrl: &mut FanCtrl,
+    ) -> Result<Handler, Error> {
+        let status = FaultStatus::new(
+            channels,
+            fan_ctrl.failed(),
+            alarms::watchdog_reset_latched(),
+        );
+        send_serialized(socket, "faults", status.json())
+    }
+
+    fn clear_faults(
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+    ) -> Result<Handler, Error> {
+        faults::clear(channels);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn identify(socket: &mut FramedSocket, seconds: Option<u32>) -> Result<Handler, Error> {
+        leds::identify(seconds.unwrap_or(leds::IDENTIFY_DEFAULT));
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn set_alarm_config(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        config: alarms::AlarmConfig,
+    ) -> Result<Handler, Error> {
+        match alarms::set_config(store, config) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save alarm config to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn set_alarm_window(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        channel: usize,
+        window: Option<[f64; 2]>,
+    ) -> Result<Handler, Error> {
+        if matches!(window, Some([min, max]) if min >= max) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("empty temperature window"),
+                Some("max"),
+            ));
+        }
+        let mut config = alarms::config();
+        config.window[channel] = window.map(|[min, max]| [min as f32, max as f32]);
+        Handler::set_alarm_config(socket, store, config)
+    }
+
+    fn set_alarm_enabled(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        kind: AlarmKind,
+        enabled: bool,
+    ) -> Result<Handler, Error> {
+        let mut config = alarms::config();
+        config.set_enabled(kind, enabled);
+        Handler::set_alarm_config(socket, store, config)
+    }
+
+    fn set_alarm_dac_feedback(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        max: Option<f64>,
+    ) -> Result<Handler, Error> {
+        if matches!(max, Some(max) if max <= 0.0) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("dac_feedback not above 0 V"),
+                Some("dac_feedback"),
+            ));
+        }
+        match alarms::set_dac_feedback_max(store, max.map(|max| max as f32)) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save dac_feedback alarm to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_name(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_fmt(socket, format_args!("{{\"name\":\"{}\"}}", config::name()));
+        Ok(Handler::Handled)
+    }
+
+    fn set_name(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        name: DeviceName,
+    ) -> Result<Handler, Error> {
+        match store.write_value(DEVICE_NAME_KEY, &name, [0; 48]) {
+            Ok(()) => {
+                config::set_name(name);
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save name to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_aliases(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        #[derive(Serialize)]
+        struct Aliases {
+            alias: [Option<ChannelAlias>; CHANNELS],
+        }
+
+        let aliases = Aliases {
+            alias: config::aliases(),
+        };
+        send_json(socket, "aliases", &aliases)
+    }
+
+    fn set_alias(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        channel: usize,
+        alias: Option<ChannelAlias>,
+    ) -> Result<Handler, Error> {
+        let mut aliases = config::aliases();
+        if let Some(alias) = &alias {
+            let taken = aliases.iter().enumerate().any(|(c, other)| {
+                c != channel && matches!(other, Some(other) if other.eq_ignore_ascii_case(alias))
+            });
+            if alias.eq_ignore_ascii_case("all") || taken {
+                return Err(send_error(
+                    socket,
+                    Error::Alias,
+                    format_args!("alias not available"),
+                    Some("alias"),
+                ));
+            }
+        }
+        aliases[channel] = alias;
+        match store.write_value(CHANNEL_ALIAS_KEY, &aliases, [0; 64]) {
+            Ok(()) => {
+                config::set_aliases(aliases);
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save aliases to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn show_autosave(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_fmt(
+            socket,
+            format_args!("{{\"autosave\":{}}}", autosave::enabled()),
+        );
+        Ok(Handler::Handled)
+    }
+
+    fn set_autosave(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        enable: bool,
     ) -> Result<Handler, Error> {
-        let filter = ad7172::PostFilter::closest(rate);
-        match filter {
-            Some(filter) => {
-                channels.set_postfilter(channel as u8, Some(filter));
+        match autosave::set(store, enable) {
+            Ok(()) => {
                 send_line(socket, b"{}");
+                Ok(Handler::Handled)
             }
-            None => {
-                error!("unable to choose postfilter for rate {:.3}", rate);
-                send_line(
+            Err(e) => {
+                error!("unable to save autosave to flash: {:?}", e);
+                Err(send_error(
                     socket,
-                    b"{{\"error\": \"unable to choose postfilter rate\"}}",
-                );
-                return Err(Error::PostFilterRate);
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
             }
         }
+    }
+
+    fn show_deadman(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        match deadman::timeout() {
+            Some(timeout) => send_fmt(socket, format_args!("{{\"deadman\":{}}}", timeout)),
+            None => send_line(socket, b"{\"deadman\":null}"),
+        };
         Ok(Handler::Handled)
     }
 
-    fn load_channel(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        store: &mut FlashStore,
-        channel: Option<usize>,
+    fn set_deadman(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        timeout: Option<u32>,
     ) -> Result<Handler, Error> {
-        for (c, key) in CHANNEL_CONFIG_KEY.iter().enumerate().take(CHANNELS) {
-            if channel.is_none() || channel == Some(c) {
-                match store.read_value::<ChannelConfig>(key) {
-                    Ok(Some(config)) => {
-                        config.apply(channels, c);
-                        send_line(socket, b"{}");
-                    }
-                    Ok(None) => {
-                        error!("flash config not found");
-                        send_line(socket, b"{{\"error\": \"flash config not found\"}}");
-                    }
-                    Err(e) => {
-                        error!("unable to load config from flash: {:?}", e);
-                        let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                        return Err(Error::Flash);
-                    }
-                }
+        if matches!(timeout, Some(timeout) if timeout == 0 || timeout > DEADMAN_MAX) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("timeout must be 1 to {} seconds", DEADMAN_MAX),
+                Some("seconds"),
+            ));
+        }
+        match deadman::set(store, timeout) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save deadman to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
             }
         }
-        Ok(Handler::Handled)
     }
 
-    fn save_channel(
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        channel: Option<usize>,
-        store: &mut FlashStore,
+    fn show_stable_output(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_serialized(socket, "stable output", stable_output::summary_json())
+    }
+
+    fn show_schedule(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        send_serialized(socket, "schedule", scheduler::summary_json())
+    }
+
+    fn schedule(
+        socket: &mut FramedSocket,
+        seconds: u32,
+        every: bool,
+        line: ScheduledLine,
     ) -> Result<Handler, Error> {
-        for (c, key) in CHANNEL_CONFIG_KEY.iter().enumerate().take(CHANNELS) {
-            let mut store_value_buf = [0u8; 256];
-            if channel.is_none() || channel == Some(c) {
-                let config = ChannelConfig::new(channels, c);
-                match store.write_value(key, &config, &mut store_value_buf) {
-                    Ok(()) => {
-                        send_line(socket, b"{}");
-                    }
-                    Err(e) => {
-                        error!("unable to save channel {} config to flash: {:?}", c, e);
-                        let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                        return Err(Error::Flash);
-                    }
-                }
+        if seconds > scheduler::MAX_SECONDS || (every && seconds == 0) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("seconds must be within 1 to {}", scheduler::MAX_SECONDS),
+                Some("seconds"),
+            ));
+        }
+        // Parsed as a session would when it is entered
+        let command = Command::parse(line.as_bytes()).or_else(|e| {
+            Command::parse_aliased(line.as_bytes(), &config::aliases()).unwrap_or(Err(e))
+        });
+        let command = match command {
+            Ok(command) if command.schedulable() => command,
+            Ok(_) => {
+                return Err(send_error(
+                    socket,
+                    Error::Parse,
+                    format_args!("command cannot be scheduled"),
+                    Some("command"),
+                ))
+            }
+            Err(e) => {
+                return Err(send_error(
+                    socket,
+                    Error::Parse,
+                    format_args!("{}", e),
+                    Some("command"),
+                ))
             }
+        };
+        match scheduler::add(line, command, seconds, every) {
+            Some(index) => {
+                send_fmt(socket, format_args!("{{\"index\":{}}}", index));
+                Ok(Handler::Handled)
+            }
+            None => Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("at most {} scheduled commands", scheduler::SLOTS),
+                None,
+            )),
+        }
+    }
+
+    fn clear_schedule(socket: &mut FramedSocket, index: Option<usize>) -> Result<Handler, Error> {
+        if !scheduler::clear(index) {
+            return Err(send_error(
+                socket,
+                Error::Range,
+                format_args!("no scheduled command {}", index.unwrap_or(0)),
+                Some("index"),
+            ));
         }
+        send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn set_ipv4(
-        socket: &mut TcpSocket,
-        store: &mut FlashStore,
-        config: Ipv4Config,
+    fn set_stable_output(
+        socket: &mut FramedSocket,
+        store: &mut impl FlashInterface,
+        channels: [bool; CHANNELS],
     ) -> Result<Handler, Error> {
-        let _ = store
-            .write_value("ipv4", &config, [0; 16])
-            .map_err(|e| error!("unable to save ipv4 config to flash: {:?}", e));
-        let new_ipv4_config = Some(config);
-        send_line(socket, b"{}");
-        Ok(Handler::NewIPV4(new_ipv4_config.unwrap()))
+        match stable_output::set(store, channels) {
+            Ok(()) => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Err(e) => {
+                error!("unable to save stable output to flash: {:?}", e);
+                Err(send_error(
+                    socket,
+                    Error::Flash,
+                    format_args!("{:?}", e),
+                    None,
+                ))
+            }
+        }
     }
 
-    fn reset(channels: &mut Channels) -> Result<Handler, Error> {
+    fn reset(channels: &mut dyn ChannelsInterface) -> Result<Handler, Error> {
         for i in 0..CHANNELS {
             channels.power_down(i);
         }
@@ -364,19 +2860,17 @@ impl Handler {
         Ok(Handler::Reset)
     }
 
-    fn dfu(channels: &mut Channels) -> Result<Handler, Error> {
+    fn dfu(channels: &mut dyn ChannelsInterface) -> Result<Handler, Error> {
         for i in 0..CHANNELS {
             channels.power_down(i);
         }
-        unsafe {
-            dfu::set_dfu_trigger();
-        }
+        Mcu::set_dfu_trigger();
         // should_reset = true;
         Ok(Handler::Reset)
     }
 
     fn set_fan(
-        socket: &mut TcpSocket,
+        socket: &mut FramedSocket,
         fan_pwm: u32,
         fan_ctrl: &mut FanCtrl,
     ) -> Result<Handler, Error> {
@@ -397,21 +2891,11 @@ impl Handler {
         Ok(Handler::Handled)
     }
 
-    fn show_fan(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
-        match fan_ctrl.summary() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-                Ok(Handler::Handled)
-            }
-            Err(e) => {
-                error!("unable to serialize fan summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                Err(Error::Report)
-            }
-        }
+    fn show_fan(socket: &mut FramedSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        send_serialized(socket, "fan summary", fan_ctrl.summary())
     }
 
-    fn fan_auto(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+    fn fan_auto(socket: &mut FramedSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
         if !fan_ctrl.fan_available() {
             send_line(
                 socket,
@@ -429,7 +2913,7 @@ impl Handler {
     }
 
     fn fan_curve(
-        socket: &mut TcpSocket,
+        socket: &mut FramedSocket,
         fan_ctrl: &mut FanCtrl,
         k_a: f32,
         k_b: f32,
@@ -440,52 +2924,261 @@ impl Handler {
         Ok(Handler::Handled)
     }
 
-    fn fan_defaults(socket: &mut TcpSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
-        fan_ctrl.restore_defaults();
+    fn fan_hysteresis(
+        socket: &mut FramedSocket,
+        fan_ctrl: &mut FanCtrl,
+        hysteresis: f32,
+    ) -> Result<Handler, Error> {
+        fan_ctrl.set_hysteresis(hysteresis);
         send_line(socket, b"{}");
         Ok(Handler::Handled)
     }
 
-    fn show_hwrev(socket: &mut TcpSocket, hwrev: HWRev) -> Result<Handler, Error> {
-        match hwrev.summary() {
-            Ok(buf) => {
-                send_line(socket, &buf);
-                Ok(Handler::Handled)
-            }
-            Err(e) => {
-                error!("unable to serialize HWRev summary: {:?}", e);
-                let _ = writeln!(socket, "{{\"error\":\"{:?}\"}}", e);
-                Err(Error::Report)
+    fn fan_min_pwm(
+        socket: &mut FramedSocket,
+        fan_ctrl: &mut FanCtrl,
+        min_pwm: u32,
+    ) -> Result<Handler, Error> {
+        fan_ctrl.set_min_pwm(min_pwm);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn fan_mcu_curve(
+        socket: &mut FramedSocket,
+        fan_ctrl: &mut FanCtrl,
+        range: Option<[f32; 2]>,
+    ) -> Result<Handler, Error> {
+        if let Some([t_low, t_high]) = range {
+            if t_high <= t_low {
+                return Err(send_error(
+                    socket,
+                    Error::Range,
+                    format_args!("empty temperature range"),
+                    Some("high"),
+                ));
             }
         }
+        fan_ctrl.set_mcu_curve(range);
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
     }
 
+    fn fan_defaults(socket: &mut FramedSocket, fan_ctrl: &mut FanCtrl) -> Result<Handler, Error> {
+        fan_ctrl.restore_defaults();
+        send_line(socket, b"{}");
+        Ok(Handler::Handled)
+    }
+
+    fn show_hwrev(socket: &mut FramedSocket, hwrev: HWRev) -> Result<Handler, Error> {
+        send_serialized(socket, "HWRev summary", hwrev.summary())
+    }
+
+    fn show_version(socket: &mut FramedSocket) -> Result<Handler, Error> {
+        let build_time = env!("BUILD_TIME").parse::<u64>().unwrap_or(0);
+        let build_time = rtc::DateTime::from_epoch_millis(build_time * 1000);
+        let features = if cfg!(feature = "semihosting") {
+            "\"semihosting\""
+        } else {
+            ""
+        };
+        send_fmt(
+            socket,
+            format_args!(
+                "{{\"version\":\"{}\",\"commit\":\"{}\",\"build_time\":\"{}\",\"features\":[{}]}}",
+                env!("CARGO_PKG_VERSION"),
+                env!("GIT_COMMIT"),
+                build_time,
+                features
+            ),
+        );
+        Ok(Handler::Handled)
+    }
+
+    /// Handle a command received from a client
     pub fn handle_command(
         command: Command,
-        socket: &mut TcpSocket,
-        channels: &mut Channels,
-        store: &mut FlashStore,
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
+        ipv4_config: &mut Ipv4Config,
+        fan_ctrl: &mut FanCtrl,
+        hwrev: HWRev,
+    ) -> Result<Self, Error> {
+        deadman::activity();
+        Handler::run_command(
+            command,
+            socket,
+            channels,
+            store,
+            ipv4_config,
+            fan_ctrl,
+            hwrev,
+        )
+    }
+
+    /// Handle a command, also one of the scheduler, which is no sign
+    /// of a client for the deadman
+    pub fn run_command(
+        mut command: Command,
+        socket: &mut FramedSocket,
+        channels: &mut dyn ChannelsInterface,
+        store: &mut impl FlashInterface,
         ipv4_config: &mut Ipv4Config,
         fan_ctrl: &mut FanCtrl,
         hwrev: HWRev,
     ) -> Result<Self, Error> {
-        match command {
+        if matches!(command.channel_mut(), Some(&mut ALL_CHANNELS)) {
+            // Acknowledge once for all channels, stopping at the first
+            // error
+            socket.acknowledge = false;
+            let mut result = Ok(Handler::Handled);
+            for channel in 0..CHANNELS {
+                let mut command = command.clone();
+                if let Some(command_channel) = command.channel_mut() {
+                    *command_channel = channel;
+                }
+                result = Handler::run_command(
+                    command,
+                    socket,
+                    channels,
+                    store,
+                    ipv4_config,
+                    fan_ctrl,
+                    hwrev,
+                );
+                if result.is_err() {
+                    break;
+                }
+            }
+            socket.acknowledge = true;
+            if result.is_ok() {
+                send_line(socket, b"{}");
+            }
+            return result;
+        }
+        let changes_config = command.changes_config();
+        let result = match command {
             Command::Quit => Ok(Handler::CloseSocket),
             Command::Show(ShowCommand::Input) => Handler::show_report(socket, channels),
             Command::Show(ShowCommand::Pid) => Handler::show_pid(socket, channels),
             Command::Show(ShowCommand::Output) => Handler::show_output(socket, channels),
             Command::Show(ShowCommand::BParameter) => Handler::show_b_parameter(socket, channels),
             Command::Show(ShowCommand::PostFilter) => Handler::show_post_filter(socket, channels),
+            Command::Show(ShowCommand::DataRate) => Handler::show_data_rate(socket, channels),
+            Command::Show(ShowCommand::RawReport) => Handler::show_raw_report(socket, channels),
+            Command::Show(ShowCommand::Cal) => Handler::show_cal(socket, channels),
+            Command::Show(ShowCommand::TecCal) => Handler::show_tec_cal(socket, channels),
+            Command::Show(ShowCommand::Stats) => Handler::show_stats(socket, channels),
+            Command::StatsReset => Handler::reset_stats(socket, channels),
+            Command::EnergyReset => Handler::reset_energy(socket, channels),
+            Command::SelfTest => Ok(Handler::SelfTest),
+            Command::Clients => Ok(Handler::Clients),
+            Command::Kick { index } => Handler::kick(socket, index),
+            Command::Show(ShowCommand::Config) => {
+                Handler::show_config(socket, store, ipv4_config, fan_ctrl)
+            }
+            Command::ConfigLoad => Handler::load_config(socket, DocumentMode::Apply),
+            Command::ConfigCheck => Handler::check_config(socket, channels, store),
+            Command::ConfigDiff => Handler::diff_config(socket, channels, store, fan_ctrl),
+            Command::Show(ShowCommand::Schedule) => Handler::show_schedule(socket),
+            Command::Schedule {
+                seconds,
+                every,
+                line,
+            } => Handler::schedule(socket, seconds, every, line),
+            Command::ScheduleClear { index } => Handler::clear_schedule(socket, index),
+            Command::ConfigCheckLoad => Handler::load_config(socket, DocumentMode::Check),
+            Command::ConfigDocument => Handler::receive_config(socket, channels, store, fan_ctrl),
+            Command::Show(ShowCommand::EventLog) => Handler::show_event_log(socket),
+            Command::EventLogClear => Handler::clear_event_log(socket),
+            Command::LogDump => Handler::dump_log(socket),
+            Command::SelfTestBoot(enable) => Handler::set_self_test_boot(socket, store, enable),
+            Command::Show(ShowCommand::AutoSave) => Handler::show_autosave(socket),
+            Command::AutoSave(enable) => Handler::set_autosave(socket, store, enable),
+            Command::Show(ShowCommand::Deadman) => Handler::show_deadman(socket),
+            Command::Deadman(timeout) => Handler::set_deadman(socket, store, timeout),
+            Command::Ping => {
+                send_line(socket, b"{}");
+                Ok(Handler::Handled)
+            }
+            Command::Show(ShowCommand::LogLevel) => Handler::show_log_level(socket),
+            Command::LogLevel(level) => Handler::set_log_level(socket, level),
+            Command::Show(ShowCommand::SafeStart) => Handler::show_safe_start(socket),
+            Command::SafeStart(enable) => Handler::set_safe_start(socket, enable),
+            Command::Show(ShowCommand::Name) => Handler::show_name(socket),
+            Command::Name { name } => Handler::set_name(socket, store, name),
+            Command::Show(ShowCommand::Alias) => Handler::show_aliases(socket),
+            Command::Alias { channel, alias } => Handler::set_alias(socket, store, channel, alias),
+            Command::Eeprom(access) => Handler::eeprom(socket, access),
+            Command::Show(ShowCommand::Alarms) => Handler::show_alarms(socket),
+            Command::AlarmsClear => Handler::clear_alarms(socket),
+            Command::Show(ShowCommand::Faults) => Handler::show_faults(socket, channels, fan_ctrl),
+            Command::ClearFaults => Handler::clear_faults(socket, channels),
+            Command::Identify { seconds } => Handler::identify(socket, seconds),
+            Command::AlarmWindow { channel, window } => {
+                Handler::set_alarm_window(socket, store, channel, window)
+            }
+            Command::AlarmEnable { kind, enabled } => {
+                Handler::set_alarm_enabled(socket, store, kind, enabled)
+            }
+            Command::AlarmDacFeedback { max } => {
+                Handler::set_alarm_dac_feedback(socket, store, max)
+            }
+            Command::Show(ShowCommand::Time) => Handler::show_time(socket),
+            Command::TimeSet { epoch } => Handler::set_time(socket, epoch),
+            Command::History { channel, samples } => {
+                Handler::show_history(socket, channel, samples)
+            }
+            Command::Capture { channel, samples } => {
+                Handler::start_capture(socket, channels, channel, samples)
+            }
+            Command::TuneStep {
+                channel,
+                delta_i,
+                duration,
+            } => Handler::tune_step(socket, channels, channel, delta_i, duration),
             Command::Show(ShowCommand::Ipv4) => Handler::show_ipv4(socket, ipv4_config),
+            Command::Show(ShowCommand::Framing) => Handler::show_framing(socket),
+            Command::Show(ShowCommand::Cascade) => Handler::show_cascade(socket, channels),
+            Command::Show(ShowCommand::Stable) => Handler::show_stable(socket, channels),
+            Command::Show(ShowCommand::StableOutput) => Handler::show_stable_output(socket),
+            Command::StableOutput { channels } => {
+                Handler::set_stable_output(socket, store, channels)
+            }
+            Command::Show(ShowCommand::DtDt) => Handler::show_dtdt(socket, channels),
+            Command::DtDtWindow { channel, window } => {
+                Handler::set_dtdt_window(socket, channels, channel, window)
+            }
+            Command::Show(ShowCommand::Locked) => Handler::show_locked(socket, channels),
+            Command::Show(ShowCommand::Interlock) => Handler::show_interlock(socket, channels),
+            Command::Show(ShowCommand::PowerOn) => Handler::show_power_on(socket, store),
+            Command::Show(ShowCommand::SteinhartHart) => {
+                Handler::show_steinhart_hart(socket, channels)
+            }
+            Command::Show(ShowCommand::Sensor) => Handler::show_sensor(socket, channels),
             Command::OutputPid { channel } => Handler::engage_pid(socket, channels, channel),
+            Command::OutputOff { channel } => Handler::output_off(socket, channels, channel),
             Command::OutputPolarity { channel, polarity } => {
                 Handler::set_polarity(socket, channels, channel, polarity)
             }
+            Command::OutputTrim { channel, enabled } => {
+                Handler::set_output_trim(socket, channels, channel, enabled)
+            }
+            Command::OutputHeaterOnly { channel, enabled } => {
+                Handler::set_output_heater_only(socket, channels, channel, enabled)
+            }
+            Command::OutputVoltage { channel, voltage } => {
+                Handler::set_output_voltage(socket, channels, channel, voltage)
+            }
             Command::Output {
                 channel,
                 pin,
                 value,
             } => Handler::set_output(socket, channels, channel, pin, value),
+            Command::OutputQuery { channel, pin } => {
+                Handler::query_output(socket, channels, channel, pin)
+            }
             Command::CenterPoint { channel, center } => {
                 Handler::set_center_point(socket, channels, channel, center)
             }
@@ -494,6 +3187,9 @@ impl Handler {
                 parameter,
                 value,
             } => Handler::set_pid(socket, channels, channel, parameter, value),
+            Command::PidQuery { channel, parameter } => {
+                Handler::query_pid(socket, channels, channel, parameter)
+            }
             Command::BParameter {
                 channel,
                 parameter,
@@ -507,8 +3203,37 @@ impl Handler {
                 channel,
                 rate: Some(rate),
             } => Handler::set_post_filter(socket, channels, channel, rate),
-            Command::Load { channel } => Handler::load_channel(socket, channels, store, channel),
-            Command::Save { channel } => Handler::save_channel(socket, channels, channel, store),
+            Command::DataRate { channel, rate } => {
+                Handler::set_data_rate(socket, channels, channel, rate)
+            }
+            Command::FilterOrder { channel, order } => {
+                Handler::set_filter_order(socket, channels, channel, order)
+            }
+            Command::Cal {
+                channel,
+                parameter,
+                value,
+            } => Handler::set_cal(socket, channels, channel, parameter, value),
+            Command::AdcCalibrate {
+                channel,
+                calibration,
+            } => Handler::calibrate_adc(socket, channels, store, channel, calibration),
+            Command::SwFilter { channel, samples } => {
+                Handler::set_sw_filter(socket, channels, channel, samples)
+            }
+            Command::MedianFilter { channel, points } => {
+                Handler::set_median_filter(socket, channels, channel, points)
+            }
+            Command::Load { channel } => {
+                Handler::load_channel(socket, channels, store, fan_ctrl, channel)
+            }
+            Command::Save { channel } => {
+                Handler::save_channel(socket, channels, channel, store, fan_ctrl)
+            }
+            Command::LoadProfile { name } => Handler::load_profile(socket, channels, store, &name),
+            Command::SaveProfile { name } => Handler::save_profile(socket, channels, store, &name),
+            Command::RemoveProfile { name } => Handler::remove_profile(socket, store, &name),
+            Command::Show(ShowCommand::Profiles) => Handler::show_profiles(socket, store),
             Command::Ipv4(config) => Handler::set_ipv4(socket, store, config),
             Command::Reset => Handler::reset(channels),
             Command::Dfu => Handler::dfu(channels),
@@ -519,7 +3244,500 @@ impl Handler {
                 Handler::fan_curve(socket, fan_ctrl, k_a, k_b, k_c)
             }
             Command::FanCurveDefaults => Handler::fan_defaults(socket, fan_ctrl),
+            Command::FanHysteresis { hysteresis } => {
+                Handler::fan_hysteresis(socket, fan_ctrl, hysteresis)
+            }
+            Command::FanMinPwm { min_pwm } => Handler::fan_min_pwm(socket, fan_ctrl, min_pwm),
+            Command::FanMcuCurve { range } => Handler::fan_mcu_curve(socket, fan_ctrl, range),
             Command::ShowHWRev => Handler::show_hwrev(socket, hwrev),
+            Command::Show(ShowCommand::Version) => Handler::show_version(socket),
+            Command::Framing(framing) => Handler::set_framing(socket, framing),
+            Command::Show(ShowCommand::ReportFormat) => Handler::show_report_format(socket),
+            Command::ReportFormat(report_format) => {
+                Handler::set_report_format(socket, report_format)
+            }
+            Command::Show(ShowCommand::Precision) => Handler::show_precision(socket),
+            Command::Precision(precision) => Handler::set_precision(socket, precision),
+            Command::Interactive(enabled) => Handler::set_interactive(socket, enabled),
+            Command::Cascade { channel, enabled } => {
+                Handler::set_cascade(socket, channels, channel, enabled)
+            }
+            Command::SensorOnly { channel, enabled } => {
+                Handler::set_sensor_only(socket, channels, channel, enabled)
+            }
+            Command::Stable {
+                channel,
+                parameter,
+                value,
+            } => Handler::set_stable(socket, channels, channel, parameter, value),
+            Command::Interlock { channel, limit } => {
+                Handler::set_interlock(socket, channels, channel, limit)
+            }
+            Command::ClearFault { channel } => Handler::clear_fault(socket, channels, channel),
+            Command::TecCheck { channel } => Handler::tec_check(socket, channels, channel),
+            Command::TecCal {
+                channel,
+                parameter,
+                value,
+            } => Handler::set_tec_cal(socket, channels, store, channel, parameter, value),
+            Command::CalibrateDac { channel } => Handler::calibrate_dac(socket, channels, channel),
+            Command::DacCode { channel, code } => {
+                Handler::set_dac_code(socket, channels, channel, code)
+            }
+            Command::PowerOn(power_on) => Handler::set_power_on(socket, store, power_on),
+            Command::Show(ShowCommand::DacChip) => Handler::show_dac_chip(socket, channels, store),
+            Command::DacChip(dac_chip) => Handler::set_dac_chip(socket, store, dac_chip),
+            Command::SteinhartHart {
+                channel,
+                parameter,
+                value,
+            } => Handler::set_steinhart_hart(socket, channels, channel, parameter, value),
+            Command::Sensor { channel, sensor } => {
+                Handler::set_sensor(socket, channels, channel, sensor)
+            }
+        };
+        if changes_config && result.is_ok() {
+            autosave::changed();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ad5680,
+        channel_state::Fault,
+        mock::{MockChannels, MockFlash},
+    };
+    use heapless::consts::U4;
+
+    /// Send buffer in place of a transport
+    struct Sink(Vec<u8, U256>);
+
+    impl Response for Sink {
+        fn send_capacity(&self) -> usize {
+            self.0.capacity()
+        }
+
+        fn send_queue(&self) -> usize {
+            self.0.len()
+        }
+
+        fn send_slice(&mut self, data: &[u8]) -> usize {
+            let len = data.len().min(self.0.capacity() - self.0.len());
+            let _ = self.0.extend_from_slice(&data[..len]);
+            len
         }
     }
+
+    #[test]
+    fn test_send_line_tagged() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        socket.set_tag(Some(RequestTag::from("a1")));
+        send_line(&mut socket, b"{}");
+        send_line(&mut socket, b"[1,2]");
+        assert_eq!(
+            &sink.0[..],
+            b"{\"id\":\"a1\"}\n{\"id\":\"a1\",\"data\":[1,2]}\n"
+        );
+    }
+
+    #[test]
+    fn test_send_json() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = send_json(&mut socket, "numbers", &[1, 2]);
+        assert_eq!(result, Ok(Handler::Handled));
+        // Longer than a JSON buffer
+        let result = send_json(&mut socket, "numbers", &[100u32; 1024][..]);
+        assert_eq!(result, Err(Error::Report));
+        assert!(sink.0.starts_with(b"[1,2]\n{\"error\":"));
+    }
+
+    #[test]
+    fn test_send_error() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::LengthPrefix);
+        let error = send_error(
+            &mut socket,
+            Error::Range,
+            format_args!("\"{}\" out of range", 7),
+            Some("epoch"),
+        );
+        assert_eq!(error, Error::Range);
+        let line = b"{\"error\":{\"code\":101,\"category\":\"input\",\
+\"message\":\"\\\"7\\\" out of range\",\"param\":\"epoch\"}}";
+        assert_eq!(&sink.0[..4], &(line.len() as u32).to_be_bytes());
+        assert_eq!(&sink.0[4..], line);
+    }
+
+    #[test]
+    fn test_send_dump_config() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let mut dump = Dump::Config { next: 0 };
+        // Never fits, rather than waiting for room
+        assert!(send_dump(&mut socket, &mut dump, &mut channels));
+        let lines: Vec<&[u8], U4> = sink.0.split(|&c| c == b'\n').collect();
+        assert_eq!(lines.len(), CHANNELS + 1);
+        assert!(lines[0].starts_with(b"{\"error\":{\"code\":500,"));
+    }
+
+    #[test]
+    fn test_send_queue() {
+        let mut sink = Sink(Vec::new());
+        let mut queue = SendQueue::new();
+        // Leave room for the first line only
+        sink.0.resize(240, b'x').unwrap();
+        {
+            let mut queued = QueuedResponse::new(&mut sink, &mut queue);
+            let mut socket = FramedSocket::new(&mut queued, Framing::Newline);
+            assert!(send_line(&mut socket, b"[1,2]"));
+            assert!(send_line(&mut socket, b"[3,4,5,6,7,8,9,10,11,12]"));
+            // Goes after the queued line, although it would fit
+            assert!(send_line(&mut socket, b"[]"));
+        }
+        assert_eq!(&sink.0[240..], b"[1,2]\n");
+        // The client takes the buffered bytes
+        sink.0 = Vec::new();
+        assert!(queue.flush(&mut sink));
+        assert_eq!(&sink.0[..], b"[3,4,5,6,7,8,9,10,11,12]\n[]\n");
+    }
+
+    #[test]
+    fn test_kick_range() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        assert_eq!(Handler::kick(&mut socket, 1), Ok(Handler::Kick(1)));
+        assert_eq!(Handler::kick(&mut socket, SOCKET_COUNT), Err(Error::Range));
+    }
+
+    #[test]
+    fn test_set_interlock_overcurrent() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let limit = Some((InterlockLimit::OverCurrent, 0.0));
+        let result = Handler::set_interlock(&mut socket, &mut channels, 0, limit);
+        assert_eq!(result, Err(Error::Range));
+        let limit = Some((InterlockLimit::OverCurrent, 2.0));
+        let result = Handler::set_interlock(&mut socket, &mut channels, 0, limit);
+        assert_eq!(result, Ok(Handler::Handled));
+        let overcurrent_time = channels.states[0].overcurrent_time.unwrap();
+        assert_eq!(overcurrent_time.get::<second>(), 2.0);
+        let result = Handler::set_interlock(&mut socket, &mut channels, 0, None);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert!(channels.states[0].overcurrent_time.is_none());
+    }
+
+    #[test]
+    fn test_set_dtdt_window() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let result = Handler::set_dtdt_window(&mut socket, &mut channels, 0, 0.0);
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::set_dtdt_window(&mut socket, &mut channels, 0, 2.0);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.states[0].dtdt_window.get::<second>(), 2.0);
+    }
+
+    #[test]
+    fn test_set_stable() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let result =
+            Handler::set_stable(&mut socket, &mut channels, 0, StableParameter::Window, -0.1);
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::set_stable(&mut socket, &mut channels, 0, StableParameter::Time, 0.0);
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::set_stable(&mut socket, &mut channels, 0, StableParameter::Time, 5.0);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.states[0].stable_time.get::<second>(), 5.0);
+    }
+
+    #[test]
+    fn test_tune_step_range() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[0].pid_engaged = true;
+        let result = Handler::tune_step(&mut socket, &mut channels, 0, 0.5, -1.0);
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::tune_step(&mut socket, &mut channels, 0, -2.5, 60.0);
+        assert_eq!(result, Err(Error::Range));
+        assert!(channels.states[0].pid_engaged);
+        let result = Handler::tune_step(&mut socket, &mut channels, 0, 0.5, 60.0);
+        assert_eq!(
+            result,
+            Ok(Handler::NewDump(Dump::StepTest { delta_i: 0.5 }))
+        );
+    }
+
+    #[test]
+    fn test_set_cascade() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let result = Handler::set_output(&mut socket, &mut channels, 1, PwmPin::ISet, 0.5);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert!(channels.powered[1]);
+
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = Handler::set_cascade(&mut socket, &mut channels, 0, true);
+        assert_eq!(result, Ok(Handler::Handled));
+        // The outer loop only sets the target of the inner one
+        assert!(channels.states[0].cascade);
+        assert!(!channels.powered[1]);
+        assert!(!channels.states[1].pid_engaged);
+        assert_eq!(channels.states[1].i_set.get::<ampere>(), 0.0);
+        assert_eq!(&sink.0[..], b"{\"disabled\":1}\n");
+    }
+
+    #[test]
+    fn test_set_output() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[1].pid_engaged = true;
+        let result = Handler::set_output(&mut socket, &mut channels, 1, PwmPin::ISet, 0.5);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.states[1].i_set.get::<ampere>(), 0.5);
+        assert!(!channels.states[1].pid_engaged);
+        assert_eq!(channels.powered, [false, true]);
+        assert_eq!(&sink.0[..], b"{}\n");
+    }
+
+    #[test]
+    fn test_set_output_fault() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[0].fault = Some(Fault::OverTemperature);
+        let result = Handler::set_output(&mut socket, &mut channels, 0, PwmPin::ISet, 0.5);
+        assert_eq!(result, Err(Error::Fault));
+        assert_eq!(channels.powered, [false, false]);
+        assert!(sink.0.starts_with(b"{\"error\":{\"code\":200,"));
+    }
+
+    #[test]
+    fn test_set_output_voltage() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[0].pid_engaged = true;
+        let result = Handler::set_output_voltage(&mut socket, &mut channels, 0, 10.0);
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::set_output_voltage(&mut socket, &mut channels, 0, -1.5);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert!(!channels.states[0].pid_engaged);
+        assert_eq!(channels.states[0].v_set.unwrap().get::<volt>(), -1.5);
+        assert_eq!(channels.powered, [true, false]);
+        // Back to current control
+        let result = Handler::set_output(&mut socket, &mut channels, 0, PwmPin::ISet, 0.5);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert!(channels.states[0].v_set.is_none());
+    }
+
+    #[test]
+    fn test_set_dac_code() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.powered[0] = true;
+        let result = Handler::set_dac_code(&mut socket, &mut channels, 0, 0);
+        assert_eq!(result, Err(Error::Powered));
+        let result = Handler::set_dac_code(&mut socket, &mut channels, 1, ad5680::MAX_VALUE + 1);
+        assert_eq!(result, Err(Error::Range));
+        sink.0 = Vec::new();
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = Handler::set_dac_code(&mut socket, &mut channels, 1, ad5680::MAX_VALUE);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(&sink.0[..], b"{\"dac_code\":262143,\"dac_feedback\":3}\n");
+    }
+
+    #[test]
+    fn test_dac_chip() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let mut flash = MockFlash::default();
+        let result = Handler::show_dac_chip(&mut socket, &mut channels, &mut flash);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(&sink.0[..], b"{\"dac_chip\":\"ad5680\",\"saved\":null}\n");
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = Handler::set_dac_chip(&mut socket, &mut flash, DacChip::Dac8560);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(&flash.written[..], ["dac_chip"]);
+
+        // Saved, but in use from the next boot
+        let mut value = Vec::new();
+        value.extend_from_slice(&[1]).unwrap();
+        flash.values.push((dac::FLASH_KEY.into(), value)).unwrap();
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = Handler::show_dac_chip(&mut socket, &mut channels, &mut flash);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(
+            &sink.0[..],
+            b"{\"dac_chip\":\"ad5680\",\"saved\":\"dac8560\"}\n"
+        );
+        assert_eq!(dac::load(&flash), DacChip::Dac8560);
+    }
+
+    #[test]
+    fn test_alarm_window_range() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut flash = MockFlash::default();
+        let result = Handler::set_alarm_window(&mut socket, &mut flash, 0, Some([30.0, 30.0]));
+        assert_eq!(result, Err(Error::Range));
+        let result = Handler::set_alarm_window(&mut socket, &mut flash, 1, Some([40.0, 20.0]));
+        assert_eq!(result, Err(Error::Range));
+        assert!(flash.written.is_empty());
+    }
+
+    #[test]
+    fn test_alarm_dac_feedback_range() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut flash = MockFlash::default();
+        let result = Handler::set_alarm_dac_feedback(&mut socket, &mut flash, Some(0.0));
+        assert_eq!(result, Err(Error::Range));
+        assert!(flash.written.is_empty());
+    }
+
+    #[test]
+    fn test_schedule_rejected() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let result = Handler::schedule(&mut socket, 10, false, ScheduledLine::from("quit"));
+        assert_eq!(result, Err(Error::Parse));
+        let result = Handler::schedule(&mut socket, 0, true, ScheduledLine::from("ping 0"));
+        assert_eq!(result, Err(Error::Range));
+        let line = ScheduledLine::from("output 0 pid");
+        let result = Handler::schedule(&mut socket, scheduler::MAX_SECONDS + 1, false, line);
+        assert_eq!(result, Err(Error::Range));
+    }
+
+    #[test]
+    fn test_heater_only() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[0].i_set = ElectricCurrent::new::<ampere>(-0.5);
+        let result = Handler::set_output_heater_only(&mut socket, &mut channels, 0, true);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.states[0].pid.parameters.output_min, 0.0);
+        assert_eq!(
+            channels.states[0].output_limits.max_i_neg.get::<ampere>(),
+            0.0
+        );
+        assert_eq!(channels.states[0].i_set.get::<ampere>(), 0.0);
+        let result = Handler::set_pid(&mut socket, &mut channels, 0, PidParameter::OutputMin, -1.0);
+        assert_eq!(result, Err(Error::Range));
+    }
+
+    #[test]
+    fn test_sensor_only() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.states[1].pid_engaged = true;
+        channels.powered[1] = true;
+        let result = Handler::set_sensor_only(&mut socket, &mut channels, 1, true);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert!(!channels.states[1].pid_engaged);
+        assert_eq!(channels.powered, [false, false]);
+        assert_eq!(
+            Handler::engage_pid(&mut socket, &mut channels, 1),
+            Err(Error::SensorOnly)
+        );
+        let result = Handler::set_output(&mut socket, &mut channels, 1, PwmPin::ISet, 0.5);
+        assert_eq!(result, Err(Error::SensorOnly));
+        assert_eq!(channels.powered, [false, false]);
+    }
+
+    #[test]
+    fn test_pid_parameter() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let _ = Handler::set_pid(&mut socket, &mut channels, 0, PidParameter::KP, 2.5);
+        let _ = Handler::query_pid(&mut socket, &mut channels, 0, PidParameter::KP);
+        assert_eq!(&sink.0[..], b"{}\n{\"kp\":2.5}\n");
+    }
+
+    #[test]
+    fn test_pid_divisor() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        for value in [0.0, -2.0, 1.5] {
+            let result =
+                Handler::set_pid(&mut socket, &mut channels, 0, PidParameter::Divisor, value);
+            assert_eq!(result, Err(Error::Range));
+        }
+        assert_eq!(channels.states[0].pid.parameters.divisor, 1);
+        let result = Handler::set_pid(&mut socket, &mut channels, 0, PidParameter::Divisor, 4.0);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.states[0].pid.parameters.divisor, 4);
+    }
+
+    #[test]
+    fn test_calibrate_dac() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        channels.powered[1] = true;
+        let result = Handler::calibrate_dac(&mut socket, &mut channels, 1);
+        assert_eq!(result, Err(Error::Powered));
+        assert_eq!(channels.dac_calibration, None);
+
+        let result = Handler::calibrate_dac(&mut socket, &mut channels, 0);
+        assert_eq!(result, Ok(Handler::Handled));
+        assert_eq!(channels.dac_calibration, Some(0));
+
+        // One at a time
+        channels.powered[1] = false;
+        let result = Handler::calibrate_dac(&mut socket, &mut channels, 1);
+        assert_eq!(result, Err(Error::DacCalibration));
+
+        // Output kept off until done
+        let result = Handler::set_output(&mut socket, &mut channels, 0, PwmPin::ISet, 0.1);
+        assert_eq!(result, Err(Error::DacCalibration));
+        let result = Handler::engage_pid(&mut socket, &mut channels, 0);
+        assert_eq!(result, Err(Error::DacCalibration));
+        let result = Handler::set_output(&mut socket, &mut channels, 1, PwmPin::ISet, 0.1);
+        assert_eq!(result, Ok(Handler::Handled));
+    }
+
+    #[test]
+    fn test_set_tec_cal_store_full() {
+        let mut sink = Sink(Vec::new());
+        let mut socket = FramedSocket::new(&mut sink, Framing::Newline);
+        let mut channels = MockChannels::new();
+        let mut flash = MockFlash {
+            full: true,
+            ..MockFlash::default()
+        };
+        let result = Handler::set_tec_cal(
+            &mut socket,
+            &mut channels,
+            &mut flash,
+            0,
+            TecCalParameter::IGain,
+            1.01,
+        );
+        assert_eq!(result, Err(Error::Flash));
+        // Applied even when it could not be saved
+        assert_eq!(channels.states[0].tec_cal.i_gain, 1.01);
+        assert!(sink.0.starts_with(b"{\"error\":{\"code\":400,"));
+    }
 }
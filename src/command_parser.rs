@@ -1,21 +1,28 @@
 use core::fmt;
 use core::num::ParseIntError;
 use core::str::{from_utf8, Utf8Error};
+use heapless::{
+    consts::{U16, U32, U64},
+    ArrayLength, String, Vec,
+};
+use log::LevelFilter;
 use nom::{
     branch::alt,
-    bytes::complete::{is_a, tag, take_while1},
+    bytes::complete::{is_a, tag_no_case, take_while, take_while1},
     character::{
         complete::{char, one_of},
-        is_digit,
+        is_digit, is_hex_digit,
     },
-    combinator::{complete, map, opt, value},
+    combinator::{complete, map, map_opt, opt, value},
     error::ErrorKind,
     multi::{fold_many0, fold_many1},
-    sequence::preceded,
+    sequence::{preceded, terminated},
     IResult, Needed,
 };
 use num_traits::{Num, ParseFloatError};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{ad7172::DigitalFilterOrder, alarms::AlarmKind, channels::CHANNELS, dac::DacChip};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
@@ -26,6 +33,12 @@ pub enum Error {
     ParseInt(ParseIntError),
     // `num_traits::ParseFloatError` does not impl Clone
     ParseFloat,
+    /// Number too large to be represented
+    NonFinite,
+    /// `all` given for a command that does not change settings
+    AllChannels,
+    /// Line longer than the line reader takes, discarded
+    LineTooLong,
 }
 
 impl<'t> From<nom::Err<(&'t [u8], ErrorKind)>> for Error {
@@ -77,6 +90,9 @@ impl fmt::Display for Error {
                 (e as &dyn core::fmt::Debug).fmt(fmt)
             }
             Error::ParseFloat => "parsing float".fmt(fmt),
+            Error::NonFinite => "number out of range".fmt(fmt),
+            Error::AllChannels => "all channels not supported".fmt(fmt),
+            Error::LineTooLong => "line too long".fmt(fmt),
         }
     }
 }
@@ -88,6 +104,21 @@ pub struct Ipv4Config {
     pub gateway: Option<[u8; 4]>,
 }
 
+/// Name of a config profile: a letter, followed by letters, digits,
+/// `_` or `-`
+pub type ProfileName = String<U16>;
+/// Name that tells devices apart, with the same characters as
+/// `ProfileName`
+pub type DeviceName = String<U32>;
+/// Name given to a channel with `alias`, with the same characters as
+/// `ProfileName`
+pub type ChannelAlias = String<U16>;
+/// Tag given before a command as `#<tag>`, and echoed in its
+/// responses: letters, digits, `_` or `-`
+pub type RequestTag = String<U16>;
+/// Command queued with `at` or `every`, as entered
+pub type ScheduledLine = String<U64>;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ShowCommand {
     Input,
@@ -96,6 +127,38 @@ pub enum ShowCommand {
     BParameter,
     PostFilter,
     Ipv4,
+    Framing,
+    ReportFormat,
+    Precision,
+    Cascade,
+    Stable,
+    StableOutput,
+    Locked,
+    Interlock,
+    PowerOn,
+    SteinhartHart,
+    Sensor,
+    DataRate,
+    RawReport,
+    Cal,
+    Stats,
+    DtDt,
+    Time,
+    Version,
+    EventLog,
+    Config,
+    Profiles,
+    AutoSave,
+    Deadman,
+    LogLevel,
+    SafeStart,
+    Name,
+    Alarms,
+    Faults,
+    TecCal,
+    Alias,
+    DacChip,
+    Schedule,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -106,6 +169,30 @@ pub enum PidParameter {
     KD,
     OutputMin,
     OutputMax,
+    Ramp,
+    DFilter,
+    FfOffset,
+    FfGain,
+    FfReference,
+    Divisor,
+}
+
+/// Stability detection parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum StableParameter {
+    Window,
+    Time,
+}
+
+/// Interlock limit
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterlockLimit {
+    /// Degrees Celsius
+    TMin,
+    /// Degrees Celsius
+    TMax,
+    /// Seconds the TEC current may stay at its limit
+    OverCurrent,
 }
 
 /// B-Parameter equation parameter
@@ -116,6 +203,45 @@ pub enum BpParameter {
     R0,
 }
 
+/// ADC system calibration
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdcCalibration {
+    /// Zero-scale input applied
+    Offset,
+    /// Full-scale input applied
+    Gain,
+}
+
+/// User calibration of the temperature
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalParameter {
+    Offset,
+    Gain,
+    /// Reference temperature of the first point of a two-point
+    /// calibration
+    Point1,
+    /// Reference temperature of the second point, which completes
+    /// the calibration
+    Point2,
+}
+
+/// Trim of the TEC readback
+#[derive(Debug, Clone, PartialEq)]
+pub enum TecCalParameter {
+    IGain,
+    IOffset,
+    VGain,
+    VOffset,
+}
+
+/// Steinhart-Hart equation parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShParameter {
+    A,
+    B,
+    C,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PwmPin {
     ISet,
@@ -124,18 +250,124 @@ pub enum PwmPin {
     MaxV,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CenterPoint {
     VRef,
     Override(f32),
 }
 
+/// Serialized as `Option<f32>`, as JSON cannot hold enums with data.
+/// Postcard encodes either the same, which keeps the config in flash.
+impl Serialize for CenterPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            CenterPoint::VRef => serializer.serialize_none(),
+            CenterPoint::Override(center_point) => serializer.serialize_some(center_point),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CenterPoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Option::<f32>::deserialize(deserializer)?
+            .map(CenterPoint::Override)
+            .unwrap_or(CenterPoint::VRef))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Polarity {
     Normal,
     Reversed,
 }
 
+/// Conversion of the sensor resistance to temperature
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Sensor {
+    BParameter,
+    SteinhartHart,
+    Pt100,
+    Pt1000,
+}
+
+/// Output state at boot
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PowerOn {
+    /// Restore `i_set` and PID engagement from the saved config
+    Restore,
+    /// Keep outputs powered down until explicitly enabled
+    Off,
+}
+
+/// Bytes written by one `eeprom write`, as their hex digits fit into a
+/// command line
+pub type EepromData = Vec<u8, U16>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EepromAccess {
+    Read { offset: u32, len: u32 },
+    Write { offset: u32, data: EepromData },
+}
+
+/// Maximum level of log messages
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn current() -> Self {
+        match log::max_level() {
+            LevelFilter::Off => LogLevel::Off,
+            LevelFilter::Error => LogLevel::Error,
+            LevelFilter::Warn => LogLevel::Warn,
+            LevelFilter::Info => LogLevel::Info,
+            LevelFilter::Debug => LogLevel::Debug,
+            LevelFilter::Trace => LogLevel::Trace,
+        }
+    }
+
+    pub fn apply(self) {
+        log::set_max_level(match self {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        });
+    }
+}
+
+/// Response framing of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// Responses are terminated by `\n`
+    Newline,
+    /// Responses are prefixed with their length as big-endian `u32`
+    LengthPrefix,
+}
+
+/// Format of the reports of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Json,
+    /// JSON with integers in milli-units
+    Milli,
+    /// A row per channel, with the header sent once when selected
+    Csv,
+}
+
+/// Channel of a command given as `all`, which is applied to each
+/// channel in turn by the handler
+pub const ALL_CHANNELS: usize = usize::MAX;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Quit,
@@ -145,6 +377,15 @@ pub enum Command {
     Save {
         channel: Option<usize>,
     },
+    LoadProfile {
+        name: ProfileName,
+    },
+    SaveProfile {
+        name: ProfileName,
+    },
+    RemoveProfile {
+        name: ProfileName,
+    },
     Reset,
     Ipv4(Ipv4Config),
     Show(ShowCommand),
@@ -158,10 +399,34 @@ pub enum Command {
     OutputPid {
         channel: usize,
     },
+    /// Disengage PID, zero `i_set` and power down
+    OutputOff {
+        channel: usize,
+    },
     OutputPolarity {
         channel: usize,
         polarity: Polarity,
     },
+    /// Show a single setting of `output`
+    OutputQuery {
+        channel: usize,
+        pin: PwmPin,
+    },
+    /// Limit the output to positive currents
+    OutputHeaterOnly {
+        channel: usize,
+        enabled: bool,
+    },
+    /// Drive the output to a TEC voltage, in Volts
+    OutputVoltage {
+        channel: usize,
+        voltage: f64,
+    },
+    /// Enable the slow loop trimming i_set by the measured TEC current
+    OutputTrim {
+        channel: usize,
+        enabled: bool,
+    },
     CenterPoint {
         channel: usize,
         center: CenterPoint,
@@ -172,15 +437,140 @@ pub enum Command {
         parameter: PidParameter,
         value: f64,
     },
+    /// Show a single PID parameter
+    PidQuery {
+        channel: usize,
+        parameter: PidParameter,
+    },
     BParameter {
         channel: usize,
         parameter: BpParameter,
         value: f64,
     },
+    SteinhartHart {
+        channel: usize,
+        parameter: ShParameter,
+        value: f64,
+    },
+    Sensor {
+        channel: usize,
+        sensor: Sensor,
+    },
     PostFilter {
         channel: usize,
         rate: Option<f32>,
     },
+    SwFilter {
+        channel: usize,
+        samples: u32,
+    },
+    MedianFilter {
+        channel: usize,
+        points: u8,
+    },
+    DataRate {
+        channel: usize,
+        rate: f32,
+    },
+    FilterOrder {
+        channel: usize,
+        order: DigitalFilterOrder,
+    },
+    AdcCalibrate {
+        channel: usize,
+        calibration: AdcCalibration,
+    },
+    Cal {
+        channel: usize,
+        parameter: CalParameter,
+        value: f64,
+    },
+    StatsReset,
+    EnergyReset,
+    SelfTest,
+    SelfTestBoot(bool),
+    AutoSave(bool),
+    /// Seconds without commands before shutting down, `None` to
+    /// disable
+    Deadman(Option<u32>),
+    Ping,
+    LogLevel(LogLevel),
+    /// Keep the outputs off at boot after a reset by the watchdog
+    SafeStart(bool),
+    Eeprom(EepromAccess),
+    AlarmsClear,
+    ClearFaults,
+    Identify {
+        seconds: Option<u32>,
+    },
+    /// Temperature window in degrees Celsius, `None` to disable
+    AlarmWindow {
+        channel: usize,
+        window: Option<[f64; 2]>,
+    },
+    AlarmEnable {
+        kind: AlarmKind,
+        enabled: bool,
+    },
+    /// Deviation of the DAC feedback in volts, `None` to disable
+    AlarmDacFeedback {
+        max: Option<f64>,
+    },
+    Name {
+        name: DeviceName,
+    },
+    /// Name a channel, `None` to remove its alias
+    Alias {
+        channel: usize,
+        alias: Option<ChannelAlias>,
+    },
+    Clients,
+    ConfigLoad,
+    /// Report the problems of the configs saved in flash
+    ConfigCheck,
+    /// Report the problems of a config document, received on the next
+    /// line, without applying it
+    ConfigCheckLoad,
+    /// Report the settings changed since `save`
+    ConfigDiff,
+    /// Run `line` in `seconds`, and every `seconds` after with `every`
+    Schedule {
+        seconds: u32,
+        every: bool,
+        line: ScheduledLine,
+    },
+    /// Remove a scheduled command, or all with `None`
+    ScheduleClear {
+        index: Option<usize>,
+    },
+    /// Config document, received on the line after `config load` or
+    /// `config check load`
+    ConfigDocument,
+    Kick {
+        index: usize,
+    },
+    EventLogClear,
+    LogDump,
+    TimeSet {
+        epoch: u32,
+    },
+    History {
+        channel: usize,
+        samples: Option<u32>,
+    },
+    DtDtWindow {
+        channel: usize,
+        window: f64,
+    },
+    Capture {
+        channel: usize,
+        samples: u32,
+    },
+    TuneStep {
+        channel: usize,
+        delta_i: f64,
+        duration: f64,
+    },
     Dfu,
     FanSet {
         fan_pwm: u32,
@@ -193,7 +583,74 @@ pub enum Command {
         k_c: f32,
     },
     FanCurveDefaults,
+    FanHysteresis {
+        hysteresis: f32,
+    },
+    FanMinPwm {
+        min_pwm: u32,
+    },
+    /// MCU temperatures in degrees Celsius, `None` to disable
+    FanMcuCurve {
+        range: Option<[f32; 2]>,
+    },
     ShowHWRev,
+    Framing(Framing),
+    ReportFormat(ReportFormat),
+    /// Significant digits of the measurements in reports, `None` for
+    /// all
+    Precision(Option<u8>),
+    /// Echo input and prompt for each line, for use from a terminal
+    Interactive(bool),
+    /// Hold the output of `channel` in shutdown, only measuring its
+    /// temperature
+    SensorOnly {
+        channel: usize,
+        enabled: bool,
+    },
+    /// Let the PID of the other channel set the target of `channel`
+    Cascade {
+        channel: usize,
+        enabled: bool,
+    },
+    Stable {
+        channel: usize,
+        parameter: StableParameter,
+        value: f64,
+    },
+    /// Channels that must be stable for the stable output to be high
+    StableOutput {
+        channels: [bool; CHANNELS],
+    },
+    /// Set an interlock limit, or disable both with `None`
+    Interlock {
+        channel: usize,
+        limit: Option<(InterlockLimit, f64)>,
+    },
+    ClearFault {
+        channel: usize,
+    },
+    /// Trim the TEC current and voltage readback
+    TecCal {
+        channel: usize,
+        parameter: TecCalParameter,
+        value: f64,
+    },
+    /// Measure TEC resistance
+    TecCheck {
+        channel: usize,
+    },
+    /// Write a raw code to the DAC, and measure its output
+    DacCode {
+        channel: usize,
+        code: u32,
+    },
+    /// Find the DAC setting for the i_set 0A center point, and save it
+    CalibrateDac {
+        channel: usize,
+    },
+    /// Save the DAC chip to use from the next boot
+    DacChip(DacChip),
+    PowerOn(PowerOn),
 }
 
 fn end(input: &[u8]) -> IResult<&[u8], ()> {
@@ -201,7 +658,7 @@ fn end(input: &[u8]) -> IResult<&[u8], ()> {
 }
 
 fn whitespace(input: &[u8]) -> IResult<&[u8], ()> {
-    fold_many1(char(' '), (), |(), _| ())(input)
+    fold_many1(one_of(" \t"), (), |(), _| ())(input)
 }
 
 fn unsigned(input: &[u8]) -> IResult<&[u8], Result<u32, Error>> {
@@ -213,87 +670,201 @@ fn unsigned(input: &[u8]) -> IResult<&[u8], Result<u32, Error>> {
     })
 }
 
+/// Decimal number, optionally with an exponent as in `1e-3`
 fn float(input: &[u8]) -> IResult<&[u8], Result<f64, Error>> {
     let (input, sign) = opt(is_a("-"))(input)?;
     let negative = sign.is_some();
-    let (input, digits) = take_while1(|c| is_digit(c) || c == b'.')(input)?;
-    let result = from_utf8(digits)
-        .map_err(|e| e.into())
-        .and_then(|digits| f64::from_str_radix(digits, 10).map_err(|e| e.into()))
-        .map(|result: f64| if negative { -result } else { result });
+    let start = input;
+    let (input, mantissa) = take_while1(|c| is_digit(c) || c == b'.')(input)?;
+    // Consume a malformed exponent too, to reject it as such
+    let (input, _) = opt(preceded(
+        one_of("eE"),
+        take_while(|c| is_digit(c) || c == b'+' || c == b'-'),
+    ))(input)?;
+    let digits = &start[..start.len() - input.len()];
+    let result = if !mantissa.iter().any(|&c| is_digit(c)) {
+        Err(Error::ParseFloat)
+    } else {
+        from_utf8(digits)
+            .map_err(|e| e.into())
+            .and_then(|digits| f64::from_str_radix(digits, 10).map_err(|e| e.into()))
+    };
+    let result = result.and_then(|result: f64| {
+        if result.is_finite() {
+            Ok(if negative { -result } else { result })
+        } else {
+            Err(Error::NonFinite)
+        }
+    });
     Ok((input, result))
 }
 
+/// Physical unit of an argument, which may be given with its symbol
+#[derive(Clone, Copy)]
+enum Unit {
+    Ampere,
+    Volt,
+    DegreeCelsius,
+    Second,
+}
+
+impl Unit {
+    fn symbol(self) -> &'static str {
+        match self {
+            Unit::Ampere => "A",
+            Unit::Volt => "V",
+            Unit::DegreeCelsius => "C",
+            Unit::Second => "s",
+        }
+    }
+}
+
+/// Number in `unit`, optionally followed by its symbol with an `m`
+/// prefix for milli, as in `250mA`, or with the decimals after the
+/// symbol, as in `2V5`
+fn quantity(unit: Unit, input: &[u8]) -> IResult<&[u8], Result<f64, Error>> {
+    let (input, number) = float(input)?;
+    let (input, scale) = opt(alt((
+        value(1.0, tag_no_case(unit.symbol())),
+        value(1e-3, preceded(char('m'), tag_no_case(unit.symbol()))),
+    )))(input)?;
+    let (input, decimals) = match scale {
+        Some(_) => opt(take_while1(is_digit))(input)?,
+        None => (input, None),
+    };
+    let result = number.and_then(|number| match decimals {
+        // Decimals on both sides of the symbol
+        Some(_) if number != (number as i64) as f64 => Err(Error::ParseFloat),
+        Some(decimals) => {
+            let fraction = decimals.iter().rev().fold(0.0, |fraction, digit| {
+                (fraction + f64::from(digit - b'0')) / 10.0
+            });
+            Ok(if number.is_sign_negative() {
+                number - fraction
+            } else {
+                number + fraction
+            })
+        }
+        None => Ok(number),
+    });
+    Ok((input, result.map(|number| number * scale.unwrap_or(1.0))))
+}
+
 fn channel(input: &[u8]) -> IResult<&[u8], usize> {
-    map(one_of("01"), |c| (c as usize) - ('0' as usize))(input)
+    map_opt(one_of("0123456789"), |c| {
+        let channel = (c as usize) - ('0' as usize);
+        (channel < CHANNELS).then_some(channel)
+    })(input)
+}
+
+/// Channel of a command, or `all` for `ALL_CHANNELS`
+fn channel_or_all(input: &[u8]) -> IResult<&[u8], usize> {
+    alt((channel, value(ALL_CHANNELS, tag_no_case("all"))))(input)
 }
 
 fn report(input: &[u8]) -> IResult<&[u8], Command> {
     preceded(
-        tag("report"),
-        // `report` - Report once
-        value(Command::Show(ShowCommand::Input), end),
+        tag_no_case("report"),
+        alt((
+            // `report raw` - Report raw ADC data once
+            value(
+                Command::Show(ShowCommand::RawReport),
+                preceded(whitespace, preceded(tag_no_case("raw"), end)),
+            ),
+            // `report` - Report once
+            value(Command::Show(ShowCommand::Input), end),
+        )),
     )(input)
 }
 
-fn pwm_setup(input: &[u8]) -> IResult<&[u8], Result<(PwmPin, f64), Error>> {
-    let result_with_pin =
-        |pin: PwmPin| move |result: Result<f64, Error>| result.map(|value| (pin, value));
-
+fn pwm_pin(input: &[u8]) -> IResult<&[u8], PwmPin> {
     alt((
-        map(
-            preceded(tag("i_set"), preceded(whitespace, float)),
-            result_with_pin(PwmPin::ISet),
-        ),
-        map(
-            preceded(tag("max_i_pos"), preceded(whitespace, float)),
-            result_with_pin(PwmPin::MaxIPos),
-        ),
-        map(
-            preceded(tag("max_i_neg"), preceded(whitespace, float)),
-            result_with_pin(PwmPin::MaxINeg),
-        ),
-        map(
-            preceded(tag("max_v"), preceded(whitespace, float)),
-            result_with_pin(PwmPin::MaxV),
-        ),
+        value(PwmPin::ISet, tag_no_case("i_set")),
+        value(PwmPin::MaxIPos, tag_no_case("max_i_pos")),
+        value(PwmPin::MaxINeg, tag_no_case("max_i_neg")),
+        value(PwmPin::MaxV, tag_no_case("max_v")),
     ))(input)
 }
 
+fn pwm_setup(input: &[u8]) -> IResult<&[u8], Result<(PwmPin, f64), Error>> {
+    let (input, pin) = pwm_pin(input)?;
+    let (input, _) = whitespace(input)?;
+    let unit = match pin {
+        PwmPin::MaxV => Unit::Volt,
+        PwmPin::ISet | PwmPin::MaxIPos | PwmPin::MaxINeg => Unit::Ampere,
+    };
+    let (input, value) = quantity(unit, input)?;
+    Ok((input, value.map(|value| (pin, value))))
+}
+
 /// `output <0-1> pid` - Set output to be controlled by PID
 fn output_pid(input: &[u8]) -> IResult<&[u8], ()> {
-    value((), tag("pid"))(input)
+    value((), tag_no_case("pid"))(input)
 }
 
 fn output_polarity(input: &[u8]) -> IResult<&[u8], Polarity> {
     preceded(
-        tag("polarity"),
+        tag_no_case("polarity"),
         preceded(
             whitespace,
             alt((
-                value(Polarity::Normal, tag("normal")),
-                value(Polarity::Reversed, tag("reversed")),
+                value(Polarity::Normal, tag_no_case("normal")),
+                value(Polarity::Reversed, tag_no_case("reversed")),
             )),
         ),
     )(input)
 }
 
 fn output(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("output")(input)?;
+    let (input, _) = tag_no_case("output")(input)?;
     alt((
         |input| {
             let (input, _) = whitespace(input)?;
-            let (input, channel) = channel(input)?;
+            let (input, channel) = channel_or_all(input)?;
             let (input, _) = whitespace(input)?;
             let (input, result) = alt((
                 |input| {
                     let (input, ()) = output_pid(input)?;
                     Ok((input, Ok(Command::OutputPid { channel })))
                 },
+                |input| {
+                    let (input, _) = tag_no_case("off")(input)?;
+                    Ok((input, Ok(Command::OutputOff { channel })))
+                },
                 |input| {
                     let (input, polarity) = output_polarity(input)?;
                     Ok((input, Ok(Command::OutputPolarity { channel, polarity })))
                 },
+                |input| {
+                    let (input, _) = tag_no_case("trim")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, enabled) = alt((
+                        value(true, tag_no_case("on")),
+                        value(false, tag_no_case("off")),
+                    ))(input)?;
+                    Ok((input, Ok(Command::OutputTrim { channel, enabled })))
+                },
+                |input| {
+                    let (input, _) = tag_no_case("heater_only")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, enabled) = alt((
+                        value(true, tag_no_case("on")),
+                        value(false, tag_no_case("off")),
+                    ))(input)?;
+                    Ok((input, Ok(Command::OutputHeaterOnly { channel, enabled })))
+                },
+                |input| {
+                    let (input, _) = tag_no_case("v_set")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, voltage) = quantity(Unit::Volt, input)?;
+                    let result = voltage.map(|voltage| Command::OutputVoltage { channel, voltage });
+                    Ok((input, result))
+                },
+                |input| {
+                    let (input, pin) = pwm_pin(input)?;
+                    let (input, _) = char('?')(input)?;
+                    Ok((input, Ok(Command::OutputQuery { channel, pin })))
+                },
                 |input| {
                     let (input, config) = pwm_setup(input)?;
                     match config {
@@ -317,17 +888,19 @@ fn output(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 }
 
 fn center_point(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("center")(input)?;
+    let (input, _) = tag_no_case("center")(input)?;
     let (input, _) = whitespace(input)?;
-    let (input, channel) = channel(input)?;
+    let (input, channel) = channel_or_all(input)?;
     let (input, _) = whitespace(input)?;
-    let (input, center) = alt((value(Ok(CenterPoint::VRef), tag("vref")), |input| {
-        let (input, value) = float(input)?;
-        Ok((
-            input,
-            value.map(|value| CenterPoint::Override(value as f32)),
-        ))
-    }))(input)?;
+    let (input, center) = alt(
+        (value(Ok(CenterPoint::VRef), tag_no_case("vref")), |input| {
+            let (input, value) = float(input)?;
+            Ok((
+                input,
+                value.map(|value| CenterPoint::Override(value as f32)),
+            ))
+        }),
+    )(input)?;
     end(input)?;
     Ok((
         input,
@@ -337,18 +910,34 @@ fn center_point(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 
 /// `pid <0-1> <parameter> <value>`
 fn pid_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, channel) = channel(input)?;
+    let (input, channel) = channel_or_all(input)?;
     let (input, _) = whitespace(input)?;
     let (input, parameter) = alt((
-        value(PidParameter::Target, tag("target")),
-        value(PidParameter::KP, tag("kp")),
-        value(PidParameter::KI, tag("ki")),
-        value(PidParameter::KD, tag("kd")),
-        value(PidParameter::OutputMin, tag("output_min")),
-        value(PidParameter::OutputMax, tag("output_max")),
+        value(PidParameter::Target, tag_no_case("target")),
+        value(PidParameter::KP, tag_no_case("kp")),
+        value(PidParameter::KI, tag_no_case("ki")),
+        value(PidParameter::KD, tag_no_case("kd")),
+        value(PidParameter::OutputMin, tag_no_case("output_min")),
+        value(PidParameter::OutputMax, tag_no_case("output_max")),
+        value(PidParameter::Ramp, tag_no_case("ramp")),
+        value(PidParameter::DFilter, tag_no_case("d_filter")),
+        value(PidParameter::FfOffset, tag_no_case("ff_offset")),
+        value(PidParameter::FfGain, tag_no_case("ff_gain")),
+        value(PidParameter::FfReference, tag_no_case("ff_reference")),
+        value(PidParameter::Divisor, tag_no_case("divisor")),
     ))(input)?;
+    let query: IResult<&[u8], char> = char('?')(input);
+    if let Ok((input, _)) = query {
+        return Ok((input, Ok(Command::PidQuery { channel, parameter })));
+    }
     let (input, _) = whitespace(input)?;
-    let (input, value) = float(input)?;
+    let (input, value) = match parameter {
+        PidParameter::Target | PidParameter::FfReference => quantity(Unit::DegreeCelsius, input)?,
+        PidParameter::OutputMin | PidParameter::OutputMax | PidParameter::FfOffset => {
+            quantity(Unit::Ampere, input)?
+        }
+        _ => float(input)?,
+    };
     let result = value.map(|value| Command::Pid {
         channel,
         parameter,
@@ -359,7 +948,7 @@ fn pid_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 
 /// `pid` | `pid <pid_parameter>`
 fn pid(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("pid")(input)?;
+    let (input, _) = tag_no_case("pid")(input)?;
     alt((
         preceded(whitespace, pid_parameter),
         value(Ok(Command::Show(ShowCommand::Pid)), end),
@@ -368,12 +957,12 @@ fn pid(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
 
 /// `b-p <0-1> <parameter> <value>`
 fn b_parameter_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, channel) = channel(input)?;
+    let (input, channel) = channel_or_all(input)?;
     let (input, _) = whitespace(input)?;
     let (input, parameter) = alt((
-        value(BpParameter::T0, tag("t0")),
-        value(BpParameter::B, tag("b")),
-        value(BpParameter::R0, tag("r0")),
+        value(BpParameter::T0, tag_no_case("t0")),
+        value(BpParameter::B, tag_no_case("b")),
+        value(BpParameter::R0, tag_no_case("r0")),
     ))(input)?;
     let (input, _) = whitespace(input)?;
     let (input, value) = float(input)?;
@@ -387,18 +976,75 @@ fn b_parameter_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>>
 
 /// `b-p` | `b-p <b_parameter_parameter>`
 fn b_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("b-p")(input)?;
+    let (input, _) = tag_no_case("b-p")(input)?;
     alt((
         preceded(whitespace, b_parameter_parameter),
         value(Ok(Command::Show(ShowCommand::BParameter)), end),
     ))(input)
 }
 
+/// `s-h <0-1> <parameter> <value>`
+fn steinhart_hart_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel_or_all(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, parameter) = alt((
+        value(ShParameter::A, tag_no_case("a")),
+        value(ShParameter::B, tag_no_case("b")),
+        value(ShParameter::C, tag_no_case("c")),
+    ))(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, value) = float(input)?;
+    let result = value.map(|value| Command::SteinhartHart {
+        channel,
+        parameter,
+        value,
+    });
+    Ok((input, result))
+}
+
+/// `s-h` | `s-h <steinhart_hart_parameter>`
+fn steinhart_hart(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("s-h")(input)?;
+    alt((
+        preceded(whitespace, steinhart_hart_parameter),
+        value(Ok(Command::Show(ShowCommand::SteinhartHart)), end),
+    ))(input)
+}
+
+/// `sensor` | `sensor <0-1> <b-p/s-h/rtd pt100/rtd pt1000>`
+fn sensor(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("sensor")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel_or_all(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, sensor) = alt((
+                value(Sensor::BParameter, tag_no_case("b-p")),
+                value(Sensor::SteinhartHart, tag_no_case("s-h")),
+                preceded(
+                    tag_no_case("rtd"),
+                    preceded(
+                        whitespace,
+                        alt((
+                            value(Sensor::Pt1000, tag_no_case("pt1000")),
+                            value(Sensor::Pt100, tag_no_case("pt100")),
+                        )),
+                    ),
+                ),
+            ))(input)?;
+            end(input)?;
+            Ok((input, Ok(Command::Sensor { channel, sensor })))
+        },
+        value(Ok(Command::Show(ShowCommand::Sensor)), end),
+    ))(input)
+}
+
 fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("postfilter")(input)?;
+    let (input, _) = tag_no_case("postfilter")(input)?;
     alt((
         preceded(whitespace, |input| {
-            let (input, channel) = channel(input)?;
+            let (input, channel) = channel_or_all(input)?;
             let (input, _) = whitespace(input)?;
             alt((
                 value(
@@ -406,10 +1052,10 @@ fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                         channel,
                         rate: None,
                     }),
-                    tag("off"),
+                    tag_no_case("off"),
                 ),
                 move |input| {
-                    let (input, _) = tag("rate")(input)?;
+                    let (input, _) = tag_no_case("rate")(input)?;
                     let (input, _) = whitespace(input)?;
                     let (input, rate) = float(input)?;
                     let result = rate.map(|rate| Command::PostFilter {
@@ -418,385 +1064,2299 @@ fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
                     });
                     Ok((input, result))
                 },
+                move |input| {
+                    let (input, _) = tag_no_case("sw")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, samples) = unsigned(input)?;
+                    let result = samples.map(|samples| Command::SwFilter { channel, samples });
+                    Ok((input, result))
+                },
+                move |input| {
+                    let (input, _) = tag_no_case("median")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, points) = alt((
+                        value(0, tag_no_case("off")),
+                        value(3, char('3')),
+                        value(5, char('5')),
+                    ))(input)?;
+                    Ok((input, Ok(Command::MedianFilter { channel, points })))
+                },
+                move |input| {
+                    let (input, _) = tag_no_case("order")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, order) = alt((
+                        value(DigitalFilterOrder::Sinc5Sinc1, tag_no_case("sinc5sinc1")),
+                        value(DigitalFilterOrder::Sinc3, tag_no_case("sinc3")),
+                    ))(input)?;
+                    Ok((input, Ok(Command::FilterOrder { channel, order })))
+                },
             ))(input)
         }),
         value(Ok(Command::Show(ShowCommand::PostFilter)), end),
     ))(input)
 }
 
-fn load(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("load")(input)?;
-    let (input, channel) = alt((
-        |input| {
+/// `datarate` | `datarate <0-1> <rate>`
+fn data_rate(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("datarate")(input)?;
+    alt((
+        preceded(whitespace, |input| {
+            let (input, channel) = channel_or_all(input)?;
             let (input, _) = whitespace(input)?;
-            let (input, channel) = channel(input)?;
-            let (input, _) = end(input)?;
-            Ok((input, Some(channel)))
-        },
-        value(None, end),
-    ))(input)?;
+            let (input, rate) = float(input)?;
+            let result = rate.map(|rate| Command::DataRate {
+                channel,
+                rate: rate as f32,
+            });
+            Ok((input, result))
+        }),
+        value(Ok(Command::Show(ShowCommand::DataRate)), end),
+    ))(input)
+}
 
-    let result = Ok(Command::Load { channel });
+/// `cal <0-1> <offset/gain/point1/point2> <value>`
+fn cal_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel_or_all(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, parameter) = alt((
+        value(CalParameter::Offset, tag_no_case("offset")),
+        value(CalParameter::Gain, tag_no_case("gain")),
+        value(CalParameter::Point1, tag_no_case("point1")),
+        value(CalParameter::Point2, tag_no_case("point2")),
+    ))(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, value) = float(input)?;
+    let result = value.map(|value| Command::Cal {
+        channel,
+        parameter,
+        value,
+    });
     Ok((input, result))
 }
 
-fn save(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("save")(input)?;
-    let (input, channel) = alt((
-        |input| {
-            let (input, _) = whitespace(input)?;
-            let (input, channel) = channel(input)?;
-            let (input, _) = end(input)?;
-            Ok((input, Some(channel)))
-        },
-        value(None, end),
-    ))(input)?;
-
-    let result = Ok(Command::Save { channel });
-    Ok((input, result))
+/// `cal` | `cal <cal_parameter>`
+fn cal(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("cal")(input)?;
+    alt((
+        preceded(whitespace, cal_parameter),
+        value(Ok(Command::Show(ShowCommand::Cal)), end),
+    ))(input)
 }
 
-fn ipv4_addr(input: &[u8]) -> IResult<&[u8], Result<[u8; 4], Error>> {
-    let (input, a) = unsigned(input)?;
-    let (input, _) = tag(".")(input)?;
-    let (input, b) = unsigned(input)?;
-    let (input, _) = tag(".")(input)?;
-    let (input, c) = unsigned(input)?;
-    let (input, _) = tag(".")(input)?;
-    let (input, d) = unsigned(input)?;
-    let address = move || Ok([a? as u8, b? as u8, c? as u8, d? as u8]);
-    Ok((input, address()))
+/// `stats` | `stats reset`
+fn stats(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("stats")(input)?;
+    alt((
+        value(
+            Ok(Command::StatsReset),
+            preceded(whitespace, preceded(tag_no_case("reset"), end)),
+        ),
+        value(Ok(Command::Show(ShowCommand::Stats)), end),
+    ))(input)
 }
 
-fn ipv4(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("ipv4")(input)?;
+/// `time` | `time set <epoch>`
+fn time(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("time")(input)?;
     alt((
         |input| {
             let (input, _) = whitespace(input)?;
-            let (input, address) = ipv4_addr(input)?;
-            let (input, _) = tag("/")(input)?;
-            let (input, mask_len) = unsigned(input)?;
-            let (input, gateway) = alt((
+            let (input, _) = tag_no_case("set")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, epoch) = unsigned(input)?;
+            end(input)?;
+            Ok((input, epoch.map(|epoch| Command::TimeSet { epoch })))
+        },
+        value(Ok(Command::Show(ShowCommand::Time)), end),
+    ))(input)
+}
+
+/// `selftest` | `selftest boot <on/off>`
+fn selftest(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("selftest")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            preceded(
+                tag_no_case("boot"),
+                preceded(
+                    whitespace,
+                    alt((
+                        value(Ok(Command::SelfTestBoot(true)), tag_no_case("on")),
+                        value(Ok(Command::SelfTestBoot(false)), tag_no_case("off")),
+                    )),
+                ),
+            ),
+        ),
+        value(Ok(Command::SelfTest), end),
+    ))(input)
+}
+
+/// `autosave` | `autosave <on/off>`
+fn autosave(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("autosave")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::AutoSave(true)),
+                    preceded(tag_no_case("on"), end),
+                ),
+                value(
+                    Ok(Command::AutoSave(false)),
+                    preceded(tag_no_case("off"), end),
+                ),
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::AutoSave)), end),
+    ))(input)
+}
+
+/// `deadman` | `deadman <seconds>` | `deadman off`
+fn deadman(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("deadman")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::Deadman(None)),
+                    preceded(tag_no_case("off"), end),
+                ),
                 |input| {
-                    let (input, _) = whitespace(input)?;
-                    let (input, gateway) = ipv4_addr(input)?;
-                    Ok((input, gateway.map(Some)))
+                    let (input, seconds) = unsigned(input)?;
+                    end(input)?;
+                    Ok((
+                        input,
+                        seconds.map(|seconds| Command::Deadman(Some(seconds))),
+                    ))
                 },
-                value(Ok(None), end),
-            ))(input)?;
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::Deadman)), end),
+    ))(input)
+}
 
-            let result = move || {
-                Ok(Command::Ipv4(Ipv4Config {
-                    address: address?,
-                    mask_len: mask_len? as u8,
-                    gateway: gateway?,
-                }))
-            };
-            Ok((input, result()))
+/// `loglevel` | `loglevel <off/error/warn/info/debug/trace>`
+fn log_level(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("loglevel")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, level) = alt((
+                value(LogLevel::Off, tag_no_case("off")),
+                value(LogLevel::Error, tag_no_case("error")),
+                value(LogLevel::Warn, tag_no_case("warn")),
+                value(LogLevel::Info, tag_no_case("info")),
+                value(LogLevel::Debug, tag_no_case("debug")),
+                value(LogLevel::Trace, tag_no_case("trace")),
+            ))(input)?;
+            end(input)?;
+            Ok((input, Ok(Command::LogLevel(level))))
         },
-        value(Ok(Command::Show(ShowCommand::Ipv4)), end),
+        value(Ok(Command::Show(ShowCommand::LogLevel)), end),
     ))(input)
 }
 
-fn fan(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("fan")(input)?;
+/// `safe_start` | `safe_start <on/off>`
+fn safe_start(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("safe_start")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::SafeStart(true)),
+                    preceded(tag_no_case("on"), end),
+                ),
+                value(
+                    Ok(Command::SafeStart(false)),
+                    preceded(tag_no_case("off"), end),
+                ),
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::SafeStart)), end),
+    ))(input)
+}
+
+/// `identify` | `identify <seconds>`
+fn identify(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("identify")(input)?;
     alt((
         |input| {
             let (input, _) = whitespace(input)?;
+            let (input, seconds) = unsigned(input)?;
+            end(input)?;
+            let result = seconds.map(|seconds| Command::Identify {
+                seconds: Some(seconds),
+            });
+            Ok((input, result))
+        },
+        value(Ok(Command::Identify { seconds: None }), end),
+    ))(input)
+}
 
-            let (input, result) = alt((
-                |input| {
-                    let (input, _) = tag("auto")(input)?;
-                    Ok((input, Ok(Command::FanAuto)))
+/// `dac <0/1> <code>`
+fn dac(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("dac")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, code) = unsigned(input)?;
+    end(input)?;
+    Ok((input, code.map(|code| Command::DacCode { channel, code })))
+}
+
+/// `dac_chip` | `dac_chip <ad5680/dac8560>`
+fn dac_chip(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("dac_chip")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            terminated(
+                alt((
+                    value(Ok(Command::DacChip(DacChip::Ad5680)), tag_no_case("ad5680")),
+                    value(
+                        Ok(Command::DacChip(DacChip::Dac8560)),
+                        tag_no_case("dac8560"),
+                    ),
+                )),
+                end,
+            ),
+        ),
+        value(Ok(Command::Show(ShowCommand::DacChip)), end),
+    ))(input)
+}
+
+/// `calibrate <0/1>`
+fn calibrate(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("calibrate")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    end(input)?;
+    Ok((input, Ok(Command::CalibrateDac { channel })))
+}
+
+/// `alarms` | `alarms clear`
+fn alarms(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("alarms")(input)?;
+    alt((
+        value(
+            Ok(Command::AlarmsClear),
+            preceded(whitespace, preceded(tag_no_case("clear"), end)),
+        ),
+        value(Ok(Command::Show(ShowCommand::Alarms)), end),
+    ))(input)
+}
+
+/// `alarm <0-1> window <min> <max>` | `alarm <0-1> window off` |
+/// `alarm dac_feedback <volts>` | `alarm dac_feedback off` |
+/// `alarm <sensor_fault/clipping/fan> <on/off>`
+fn alarm(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("alarm")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        |input| {
+            let (input, channel) = channel(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, _) = tag_no_case("window")(input)?;
+            let (input, _) = whitespace(input)?;
+            alt((
+                value(
+                    Ok(Command::AlarmWindow {
+                        channel,
+                        window: None,
+                    }),
+                    preceded(tag_no_case("off"), end),
+                ),
+                move |input| {
+                    let (input, min) = float(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, max) = float(input)?;
+                    end(input)?;
+                    let result = min.and_then(|min| {
+                        max.map(|max| Command::AlarmWindow {
+                            channel,
+                            window: Some([min, max]),
+                        })
+                    });
+                    Ok((input, result))
                 },
+            ))(input)
+        },
+        |input| {
+            let (input, _) = tag_no_case("dac_feedback")(input)?;
+            let (input, _) = whitespace(input)?;
+            alt((
+                value(
+                    Ok(Command::AlarmDacFeedback { max: None }),
+                    preceded(tag_no_case("off"), end),
+                ),
                 |input| {
-                    let (input, value) = unsigned(input)?;
+                    let (input, max) = float(input)?;
+                    end(input)?;
                     Ok((
                         input,
-                        Ok(Command::FanSet {
-                            fan_pwm: value.unwrap_or(0),
-                        }),
+                        max.map(|max| Command::AlarmDacFeedback { max: Some(max) }),
                     ))
                 },
+            ))(input)
+        },
+        |input| {
+            let (input, kind) = alt((
+                value(AlarmKind::SensorFault, tag_no_case("sensor_fault")),
+                value(AlarmKind::Clipping, tag_no_case("clipping")),
+                value(AlarmKind::Fan, tag_no_case("fan")),
             ))(input)?;
-            Ok((input, result))
+            let (input, _) = whitespace(input)?;
+            let (input, enabled) = alt((
+                value(true, tag_no_case("on")),
+                value(false, tag_no_case("off")),
+            ))(input)?;
+            end(input)?;
+            Ok((input, Ok(Command::AlarmEnable { kind, enabled })))
         },
-        value(Ok(Command::ShowFan), end),
     ))(input)
 }
 
-fn fan_curve(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, _) = tag("fcurve")(input)?;
+/// Bytes as pairs of hex digits
+fn hex_data(input: &[u8]) -> IResult<&[u8], Result<EepromData, Error>> {
+    let (input, digits) = take_while1(is_hex_digit)(input)?;
+    let result = if digits.len() % 2 != 0 {
+        Err(Error::Parser(ErrorKind::HexDigit))
+    } else if digits.len() > 2 * EepromData::new().capacity() {
+        Err(Error::Parser(ErrorKind::TooLarge))
+    } else {
+        digits
+            .chunks(2)
+            .map(|pair| {
+                from_utf8(pair)
+                    .map_err(|e| e.into())
+                    .and_then(|pair| u8::from_str_radix(pair, 16).map_err(|e| e.into()))
+            })
+            .collect()
+    };
+    Ok((input, result))
+}
+
+/// `eeprom read <offset> <length>` | `eeprom write <offset> <hex data>`
+fn eeprom(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("eeprom")(input)?;
+    let (input, _) = whitespace(input)?;
     alt((
         |input| {
+            let (input, _) = tag_no_case("read")(input)?;
             let (input, _) = whitespace(input)?;
-            let (input, result) = alt((
-                |input| {
-                    let (input, _) = tag("default")(input)?;
-                    Ok((input, Ok(Command::FanCurveDefaults)))
-                },
-                |input| {
-                    let (input, k_a) = float(input)?;
-                    let (input, _) = whitespace(input)?;
-                    let (input, k_b) = float(input)?;
-                    let (input, _) = whitespace(input)?;
-                    let (input, k_c) = float(input)?;
-                    if let (Ok(k_a), Ok(k_b), Ok(k_c)) = (k_a, k_b, k_c) {
-                        Ok((
-                            input,
-                            Ok(Command::FanCurve {
-                                k_a: k_a as f32,
-                                k_b: k_b as f32,
-                                k_c: k_c as f32,
-                            }),
-                        ))
-                    } else {
-                        Err(nom::Err::Incomplete(Needed::Size(3)))
-                    }
-                },
-            ))(input)?;
+            let (input, offset) = unsigned(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, len) = unsigned(input)?;
+            end(input)?;
+            let result = offset.and_then(|offset| {
+                len.map(|len| Command::Eeprom(EepromAccess::Read { offset, len }))
+            });
+            Ok((input, result))
+        },
+        |input| {
+            let (input, _) = tag_no_case("write")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, offset) = unsigned(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, data) = hex_data(input)?;
+            end(input)?;
+            let result = offset.and_then(|offset| {
+                data.map(|data| Command::Eeprom(EepromAccess::Write { offset, data }))
+            });
             Ok((input, result))
         },
-        value(Err(Error::Incomplete), end),
     ))(input)
 }
 
-fn command(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+/// `name` | `name <name>`
+fn device_name(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("name")(input)?;
     alt((
-        value(Ok(Command::Quit), tag("quit")),
-        load,
-        save,
-        value(Ok(Command::Reset), tag("reset")),
-        ipv4,
-        map(report, Ok),
-        output,
-        center_point,
-        pid,
-        b_parameter,
-        postfilter,
-        value(Ok(Command::Dfu), tag("dfu")),
-        fan,
-        fan_curve,
-        value(Ok(Command::ShowHWRev), tag("hwrev")),
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, name) = identifier(input)?;
+            end(input)?;
+            Ok((input, name.map(|name| Command::Name { name })))
+        },
+        value(Ok(Command::Show(ShowCommand::Name)), end),
     ))(input)
 }
 
-impl Command {
-    pub fn parse(input: &[u8]) -> Result<Self, Error> {
-        match command(input) {
-            Ok((input_remain, result)) if input_remain.is_empty() => result,
-            Ok((input_remain, _)) => Err(Error::UnexpectedInput(input_remain[0])),
-            Err(e) => Err(e.into()),
-        }
-    }
+/// `alias` | `alias <0-1> <name>` | `alias <0-1> off`
+fn alias(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("alias")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, alias) = identifier::<U16>(input)?;
+            end(input)?;
+            let result = alias.map(|alias| Command::Alias {
+                channel,
+                alias: Some(alias).filter(|alias| !alias.eq_ignore_ascii_case("off")),
+            });
+            Ok((input, result))
+        },
+        value(Ok(Command::Show(ShowCommand::Alias)), end),
+    ))(input)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// `config dump` | `config load` | `config check [load]` | `config diff`
+fn config(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("config")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(
+            Ok(Command::Show(ShowCommand::Config)),
+            preceded(tag_no_case("dump"), end),
+        ),
+        value(Ok(Command::ConfigLoad), preceded(tag_no_case("load"), end)),
+        preceded(
+            tag_no_case("check"),
+            alt((
+                value(
+                    Ok(Command::ConfigCheckLoad),
+                    preceded(preceded(whitespace, tag_no_case("load")), end),
+                ),
+                value(Ok(Command::ConfigCheck), end),
+            )),
+        ),
+        value(Ok(Command::ConfigDiff), preceded(tag_no_case("diff"), end)),
+    ))(input)
+}
 
-    #[test]
-    fn parse_quit() {
-        let command = Command::parse(b"quit");
-        assert_eq!(command, Ok(Command::Quit));
-    }
+/// `kick <0-7>`
+fn kick(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("kick")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, index) = map(one_of("01234567"), |c| (c as usize) - ('0' as usize))(input)?;
+    end(input)?;
+    Ok((input, Ok(Command::Kick { index })))
+}
 
-    #[test]
-    fn parse_load() {
-        let command = Command::parse(b"load");
-        assert_eq!(command, Ok(Command::Load { channel: None }));
-    }
+/// `log show` | `log clear` | `log dump`
+fn event_log(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("log")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(
+            Ok(Command::Show(ShowCommand::EventLog)),
+            preceded(tag_no_case("show"), end),
+        ),
+        value(
+            Ok(Command::EventLogClear),
+            preceded(tag_no_case("clear"), end),
+        ),
+        value(Ok(Command::LogDump), preceded(tag_no_case("dump"), end)),
+    ))(input)
+}
 
-    #[test]
-    fn parse_load_channel() {
-        let command = Command::parse(b"load 0");
-        assert_eq!(command, Ok(Command::Load { channel: Some(0) }));
+/// `energy reset`
+fn energy(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("energy")(input)?;
+    let (input, _) = whitespace(input)?;
+    value(
+        Ok(Command::EnergyReset),
+        preceded(tag_no_case("reset"), end),
+    )(input)
+}
+
+/// `history <0-1>` | `history <0-1> <samples>`
+fn history(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("history")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    alt((
+        move |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, samples) = unsigned(input)?;
+            end(input)?;
+            let result = samples.map(|samples| Command::History {
+                channel,
+                samples: Some(samples),
+            });
+            Ok((input, result))
+        },
+        value(
+            Ok(Command::History {
+                channel,
+                samples: None,
+            }),
+            end,
+        ),
+    ))(input)
+}
+
+/// `dtdt` | `dtdt <0-1> <window>`
+fn dtdt(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("dtdt")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel_or_all(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, window) = float(input)?;
+            end(input)?;
+            let result = window.map(|window| Command::DtDtWindow { channel, window });
+            Ok((input, result))
+        },
+        value(Ok(Command::Show(ShowCommand::DtDt)), end),
+    ))(input)
+}
+
+/// `capture <0-1> <samples>`
+fn capture(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("capture")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, samples) = unsigned(input)?;
+    end(input)?;
+    let result = samples.map(|samples| Command::Capture { channel, samples });
+    Ok((input, result))
+}
+
+/// `tune step <0-1> <delta_i> <duration>`
+fn tune(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("tune")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag_no_case("step")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, delta_i) = quantity(Unit::Ampere, input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, duration) = float(input)?;
+    end(input)?;
+    let result = delta_i.and_then(|delta_i| {
+        duration.map(|duration| Command::TuneStep {
+            channel,
+            delta_i,
+            duration,
+        })
+    });
+    Ok((input, result))
+}
+
+/// `adc <0-1> calibrate <offset/gain>`
+fn adc(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("adc")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag_no_case("calibrate")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, calibration) = alt((
+        value(AdcCalibration::Offset, tag_no_case("offset")),
+        value(AdcCalibration::Gain, tag_no_case("gain")),
+    ))(input)?;
+    end(input)?;
+    Ok((
+        input,
+        Ok(Command::AdcCalibrate {
+            channel,
+            calibration,
+        }),
+    ))
+}
+
+fn load(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("load")(input)?;
+    let (input, channel) = alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Some(channel)))
+        },
+        value(None, end),
+    ))(input)?;
+
+    let result = Ok(Command::Load { channel });
+    Ok((input, result))
+}
+
+fn save(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("save")(input)?;
+    let (input, channel) = alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel(input)?;
+            let (input, _) = end(input)?;
+            Ok((input, Some(channel)))
+        },
+        value(None, end),
+    ))(input)?;
+
+    let result = Ok(Command::Save { channel });
+    Ok((input, result))
+}
+
+/// A letter, followed by letters, digits, `_` or `-`, as many as fit
+/// into `String<N>`
+fn identifier<N: ArrayLength<u8>>(input: &[u8]) -> IResult<&[u8], Result<String<N>, Error>> {
+    if !matches!(input.first(), Some(c) if c.is_ascii_alphabetic()) {
+        return Err(nom::Err::Error((input, ErrorKind::Alpha)));
+    }
+    let (input, name) =
+        take_while1(|c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')(input)?;
+    let result = from_utf8(name).map_err(|e| e.into()).and_then(|name| {
+        let mut string = String::new();
+        string
+            .push_str(name)
+            .map(|()| string)
+            .map_err(|()| Error::Parser(ErrorKind::TooLarge))
+    });
+    Ok((input, result))
+}
+
+/// `load <name>`
+fn load_profile(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("load")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, name) = identifier(input)?;
+    end(input)?;
+    Ok((input, name.map(|name| Command::LoadProfile { name })))
+}
+
+/// `save as <name>`
+fn save_profile(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("save")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, _) = tag_no_case("as")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, name) = identifier(input)?;
+    end(input)?;
+    Ok((input, name.map(|name| Command::SaveProfile { name })))
+}
+
+/// `profile list` | `profile remove <name>`
+fn profile(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("profile")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(
+            Ok(Command::Show(ShowCommand::Profiles)),
+            preceded(tag_no_case("list"), end),
+        ),
+        |input| {
+            let (input, _) = tag_no_case("remove")(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, name) = identifier(input)?;
+            end(input)?;
+            Ok((input, name.map(|name| Command::RemoveProfile { name })))
+        },
+    ))(input)
+}
+
+fn ipv4_addr(input: &[u8]) -> IResult<&[u8], Result<[u8; 4], Error>> {
+    let (input, a) = unsigned(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, b) = unsigned(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, c) = unsigned(input)?;
+    let (input, _) = char('.')(input)?;
+    let (input, d) = unsigned(input)?;
+    let address = move || Ok([a? as u8, b? as u8, c? as u8, d? as u8]);
+    Ok((input, address()))
+}
+
+fn ipv4(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("ipv4")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, address) = ipv4_addr(input)?;
+            let (input, _) = char('/')(input)?;
+            let (input, mask_len) = unsigned(input)?;
+            let (input, gateway) = alt((
+                |input| {
+                    let (input, _) = whitespace(input)?;
+                    let (input, gateway) = ipv4_addr(input)?;
+                    Ok((input, gateway.map(Some)))
+                },
+                value(Ok(None), end),
+            ))(input)?;
+
+            let result = move || {
+                Ok(Command::Ipv4(Ipv4Config {
+                    address: address?,
+                    mask_len: mask_len? as u8,
+                    gateway: gateway?,
+                }))
+            };
+            Ok((input, result()))
+        },
+        value(Ok(Command::Show(ShowCommand::Ipv4)), end),
+    ))(input)
+}
+
+fn fan(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("fan")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+
+            let (input, result) = alt((
+                |input| {
+                    let (input, _) = tag_no_case("auto")(input)?;
+                    Ok((input, Ok(Command::FanAuto)))
+                },
+                |input| {
+                    let (input, value) = unsigned(input)?;
+                    Ok((
+                        input,
+                        Ok(Command::FanSet {
+                            fan_pwm: value.unwrap_or(0),
+                        }),
+                    ))
+                },
+            ))(input)?;
+            Ok((input, result))
+        },
+        value(Ok(Command::ShowFan), end),
+    ))(input)
+}
+
+fn fan_curve(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("fcurve")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, result) = alt((
+                |input| {
+                    let (input, _) = tag_no_case("default")(input)?;
+                    Ok((input, Ok(Command::FanCurveDefaults)))
+                },
+                |input| {
+                    let (input, _) = tag_no_case("hysteresis")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, hysteresis) = float(input)?;
+                    end(input)?;
+                    let result = hysteresis.map(|hysteresis| Command::FanHysteresis {
+                        hysteresis: hysteresis as f32,
+                    });
+                    Ok((input, result))
+                },
+                |input| {
+                    let (input, _) = tag_no_case("mcu")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    alt((
+                        value(
+                            Ok(Command::FanMcuCurve { range: None }),
+                            preceded(tag_no_case("off"), end),
+                        ),
+                        |input| {
+                            let (input, t_low) = float(input)?;
+                            let (input, _) = whitespace(input)?;
+                            let (input, t_high) = float(input)?;
+                            end(input)?;
+                            let result = t_low.and_then(|t_low| {
+                                t_high.map(|t_high| Command::FanMcuCurve {
+                                    range: Some([t_low as f32, t_high as f32]),
+                                })
+                            });
+                            Ok((input, result))
+                        },
+                    ))(input)
+                },
+                |input| {
+                    let (input, _) = tag_no_case("min")(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, min_pwm) = unsigned(input)?;
+                    end(input)?;
+                    Ok((input, min_pwm.map(|min_pwm| Command::FanMinPwm { min_pwm })))
+                },
+                |input| {
+                    let (input, k_a) = float(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, k_b) = float(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let (input, k_c) = float(input)?;
+                    if let (Ok(k_a), Ok(k_b), Ok(k_c)) = (k_a, k_b, k_c) {
+                        Ok((
+                            input,
+                            Ok(Command::FanCurve {
+                                k_a: k_a as f32,
+                                k_b: k_b as f32,
+                                k_c: k_c as f32,
+                            }),
+                        ))
+                    } else {
+                        Err(nom::Err::Incomplete(Needed::Size(3)))
+                    }
+                },
+            ))(input)?;
+            Ok((input, result))
+        },
+        value(Err(Error::Incomplete), end),
+    ))(input)
+}
+
+/// `framing` | `framing <newline/length>`
+fn framing(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("framing")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::Framing(Framing::Newline)),
+                    tag_no_case("newline"),
+                ),
+                value(
+                    Ok(Command::Framing(Framing::LengthPrefix)),
+                    tag_no_case("length"),
+                ),
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::Framing)), end),
+    ))(input)
+}
+
+/// `format` | `format <json/milli/csv>`
+fn report_format(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("format")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::ReportFormat(ReportFormat::Json)),
+                    tag_no_case("json"),
+                ),
+                value(
+                    Ok(Command::ReportFormat(ReportFormat::Milli)),
+                    tag_no_case("milli"),
+                ),
+                value(
+                    Ok(Command::ReportFormat(ReportFormat::Csv)),
+                    tag_no_case("csv"),
+                ),
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::ReportFormat)), end),
+    ))(input)
+}
+
+/// `precision` | `precision <1-17>` | `precision off`
+fn precision(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("precision")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, digits) = alt((
+                value(Ok(None), tag_no_case("off")),
+                map(unsigned, |digits| {
+                    digits.and_then(|digits| match digits {
+                        1..=17 => Ok(Some(digits as u8)),
+                        _ => Err(Error::Parser(ErrorKind::TooLarge)),
+                    })
+                }),
+            ))(input)?;
+            end(input)?;
+            Ok((input, digits.map(Command::Precision)))
+        },
+        value(Ok(Command::Show(ShowCommand::Precision)), end),
+    ))(input)
+}
+
+/// `interactive <on/off>`
+fn interactive(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("interactive")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, enabled) = alt((
+        value(true, tag_no_case("on")),
+        value(false, tag_no_case("off")),
+    ))(input)?;
+    end(input)?;
+    Ok((input, Ok(Command::Interactive(enabled))))
+}
+
+/// `sensor_only <0-1> <on/off>`
+fn sensor_only(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("sensor_only")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel_or_all(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, enabled) = alt((
+        value(true, tag_no_case("on")),
+        value(false, tag_no_case("off")),
+    ))(input)?;
+    end(input)?;
+    Ok((input, Ok(Command::SensorOnly { channel, enabled })))
+}
+
+/// `cascade` | `cascade <0-1> <on/off>`
+fn cascade(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("cascade")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, enabled) = alt((
+                value(true, tag_no_case("on")),
+                value(false, tag_no_case("off")),
+            ))(input)?;
+            end(input)?;
+            Ok((input, Ok(Command::Cascade { channel, enabled })))
+        },
+        value(Ok(Command::Show(ShowCommand::Cascade)), end),
+    ))(input)
+}
+
+/// `stable <0-1> <window/time> <value>`
+fn stable_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channel) = channel_or_all(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, parameter) = alt((
+        value(StableParameter::Window, tag_no_case("window")),
+        value(StableParameter::Time, tag_no_case("time")),
+    ))(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, value) = float(input)?;
+    let result = value.map(|value| Command::Stable {
+        channel,
+        parameter,
+        value,
+    });
+    Ok((input, result))
+}
+
+/// `stable output` | `stable output <0-1/all/off>`
+fn stable_output(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("output")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channels) = alt((
+                value([false; CHANNELS], tag_no_case("off")),
+                value([true; CHANNELS], tag_no_case("all")),
+                map(channel, |channel| {
+                    let mut channels = [false; CHANNELS];
+                    channels[channel] = true;
+                    channels
+                }),
+            ))(input)?;
+            end(input)?;
+            Ok((input, Ok(Command::StableOutput { channels })))
+        },
+        value(Ok(Command::Show(ShowCommand::StableOutput)), end),
+    ))(input)
+}
+
+/// `stable` | `stable <stable_parameter>` | `stable <stable_output>`
+fn stable(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("stable")(input)?;
+    alt((
+        preceded(whitespace, stable_output),
+        preceded(whitespace, stable_parameter),
+        value(Ok(Command::Show(ShowCommand::Stable)), end),
+    ))(input)
+}
+
+/// `interlock` | `interlock <0-1> off` | `interlock <0-1> <t_min/t_max> <deg_celsius>` |
+/// `interlock <0-1> overcurrent <seconds>`
+fn interlock(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("interlock")(input)?;
+    alt((
+        |input| {
+            let (input, _) = whitespace(input)?;
+            let (input, channel) = channel_or_all(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, result) = alt((
+                value(
+                    Ok(Command::Interlock {
+                        channel,
+                        limit: None,
+                    }),
+                    tag_no_case("off"),
+                ),
+                |input| {
+                    let (input, limit) = alt((
+                        value(InterlockLimit::TMin, tag_no_case("t_min")),
+                        value(InterlockLimit::TMax, tag_no_case("t_max")),
+                        value(InterlockLimit::OverCurrent, tag_no_case("overcurrent")),
+                    ))(input)?;
+                    let (input, _) = whitespace(input)?;
+                    let unit = match limit {
+                        InterlockLimit::OverCurrent => Unit::Second,
+                        _ => Unit::DegreeCelsius,
+                    };
+                    let (input, value) = quantity(unit, input)?;
+                    let result = value.map(|value| Command::Interlock {
+                        channel,
+                        limit: Some((limit, value)),
+                    });
+                    Ok((input, result))
+                },
+            ))(input)?;
+            end(input)?;
+            Ok((input, result))
+        },
+        value(Ok(Command::Show(ShowCommand::Interlock)), end),
+    ))(input)
+}
+
+/// `faults` | `clear_faults`
+fn faults(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    alt((
+        value(
+            Ok(Command::Show(ShowCommand::Faults)),
+            preceded(tag_no_case("faults"), end),
+        ),
+        value(
+            Ok(Command::ClearFaults),
+            preceded(tag_no_case("clear_faults"), end),
+        ),
+    ))(input)
+}
+
+/// `clear_fault <0-1>`
+fn clear_fault(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("clear_fault")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    end(input)?;
+    Ok((input, Ok(Command::ClearFault { channel })))
+}
+
+/// `tec <0-1> check`
+fn tec_cal(input: &[u8], channel: usize) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("cal")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, parameter) = alt((
+        value(TecCalParameter::IGain, tag_no_case("i_gain")),
+        value(TecCalParameter::IOffset, tag_no_case("i_offset")),
+        value(TecCalParameter::VGain, tag_no_case("v_gain")),
+        value(TecCalParameter::VOffset, tag_no_case("v_offset")),
+    ))(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, value) = float(input)?;
+    end(input)?;
+    let result = value.map(|value| Command::TecCal {
+        channel,
+        parameter,
+        value,
+    });
+    Ok((input, result))
+}
+
+/// `tec cal` | `tec <0/1> check` | `tec <0/1> cal <parameter> <value>`
+fn tec(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("tec")(input)?;
+    let (input, _) = whitespace(input)?;
+    alt((
+        value(
+            Ok(Command::Show(ShowCommand::TecCal)),
+            preceded(tag_no_case("cal"), end),
+        ),
+        |input| {
+            let (input, channel) = channel(input)?;
+            let (input, _) = whitespace(input)?;
+            alt((
+                move |input| {
+                    let (input, _) = tag_no_case("check")(input)?;
+                    end(input)?;
+                    Ok((input, Ok(Command::TecCheck { channel })))
+                },
+                move |input| tec_cal(input, channel),
+            ))(input)
+        },
+    ))(input)
+}
+
+/// `power_on` | `power_on <restore/off>`
+fn power_on(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag_no_case("power_on")(input)?;
+    alt((
+        preceded(
+            whitespace,
+            alt((
+                value(
+                    Ok(Command::PowerOn(PowerOn::Restore)),
+                    tag_no_case("restore"),
+                ),
+                value(Ok(Command::PowerOn(PowerOn::Off)), tag_no_case("off")),
+            )),
+        ),
+        value(Ok(Command::Show(ShowCommand::PowerOn)), end),
+    ))(input)
+}
+
+/// `at <seconds> <command>` | `every <seconds> <command>` |
+/// `schedule` | `schedule clear [index]`
+fn schedule(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    alt((
+        |input| {
+            let (input, every) = alt((
+                value(false, tag_no_case("at")),
+                value(true, tag_no_case("every")),
+            ))(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, seconds) = unsigned(input)?;
+            let (input, _) = whitespace(input)?;
+            let (input, line) = take_while1(|c| c != b'\r' && c != b'\n')(input)?;
+            let (input, _) = end(input)?;
+            let result = seconds.and_then(|seconds| {
+                let line = from_utf8(line)?.trim_end();
+                let mut string = ScheduledLine::new();
+                string
+                    .push_str(line)
+                    .map_err(|()| Error::Parser(ErrorKind::TooLarge))?;
+                Ok(Command::Schedule {
+                    seconds,
+                    every,
+                    line: string,
+                })
+            });
+            Ok((input, result))
+        },
+        |input| {
+            let (input, _) = tag_no_case("schedule")(input)?;
+            alt((
+                |input| {
+                    let (input, _) = whitespace(input)?;
+                    let (input, _) = tag_no_case("clear")(input)?;
+                    let (input, index) = opt(preceded(whitespace, unsigned))(input)?;
+                    let (input, _) = end(input)?;
+                    let result = match index {
+                        Some(Ok(index)) => Ok(Command::ScheduleClear {
+                            index: Some(index as usize),
+                        }),
+                        Some(Err(e)) => Err(e),
+                        None => Ok(Command::ScheduleClear { index: None }),
+                    };
+                    Ok((input, result))
+                },
+                value(Ok(Command::Show(ShowCommand::Schedule)), end),
+            ))(input)
+        },
+    ))(input)
+}
+
+fn command(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    // alt() is limited to 21 parsers
+    alt((
+        alt((
+            value(Ok(Command::Quit), tag_no_case("quit")),
+            load_profile,
+            save_profile,
+            load,
+            save,
+            value(Ok(Command::Reset), tag_no_case("reset")),
+            ipv4,
+            map(report, Ok),
+            output,
+            center_point,
+            pid,
+            b_parameter,
+            postfilter,
+            value(Ok(Command::Dfu), tag_no_case("dfu")),
+            fan,
+            fan_curve,
+            value(Ok(Command::ShowHWRev), tag_no_case("hwrev")),
+            framing,
+            dac,
+            dac_chip,
+            schedule,
+        )),
+        alt((
+            cascade,
+            stable,
+            value(
+                Ok(Command::Show(ShowCommand::Locked)),
+                tag_no_case("locked"),
+            ),
+            interlock,
+            clear_fault,
+            tec,
+            power_on,
+            steinhart_hart,
+            // Before `sensor`, which it starts with
+            sensor_only,
+            sensor,
+            data_rate,
+            adc,
+            // Before `cal`, which it starts with
+            calibrate,
+            cal,
+            stats,
+            history,
+            capture,
+            tune,
+            dtdt,
+            energy,
+            time,
+        )),
+        alt((
+            selftest,
+            event_log,
+            value(Ok(Command::Clients), tag_no_case("clients")),
+            kick,
+            config,
+            profile,
+            autosave,
+            deadman,
+            value(Ok(Command::Ping), preceded(tag_no_case("ping"), end)),
+            log_level,
+            device_name,
+            eeprom,
+            alarms,
+            alarm,
+            faults,
+            identify,
+            interactive,
+            alias,
+            report_format,
+            precision,
+        )),
+        alt((
+            value(
+                Ok(Command::Show(ShowCommand::Version)),
+                tag_no_case("version"),
+            ),
+            safe_start,
+        )),
+    ))(input)
+}
+
+impl Command {
+    /// Keywords are matched regardless of case, and may be separated
+    /// by any number of spaces or tabs
+    pub fn parse(input: &[u8]) -> Result<Self, Error> {
+        let start = input
+            .iter()
+            .position(|&c| c != b' ' && c != b'\t')
+            .unwrap_or(input.len());
+        match command(&input[start..]) {
+            Ok((input_remain, result)) if input_remain.is_empty() => {
+                result.and_then(|mut command| {
+                    let all = matches!(command.channel_mut(), Some(&mut ALL_CHANNELS));
+                    if all && !command.changes_config() {
+                        Err(Error::AllChannels)
+                    } else {
+                        Ok(command)
+                    }
+                })
+            }
+            Ok((input_remain, _)) => Err(Error::UnexpectedInput(input_remain[0])),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Split off the `#<tag>` that a command may start with
+    pub fn split_tag(input: &[u8]) -> Result<(Option<RequestTag>, &[u8]), Error> {
+        let rest = match input.strip_prefix(b"#") {
+            Some(rest) => rest,
+            None => return Ok((None, input)),
+        };
+        let len = rest
+            .iter()
+            .position(|&c| !(c.is_ascii_alphanumeric() || c == b'_' || c == b'-'))
+            .unwrap_or(rest.len());
+        let (tag, rest) = rest.split_at(len);
+        if tag.is_empty() {
+            return Err(Error::Parser(ErrorKind::AlphaNumeric));
+        }
+        match rest.first() {
+            None | Some(b' ') | Some(b'\t') => {}
+            Some(&c) => return Err(Error::UnexpectedInput(c)),
+        }
+        let mut string = RequestTag::new();
+        string
+            .push_str(from_utf8(tag)?)
+            .map_err(|()| Error::Parser(ErrorKind::TooLarge))?;
+        Ok((Some(string), rest))
+    }
+
+    /// Parse a line that `parse()` has rejected again, with the
+    /// aliases of channels replaced by their numbers. `None` if the
+    /// line names no channel by its alias.
+    pub fn parse_aliased(
+        input: &[u8],
+        aliases: &[Option<ChannelAlias>; CHANNELS],
+    ) -> Option<Result<Self, Error>> {
+        let mut line: Vec<u8, U64> = Vec::new();
+        let mut aliased = false;
+        for word in input.split(|&c| c == b' ' || c == b'\t') {
+            if !line.is_empty() {
+                line.push(b' ').ok()?;
+            }
+            let channel = aliases.iter().position(
+                |alias| matches!(alias, Some(alias) if alias.as_bytes().eq_ignore_ascii_case(word)),
+            );
+            match channel {
+                Some(channel) => {
+                    line.push(b'0' + channel as u8).ok()?;
+                    aliased = true;
+                }
+                None => line.extend_from_slice(word).ok()?,
+            }
+        }
+        if aliased {
+            Some(Command::parse(&line))
+        } else {
+            None
+        }
+    }
+
+    /// Channel of the commands that accept `all` in place of it
+    pub fn channel_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Command::Output { channel, .. }
+            | Command::OutputPid { channel }
+            | Command::OutputOff { channel }
+            | Command::OutputPolarity { channel, .. }
+            | Command::OutputQuery { channel, .. }
+            | Command::OutputTrim { channel, .. }
+            | Command::OutputVoltage { channel, .. }
+            | Command::OutputHeaterOnly { channel, .. }
+            | Command::CenterPoint { channel, .. }
+            | Command::Pid { channel, .. }
+            | Command::PidQuery { channel, .. }
+            | Command::BParameter { channel, .. }
+            | Command::SteinhartHart { channel, .. }
+            | Command::Sensor { channel, .. }
+            | Command::PostFilter { channel, .. }
+            | Command::SwFilter { channel, .. }
+            | Command::MedianFilter { channel, .. }
+            | Command::DataRate { channel, .. }
+            | Command::FilterOrder { channel, .. }
+            | Command::Cal { channel, .. }
+            | Command::DtDtWindow { channel, .. }
+            | Command::Stable { channel, .. }
+            | Command::Interlock { channel, .. }
+            | Command::SensorOnly { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Whether the command can be queued with `at` or `every`, which
+    /// leaves out those of a session, those that only send a response,
+    /// and the scheduling commands themselves
+    pub fn schedulable(&self) -> bool {
+        !matches!(
+            self,
+            Command::Quit
+                | Command::Show(_)
+                | Command::OutputQuery { .. }
+                | Command::PidQuery { .. }
+                | Command::ShowFan
+                | Command::ShowHWRev
+                | Command::Ping
+                | Command::SelfTest
+                | Command::Eeprom(_)
+                | Command::Clients
+                | Command::Kick { .. }
+                | Command::ConfigLoad
+                | Command::ConfigCheck
+                | Command::ConfigCheckLoad
+                | Command::ConfigDiff
+                | Command::ConfigDocument
+                | Command::LogDump
+                | Command::History { .. }
+                | Command::Capture { .. }
+                | Command::Framing(_)
+                | Command::ReportFormat(_)
+                | Command::Precision(_)
+                | Command::Interactive(_)
+                | Command::Schedule { .. }
+                | Command::ScheduleClear { .. }
+        )
+    }
+
+    /// Whether the command changes settings that are saved by `save`
+    pub fn changes_config(&self) -> bool {
+        matches!(
+            self,
+            Command::Output { .. }
+                | Command::OutputPid { .. }
+                | Command::OutputOff { .. }
+                | Command::OutputVoltage { .. }
+                | Command::OutputHeaterOnly { .. }
+                | Command::OutputPolarity { .. }
+                | Command::CenterPoint { .. }
+                | Command::Pid { .. }
+                | Command::BParameter { .. }
+                | Command::SteinhartHart { .. }
+                | Command::Sensor { .. }
+                | Command::PostFilter { .. }
+                | Command::SwFilter { .. }
+                | Command::MedianFilter { .. }
+                | Command::DataRate { .. }
+                | Command::FilterOrder { .. }
+                | Command::Cal { .. }
+                | Command::DtDtWindow { .. }
+                | Command::Cascade { .. }
+                | Command::SensorOnly { .. }
+                | Command::Stable { .. }
+                | Command::Interlock { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_quit() {
+        let command = Command::parse(b"quit");
+        assert_eq!(command, Ok(Command::Quit));
+    }
+
+    #[test]
+    fn parse_load() {
+        let command = Command::parse(b"load");
+        assert_eq!(command, Ok(Command::Load { channel: None }));
+    }
+
+    #[test]
+    fn parse_load_channel() {
+        let command = Command::parse(b"load 0");
+        assert_eq!(command, Ok(Command::Load { channel: Some(0) }));
+    }
+
+    #[test]
+    fn parse_save() {
+        let command = Command::parse(b"save");
+        assert_eq!(command, Ok(Command::Save { channel: None }));
+    }
+
+    #[test]
+    fn parse_save_channel() {
+        let command = Command::parse(b"save 0");
+        assert_eq!(command, Ok(Command::Save { channel: Some(0) }));
+    }
+
+    #[test]
+    fn parse_load_profile() {
+        let command = Command::parse(b"load coldtrap");
+        let name = ProfileName::from("coldtrap");
+        assert_eq!(command, Ok(Command::LoadProfile { name }));
+    }
+
+    #[test]
+    fn parse_save_profile() {
+        let command = Command::parse(b"save as cold_trap-2");
+        let name = ProfileName::from("cold_trap-2");
+        assert_eq!(command, Ok(Command::SaveProfile { name }));
+    }
+
+    #[test]
+    fn parse_save_profile_too_long() {
+        let command = Command::parse(b"save as abcdefghijklmnopq");
+        assert_eq!(command, Err(Error::Parser(ErrorKind::TooLarge)));
+    }
+
+    #[test]
+    fn parse_profile_list() {
+        let command = Command::parse(b"profile list");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Profiles)));
+    }
+
+    #[test]
+    fn parse_profile_remove() {
+        let command = Command::parse(b"profile remove coldtrap");
+        let name = ProfileName::from("coldtrap");
+        assert_eq!(command, Ok(Command::RemoveProfile { name }));
+    }
+
+    #[test]
+    fn parse_show_ipv4() {
+        let command = Command::parse(b"ipv4");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Ipv4)));
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        let command = Command::parse(b"ipv4 192.168.1.26/24");
+        assert_eq!(
+            command,
+            Ok(Command::Ipv4(Ipv4Config {
+                address: [192, 168, 1, 26],
+                mask_len: 24,
+                gateway: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_ipv4_and_gateway() {
+        let command = Command::parse(b"ipv4 10.42.0.126/8 10.1.0.1");
+        assert_eq!(
+            command,
+            Ok(Command::Ipv4(Ipv4Config {
+                address: [10, 42, 0, 126],
+                mask_len: 8,
+                gateway: Some([10, 1, 0, 1]),
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_report() {
+        let command = Command::parse(b"report");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Input)));
+    }
+
+    #[test]
+    fn parse_output_i_set() {
+        let command = Command::parse(b"output 1 i_set 16383");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 1,
+                pin: PwmPin::ISet,
+                value: 16383.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_off() {
+        let command = Command::parse(b"output 1 off");
+        assert_eq!(command, Ok(Command::OutputOff { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_output_polarity() {
+        let command = Command::parse(b"output 0 polarity reversed");
+        assert_eq!(
+            command,
+            Ok(Command::OutputPolarity {
+                channel: 0,
+                polarity: Polarity::Reversed,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_dac_code() {
+        let command = Command::parse(b"dac 1 131072");
+        assert_eq!(
+            command,
+            Ok(Command::DacCode {
+                channel: 1,
+                code: 131072,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_dac_chip() {
+        let command = Command::parse(b"dac_chip dac8560");
+        assert_eq!(command, Ok(Command::DacChip(DacChip::Dac8560)));
+        let command = Command::parse(b"dac_chip");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::DacChip)));
+        assert!(Command::parse(b"dac_chip dac8560x").is_err());
+    }
+
+    #[test]
+    fn parse_output_heater_only() {
+        let command = Command::parse(b"output 1 heater_only on");
+        assert_eq!(
+            command,
+            Ok(Command::OutputHeaterOnly {
+                channel: 1,
+                enabled: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_v_set() {
+        let command = Command::parse(b"output 0 v_set 1V5");
+        assert_eq!(
+            command,
+            Ok(Command::OutputVoltage {
+                channel: 0,
+                voltage: 1.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_trim() {
+        let command = Command::parse(b"output 1 trim on");
+        assert_eq!(
+            command,
+            Ok(Command::OutputTrim {
+                channel: 1,
+                enabled: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_pid() {
+        let command = Command::parse(b"output 0 pid");
+        assert_eq!(command, Ok(Command::OutputPid { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_output_units() {
+        let command = Command::parse(b"output 0 i_set 250mA");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 0,
+                pin: PwmPin::ISet,
+                value: 0.25,
+            })
+        );
+        let command = Command::parse(b"output 0 max_v 2V5");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 0,
+                pin: PwmPin::MaxV,
+                value: 2.5,
+            })
+        );
+        // Mega is not a prefix
+        assert!(Command::parse(b"output 0 i_set 250MA").is_err());
+    }
+
+    #[test]
+    fn parse_output_all_off() {
+        let command = Command::parse(b"output all off");
+        assert_eq!(
+            command,
+            Ok(Command::OutputOff {
+                channel: ALL_CHANNELS
+            })
+        );
+        assert_eq!(
+            Command::parse(b"output all max_v?"),
+            Err(Error::AllChannels)
+        );
+    }
+
+    #[test]
+    fn parse_output_query() {
+        let command = Command::parse(b"output 1 max_v?");
+        assert_eq!(
+            command,
+            Ok(Command::OutputQuery {
+                channel: 1,
+                pin: PwmPin::MaxV,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_max_i_pos() {
+        let command = Command::parse(b"output 0 max_i_pos 7");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 0,
+                pin: PwmPin::MaxIPos,
+                value: 7.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_max_i_neg() {
+        let command = Command::parse(b"output 0 max_i_neg 128");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 0,
+                pin: PwmPin::MaxINeg,
+                value: 128.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_output_max_v() {
+        let command = Command::parse(b"output 0 max_v 32768");
+        assert_eq!(
+            command,
+            Ok(Command::Output {
+                channel: 0,
+                pin: PwmPin::MaxV,
+                value: 32768.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid() {
+        let command = Command::parse(b"pid");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Pid)));
     }
 
     #[test]
-    fn parse_save() {
-        let command = Command::parse(b"save");
-        assert_eq!(command, Ok(Command::Save { channel: None }));
+    fn parse_pid_target_celsius() {
+        let command = Command::parse(b"pid 0 target 21.5C");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 0,
+                parameter: PidParameter::Target,
+                value: 21.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_exponent() {
+        let command = Command::parse(b"pid 0 ki 1.5e-3");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 0,
+                parameter: PidParameter::KI,
+                value: 1.5e-3,
+            })
+        );
+        assert_eq!(Command::parse(b"pid 0 ki 1e"), Err(Error::ParseFloat));
+        assert_eq!(Command::parse(b"pid 0 ki ."), Err(Error::ParseFloat));
+        assert_eq!(Command::parse(b"pid 0 ki 1e999"), Err(Error::NonFinite));
+    }
+
+    #[test]
+    fn parse_pid_query() {
+        let command = Command::parse(b"pid 0 kp?");
+        assert_eq!(
+            command,
+            Ok(Command::PidQuery {
+                channel: 0,
+                parameter: PidParameter::KP,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_ramp() {
+        let command = Command::parse(b"pid 1 ramp 0.5");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 1,
+                parameter: PidParameter::Ramp,
+                value: 0.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_d_filter() {
+        let command = Command::parse(b"pid 0 d_filter 2.5");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 0,
+                parameter: PidParameter::DFilter,
+                value: 2.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_ff_gain() {
+        let command = Command::parse(b"pid 0 ff_gain -0.02");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 0,
+                parameter: PidParameter::FfGain,
+                value: -0.02,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_ff_reference() {
+        let command = Command::parse(b"pid 1 ff_reference 22");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 1,
+                parameter: PidParameter::FfReference,
+                value: 22.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_divisor() {
+        let command = Command::parse(b"pid 1 divisor 5");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 1,
+                parameter: PidParameter::Divisor,
+                value: 5.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_pid_target() {
+        let command = Command::parse(b"pid 0 target 36.5");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 0,
+                parameter: PidParameter::Target,
+                value: 36.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_b_parameter() {
+        let command = Command::parse(b"b-p");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::BParameter)));
+    }
+
+    #[test]
+    fn parse_b_parameter_set() {
+        let command = Command::parse(b"b-p 1 t0 23.05");
+        assert_eq!(
+            command,
+            Ok(Command::BParameter {
+                channel: 1,
+                parameter: BpParameter::T0,
+                value: 23.05,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_steinhart_hart_set() {
+        let command = Command::parse(b"s-h 0 c 0.0000000876741");
+        assert_eq!(
+            command,
+            Ok(Command::SteinhartHart {
+                channel: 0,
+                parameter: ShParameter::C,
+                value: 0.0000000876741,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sensor() {
+        let command = Command::parse(b"sensor 1 s-h");
+        assert_eq!(
+            command,
+            Ok(Command::Sensor {
+                channel: 1,
+                sensor: Sensor::SteinhartHart,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sensor_rtd() {
+        let command = Command::parse(b"sensor 0 rtd pt1000");
+        assert_eq!(
+            command,
+            Ok(Command::Sensor {
+                channel: 0,
+                sensor: Sensor::Pt1000,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_postfilter() {
+        let command = Command::parse(b"postfilter");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::PostFilter)));
+    }
+
+    #[test]
+    fn parse_postfilter_off() {
+        let command = Command::parse(b"postfilter 1 off");
+        assert_eq!(
+            command,
+            Ok(Command::PostFilter {
+                channel: 1,
+                rate: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_postfilter_rate() {
+        let command = Command::parse(b"postfilter 0 rate 21");
+        assert_eq!(
+            command,
+            Ok(Command::PostFilter {
+                channel: 0,
+                rate: Some(21.0),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_postfilter_sw() {
+        let command = Command::parse(b"postfilter 1 sw 8");
+        assert_eq!(
+            command,
+            Ok(Command::SwFilter {
+                channel: 1,
+                samples: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_postfilter_median() {
+        let command = Command::parse(b"postfilter 0 median 5");
+        assert_eq!(
+            command,
+            Ok(Command::MedianFilter {
+                channel: 0,
+                points: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_postfilter_order() {
+        let command = Command::parse(b"postfilter 0 order sinc3");
+        assert_eq!(
+            command,
+            Ok(Command::FilterOrder {
+                channel: 0,
+                order: DigitalFilterOrder::Sinc3,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_report_raw() {
+        let command = Command::parse(b"report raw");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::RawReport)));
+    }
+
+    #[test]
+    fn parse_cal_offset() {
+        let command = Command::parse(b"cal 0 offset -0.25");
+        assert_eq!(
+            command,
+            Ok(Command::Cal {
+                channel: 0,
+                parameter: CalParameter::Offset,
+                value: -0.25,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_cal_point2() {
+        let command = Command::parse(b"cal 1 point2 60.5");
+        assert_eq!(
+            command,
+            Ok(Command::Cal {
+                channel: 1,
+                parameter: CalParameter::Point2,
+                value: 60.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_stats_reset() {
+        let command = Command::parse(b"stats reset");
+        assert_eq!(command, Ok(Command::StatsReset));
+    }
+
+    #[test]
+    fn parse_time_set() {
+        let command = Command::parse(b"time set 1760572800");
+        assert_eq!(command, Ok(Command::TimeSet { epoch: 1760572800 }));
+    }
+
+    #[test]
+    fn parse_selftest_boot() {
+        let command = Command::parse(b"selftest boot on");
+        assert_eq!(command, Ok(Command::SelfTestBoot(true)));
+    }
+
+    #[test]
+    fn parse_log_show() {
+        let command = Command::parse(b"log show");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::EventLog)));
+    }
+
+    #[test]
+    fn parse_log_clear() {
+        let command = Command::parse(b"log clear");
+        assert_eq!(command, Ok(Command::EventLogClear));
+    }
+
+    #[test]
+    fn parse_log_dump() {
+        let command = Command::parse(b"log dump");
+        assert_eq!(command, Ok(Command::LogDump));
+    }
+
+    #[test]
+    fn parse_config_load() {
+        let command = Command::parse(b"config load");
+        assert_eq!(command, Ok(Command::ConfigLoad));
+    }
+
+    #[test]
+    fn parse_config_check() {
+        let command = Command::parse(b"config check");
+        assert_eq!(command, Ok(Command::ConfigCheck));
+        let command = Command::parse(b"config check load");
+        assert_eq!(command, Ok(Command::ConfigCheckLoad));
+    }
+
+    #[test]
+    fn parse_schedule() {
+        let command = Command::parse(b"at 60 output 0 pid");
+        assert_eq!(
+            command,
+            Ok(Command::Schedule {
+                seconds: 60,
+                every: false,
+                line: ScheduledLine::from("output 0 pid"),
+            })
+        );
+        let command = Command::parse(b"every 3600 pid all target 25 ");
+        assert_eq!(
+            command,
+            Ok(Command::Schedule {
+                seconds: 3600,
+                every: true,
+                line: ScheduledLine::from("pid all target 25"),
+            })
+        );
+        let command = Command::parse(b"schedule");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Schedule)));
+        let command = Command::parse(b"schedule clear");
+        assert_eq!(command, Ok(Command::ScheduleClear { index: None }));
+        let command = Command::parse(b"schedule clear 3");
+        assert_eq!(command, Ok(Command::ScheduleClear { index: Some(3) }));
+        assert!(Command::parse(b"at 60").is_err());
+    }
+
+    #[test]
+    fn parse_config_diff() {
+        let command = Command::parse(b"config diff");
+        assert_eq!(command, Ok(Command::ConfigDiff));
+    }
+
+    #[test]
+    fn parse_autosave() {
+        let command = Command::parse(b"autosave");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::AutoSave)));
+    }
+
+    #[test]
+    fn parse_autosave_on() {
+        let command = Command::parse(b"autosave on");
+        assert_eq!(command, Ok(Command::AutoSave(true)));
+    }
+
+    #[test]
+    fn parse_deadman() {
+        let command = Command::parse(b"deadman 30");
+        assert_eq!(command, Ok(Command::Deadman(Some(30))));
+        let command = Command::parse(b"deadman off");
+        assert_eq!(command, Ok(Command::Deadman(None)));
+        let command = Command::parse(b"deadman");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Deadman)));
+    }
+
+    #[test]
+    fn parse_identify() {
+        let command = Command::parse(b"identify 30");
+        assert_eq!(command, Ok(Command::Identify { seconds: Some(30) }));
+    }
+
+    #[test]
+    fn parse_log_level() {
+        let command = Command::parse(b"loglevel warn");
+        assert_eq!(command, Ok(Command::LogLevel(LogLevel::Warn)));
     }
 
     #[test]
-    fn parse_save_channel() {
-        let command = Command::parse(b"save 0");
-        assert_eq!(command, Ok(Command::Save { channel: Some(0) }));
+    fn parse_safe_start() {
+        let command = Command::parse(b"safe_start");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::SafeStart)));
+        let command = Command::parse(b"safe_start on");
+        assert_eq!(command, Ok(Command::SafeStart(true)));
+        assert!(Command::parse(b"safe_start yes").is_err());
     }
 
     #[test]
-    fn parse_show_ipv4() {
-        let command = Command::parse(b"ipv4");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Ipv4)));
+    fn parse_name() {
+        let command = Command::parse(b"name coldtrap-lab2");
+        let name = DeviceName::from("coldtrap-lab2");
+        assert_eq!(command, Ok(Command::Name { name }));
     }
 
     #[test]
-    fn parse_ipv4() {
-        let command = Command::parse(b"ipv4 192.168.1.26/24");
+    fn parse_eeprom_read() {
+        let command = Command::parse(b"eeprom read 16 8");
         assert_eq!(
             command,
-            Ok(Command::Ipv4(Ipv4Config {
-                address: [192, 168, 1, 26],
-                mask_len: 24,
-                gateway: None,
-            }))
+            Ok(Command::Eeprom(EepromAccess::Read { offset: 16, len: 8 }))
         );
     }
 
     #[test]
-    fn parse_ipv4_and_gateway() {
-        let command = Command::parse(b"ipv4 10.42.0.126/8 10.1.0.1");
+    fn parse_eeprom_write() {
+        let command = Command::parse(b"eeprom write 0 00a5FF");
+        let data = [0x00, 0xa5, 0xff].iter().copied().collect();
         assert_eq!(
             command,
-            Ok(Command::Ipv4(Ipv4Config {
-                address: [10, 42, 0, 126],
-                mask_len: 8,
-                gateway: Some([10, 1, 0, 1]),
-            }))
+            Ok(Command::Eeprom(EepromAccess::Write { offset: 0, data }))
         );
     }
 
     #[test]
-    fn parse_report() {
-        let command = Command::parse(b"report");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Input)));
+    fn parse_eeprom_write_odd() {
+        let command = Command::parse(b"eeprom write 0 abc");
+        assert_eq!(command, Err(Error::Parser(ErrorKind::HexDigit)));
     }
 
     #[test]
-    fn parse_output_i_set() {
-        let command = Command::parse(b"output 1 i_set 16383");
+    fn parse_clear_faults() {
+        let command = Command::parse(b"clear_faults");
+        assert_eq!(command, Ok(Command::ClearFaults));
+        let command = Command::parse(b"faults");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Faults)));
+    }
+
+    #[test]
+    fn parse_alarms_clear() {
+        let command = Command::parse(b"alarms clear");
+        assert_eq!(command, Ok(Command::AlarmsClear));
+    }
+
+    #[test]
+    fn parse_alarm_window() {
+        let command = Command::parse(b"alarm 1 window 20 30.5");
         assert_eq!(
             command,
-            Ok(Command::Output {
+            Ok(Command::AlarmWindow {
                 channel: 1,
-                pin: PwmPin::ISet,
-                value: 16383.0,
+                window: Some([20.0, 30.5]),
             })
         );
     }
 
     #[test]
-    fn parse_output_polarity() {
-        let command = Command::parse(b"output 0 polarity reversed");
+    fn parse_alarm_enable() {
+        let command = Command::parse(b"alarm clipping on");
         assert_eq!(
             command,
-            Ok(Command::OutputPolarity {
-                channel: 0,
-                polarity: Polarity::Reversed,
+            Ok(Command::AlarmEnable {
+                kind: AlarmKind::Clipping,
+                enabled: true,
             })
         );
     }
 
     #[test]
-    fn parse_output_pid() {
-        let command = Command::parse(b"output 0 pid");
-        assert_eq!(command, Ok(Command::OutputPid { channel: 0 }));
+    fn parse_alarm_dac_feedback() {
+        let command = Command::parse(b"alarm dac_feedback 0.05");
+        assert_eq!(command, Ok(Command::AlarmDacFeedback { max: Some(0.05) }));
+        let command = Command::parse(b"alarm dac_feedback off");
+        assert_eq!(command, Ok(Command::AlarmDacFeedback { max: None }));
     }
 
     #[test]
-    fn parse_output_max_i_pos() {
-        let command = Command::parse(b"output 0 max_i_pos 7");
-        assert_eq!(
-            command,
-            Ok(Command::Output {
-                channel: 0,
-                pin: PwmPin::MaxIPos,
-                value: 7.0,
-            })
-        );
+    fn parse_kick() {
+        let command = Command::parse(b"kick 3");
+        assert_eq!(command, Ok(Command::Kick { index: 3 }));
     }
 
     #[test]
-    fn parse_output_max_i_neg() {
-        let command = Command::parse(b"output 0 max_i_neg 128");
+    fn parse_energy_reset() {
+        let command = Command::parse(b"energy reset");
+        assert_eq!(command, Ok(Command::EnergyReset));
+    }
+
+    #[test]
+    fn parse_history() {
+        let command = Command::parse(b"history 1 100");
         assert_eq!(
             command,
-            Ok(Command::Output {
-                channel: 0,
-                pin: PwmPin::MaxINeg,
-                value: 128.0,
+            Ok(Command::History {
+                channel: 1,
+                samples: Some(100),
             })
         );
     }
 
     #[test]
-    fn parse_output_max_v() {
-        let command = Command::parse(b"output 0 max_v 32768");
+    fn parse_dtdt_window() {
+        let command = Command::parse(b"dtdt 0 30");
         assert_eq!(
             command,
-            Ok(Command::Output {
+            Ok(Command::DtDtWindow {
                 channel: 0,
-                pin: PwmPin::MaxV,
-                value: 32768.0,
+                window: 30.0,
             })
         );
     }
 
     #[test]
-    fn parse_pid() {
-        let command = Command::parse(b"pid");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Pid)));
-    }
-
-    #[test]
-    fn parse_pid_target() {
-        let command = Command::parse(b"pid 0 target 36.5");
+    fn parse_capture() {
+        let command = Command::parse(b"capture 0 1000");
         assert_eq!(
             command,
-            Ok(Command::Pid {
+            Ok(Command::Capture {
                 channel: 0,
-                parameter: PidParameter::Target,
-                value: 36.5,
+                samples: 1000,
             })
         );
     }
 
     #[test]
-    fn parse_b_parameter() {
-        let command = Command::parse(b"b-p");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::BParameter)));
-    }
-
-    #[test]
-    fn parse_b_parameter_set() {
-        let command = Command::parse(b"b-p 1 t0 23.05");
+    fn parse_tune_step() {
+        let command = Command::parse(b"tune step 1 -0.2 300");
         assert_eq!(
             command,
-            Ok(Command::BParameter {
+            Ok(Command::TuneStep {
                 channel: 1,
-                parameter: BpParameter::T0,
-                value: 23.05,
+                delta_i: -0.2,
+                duration: 300.0,
             })
         );
     }
 
     #[test]
-    fn parse_postfilter() {
-        let command = Command::parse(b"postfilter");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::PostFilter)));
-    }
-
-    #[test]
-    fn parse_postfilter_off() {
-        let command = Command::parse(b"postfilter 1 off");
+    fn parse_adc_calibrate() {
+        let command = Command::parse(b"adc 1 calibrate gain");
         assert_eq!(
             command,
-            Ok(Command::PostFilter {
+            Ok(Command::AdcCalibrate {
                 channel: 1,
-                rate: None,
+                calibration: AdcCalibration::Gain,
             })
         );
     }
 
     #[test]
-    fn parse_postfilter_rate() {
-        let command = Command::parse(b"postfilter 0 rate 21");
+    fn parse_data_rate() {
+        let command = Command::parse(b"datarate 1 1.25");
         assert_eq!(
             command,
-            Ok(Command::PostFilter {
-                channel: 0,
-                rate: Some(21.0),
+            Ok(Command::DataRate {
+                channel: 1,
+                rate: 1.25,
             })
         );
     }
@@ -862,9 +3422,275 @@ mod test {
         assert_eq!(command, Ok(Command::FanCurveDefaults));
     }
 
+    #[test]
+    fn parse_fcurve_hysteresis() {
+        let command = Command::parse(b"fcurve hysteresis 5");
+        assert_eq!(command, Ok(Command::FanHysteresis { hysteresis: 5.0 }));
+    }
+
+    #[test]
+    fn parse_fcurve_mcu() {
+        let command = Command::parse(b"fcurve mcu 50 70");
+        assert_eq!(
+            command,
+            Ok(Command::FanMcuCurve {
+                range: Some([50.0, 70.0])
+            })
+        );
+    }
+
+    #[test]
+    fn parse_fcurve_min() {
+        let command = Command::parse(b"fcurve min 20");
+        assert_eq!(command, Ok(Command::FanMinPwm { min_pwm: 20 }));
+    }
+
     #[test]
     fn parse_hwrev() {
         let command = Command::parse(b"hwrev");
         assert_eq!(command, Ok(Command::ShowHWRev));
     }
+
+    #[test]
+    fn parse_version() {
+        let command = Command::parse(b"version");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Version)));
+    }
+
+    #[test]
+    fn parse_framing_show() {
+        let command = Command::parse(b"framing");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Framing)));
+    }
+
+    #[test]
+    fn parse_framing_length() {
+        let command = Command::parse(b"framing length");
+        assert_eq!(command, Ok(Command::Framing(Framing::LengthPrefix)));
+    }
+
+    #[test]
+    fn parse_framing_newline() {
+        let command = Command::parse(b"framing newline");
+        assert_eq!(command, Ok(Command::Framing(Framing::Newline)));
+    }
+
+    #[test]
+    fn parse_sensor_only() {
+        let command = Command::parse(b"sensor_only 1 on");
+        assert_eq!(
+            command,
+            Ok(Command::SensorOnly {
+                channel: 1,
+                enabled: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_cascade_show() {
+        let command = Command::parse(b"cascade");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Cascade)));
+    }
+
+    #[test]
+    fn parse_cascade_on() {
+        let command = Command::parse(b"cascade 1 on");
+        assert_eq!(
+            command,
+            Ok(Command::Cascade {
+                channel: 1,
+                enabled: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_stable_output() {
+        let command = Command::parse(b"stable output 1");
+        assert_eq!(
+            command,
+            Ok(Command::StableOutput {
+                channels: [false, true],
+            })
+        );
+        let command = Command::parse(b"stable output off");
+        assert_eq!(
+            command,
+            Ok(Command::StableOutput {
+                channels: [false, false],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_stable_window() {
+        let command = Command::parse(b"stable 0 window 0.05");
+        assert_eq!(
+            command,
+            Ok(Command::Stable {
+                channel: 0,
+                parameter: StableParameter::Window,
+                value: 0.05,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_locked() {
+        let command = Command::parse(b"locked");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Locked)));
+    }
+
+    #[test]
+    fn parse_interlock_t_max() {
+        let command = Command::parse(b"interlock 1 t_max 80");
+        assert_eq!(
+            command,
+            Ok(Command::Interlock {
+                channel: 1,
+                limit: Some((InterlockLimit::TMax, 80.0)),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_interlock_overcurrent() {
+        let command = Command::parse(b"interlock 0 overcurrent 500ms");
+        assert_eq!(
+            command,
+            Ok(Command::Interlock {
+                channel: 0,
+                limit: Some((InterlockLimit::OverCurrent, 0.5)),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_interlock_off() {
+        let command = Command::parse(b"interlock 0 off");
+        assert_eq!(
+            command,
+            Ok(Command::Interlock {
+                channel: 0,
+                limit: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_clear_fault() {
+        let command = Command::parse(b"clear_fault 1");
+        assert_eq!(command, Ok(Command::ClearFault { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_tec_check() {
+        let command = Command::parse(b"tec 0 check");
+        assert_eq!(command, Ok(Command::TecCheck { channel: 0 }));
+    }
+
+    #[test]
+    fn parse_tec_cal() {
+        let command = Command::parse(b"tec 1 cal i_gain 1.03");
+        assert_eq!(
+            command,
+            Ok(Command::TecCal {
+                channel: 1,
+                parameter: TecCalParameter::IGain,
+                value: 1.03,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_upper_case() {
+        let command = Command::parse(b" PID\t1  KP 1.5");
+        assert_eq!(
+            command,
+            Ok(Command::Pid {
+                channel: 1,
+                parameter: PidParameter::KP,
+                value: 1.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_interactive() {
+        let command = Command::parse(b"interactive on");
+        assert_eq!(command, Ok(Command::Interactive(true)));
+    }
+
+    #[test]
+    fn parse_alias() {
+        let command = Command::parse(b"alias 0 laser");
+        let alias = ChannelAlias::from("laser");
+        assert_eq!(
+            command,
+            Ok(Command::Alias {
+                channel: 0,
+                alias: Some(alias.clone()),
+            })
+        );
+
+        let aliases = [None, Some(alias)];
+        let line = b"pid LASER\ttarget 25";
+        assert!(Command::parse(line).is_err());
+        assert_eq!(
+            Command::parse_aliased(line, &aliases),
+            Some(Ok(Command::Pid {
+                channel: 1,
+                parameter: PidParameter::Target,
+                value: 25.0,
+            }))
+        );
+        assert_eq!(Command::parse_aliased(b"pid 2 target 25", &aliases), None);
+    }
+
+    #[test]
+    fn parse_report_format() {
+        let command = Command::parse(b"format csv");
+        assert_eq!(command, Ok(Command::ReportFormat(ReportFormat::Csv)));
+        let command = Command::parse(b"format milli");
+        assert_eq!(command, Ok(Command::ReportFormat(ReportFormat::Milli)));
+    }
+
+    #[test]
+    fn parse_precision() {
+        let command = Command::parse(b"precision 6");
+        assert_eq!(command, Ok(Command::Precision(Some(6))));
+        let command = Command::parse(b"precision 18");
+        assert_eq!(command, Err(Error::Parser(ErrorKind::TooLarge)));
+    }
+
+    #[test]
+    fn parse_tag() {
+        let (tag, rest) = Command::split_tag(b"#req-7 pid 0 kp 10").unwrap();
+        assert_eq!(tag, Some(RequestTag::from("req-7")));
+        assert_eq!(rest, b" pid 0 kp 10");
+        assert_eq!(Command::split_tag(b"report"), Ok((None, &b"report"[..])));
+        assert_eq!(
+            Command::split_tag(b"#7.1 report"),
+            Err(Error::UnexpectedInput(b'.'))
+        );
+    }
+
+    #[test]
+    fn parse_calibrate_dac() {
+        let command = Command::parse(b"calibrate 1");
+        assert_eq!(command, Ok(Command::CalibrateDac { channel: 1 }));
+    }
+
+    #[test]
+    fn parse_power_on() {
+        let command = Command::parse(b"power_on");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::PowerOn)));
+    }
+
+    #[test]
+    fn parse_power_on_off() {
+        let command = Command::parse(b"power_on off");
+        assert_eq!(command, Ok(Command::PowerOn(PowerOn::Off)));
+    }
 }
@@ -1,16 +1,33 @@
 use crate::{
-    ad7172::PostFilter,
+    ad7172::{DataRate, DigitalFilterOrder, PostFilter},
     b_parameter,
-    channels::Channels,
-    command_parser::{CenterPoint, Polarity},
-    pid,
+    channels::{ChannelsInterface, CHANNELS, MAX_TEC_I, MAX_TEC_V},
+    command_parser::{
+        CenterPoint, ChannelAlias, DeviceName, Ipv4Config, LogLevel, Polarity, PowerOn, Sensor,
+    },
+    fan_ctrl::{FanConfig, FanCtrl},
+    flash_store::{FlashInterface, StoreError},
+    interrupt::{self, Mutex},
+    pid, steinhart_hart, CHANNEL_CONFIG_KEY, CHANNEL_CONFIG_V1_KEY, DEVICE_CONFIG_KEY,
+    DEVICE_CONFIG_V1_KEY,
+};
+use core::cell::{Cell, RefCell};
+use heapless::{
+    consts::{U2048, U32, U64},
+    Vec,
 };
 use serde::{Deserialize, Serialize};
 use uom::{
-    si::f64::{ElectricCurrent, ElectricPotential},
+    si::{
+        electric_current::ampere,
+        f64::{ElectricCurrent, ElectricPotential, TemperatureInterval, Time},
+    },
     ConstZero,
 };
 
+/// Saved under `CHANNEL_CONFIG_KEY`. Postcard encodes the fields in
+/// order and without their names, so a change of them needs a new key,
+/// and the migration of the configs saved under the previous one.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChannelConfig {
     center: CenterPoint,
@@ -20,18 +37,38 @@ pub struct ChannelConfig {
     i_set: ElectricCurrent,
     polarity: Polarity,
     bp: b_parameter::Parameters,
+    sh: steinhart_hart::Parameters,
+    sensor: Sensor,
+    cal_offset: f32,
+    cal_gain: f32,
     output_limits: OutputLimits,
     /// uses variant `PostFilter::Invalid` instead of `None` to save space
     adc_postfilter: PostFilter,
+    adc_data_rate: DataRate,
+    adc_filter_order: DigitalFilterOrder,
+    sw_filter: u32,
+    median_filter: u8,
+    cascade: bool,
+    stable_window: TemperatureInterval,
+    stable_time: Time,
+    dtdt_window: Time,
+    t_min: Option<f32>,
+    t_max: Option<f32>,
+    overcurrent_time: Option<Time>,
+    sensor_only: bool,
+    v_set: Option<ElectricPotential>,
+    heater_only: bool,
 }
 
 impl ChannelConfig {
-    pub fn new(channels: &mut Channels, channel: usize) -> Self {
+    pub fn new(channels: &mut dyn ChannelsInterface, channel: usize) -> Self {
         let output_limits = OutputLimits::new(channels, channel);
 
         let adc_postfilter = channels
             .get_postfilter(channel as u8)
             .unwrap_or(PostFilter::Invalid);
+        let adc_data_rate = channels.get_data_rate(channel as u8);
+        let adc_filter_order = channels.get_filter_order(channel as u8);
 
         let state = channels.channel_state(channel);
         let i_set = if state.pid_engaged {
@@ -47,18 +84,53 @@ impl ChannelConfig {
             i_set,
             polarity: state.polarity.clone(),
             bp: state.bp.clone(),
+            sh: state.sh.clone(),
+            sensor: state.sensor,
+            cal_offset: state.cal_offset as f32,
+            cal_gain: state.cal_gain as f32,
             output_limits,
             adc_postfilter,
+            adc_data_rate,
+            adc_filter_order,
+            sw_filter: state.sw_filter,
+            median_filter: state.median_filter,
+            cascade: state.cascade,
+            stable_window: state.stable_window,
+            stable_time: state.stable_time,
+            dtdt_window: state.dtdt_window,
+            t_min: state.t_min.map(|t_min| t_min as f32),
+            t_max: state.t_max.map(|t_max| t_max as f32),
+            overcurrent_time: state.overcurrent_time,
+            sensor_only: state.sensor_only,
+            v_set: state.v_set,
+            heater_only: state.heater_only,
         }
     }
 
-    pub fn apply(&self, channels: &mut Channels, channel: usize) {
+    pub fn apply(&self, channels: &mut dyn ChannelsInterface, channel: usize) {
         let state = channels.channel_state(channel);
         state.center = self.center.clone();
         state.pid.parameters = self.pid.clone();
         state.pid.target = self.pid_target.into();
         state.pid_engaged = self.pid_engaged;
         state.bp = self.bp.clone();
+        state.sh = self.sh.clone();
+        state.sensor = self.sensor;
+        state.cal_offset = self.cal_offset.into();
+        state.cal_gain = self.cal_gain.into();
+        state.sw_filter = self.sw_filter;
+        state.median_filter = self.median_filter;
+        state.stable_window = self.stable_window;
+        state.stable_time = self.stable_time;
+        state.dtdt_window = self.dtdt_window;
+        state.t_min = self.t_min.map(|t_min| t_min.into());
+        state.t_max = self.t_max.map(|t_max| t_max.into());
+        state.overcurrent_time = self.overcurrent_time;
+        state.sensor_only = self.sensor_only;
+        // Not to be engaged again by an earlier config
+        state.pid_engaged &= !self.sensor_only;
+        // Before the output limits and i_set, which it clamps
+        state.heater_only = self.heater_only;
 
         self.output_limits.apply(channels, channel);
 
@@ -66,12 +138,239 @@ impl ChannelConfig {
             PostFilter::Invalid => None,
             adc_postfilter => Some(adc_postfilter),
         };
+        channels.set_filter_order(channel as u8, self.adc_filter_order);
+        channels.set_data_rate(channel as u8, self.adc_data_rate);
         let _ = channels.set_postfilter(channel as u8, adc_postfilter);
         let _ = channels.set_i(channel, self.i_set);
+        channels.channel_state(channel).v_set = self.v_set;
         channels.set_polarity(channel, self.polarity.clone());
+        channels.set_cascade(channel, self.cascade);
+    }
+
+    /// Find the settings that are beyond the limits of the hardware,
+    /// or would not make the PID controller work, without applying them
+    pub fn check(&self, channel: usize, problems: &mut Problems) {
+        let mut problem = |field, message| {
+            let _ = problems.push(Problem {
+                channel: Some(channel),
+                field,
+                message,
+            });
+        };
+
+        if self.i_set.abs() > MAX_TEC_I {
+            problem("i_set", "beyond MAX_TEC_I");
+        }
+        if matches!(self.v_set, Some(v_set) if v_set.abs() > MAX_TEC_V) {
+            problem("v_set", "beyond MAX_TEC_V");
+        }
+        let limits = &self.output_limits;
+        if !(ElectricPotential::ZERO..=MAX_TEC_V).contains(&limits.max_v) {
+            problem("max_v", "outside 0 to MAX_TEC_V");
+        }
+        if !(ElectricCurrent::ZERO..=MAX_TEC_I).contains(&limits.max_i_pos) {
+            problem("max_i_pos", "outside 0 to MAX_TEC_I");
+        }
+        if !(ElectricCurrent::ZERO..=MAX_TEC_I).contains(&limits.max_i_neg) {
+            problem("max_i_neg", "outside 0 to MAX_TEC_I");
+        }
+
+        let pid = &self.pid;
+        for (field, gain) in [("kp", pid.kp), ("ki", pid.ki), ("kd", pid.kd)] {
+            if !(gain >= 0.0 && gain.is_finite()) {
+                problem(field, "negative or not finite");
+            }
+        }
+        let max_tec_i = MAX_TEC_I.get::<ampere>() as f32;
+        for (field, output) in [
+            ("output_min", pid.output_min),
+            ("output_max", pid.output_max),
+        ] {
+            if !(-max_tec_i..=max_tec_i).contains(&output) {
+                problem(field, "beyond MAX_TEC_I");
+            }
+        }
+        if pid.output_min > pid.output_max {
+            problem("output_min", "above output_max");
+        }
+        if self.heater_only && pid.output_min < 0.0 {
+            problem("output_min", "negative on a heater-only channel");
+        }
+        for (field, value) in [("ramp", pid.ramp_rate), ("d_filter", pid.d_filter)] {
+            if !(value >= 0.0 && value.is_finite()) {
+                problem(field, "negative or not finite");
+            }
+        }
+        if pid.divisor == 0 {
+            problem("divisor", "zero");
+        }
+
+        if !self.pid_target.is_finite() {
+            problem("target", "not finite");
+        } else if matches!(self.t_min, Some(t_min) if self.pid_target < t_min)
+            || matches!(self.t_max, Some(t_max) if self.pid_target > t_max)
+        {
+            problem("target", "outside t_min to t_max");
+        }
+        if let (Some(t_min), Some(t_max)) = (self.t_min, self.t_max) {
+            if t_min >= t_max {
+                problem("t_min", "not below t_max");
+            }
+        }
+        if !(self.cal_gain != 0.0 && self.cal_gain.is_finite()) {
+            problem("cal_gain", "zero or not finite");
+        }
+    }
+
+    /// Settings that differ from `saved`, named as by `config check`
+    pub fn diff(&self, saved: &Self) -> Fields {
+        let (pid, saved_pid) = (&self.pid, &saved.pid);
+        let (limits, saved_limits) = (&self.output_limits, &saved.output_limits);
+        let mut fields = Fields::new();
+        diff_fields(
+            &mut fields,
+            [
+                ("center", self.center != saved.center),
+                ("kp", pid.kp != saved_pid.kp),
+                ("ki", pid.ki != saved_pid.ki),
+                ("kd", pid.kd != saved_pid.kd),
+                ("output_min", pid.output_min != saved_pid.output_min),
+                ("output_max", pid.output_max != saved_pid.output_max),
+                ("ramp", pid.ramp_rate != saved_pid.ramp_rate),
+                ("d_filter", pid.d_filter != saved_pid.d_filter),
+                ("ff_offset", pid.ff_offset != saved_pid.ff_offset),
+                ("ff_gain", pid.ff_gain != saved_pid.ff_gain),
+                ("ff_reference", pid.ff_reference != saved_pid.ff_reference),
+                ("divisor", pid.divisor != saved_pid.divisor),
+                ("target", self.pid_target != saved.pid_target),
+                ("pid_engaged", self.pid_engaged != saved.pid_engaged),
+                ("i_set", self.i_set != saved.i_set),
+                ("polarity", self.polarity != saved.polarity),
+                ("bp", self.bp != saved.bp),
+                ("sh", self.sh != saved.sh),
+                ("sensor", self.sensor != saved.sensor),
+                ("cal_offset", self.cal_offset != saved.cal_offset),
+                ("cal_gain", self.cal_gain != saved.cal_gain),
+                ("max_v", limits.max_v != saved_limits.max_v),
+                ("max_i_pos", limits.max_i_pos != saved_limits.max_i_pos),
+                ("max_i_neg", limits.max_i_neg != saved_limits.max_i_neg),
+                ("postfilter", self.adc_postfilter != saved.adc_postfilter),
+                ("data_rate", self.adc_data_rate != saved.adc_data_rate),
+                (
+                    "filter_order",
+                    self.adc_filter_order != saved.adc_filter_order,
+                ),
+                ("sw_filter", self.sw_filter != saved.sw_filter),
+                ("median_filter", self.median_filter != saved.median_filter),
+                ("cascade", self.cascade != saved.cascade),
+                ("stable_window", self.stable_window != saved.stable_window),
+                ("stable_time", self.stable_time != saved.stable_time),
+                ("dtdt_window", self.dtdt_window != saved.dtdt_window),
+                ("t_min", self.t_min != saved.t_min),
+                ("t_max", self.t_max != saved.t_max),
+                (
+                    "overcurrent_time",
+                    self.overcurrent_time != saved.overcurrent_time,
+                ),
+                ("sensor_only", self.sensor_only != saved.sensor_only),
+                ("v_set", self.v_set != saved.v_set),
+                ("heater_only", self.heater_only != saved.heater_only),
+            ],
+        );
+        fields
+    }
+}
+
+/// Names of the settings that differ between two configs, as `config
+/// diff` reports them
+pub type Fields = Vec<&'static str, U64>;
+
+/// Push the names of the settings whose values differ
+fn diff_fields(fields: &mut Fields, values: impl IntoIterator<Item = (&'static str, bool)>) {
+    for (field, differs) in values {
+        if differs {
+            let _ = fields.push(field);
+        }
+    }
+}
+
+/// Channel config of the first format, saved under
+/// `CHANNEL_CONFIG_V1_KEY`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ChannelConfigV1 {
+    center: CenterPoint,
+    pid: PidParametersV1,
+    pid_target: f32,
+    pid_engaged: bool,
+    i_set: ElectricCurrent,
+    polarity: Polarity,
+    bp: b_parameter::Parameters,
+    output_limits: OutputLimits,
+    adc_postfilter: PostFilter,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+struct PidParametersV1 {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    output_min: f32,
+    output_max: f32,
+}
+
+impl ChannelConfigV1 {
+    /// `current` with the settings of this config, those added since
+    /// are kept
+    pub fn migrate(self, current: ChannelConfig) -> ChannelConfig {
+        ChannelConfig {
+            center: self.center,
+            pid: pid::Parameters {
+                kp: self.pid.kp,
+                ki: self.pid.ki,
+                kd: self.pid.kd,
+                output_min: self.pid.output_min,
+                output_max: self.pid.output_max,
+                ..current.pid
+            },
+            pid_target: self.pid_target,
+            pid_engaged: self.pid_engaged,
+            i_set: self.i_set,
+            polarity: self.polarity,
+            bp: self.bp,
+            output_limits: self.output_limits,
+            adc_postfilter: self.adc_postfilter,
+            ..current
+        }
+    }
+}
+
+/// Read the config of `channel` saved in flash, or migrate the one
+/// saved in the first format, onto the current settings
+pub fn read_channel_config(
+    store: &impl FlashInterface,
+    channels: &mut dyn ChannelsInterface,
+    channel: usize,
+) -> Result<Option<ChannelConfig>, StoreError> {
+    if let Some(config) = store.read_value(CHANNEL_CONFIG_KEY[channel])? {
+        return Ok(Some(config));
     }
+    Ok(store
+        .read_value::<ChannelConfigV1>(CHANNEL_CONFIG_V1_KEY[channel])?
+        .map(|config| config.migrate(ChannelConfig::new(channels, channel))))
 }
 
+/// Setting found by `config check`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Problem {
+    /// `None` for the settings of the device
+    pub channel: Option<usize>,
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+/// Problems of a config, those beyond the capacity are left out
+pub type Problems = Vec<Problem, U32>;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct OutputLimits {
     pub max_v: ElectricPotential,
@@ -80,7 +379,7 @@ pub struct OutputLimits {
 }
 
 impl OutputLimits {
-    pub fn new(channels: &mut Channels, channel: usize) -> Self {
+    pub fn new(channels: &mut dyn ChannelsInterface, channel: usize) -> Self {
         let max_v = channels.get_max_v(channel);
         let max_i_pos = channels.get_max_i_pos(channel);
         let max_i_neg = channels.get_max_i_neg(channel);
@@ -91,9 +390,501 @@ impl OutputLimits {
         }
     }
 
-    pub fn apply(&self, channels: &mut Channels, channel: usize) {
+    pub fn apply(&self, channels: &mut dyn ChannelsInterface, channel: usize) {
         channels.set_max_v(channel, self.max_v);
         channels.set_max_i_pos(channel, self.max_i_pos);
         channels.set_max_i_neg(channel, self.max_i_neg);
     }
 }
+
+/// Device name set with `name`, `None` for `DEFAULT_NAME`
+static NAME: Mutex<RefCell<Option<DeviceName>>> = Mutex::new(RefCell::new(None));
+const DEFAULT_NAME: &str = "thermostat";
+
+pub fn name() -> DeviceName {
+    interrupt::free(|cs| NAME.borrow(cs).borrow().clone())
+        .unwrap_or_else(|| DeviceName::from(DEFAULT_NAME))
+}
+
+pub fn set_name(name: DeviceName) {
+    interrupt::free(|cs| *NAME.borrow(cs).borrow_mut() = Some(name))
+}
+
+const NO_ALIAS: Option<ChannelAlias> = None;
+/// Aliases of the channels set with `alias`
+static ALIASES: Mutex<RefCell<[Option<ChannelAlias>; CHANNELS]>> =
+    Mutex::new(RefCell::new([NO_ALIAS; CHANNELS]));
+
+pub fn aliases() -> [Option<ChannelAlias>; CHANNELS] {
+    interrupt::free(|cs| ALIASES.borrow(cs).borrow().clone())
+}
+
+pub fn set_aliases(aliases: [Option<ChannelAlias>; CHANNELS]) {
+    interrupt::free(|cs| *ALIASES.borrow(cs).borrow_mut() = aliases)
+}
+
+/// Whether the outputs stay off at boot after a reset by the watchdog,
+/// set with `safe_start`
+static SAFE_START: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn safe_start() -> bool {
+    interrupt::free(|cs| SAFE_START.borrow(cs).get())
+}
+
+pub fn set_safe_start(enable: bool) {
+    interrupt::free(|cs| SAFE_START.borrow(cs).set(enable))
+}
+
+/// Whether the boot keeps the outputs off rather than restore them
+/// from the saved channel configs
+pub fn outputs_off_at_boot(power_on: PowerOn, safe_start: bool, watchdog_reset: bool) -> bool {
+    power_on == PowerOn::Off || (safe_start && watchdog_reset)
+}
+
+/// Settings of the device beyond the channels, saved by `save` under
+/// `DEVICE_CONFIG_KEY` and restored at boot. The device name is saved
+/// by `name` on its own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    fan: FanConfig,
+    log_level: LogLevel,
+    safe_start: bool,
+}
+
+impl DeviceConfig {
+    pub fn new(fan_ctrl: &FanCtrl) -> Self {
+        DeviceConfig {
+            fan: fan_ctrl.config(),
+            log_level: LogLevel::current(),
+            safe_start: safe_start(),
+        }
+    }
+
+    pub fn apply(&self, fan_ctrl: &mut FanCtrl) {
+        fan_ctrl.apply_config(&self.fan);
+        self.log_level.apply();
+        set_safe_start(self.safe_start);
+    }
+
+    /// Settings that differ from `saved`
+    pub fn diff(&self, saved: &Self) -> Fields {
+        let mut fields = Fields::new();
+        diff_fields(
+            &mut fields,
+            [
+                ("fan", self.fan != saved.fan),
+                ("log_level", self.log_level != saved.log_level),
+                ("safe_start", self.safe_start != saved.safe_start),
+            ],
+        );
+        fields
+    }
+}
+
+/// Device config of the first format, saved under
+/// `DEVICE_CONFIG_V1_KEY`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DeviceConfigV1 {
+    fan: FanConfig,
+    log_level: LogLevel,
+}
+
+impl DeviceConfigV1 {
+    pub fn migrate(self) -> DeviceConfig {
+        DeviceConfig {
+            fan: self.fan,
+            log_level: self.log_level,
+            safe_start: false,
+        }
+    }
+}
+
+/// Read the device config saved in flash, or migrate the one saved in
+/// the first format
+pub fn read_device_config(store: &impl FlashInterface) -> Result<Option<DeviceConfig>, StoreError> {
+    if let Some(config) = store.read_value(DEVICE_CONFIG_KEY)? {
+        return Ok(Some(config));
+    }
+    Ok(store
+        .read_value::<DeviceConfigV1>(DEVICE_CONFIG_V1_KEY)?
+        .map(DeviceConfigV1::migrate))
+}
+
+/// Settings changed since `save`, as `config diff` reports them, `None`
+/// where nothing has been saved
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    pub channels: [Option<Fields>; CHANNELS],
+    pub device: Option<Fields>,
+}
+
+/// Settings of a device, for backing them up and cloning them between
+/// devices. Settings left out are kept by `config load`. `config dump`
+/// sends those of the device and those of each channel as separate
+/// documents, as all of them at their longest would not fit into a
+/// send buffer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDocument {
+    pub channels: Option<[Option<ChannelConfig>; CHANNELS]>,
+    pub ipv4: Option<Ipv4Config>,
+    pub fan: Option<FanConfig>,
+    pub power_on: Option<PowerOn>,
+    pub selftest_boot: Option<bool>,
+}
+
+impl ConfigDocument {
+    /// Settings of the device beyond the channels
+    pub fn device(
+        ipv4_config: &Ipv4Config,
+        fan_ctrl: &FanCtrl,
+        power_on: Option<PowerOn>,
+        selftest_boot: Option<bool>,
+    ) -> Self {
+        ConfigDocument {
+            channels: None,
+            ipv4: Some(ipv4_config.clone()),
+            fan: Some(fan_ctrl.config()),
+            power_on,
+            selftest_boot,
+        }
+    }
+
+    /// Settings of `channel`
+    pub fn channel(channels: &mut dyn ChannelsInterface, channel: usize) -> Self {
+        const NO_CONFIG: Option<ChannelConfig> = None;
+        let mut configs = [NO_CONFIG; CHANNELS];
+        configs[channel] = Some(ChannelConfig::new(channels, channel));
+        ConfigDocument {
+            channels: Some(configs),
+            ipv4: None,
+            fan: None,
+            power_on: None,
+            selftest_boot: None,
+        }
+    }
+
+    /// Problems of the settings in the document, as `config check`
+    /// reports them
+    pub fn check(&self) -> Problems {
+        let mut problems = Problems::new();
+        if let Some(configs) = &self.channels {
+            for (c, config) in configs.iter().enumerate() {
+                if let Some(config) = config {
+                    config.check(c, &mut problems);
+                }
+            }
+        }
+        if let Some(ipv4) = &self.ipv4 {
+            if ipv4.mask_len > 32 {
+                let _ = problems.push(Problem {
+                    channel: None,
+                    field: "mask_len",
+                    message: "above 32",
+                });
+            }
+        }
+        problems
+    }
+}
+
+/// Config document being received for `config load` or `config check
+/// load`, as it is too long for the line reader of a session. Only one
+/// session can send one at a time.
+static DOCUMENT: Mutex<RefCell<Option<DocumentBuffer>>> = Mutex::new(RefCell::new(None));
+
+struct DocumentBuffer {
+    data: Vec<u8, U2048>,
+    overflow: bool,
+    mode: DocumentMode,
+}
+
+/// What to do with a received config document
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DocumentMode {
+    Apply,
+    /// Only report its problems
+    Check,
+}
+
+#[derive(Debug)]
+pub enum DocumentError {
+    NotReceiving,
+    TooLong,
+    Json(serde_json_core::de::Error),
+}
+
+/// Start receiving a config document, unless another session is
+pub fn receive_begin(mode: DocumentMode) -> bool {
+    interrupt::free(|cs| {
+        let mut document = DOCUMENT.borrow(cs).borrow_mut();
+        if document.is_some() {
+            return false;
+        }
+        *document = Some(DocumentBuffer {
+            data: Vec::new(),
+            overflow: false,
+            mode,
+        });
+        true
+    })
+}
+
+pub fn receive_data(data: &[u8]) {
+    interrupt::free(|cs| {
+        if let Some(document) = DOCUMENT.borrow(cs).borrow_mut().as_mut() {
+            if document.data.extend_from_slice(data).is_err() {
+                document.overflow = true;
+            }
+        }
+    })
+}
+
+/// Stop receiving, as the session has been closed
+pub fn receive_cancel() {
+    interrupt::free(|cs| *DOCUMENT.borrow(cs).borrow_mut() = None)
+}
+
+/// Parse the complete document, and stop receiving
+pub fn receive_end() -> Result<(ConfigDocument, DocumentMode), DocumentError> {
+    interrupt::free(|cs| {
+        let document = DOCUMENT
+            .borrow(cs)
+            .borrow_mut()
+            .take()
+            .ok_or(DocumentError::NotReceiving)?;
+        if document.overflow {
+            return Err(DocumentError::TooLong);
+        }
+        serde_json_core::from_slice(&document.data)
+            .map(|config| (config, document.mode))
+            .map_err(DocumentError::Json)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockChannels, MockFlash};
+    use heapless::String;
+    use uom::si::{
+        electric_current::ampere,
+        electric_potential::volt,
+        electrical_resistance::ohm,
+        f64::{ElectricalResistance, ThermodynamicTemperature},
+        temperature_interval::kelvin as kelvin_interval,
+        thermodynamic_temperature::kelvin,
+        time::second,
+    };
+
+    /// Config of channel 0 as saved by the firmware of the first format
+    #[rustfmt::skip]
+    const CHANNEL_CONFIG_V1: [u8; 88] = [
+        // center: Override(0.5)
+        0x01, 0x00, 0x00, 0x00, 0x3f,
+        // pid: kp, ki, kd, output_min, output_max
+        0x00, 0x00, 0x20, 0x41, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x40,
+        // pid_target, pid_engaged
+        0x00, 0x00, 0xc8, 0x41, 0x01,
+        // i_set
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // polarity: Reversed
+        0x01,
+        // bp: t0, r0, b
+        0x66, 0x66, 0x66, 0x66, 0x66, 0xa2, 0x72, 0x40, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x88, 0xc3, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0xb0, 0xad, 0x40,
+        // output_limits: max_v, max_i_pos, max_i_neg
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x40, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x3f,
+        // adc_postfilter: F20SPS
+        0x02,
+    ];
+
+    #[test]
+    fn test_channel_config_v1() {
+        assert!(postcard::from_bytes::<ChannelConfig>(&CHANNEL_CONFIG_V1).is_err());
+
+        let mut channels = MockChannels::new();
+        channels.channel_state(0).stable_time = Time::new::<second>(30.0);
+        let mut store = MockFlash::default();
+        let mut value = Vec::new();
+        value.extend_from_slice(&CHANNEL_CONFIG_V1).unwrap();
+        store
+            .values
+            .push((String::from(CHANNEL_CONFIG_V1_KEY[0]), value))
+            .unwrap();
+
+        let config = read_channel_config(&store, &mut channels, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.center, CenterPoint::Override(0.5));
+        assert_eq!((config.pid.kp, config.pid.ki), (10.0, 0.5));
+        assert_eq!(config.pid.output_min, -2.0);
+        assert_eq!(config.pid.divisor, 1);
+        assert_eq!(config.pid_target, 25.0);
+        assert!(config.pid_engaged);
+        assert_eq!(config.polarity, Polarity::Reversed);
+        assert_eq!(config.bp, b_parameter::Parameters::default());
+        assert_eq!(
+            config.output_limits.max_i_neg,
+            ElectricCurrent::new::<ampere>(1.5)
+        );
+        assert_eq!(config.adc_postfilter, PostFilter::F20SPS);
+        // Added since, kept
+        assert_eq!(config.stable_time, Time::new::<second>(30.0));
+        assert_eq!(config.sensor, Sensor::BParameter);
+
+        assert!(matches!(
+            read_channel_config(&store, &mut channels, 1),
+            Ok(None)
+        ));
+    }
+
+    fn fields(problems: &Problems) -> Vec<&'static str, U32> {
+        problems.iter().map(|problem| problem.field).collect()
+    }
+
+    #[test]
+    fn test_channel_config_check() {
+        let mut channels = MockChannels::new();
+        let mut config = ChannelConfig::new(&mut channels, 1);
+        let mut problems = Problems::new();
+        config.check(1, &mut problems);
+        assert!(problems.is_empty());
+
+        config.output_limits.max_i_pos = ElectricCurrent::new::<ampere>(2.5);
+        config.output_limits.max_v = ElectricPotential::new::<volt>(-1.0);
+        config.pid.kp = -1.0;
+        config.pid.output_min = 1.0;
+        config.pid.output_max = 0.5;
+        config.t_min = Some(20.0);
+        config.t_max = Some(30.0);
+        config.pid_target = 35.0;
+        config.check(1, &mut problems);
+        assert_eq!(
+            &fields(&problems)[..],
+            ["max_v", "max_i_pos", "kp", "output_min", "target"]
+        );
+        assert!(problems.iter().all(|problem| problem.channel == Some(1)));
+    }
+
+    #[test]
+    fn test_channel_config_diff() {
+        let mut channels = MockChannels::new();
+        let saved = ChannelConfig::new(&mut channels, 0);
+        assert!(ChannelConfig::new(&mut channels, 0).diff(&saved).is_empty());
+
+        let state = channels.channel_state(0);
+        state.pid.parameters.ki = 0.5;
+        state.t_max = Some(40.0);
+        channels.set_max_i_neg(0, ElectricCurrent::new::<ampere>(1.0));
+        let live = ChannelConfig::new(&mut channels, 0);
+        assert_eq!(&live.diff(&saved)[..], ["ki", "max_i_neg", "t_max"]);
+    }
+
+    #[test]
+    fn test_device_config_v1() {
+        #[rustfmt::skip]
+        const DEVICE_CONFIG_V1: [u8; 21] = [
+            // fan: auto_mode, fan_pwm, k_a, k_b, k_c, hysteresis,
+            // min_pwm, mcu_curve
+            1, 80, 0, 0, 0x80, 0x3F, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 20, 0,
+            // log_level
+            3,
+        ];
+        let mut store = MockFlash::default();
+        assert!(matches!(read_device_config(&store), Ok(None)));
+        let mut value = Vec::new();
+        value.extend_from_slice(&DEVICE_CONFIG_V1).unwrap();
+        store
+            .values
+            .push((String::from(DEVICE_CONFIG_V1_KEY), value))
+            .unwrap();
+
+        let config = read_device_config(&store).unwrap().unwrap();
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert!(!config.safe_start);
+    }
+
+    #[test]
+    fn test_outputs_off_at_boot() {
+        assert!(!outputs_off_at_boot(PowerOn::Restore, false, true));
+        assert!(!outputs_off_at_boot(PowerOn::Restore, true, false));
+        assert!(outputs_off_at_boot(PowerOn::Restore, true, true));
+        assert!(outputs_off_at_boot(PowerOn::Off, false, false));
+    }
+
+    #[test]
+    fn test_config_document_len() {
+        // Those with the longest JSON, as `{:e}` formats them
+        let float = -1.31778565e-36_f32;
+        let double = -1.0000080530313386e-308_f64;
+        let mut channels = MockChannels::new();
+        let state = channels.channel_state(0);
+        state.center = CenterPoint::Override(float);
+        let pid = &mut state.pid.parameters;
+        for value in [
+            &mut pid.kp,
+            &mut pid.ki,
+            &mut pid.kd,
+            &mut pid.output_min,
+            &mut pid.output_max,
+            &mut pid.ramp_rate,
+            &mut pid.d_filter,
+            &mut pid.ff_offset,
+            &mut pid.ff_gain,
+            &mut pid.ff_reference,
+        ] {
+            *value = float;
+        }
+        pid.divisor = u32::MAX;
+        state.pid.target = double;
+        state.i_set = ElectricCurrent::new::<ampere>(double);
+        state.polarity = Polarity::Reversed;
+        state.bp = b_parameter::Parameters {
+            t0: ThermodynamicTemperature::new::<kelvin>(double),
+            r0: ElectricalResistance::new::<ohm>(double),
+            b: TemperatureInterval::new::<kelvin_interval>(double),
+        };
+        state.sh = steinhart_hart::Parameters {
+            a: double,
+            b: double,
+            c: double,
+        };
+        state.sensor = Sensor::SteinhartHart;
+        state.cal_offset = double;
+        state.cal_gain = double;
+        state.sw_filter = u32::MAX;
+        state.median_filter = u8::MAX;
+        state.stable_window = TemperatureInterval::new::<kelvin_interval>(double);
+        state.stable_time = Time::new::<second>(double);
+        state.dtdt_window = Time::new::<second>(double);
+        state.t_min = Some(double);
+        state.t_max = Some(double);
+        state.overcurrent_time = Some(Time::new::<second>(double));
+        state.v_set = Some(ElectricPotential::new::<volt>(double));
+        channels.set_max_v(0, ElectricPotential::new::<volt>(double));
+        channels.set_max_i_pos(0, ElectricCurrent::new::<ampere>(double));
+        channels.set_max_i_neg(0, ElectricCurrent::new::<ampere>(double));
+        channels.set_postfilter(0, Some(PostFilter::Invalid));
+
+        let device = ConfigDocument {
+            channels: None,
+            ipv4: Some(Ipv4Config {
+                address: [255; 4],
+                mask_len: 255,
+                gateway: Some([255; 4]),
+            }),
+            fan: Some(FanConfig::longest(float)),
+            power_on: Some(PowerOn::Restore),
+            selftest_boot: Some(false),
+        };
+        let channel = ConfigDocument::channel(&mut channels, 0);
+        // Room for the tag and the framing of a line in the send
+        // buffers, and in the one of `config load`
+        for document in [device, channel] {
+            let json = serde_json_core::to_vec::<U2048, _>(&document).unwrap();
+            assert!(json.len() < 1536, "{}", json.len());
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! The DAC that sets `i_set`, behind a trait so that boards with a
+//! substituted DAC run the same firmware. The hardware revision does
+//! not tell the chip, so it is the AD5680 unless set with `dac_chip`.
+
+use crate::{ad5680, dac8560, flash_store::FlashInterface};
+use log::error;
+use serde::{Deserialize, Serialize};
+use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+pub trait Dac {
+    type Error;
+
+    /// Largest code, for the full scale output
+    fn max_value(&self) -> u32;
+    /// Set the code, clamped to `max_value()`. Returns the code set.
+    fn set(&mut self, value: u32) -> Result<u32, Self::Error>;
+}
+
+impl<SPI: Transfer<u8>, S: OutputPin> Dac for ad5680::Dac<SPI, S> {
+    type Error = SPI::Error;
+
+    fn max_value(&self) -> u32 {
+        ad5680::MAX_VALUE
+    }
+
+    fn set(&mut self, value: u32) -> Result<u32, SPI::Error> {
+        ad5680::Dac::set(self, value)
+    }
+}
+
+impl<SPI: Transfer<u8>, S: OutputPin> Dac for dac8560::Dac<SPI, S> {
+    type Error = SPI::Error;
+
+    fn max_value(&self) -> u32 {
+        dac8560::MAX_VALUE
+    }
+
+    fn set(&mut self, value: u32) -> Result<u32, SPI::Error> {
+        dac8560::Dac::set(self, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DacChip {
+    /// 18 bits
+    Ad5680,
+    /// 16 bits
+    Dac8560,
+}
+
+/// Flash key of the chip set with `dac_chip`
+pub const FLASH_KEY: &str = "dac_chip";
+
+/// The chip saved with `dac_chip`, otherwise the AD5680
+pub fn load(store: &impl FlashInterface) -> DacChip {
+    match store.read_value(FLASH_KEY) {
+        Ok(Some(chip)) => chip,
+        Ok(None) => DacChip::Ad5680,
+        Err(e) => {
+            error!("cannot read dac_chip: {:?}", e);
+            DacChip::Ad5680
+        }
+    }
+}
+
+/// The DAC fitted for a channel
+pub enum BoardDac<SPI: Transfer<u8>, S: OutputPin> {
+    Ad5680(ad5680::Dac<SPI, S>),
+    Dac8560(dac8560::Dac<SPI, S>),
+}
+
+impl<SPI: Transfer<u8>, S: OutputPin> BoardDac<SPI, S> {
+    pub fn new(chip: DacChip, spi: SPI, sync: S) -> Self {
+        match chip {
+            DacChip::Ad5680 => BoardDac::Ad5680(ad5680::Dac::new(spi, sync)),
+            DacChip::Dac8560 => BoardDac::Dac8560(dac8560::Dac::new(spi, sync)),
+        }
+    }
+
+    pub fn chip(&self) -> DacChip {
+        match self {
+            BoardDac::Ad5680(_) => DacChip::Ad5680,
+            BoardDac::Dac8560(_) => DacChip::Dac8560,
+        }
+    }
+}
+
+impl<SPI: Transfer<u8>, S: OutputPin> Dac for BoardDac<SPI, S> {
+    type Error = SPI::Error;
+
+    fn max_value(&self) -> u32 {
+        match self {
+            BoardDac::Ad5680(dac) => dac.max_value(),
+            BoardDac::Dac8560(dac) => dac.max_value(),
+        }
+    }
+
+    fn set(&mut self, value: u32) -> Result<u32, SPI::Error> {
+        match self {
+            BoardDac::Ad5680(dac) => Dac::set(dac, value),
+            BoardDac::Dac8560(dac) => Dac::set(dac, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockDac, MockFlash};
+    use heapless::Vec;
+
+    #[test]
+    fn test_board_dac() {
+        let (dac, spi, sync) = MockDac::default().bus();
+        let mut board_dac = BoardDac::new(DacChip::Ad5680, spi, sync);
+        assert_eq!(board_dac.chip(), DacChip::Ad5680);
+        assert_eq!(board_dac.max_value(), ad5680::MAX_VALUE);
+        assert_eq!(board_dac.set(0x20000), Ok(0x20000));
+        assert_eq!(&dac.borrow().frames[..], [[0x08, 0x00, 0x00]]);
+
+        let (dac, spi, sync) = MockDac::default().bus();
+        let mut board_dac = BoardDac::new(DacChip::Dac8560, spi, sync);
+        assert_eq!(board_dac.chip(), DacChip::Dac8560);
+        assert_eq!(board_dac.max_value(), dac8560::MAX_VALUE);
+        assert_eq!(board_dac.set(0x20000), Ok(dac8560::MAX_VALUE));
+        assert_eq!(&dac.borrow().frames[..], [[0, 0xFF, 0xFF]]);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut flash = MockFlash::default();
+        assert_eq!(load(&flash), DacChip::Ad5680);
+        let mut value = Vec::new();
+        value.extend_from_slice(&[1]).unwrap();
+        flash.values.push((FLASH_KEY.into(), value)).unwrap();
+        assert_eq!(load(&flash), DacChip::Dac8560);
+    }
+}
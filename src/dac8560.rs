@@ -0,0 +1,55 @@
+use crate::timer::sleep;
+use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+/// 16 bits
+pub const MAX_VALUE: u32 = 0xFFFF;
+
+/// [DAC8560](https://www.ti.com/lit/ds/symlink/dac8560.pdf) DAC, a
+/// substitute for the AD5680 on the same SPI mode and clock. Its
+/// output range must be matched to the AD5680 by the reference and
+/// divider of the board.
+pub struct Dac<SPI: Transfer<u8>, S: OutputPin> {
+    spi: SPI,
+    sync: S,
+}
+
+impl<SPI: Transfer<u8>, S: OutputPin> Dac<SPI, S> {
+    pub fn new(spi: SPI, mut sync: S) -> Self {
+        let _ = sync.set_low();
+
+        Dac { spi, sync }
+    }
+
+    fn write(&mut self, buf: &mut [u8]) -> Result<(), SPI::Error> {
+        // pulse sync to start a new transfer, as for the AD5680
+        let _ = self.sync.set_high();
+        // must be high for >= 33 ns
+        sleep(1);
+        let _ = self.sync.set_low();
+        self.spi.transfer(buf)?;
+        Ok(())
+    }
+
+    /// Write the DAC register with the output powered up
+    pub fn set(&mut self, value: u32) -> Result<u32, SPI::Error> {
+        let value = value.min(MAX_VALUE);
+        let mut buf = [0, (value >> 8) as u8, value as u8];
+        self.write(&mut buf)?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockDac;
+
+    #[test]
+    fn test_set() {
+        let (dac, spi, sync) = MockDac::default().bus();
+        let mut dac8560 = Dac::new(spi, sync);
+        assert_eq!(dac8560.set(0x1234), Ok(0x1234));
+        assert_eq!(dac8560.set(0x3FFFF), Ok(MAX_VALUE));
+        assert_eq!(&dac.borrow().frames[..], [[0, 0x12, 0x34], [0, 0xFF, 0xFF]]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Shutting down the outputs when no client has been heard from for a
+//! while, when enabled with `deadman <seconds>`.
+//!
+//! Every command received over TCP or USB, such as `ping`, is marked
+//! by `activity()`. Once the timeout passes without one, `check()`
+//! reports it a single time, for the main loop to disengage the PIDs
+//! and zero the outputs. The next command arms it again.
+
+use crate::{
+    flash_store::{FlashInterface, FlashStore, StoreError},
+    interrupt::{self, Mutex},
+    timer,
+};
+use core::cell::RefCell;
+use log::error;
+
+const FLASH_KEY: &str = "deadman";
+
+struct Deadman {
+    /// Seconds without commands before shutting down
+    timeout: Option<u32>,
+    /// Time of the last command in milliseconds since boot
+    last_activity: u32,
+    /// Shut down since the last command
+    tripped: bool,
+}
+
+impl Deadman {
+    const fn new() -> Self {
+        Deadman {
+            timeout: None,
+            last_activity: 0,
+            tripped: false,
+        }
+    }
+
+    fn activity(&mut self, now: u32) {
+        self.last_activity = now;
+        self.tripped = false;
+    }
+
+    fn check(&mut self, now: u32) -> bool {
+        match self.timeout {
+            Some(timeout)
+                if !self.tripped
+                    && now.wrapping_sub(self.last_activity) >= timeout.saturating_mul(1000) =>
+            {
+                self.tripped = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+static STATE: Mutex<RefCell<Deadman>> = Mutex::new(RefCell::new(Deadman::new()));
+
+/// Read the setting from flash at boot. The timeout runs from boot
+/// until the first command.
+pub fn load(store: &mut FlashStore) {
+    match store.read_value::<Option<u32>>(FLASH_KEY) {
+        Ok(Some(timeout)) => interrupt::free(|cs| STATE.borrow(cs).borrow_mut().timeout = timeout),
+        Ok(None) => {}
+        Err(e) => error!("cannot read deadman: {:?}", e),
+    }
+}
+
+pub fn timeout() -> Option<u32> {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().timeout)
+}
+
+/// Set the timeout in seconds, or disable with `None`, and save the
+/// setting to flash
+pub fn set(store: &mut impl FlashInterface, timeout: Option<u32>) -> Result<(), StoreError> {
+    store.write_value(FLASH_KEY, &timeout, [0; 16])?;
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.timeout = timeout;
+        state.activity(timer::now());
+    });
+    Ok(())
+}
+
+/// Note a command from a client
+pub fn activity() {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().activity(timer::now()))
+}
+
+/// Whether the timeout has just passed without commands
+pub fn check() -> bool {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().check(timer::now()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut deadman = Deadman::new();
+        assert!(!deadman.check(100_000));
+        deadman.timeout = Some(10);
+        deadman.activity(1_000);
+        assert!(!deadman.check(10_999));
+        assert!(deadman.check(11_000));
+        // Only once until the next command
+        assert!(!deadman.check(30_000));
+        deadman.activity(30_000);
+        assert!(deadman.check(40_000));
+    }
+}
@@ -0,0 +1,265 @@
+//! Log of rare events kept in flash across power cycles, for
+//! diagnosing intermittent failures in the field.
+//!
+//! Events are recorded into a copy in RAM from anywhere, and written
+//! to flash from the main loop by `flush()`, at most every
+//! `FLUSH_INTERVAL` to spare the flash, and by `flush_now()` before a
+//! reset.
+
+use crate::{
+    channel_state::{Fault, SensorFault},
+    command_handler::JsonBuffer,
+    flash_store::FlashStore,
+    interrupt::{self, Mutex},
+    rtc, timer,
+};
+use core::cell::{Cell, RefCell};
+use log::error;
+use serde::{Deserialize, Serialize};
+use stm32f4xx_hal::stm32::RCC;
+
+/// Events kept, the oldest are overwritten
+const LOG_LEN: usize = 24;
+const FLASH_KEY: &str = "event_log";
+/// Milliseconds between writes of the log to flash
+const FLUSH_INTERVAL: u32 = 5 * 60_000;
+
+const RCC_CSR_RMVF: u32 = 1 << 24;
+const RCC_CSR_IWDGRSTF: u32 = 1 << 29;
+
+/// Log, and whether it has changed since it has last been written to
+/// flash
+static LOG: Mutex<RefCell<(EventLog, bool)>> = Mutex::new(RefCell::new((EventLog::new(), false)));
+/// `timer::now()` of the last write to flash
+static LAST_FLUSH: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    WatchdogReset,
+    Fault,
+    SensorFault,
+    ConfigSaved,
+    ConfigLoaded,
+    Ipv4Changed,
+    DacCalibrated,
+    Deadman,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Seconds since the Unix epoch, if the RTC has been set
+    time: Option<u32>,
+    /// Seconds since boot
+    uptime: u32,
+    event: EventKind,
+    channel: Option<u8>,
+    fault: Option<Fault>,
+    sensor_fault: Option<SensorFault>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, channel: Option<usize>) -> Self {
+        Event {
+            time: rtc::now().map(|time| (time.to_epoch_millis() / 1000) as u32),
+            uptime: (timer::now_u64() / 1000) as u32,
+            event,
+            channel: channel.map(|channel| channel as u8),
+            fault: None,
+            sensor_fault: None,
+        }
+    }
+
+    pub fn fault(channel: usize, fault: Fault) -> Self {
+        Event {
+            fault: Some(fault),
+            ..Event::new(EventKind::Fault, Some(channel))
+        }
+    }
+
+    pub fn sensor_fault(channel: usize, sensor_fault: SensorFault) -> Self {
+        Event {
+            sensor_fault: Some(sensor_fault),
+            ..Event::new(EventKind::SensorFault, Some(channel))
+        }
+    }
+
+    pub fn json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventLog {
+    events: [Option<Event>; LOG_LEN],
+    /// Index of the next event to write
+    next: u8,
+}
+
+impl EventLog {
+    const fn new() -> Self {
+        EventLog {
+            events: [None; LOG_LEN],
+            next: 0,
+        }
+    }
+
+    /// Returns whether the event has been added. A sensor fault that
+    /// repeats the last event is left out, as a flaky sensor would
+    /// flood the log otherwise.
+    fn push(&mut self, event: Event) -> bool {
+        let last = (usize::from(self.next) + LOG_LEN - 1) % LOG_LEN;
+        if let Some(last) = self.events[last] {
+            if event.event == EventKind::SensorFault
+                && (last.event, last.channel, last.sensor_fault)
+                    == (event.event, event.channel, event.sensor_fault)
+            {
+                return false;
+            }
+        }
+        self.events[usize::from(self.next)] = Some(event);
+        self.next = ((usize::from(self.next) + 1) % LOG_LEN) as u8;
+        true
+    }
+
+    /// Events from the oldest to the newest
+    fn iter(&self) -> impl Iterator<Item = &Event> {
+        let (newer, older) = self.events.split_at(usize::from(self.next) % LOG_LEN);
+        older.iter().chain(newer.iter()).flatten()
+    }
+}
+
+/// Record an event, to be written to flash by the next `flush()`
+pub fn record(event: Event) {
+    interrupt::free(|cs| {
+        let mut log = LOG.borrow(cs).borrow_mut();
+        if log.0.push(event) {
+            log.1 = true;
+        }
+    })
+}
+
+pub fn clear() {
+    interrupt::free(|cs| *LOG.borrow(cs).borrow_mut() = (EventLog::new(), true))
+}
+
+/// Read the log from flash at boot, and record a reset by the
+/// watchdog. Returns whether the watchdog has reset the device.
+pub fn load(store: &mut FlashStore) -> bool {
+    match store.read_value::<EventLog>(FLASH_KEY) {
+        Ok(Some(log)) => interrupt::free(|cs| LOG.borrow(cs).borrow_mut().0 = log),
+        Ok(None) => {}
+        Err(e) => error!("unable to load event log from flash: {:?}", e),
+    }
+
+    let watchdog_reset = take_watchdog_reset();
+    if watchdog_reset {
+        record(Event::new(EventKind::WatchdogReset, None));
+    }
+    watchdog_reset
+}
+
+/// Whether the last reset was by the watchdog, clearing the reset
+/// flags
+#[cfg(not(feature = "sim"))]
+fn take_watchdog_reset() -> bool {
+    let rcc = unsafe { &*RCC::ptr() };
+    let csr = rcc.csr.read().bits();
+    // Reset flags are kept until removed
+    rcc.csr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CSR_RMVF) });
+    csr & RCC_CSR_IWDGRSTF != 0
+}
+
+/// The simulation has no watchdog
+#[cfg(feature = "sim")]
+fn take_watchdog_reset() -> bool {
+    false
+}
+
+/// Whether a changed log may be written, right away after a quiet
+/// period
+fn flush_due(last_flush: Option<u32>, now: u32) -> bool {
+    match last_flush {
+        Some(time) => now.wrapping_sub(time) >= FLUSH_INTERVAL,
+        None => true,
+    }
+}
+
+/// Write the log to flash if events have been recorded since, and
+/// `FLUSH_INTERVAL` has passed since the last write
+pub fn flush(store: &mut FlashStore) {
+    let last_flush = interrupt::free(|cs| LAST_FLUSH.borrow(cs).get());
+    if flush_due(last_flush, timer::now()) {
+        flush_now(store);
+    }
+}
+
+/// Write the log to flash if events have been recorded since, as before
+/// a reset
+pub fn flush_now(store: &mut FlashStore) {
+    let log = interrupt::free(|cs| {
+        let mut log = LOG.borrow(cs).borrow_mut();
+        if log.1 {
+            log.1 = false;
+            Some(log.0.clone())
+        } else {
+            None
+        }
+    });
+    if let Some(log) = log {
+        interrupt::free(|cs| LAST_FLUSH.borrow(cs).set(Some(timer::now())));
+        let mut store_value_buf = [0u8; 1024];
+        if let Err(e) = store.write_value(FLASH_KEY, &log, &mut store_value_buf) {
+            error!("unable to save event log to flash: {:?}", e);
+        }
+    }
+}
+
+/// Number of events in the log
+pub fn len() -> usize {
+    interrupt::free(|cs| LOG.borrow(cs).borrow().0.iter().count())
+}
+
+/// Event `index`, counted from the oldest
+pub fn get(index: usize) -> Option<Event> {
+    interrupt::free(|cs| LOG.borrow(cs).borrow().0.iter().nth(index).copied())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(event: EventKind, uptime: u32) -> Event {
+        Event {
+            time: None,
+            uptime,
+            event,
+            channel: Some(0),
+            fault: None,
+            sensor_fault: Some(SensorFault::Open),
+        }
+    }
+
+    #[test]
+    fn test_event_log() {
+        let mut log = EventLog::new();
+        for uptime in 0..LOG_LEN as u32 + 2 {
+            assert!(log.push(event(EventKind::ConfigSaved, uptime)));
+        }
+        let uptimes = log.iter().map(|event| event.uptime);
+        assert!(uptimes.eq(2..LOG_LEN as u32 + 2));
+
+        assert!(log.push(event(EventKind::SensorFault, 100)));
+        assert!(!log.push(event(EventKind::SensorFault, 101)));
+        assert_eq!(log.iter().last().unwrap().uptime, 100);
+    }
+
+    #[test]
+    fn test_flush_due() {
+        assert!(flush_due(None, 0));
+        assert!(!flush_due(Some(1_000), 1_000 + FLUSH_INTERVAL - 1));
+        assert!(flush_due(Some(1_000), 1_000 + FLUSH_INTERVAL));
+        assert!(flush_due(Some(u32::MAX), FLUSH_INTERVAL));
+    }
+}
@@ -1,6 +1,8 @@
-use crate::{channels::MAX_TEC_I, command_handler::JsonBuffer, hw_rev::HWSettings};
+use crate::{
+    channels::MAX_TEC_I, command_handler::JsonBuffer, fan_tach, hw_rev::HWSettings, timer,
+};
 use num_traits::Float;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use stm32f4xx_hal::{
     pac::TIM8,
     pwm::{self, PwmChannels},
@@ -11,14 +13,31 @@ pub type FanPin = PwmChannels<TIM8, pwm::C4>;
 
 const MAX_USER_FAN_PWM: f32 = 100.0;
 const MIN_USER_FAN_PWM: f32 = 1.0;
+/// Time in milliseconds for the fan to spin up before it counts as
+/// stalled
+const STALL_TIME: u32 = 3_000;
 
 pub struct FanCtrl {
     fan: Option<FanPin>,
     fan_auto: bool,
     pwm_enabled: bool,
+    /// Duty cycle last set
+    duty: u16,
+    /// Time since which the fan is driven but does not turn
+    stalled_since: Option<u32>,
     k_a: f32,
     k_b: f32,
     k_c: f32,
+    /// Drop of the fan curve below the last setting in auto mode that
+    /// is needed to slow the fan down
+    hysteresis: f32,
+    /// Lowest setting in auto mode
+    min_pwm: u32,
+    /// MCU temperatures in degrees Celsius from which the fan is sped
+    /// up in auto mode, and at which it reaches full power
+    mcu_curve: Option<[f32; 2]>,
+    /// Setting last made in auto mode
+    auto_pwm: Option<f32>,
     abs_max_tec_i: f32,
     hw_settings: HWSettings,
 }
@@ -31,9 +50,15 @@ impl FanCtrl {
             // but allow to turn it at the user's own risk
             fan_auto: hw_settings.fan_pwm_recommended,
             pwm_enabled: false,
+            duty: 0,
+            stalled_since: None,
             k_a: hw_settings.fan_k_a,
             k_b: hw_settings.fan_k_b,
             k_c: hw_settings.fan_k_c,
+            hysteresis: 0.0,
+            min_pwm: MIN_USER_FAN_PWM as u32,
+            mcu_curve: None,
+            auto_pwm: None,
             abs_max_tec_i: 0f32,
             hw_settings,
         };
@@ -43,27 +68,68 @@ impl FanCtrl {
         fan_ctrl
     }
 
-    pub fn cycle(&mut self, abs_max_tec_i: ElectricCurrent) {
+    pub fn cycle(&mut self, abs_max_tec_i: ElectricCurrent, mcu_temperature: f64) {
         self.abs_max_tec_i = abs_max_tec_i.get::<ampere>() as f32;
         if self.fan_auto && self.hw_settings.fan_available {
             let scaled_current = self.abs_max_tec_i / MAX_TEC_I.get::<ampere>() as f32;
-            // do not limit upper bound, as it will be limited in the set_pwm()
-            let pwm = (MAX_USER_FAN_PWM
-                * (scaled_current * (scaled_current * self.k_a + self.k_b) + self.k_c))
-                as u32;
-            self.set_pwm(pwm);
+            let mut pwm = MAX_USER_FAN_PWM
+                * (scaled_current * (scaled_current * self.k_a + self.k_b) + self.k_c);
+            // a hot board speeds the fan up even at low currents
+            if let Some([t_low, t_high]) = self.mcu_curve {
+                let scaled_temperature = (mcu_temperature as f32 - t_low) / (t_high - t_low);
+                pwm = pwm.max(MAX_USER_FAN_PWM * scaled_temperature);
+            }
+            // limit upper bound too, for the hysteresis to apply below it
+            let pwm = pwm.clamp(self.min_pwm as f32, MAX_USER_FAN_PWM);
+            // speed up at once, but slow down only on a clear drop
+            let pwm = match self.auto_pwm {
+                Some(last) if pwm < last && last - pwm <= self.hysteresis => last,
+                _ => pwm,
+            };
+            self.auto_pwm = Some(pwm);
+            self.set_pwm(pwm as u32);
+        }
+        self.update_stall();
+    }
+
+    /// Drop the fan to minimum speed while the device is idle. A fan
+    /// speed set by the user is left untouched.
+    pub fn idle(&mut self) {
+        self.abs_max_tec_i = 0f32;
+        if self.fan_auto && self.hw_settings.fan_available {
+            self.auto_pwm = None;
+            self.set_pwm(self.min_pwm);
         }
+        self.update_stall();
+    }
+
+    fn update_stall(&mut self) {
+        let driven = self.hw_settings.fan_available && self.pwm_enabled && self.duty > 0;
+        if !driven || fan_tach::rpm() > 0 {
+            self.stalled_since = None;
+        } else if self.stalled_since.is_none() {
+            self.stalled_since = Some(timer::now());
+        }
+    }
+
+    /// Whether the fan has not turned for `STALL_TIME` while driven
+    pub fn stalled(&self) -> bool {
+        matches!(self.stalled_since, Some(since) if timer::now().wrapping_sub(since) >= STALL_TIME)
     }
 
     pub fn summary(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         if self.hw_settings.fan_available {
             let summary = FanSummary {
                 fan_pwm: self.get_pwm(),
+                fan_rpm: fan_tach::rpm(),
                 abs_max_tec_i: self.abs_max_tec_i,
                 auto_mode: self.fan_auto,
                 k_a: self.k_a,
                 k_b: self.k_b,
                 k_c: self.k_c,
+                hysteresis: self.hysteresis,
+                min_pwm: self.min_pwm,
+                mcu_curve: self.mcu_curve,
             };
             serde_json_core::to_vec(&summary)
         } else {
@@ -72,6 +138,32 @@ impl FanCtrl {
         }
     }
 
+    pub fn config(&self) -> FanConfig {
+        FanConfig {
+            auto_mode: self.fan_auto,
+            fan_pwm: self.get_pwm(),
+            k_a: self.k_a,
+            k_b: self.k_b,
+            k_c: self.k_c,
+            hysteresis: self.hysteresis,
+            min_pwm: self.min_pwm,
+            mcu_curve: self.mcu_curve,
+        }
+    }
+
+    /// Apply settings from `config dump`, the PWM duty cycle only
+    /// without auto mode and if there is a fan
+    pub fn apply_config(&mut self, config: &FanConfig) {
+        self.set_curve(config.k_a, config.k_b, config.k_c);
+        self.set_hysteresis(config.hysteresis);
+        self.set_min_pwm(config.min_pwm);
+        self.set_mcu_curve(config.mcu_curve);
+        self.set_auto_mode(config.auto_mode);
+        if !config.auto_mode && self.fan_available() {
+            self.set_pwm(config.fan_pwm);
+        }
+    }
+
     pub fn set_auto_mode(&mut self, fan_auto: bool) {
         self.fan_auto = fan_auto;
     }
@@ -80,6 +172,23 @@ impl FanCtrl {
         self.k_a = k_a;
         self.k_b = k_b;
         self.k_c = k_c;
+        self.auto_pwm = None;
+    }
+
+    pub fn set_hysteresis(&mut self, hysteresis: f32) {
+        self.hysteresis = hysteresis.max(0.0);
+    }
+
+    /// Speed the fan up between MCU temperatures `t_low` and `t_high`,
+    /// or not with `None`
+    pub fn set_mcu_curve(&mut self, mcu_curve: Option<[f32; 2]>) {
+        self.mcu_curve = mcu_curve;
+        self.auto_pwm = None;
+    }
+
+    pub fn set_min_pwm(&mut self, min_pwm: u32) {
+        self.min_pwm = min_pwm.clamp(MIN_USER_FAN_PWM as u32, MAX_USER_FAN_PWM as u32);
+        self.auto_pwm = None;
     }
 
     pub fn restore_defaults(&mut self) {
@@ -88,6 +197,9 @@ impl FanCtrl {
             self.hw_settings.fan_k_b,
             self.hw_settings.fan_k_c,
         );
+        self.set_hysteresis(0.0);
+        self.set_min_pwm(MIN_USER_FAN_PWM as u32);
+        self.set_mcu_curve(None);
     }
 
     pub fn set_pwm(&mut self, fan_pwm: u32) -> f32 {
@@ -106,9 +218,41 @@ impl FanCtrl {
         let max = fan.get_max_duty();
         let value = ((duty * (max as f32)) as u16).min(max);
         fan.set_duty(value);
+        self.duty = value;
         value as f32 / (max as f32)
     }
 
+    /// Whether the PWM duty cycle follows a setting of 50. Skipped
+    /// with `None` without a fan. The previous duty cycle is restored
+    /// afterwards.
+    pub fn self_test(&mut self) -> Option<bool> {
+        if self.fan.is_none() || !self.hw_settings.fan_available {
+            return None;
+        }
+        let pwm_enabled = self.pwm_enabled;
+        let duty = self.fan.as_ref().unwrap().get_duty();
+        self.set_pwm(50);
+        let pass = self.get_pwm() == 50;
+        let fan = self.fan.as_mut().unwrap();
+        fan.set_duty(duty);
+        self.duty = duty;
+        if !pwm_enabled {
+            fan.disable();
+            self.pwm_enabled = false;
+        }
+        Some(pass)
+    }
+
+    /// Whether the fan does not turn as driven: the board has a fan,
+    /// but its PWM output is missing, or the tachometer has seen no
+    /// pulse for `STALL_TIME` while it is driven.
+    pub fn failed(&self) -> bool {
+        if !self.hw_settings.fan_available {
+            return false;
+        }
+        self.fan.is_none() || self.stalled()
+    }
+
     pub fn fan_pwm_recommended(&self) -> bool {
         self.hw_settings.fan_pwm_recommended
     }
@@ -140,6 +284,7 @@ impl FanCtrl {
             fan.set_duty(0);
             fan.enable();
             self.pwm_enabled = true;
+            self.duty = 0;
             true
         } else {
             false
@@ -151,12 +296,54 @@ fn scale_number(unscaled: f32, to_min: f32, to_max: f32, from_min: f32, from_max
     (to_max - to_min) * (unscaled - from_min) / (from_max - from_min) + to_min
 }
 
+/// Fan settings of a config document
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FanConfig {
+    auto_mode: bool,
+    fan_pwm: u32,
+    k_a: f32,
+    k_b: f32,
+    k_c: f32,
+    #[serde(default)]
+    hysteresis: f32,
+    #[serde(default = "default_min_pwm")]
+    min_pwm: u32,
+    #[serde(default)]
+    mcu_curve: Option<[f32; 2]>,
+}
+
+fn default_min_pwm() -> u32 {
+    MIN_USER_FAN_PWM as u32
+}
+
+#[cfg(test)]
+impl FanConfig {
+    /// With every setting at the longest in JSON, `float` for those of
+    /// `f32`
+    pub fn longest(float: f32) -> Self {
+        FanConfig {
+            auto_mode: false,
+            fan_pwm: u32::MAX,
+            k_a: float,
+            k_b: float,
+            k_c: float,
+            hysteresis: float,
+            min_pwm: u32::MAX,
+            mcu_curve: Some([float; 2]),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct FanSummary {
     fan_pwm: u32,
+    fan_rpm: u32,
     abs_max_tec_i: f32,
     auto_mode: bool,
     k_a: f32,
     k_b: f32,
     k_c: f32,
+    hysteresis: f32,
+    min_pwm: u32,
+    mcu_curve: Option<[f32; 2]>,
 }
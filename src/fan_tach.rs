@@ -0,0 +1,126 @@
+//! Fan speed from the tachometer signal on PD12, measured as the
+//! period between pulses by input capture on TIM4 channel 1.
+
+use crate::{
+    interrupt::{free, Mutex},
+    timer,
+};
+use core::cell::RefCell;
+use stm32f4xx_hal::{
+    gpio::{gpiod::PD12, Alternate, AF2},
+    rcc::Clocks,
+    stm32::{interrupt, Interrupt, NVIC, RCC, TIM4},
+};
+
+/// Counter rate in Hz, so that the 16-bit counter wraps around
+/// every 655 ms
+const TICK_RATE: u32 = 100_000;
+/// Tachometer pulses per revolution of a PC fan
+const PULSES_PER_REV: u32 = 2;
+/// Longest period in milliseconds that can be told from the counter
+/// wrapping around, about 50 rpm
+const MAX_PERIOD: u32 = 600;
+/// Time in milliseconds without a pulse after which the fan is
+/// considered stopped
+const STOP_TIMEOUT: u32 = 1_000;
+
+const RCC_APB1ENR_TIM4EN: u32 = 1 << 2;
+const TIM_CR1_CEN: u32 = 1 << 0;
+const TIM_DIER_CC1IE: u32 = 1 << 1;
+const TIM_SR_CC1IF: u32 = 1 << 1;
+const TIM_EGR_UG: u32 = 1 << 0;
+/// CC1 channel is an input, mapped on TI1
+const TIM_CCMR1_CC1S_TI1: u32 = 0b01 << 0;
+/// Input capture 1 filter: 8 samples at fDTS/32
+const TIM_CCMR1_IC1F: u32 = 0b1111 << 4;
+const TIM_CCER_CC1E: u32 = 1 << 0;
+
+struct Tach {
+    /// Last captured counter value, and the time of its capture
+    last: Option<(u16, u32)>,
+    /// Counter ticks between the last two pulses
+    period: Option<u32>,
+}
+
+static TACH: Mutex<RefCell<Tach>> = Mutex::new(RefCell::new(Tach {
+    last: None,
+    period: None,
+}));
+
+/// Start capturing rising edges of the tachometer signal
+pub fn setup(tim4: TIM4, _pin: PD12<Alternate<AF2>>, clocks: &Clocks) {
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.apb1enr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_APB1ENR_TIM4EN) });
+    // Timers on APB1 run at twice its clock when it is divided
+    let timer_clock = clocks.pclk1().0 * if clocks.ppre1() == 1 { 1 } else { 2 };
+    tim4.psc
+        .write(|w| unsafe { w.bits(timer_clock / TICK_RATE - 1) });
+    tim4.arr.write(|w| unsafe { w.bits(0xFFFF) });
+    tim4.ccmr1_input()
+        .write(|w| unsafe { w.bits(TIM_CCMR1_CC1S_TI1 | TIM_CCMR1_IC1F) });
+    tim4.ccer.write(|w| unsafe { w.bits(TIM_CCER_CC1E) });
+    // Load the prescaler
+    tim4.egr.write(|w| unsafe { w.bits(TIM_EGR_UG) });
+    tim4.sr.write(|w| unsafe { w.bits(0) });
+    tim4.dier.write(|w| unsafe { w.bits(TIM_DIER_CC1IE) });
+    tim4.cr1.write(|w| unsafe { w.bits(TIM_CR1_CEN) });
+    unsafe {
+        NVIC::unmask(Interrupt::TIM4);
+    }
+}
+
+/// Fan speed in revolutions per minute, 0 when stopped
+pub fn rpm() -> u32 {
+    let now = timer::now();
+    free(|cs| {
+        let tach = TACH.borrow(cs).borrow();
+        match (tach.last, tach.period) {
+            (Some((_, time)), Some(period)) if now.wrapping_sub(time) < STOP_TIMEOUT => {
+                period_rpm(period)
+            }
+            _ => 0,
+        }
+    })
+}
+
+fn period_rpm(period: u32) -> u32 {
+    if period == 0 {
+        0
+    } else {
+        60 * TICK_RATE / (PULSES_PER_REV * period)
+    }
+}
+
+#[interrupt]
+fn TIM4() {
+    let tim4 = unsafe { &*TIM4::ptr() };
+    if tim4.sr.read().bits() & TIM_SR_CC1IF == 0 {
+        return;
+    }
+    // Reading the captured value clears CC1IF
+    let capture = tim4.ccr1.read().bits() as u16;
+    let now = timer::now();
+    free(|cs| {
+        let mut tach = TACH.borrow(cs).borrow_mut();
+        tach.period = match tach.last {
+            Some((last, time)) if now.wrapping_sub(time) < MAX_PERIOD => {
+                Some(u32::from(capture.wrapping_sub(last)))
+            }
+            _ => None,
+        };
+        tach.last = Some((capture, now));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_period_rpm() {
+        // 2 pulses per revolution at 100 Hz
+        assert_eq!(period_rpm(1_000), 3_000);
+        assert_eq!(period_rpm(0), 0);
+    }
+}
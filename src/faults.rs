@@ -0,0 +1,118 @@
+//! Latched fault conditions of the whole device in one place, for
+//! `faults` and `clear_faults`.
+//!
+//! Each condition is a bit of `mask`, so that clients can tell at a
+//! glance whether anything needs attention.
+
+use crate::{
+    alarms,
+    channel_state::{Fault, SensorFault},
+    channels::{ChannelsInterface, CHANNELS},
+    command_handler::JsonBuffer,
+};
+use serde::Serialize;
+
+/// Sensor fault on a channel
+pub const SENSOR: u8 = 1 << 0;
+/// Temperature beyond an interlock limit
+pub const OVER_TEMPERATURE: u8 = 1 << 1;
+/// TEC current at its limit for too long
+pub const OVER_CURRENT: u8 = 1 << 2;
+/// No data from the ADC
+pub const ADC_SILENT: u8 = 1 << 3;
+/// Fan not driven as set, or stalled
+pub const FAN: u8 = 1 << 4;
+/// Last reset was by the watchdog
+pub const WATCHDOG_RESET: u8 = 1 << 5;
+
+#[derive(Serialize)]
+struct ChannelFaults {
+    fault: Option<Fault>,
+    sensor_fault: Option<SensorFault>,
+}
+
+#[derive(Serialize)]
+pub struct FaultStatus {
+    mask: u8,
+    channels: [ChannelFaults; CHANNELS],
+    fan: bool,
+    watchdog_reset: bool,
+}
+
+impl FaultStatus {
+    pub fn new(channels: &mut dyn ChannelsInterface, fan: bool, watchdog_reset: bool) -> Self {
+        let channels = core::array::from_fn(|channel| {
+            let state = channels.channel_state(channel);
+            ChannelFaults {
+                fault: state.fault,
+                sensor_fault: state.sensor_fault,
+            }
+        });
+        let mut status = FaultStatus {
+            mask: 0,
+            channels,
+            fan,
+            watchdog_reset,
+        };
+        status.mask = status.bits();
+        status
+    }
+
+    fn bits(&self) -> u8 {
+        let mut mask = 0;
+        for channel in self.channels.iter() {
+            if channel.sensor_fault.is_some() {
+                mask |= SENSOR;
+            }
+            mask |= match channel.fault {
+                Some(Fault::UnderTemperature | Fault::OverTemperature) => OVER_TEMPERATURE,
+                Some(Fault::OverCurrent) => OVER_CURRENT,
+                Some(Fault::AdcSilent) => ADC_SILENT,
+                None => 0,
+            };
+        }
+        if self.fan {
+            mask |= FAN;
+        }
+        if self.watchdog_reset {
+            mask |= WATCHDOG_RESET;
+        }
+        mask
+    }
+
+    pub fn json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
+    }
+}
+
+/// Clear the latched faults of both channels and the watchdog reset.
+/// The outputs stay off until enabled again. Sensor and fan faults
+/// last only as long as their condition.
+pub fn clear(channels: &mut dyn ChannelsInterface) {
+    for channel in 0..CHANNELS {
+        channels.channel_state(channel).fault = None;
+    }
+    alarms::clear_watchdog_reset();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockChannels;
+
+    #[test]
+    fn test_mask() {
+        let mut channels = MockChannels::new();
+        assert_eq!(FaultStatus::new(&mut channels, false, false).mask, 0);
+        channels.states[0].sensor_fault = Some(SensorFault::Open);
+        channels.states[1].fault = Some(Fault::OverCurrent);
+        let status = FaultStatus::new(&mut channels, true, false);
+        assert_eq!(status.mask, SENSOR | OVER_CURRENT | FAN);
+        channels.states[0].fault = Some(Fault::UnderTemperature);
+        let status = FaultStatus::new(&mut channels, false, true);
+        assert_eq!(
+            status.mask,
+            SENSOR | OVER_TEMPERATURE | OVER_CURRENT | WATCHDOG_RESET
+        );
+    }
+}
@@ -1,4 +1,11 @@
+use crate::command_parser::ProfileName;
+use core::fmt::Write;
+use heapless::{
+    consts::{U24, U8},
+    String, Vec,
+};
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 use sfkv::{Store, StoreBackend};
 use stm32f4xx_hal::{
     flash::{Error, FlashExt},
@@ -7,9 +14,22 @@ use stm32f4xx_hal::{
 
 /// 16 KiB
 pub const FLASH_SECTOR_SIZE: usize = 0x4000;
+/// First of the sectors reserved for the store in memory.x
 pub const FLASH_SECTOR: u8 = 12;
+/// Sectors the store rotates through, one more on each compaction, to
+/// spread the wear of erasing
+pub const FLASH_SECTORS: usize = 4;
 static mut BACKUP_SPACE: [u8; FLASH_SECTOR_SIZE] = [0; FLASH_SECTOR_SIZE];
 
+/// Each sector starts with `HEADER_MAGIC` and a generation, both
+/// little-endian `u32`. The sector of the highest generation holds the
+/// store, unless a reset has cut its compaction short, which leaves the
+/// previous generation in use. A store written before the rotation has
+/// no header, and is kept in the first sector until its next
+/// compaction.
+const HEADER_MAGIC: u32 = 0x9a5e_17c3;
+const HEADER_LEN: usize = 8;
+
 extern "C" {
     // These are from memory.x
     static _config_start: usize;
@@ -18,42 +38,252 @@ extern "C" {
 
 pub struct FlashBackend {
     flash: FLASH,
+    /// Index of the sector holding the store
+    sector: usize,
+    /// Generation of that sector, `None` if it has no header
+    generation: Option<u32>,
 }
 
 fn get_offset() -> usize {
     unsafe { (&_config_start as *const usize as usize) - (&_flash_start as *const usize as usize) }
 }
 
+fn sector_offset(sector: usize) -> usize {
+    get_offset() + sector * FLASH_SECTOR_SIZE
+}
+
+impl FlashBackend {
+    /// Find the sector of the highest generation, or of the previous
+    /// one if the compaction into it has not completed
+    fn new(flash: FLASH) -> Self {
+        let mut backend = FlashBackend {
+            flash,
+            sector: 0,
+            generation: None,
+        };
+        for sector in 0..FLASH_SECTORS {
+            let generation = backend.header(sector);
+            if generation.is_some() && generation > backend.generation {
+                backend.sector = sector;
+                backend.generation = generation;
+            }
+        }
+
+        // The compaction into generation 0 is from the store without
+        // a header
+        let previous = match backend.generation {
+            Some(0) => Some(0).filter(|&sector| backend.header(sector).is_none()),
+            Some(generation) => {
+                (0..FLASH_SECTORS).find(|&sector| backend.header(sector) == Some(generation - 1))
+            }
+            None => None,
+        };
+        if let Some(previous) = previous {
+            let previous_generation = backend.header(previous);
+            let data = backend.sector_data(backend.sector, backend.generation);
+            if !compacted(data, backend.sector_data(previous, previous_generation)) {
+                error!(
+                    "compaction into store flash sector {} incomplete, using sector {}",
+                    FLASH_SECTOR + backend.sector as u8,
+                    FLASH_SECTOR + previous as u8
+                );
+                backend.sector = previous;
+                backend.generation = previous_generation;
+            }
+        }
+        backend
+    }
+
+    fn header(&self, sector: usize) -> Option<u32> {
+        let offset = sector_offset(sector);
+        let header = &self.flash.read()[offset..(offset + HEADER_LEN)];
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&header[(4 * i)..(4 * i + 4)]);
+            u32::from_le_bytes(bytes)
+        };
+        if word(0) == HEADER_MAGIC {
+            Some(word(1))
+        } else {
+            None
+        }
+    }
+
+    /// Offset of the store data in flash
+    fn data_offset(&self) -> usize {
+        data_offset(self.sector, self.generation)
+    }
+
+    fn sector_data(&self, sector: usize, generation: Option<u32>) -> &[u8] {
+        &self.flash.read()
+            [data_offset(sector, generation)..(sector_offset(sector) + FLASH_SECTOR_SIZE)]
+    }
+}
+
+fn data_offset(sector: usize, generation: Option<u32>) -> usize {
+    let header_len = if generation.is_some() { HEADER_LEN } else { 0 };
+    sector_offset(sector) + header_len
+}
+
+/// Records of an sfkv store, each a `u32` big-endian size including
+/// itself, the key, a 0 byte, and the value, up to the erased flash.
+/// An invalid record is yielded as `None`, and ends the iteration.
+struct Records<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Option<(&'a [u8], &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut size = [0; 4];
+        size.copy_from_slice(self.data.get(self.offset..(self.offset + 4))?);
+        let size = u32::from_be_bytes(size);
+        if size == u32::MAX {
+            return None;
+        }
+        let record = self
+            .data
+            .get((self.offset + 4)..(self.offset + size as usize))
+            .and_then(|body| {
+                let separator = body.iter().position(|&byte| byte == 0)?;
+                Some((&body[..separator], &body[(separator + 1)..]))
+            });
+        self.offset = match record {
+            Some(_) => self.offset + size as usize,
+            None => self.data.len(),
+        };
+        Some(record)
+    }
+}
+
+/// Whether `data` holds each key that a compaction of `previous` keeps,
+/// those with a value in their last record
+fn compacted(data: &[u8], previous: &[u8]) -> bool {
+    let records = |data| Records { data, offset: 0 };
+    // A corrupt store is erased rather than compacted
+    if records(previous).any(|record| record.is_none()) {
+        return true;
+    }
+    records(previous)
+        .flatten()
+        .enumerate()
+        .all(|(index, (key, value))| {
+            value.is_empty()
+                || records(previous)
+                    .flatten()
+                    .skip(index + 1)
+                    .any(|(later, _)| later == key)
+                || records(data).flatten().any(|(other, _)| other == key)
+        })
+}
+
 impl StoreBackend for FlashBackend {
     type Data = [u8];
 
     fn data(&self) -> &Self::Data {
-        &self.flash.read()[get_offset()..(get_offset() + FLASH_SECTOR_SIZE)]
+        &self.flash.read()[self.data_offset()..(sector_offset(self.sector) + FLASH_SECTOR_SIZE)]
     }
 
     type Error = Error;
+    /// Move on to the next sector, leaving the current one as it is
+    /// until the rotation comes back to it
     fn erase(&mut self) -> Result<(), Self::Error> {
-        info!("erasing store flash");
-        self.flash.unlocked().erase(FLASH_SECTOR)
+        let sector = (self.sector + 1) % FLASH_SECTORS;
+        let generation = self.generation.map_or(0, |generation| generation + 1);
+        info!(
+            "erasing store flash sector {}, generation {}",
+            FLASH_SECTOR + sector as u8,
+            generation
+        );
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        let mut flash = self.flash.unlocked();
+        flash.erase(FLASH_SECTOR + sector as u8)?;
+        flash.program(sector_offset(sector), header.iter())?;
+        self.sector = sector;
+        self.generation = Some(generation);
+        Ok(())
     }
 
     fn program(&mut self, offset: usize, payload: &[u8]) -> Result<(), Self::Error> {
-        self.flash
-            .unlocked()
-            .program(get_offset() + offset, payload.iter())
+        let offset = self.data_offset() + offset;
+        self.flash.unlocked().program(offset, payload.iter())
     }
 
     fn backup_space(&self) -> &'static mut [u8] {
-        unsafe { &mut BACKUP_SPACE }
+        let len = self.data().len();
+        unsafe { &mut BACKUP_SPACE[..len] }
     }
 }
 
+#[cfg(not(feature = "sim"))]
 pub type FlashStore = Store<FlashBackend>;
+/// The simulation keeps the store in a file
+#[cfg(feature = "sim")]
+pub type FlashStore = Store<crate::sim::FileBackend>;
+pub type StoreError = sfkv::Error<Error>;
+
+/// Values in the store as used by the command handler, implemented by
+/// `FlashStore` on the hardware and by a mock in the host tests
+pub trait FlashInterface {
+    fn read_value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<Option<T>, StoreError>;
+    fn write_value<T: Serialize, V: AsMut<[u8]>>(
+        &mut self,
+        key: &str,
+        value: &T,
+        value_buf: V,
+    ) -> Result<(), StoreError>;
+    fn remove(&mut self, key: &str) -> Result<(), StoreError>;
+}
+
+impl FlashInterface for FlashStore {
+    fn read_value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<Option<T>, StoreError> {
+        Store::read_value(self, key)
+    }
+
+    fn write_value<T: Serialize, V: AsMut<[u8]>>(
+        &mut self,
+        key: &str,
+        value: &T,
+        value_buf: V,
+    ) -> Result<(), StoreError> {
+        Store::write_value(self, key, value, value_buf)
+    }
 
+    fn remove(&mut self, key: &str) -> Result<(), StoreError> {
+        Store::remove(self, key)
+    }
+}
+
+/// Names of the saved config profiles
+pub type Profiles = Vec<ProfileName, U8>;
+const PROFILES_KEY: &str = "profiles";
+
+#[derive(Debug)]
+pub enum ProfileError {
+    Store(StoreError),
+    NotFound,
+    /// No room for another profile in `Profiles`
+    TooMany,
+}
+
+impl From<StoreError> for ProfileError {
+    fn from(e: StoreError) -> Self {
+        ProfileError::Store(e)
+    }
+}
+
+#[cfg(not(feature = "sim"))]
 pub fn store(flash: FLASH) -> FlashStore {
-    let backend = FlashBackend { flash };
-    let mut store = FlashStore::new(backend);
+    let backend = FlashBackend::new(flash);
+    checked(FlashStore::new(backend))
+}
 
+/// `store`, erased if it cannot be read
+pub fn checked(mut store: FlashStore) -> FlashStore {
     // just try to read the store
     match store.get_bytes_used() {
         Ok(_) => {}
@@ -67,3 +297,118 @@ pub fn store(flash: FLASH) -> FlashStore {
 
     store
 }
+
+fn profile_key(name: &str) -> String<U24> {
+    let mut key = String::new();
+    let _ = write!(key, "profile_{}", name);
+    key
+}
+
+pub fn profiles(store: &impl FlashInterface) -> Result<Profiles, StoreError> {
+    Ok(store.read_value(PROFILES_KEY)?.unwrap_or_else(Vec::new))
+}
+
+/// Save `value` as profile `name`, replacing a profile of the same
+/// name
+pub fn save_profile<T: Serialize>(
+    store: &mut impl FlashInterface,
+    name: &ProfileName,
+    value: &T,
+) -> Result<(), ProfileError> {
+    let mut profiles = profiles(store)?;
+    if !profiles.contains(name) {
+        profiles
+            .push(name.clone())
+            .map_err(|_| ProfileError::TooMany)?;
+    }
+    let mut store_value_buf = [0u8; 512];
+    store.write_value(&profile_key(name), value, &mut store_value_buf)?;
+    store.write_value(PROFILES_KEY, &profiles, [0; 160])?;
+    Ok(())
+}
+
+pub fn load_profile<'a, T: Deserialize<'a>>(
+    store: &'a impl FlashInterface,
+    name: &ProfileName,
+) -> Result<T, ProfileError> {
+    store
+        .read_value(&profile_key(name))?
+        .ok_or(ProfileError::NotFound)
+}
+
+pub fn remove_profile(
+    store: &mut impl FlashInterface,
+    name: &ProfileName,
+) -> Result<(), ProfileError> {
+    let profiles = profiles(store)?;
+    if !profiles.contains(name) {
+        return Err(ProfileError::NotFound);
+    }
+    let profiles = profiles
+        .into_iter()
+        .filter(|profile| profile != name)
+        .collect::<Profiles>();
+    store.remove(&profile_key(name))?;
+    store.write_value(PROFILES_KEY, &profiles, [0; 160])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Store of `records`, followed by erased flash
+    fn store(records: &[(&[u8], &[u8])]) -> [u8; 64] {
+        let mut data = [0xff; 64];
+        let mut offset = 0;
+        for (key, value) in records {
+            let size = 4 + key.len() + 1 + value.len();
+            data[offset..(offset + 4)].copy_from_slice(&(size as u32).to_be_bytes());
+            data[(offset + 4)..(offset + 4 + key.len())].copy_from_slice(key);
+            data[offset + 4 + key.len()] = 0;
+            data[(offset + 5 + key.len())..(offset + size)].copy_from_slice(value);
+            offset += size;
+        }
+        data
+    }
+
+    #[test]
+    fn test_records() {
+        let data = store(&[(b"ipv4", b"\x01\x02"), (b"name", b"")]);
+        let mut records = Records {
+            data: &data,
+            offset: 0,
+        };
+        assert_eq!(records.next(), Some(Some((&b"ipv4"[..], &b"\x01\x02"[..]))));
+        assert_eq!(records.next(), Some(Some((&b"name"[..], &b""[..]))));
+        assert_eq!(records.next(), None);
+
+        let mut data = data;
+        data[3] = 100;
+        let mut records = Records {
+            data: &data,
+            offset: 0,
+        };
+        assert_eq!(records.next(), Some(None));
+        assert_eq!(records.next(), None);
+    }
+
+    #[test]
+    fn test_compacted() {
+        let previous = store(&[
+            (b"ipv4", b"\x01"),
+            (b"name", b"a"),
+            (b"ipv4", b"\x02"),
+            (b"name", b""),
+        ]);
+        assert!(compacted(&store(&[(b"ipv4", b"\x02")]), &previous));
+        // Cut short by a reset
+        assert!(!compacted(&store(&[]), &previous));
+        assert!(compacted(&store(&[]), &store(&[(b"name", b"")])));
+
+        // Nothing to recover from a corrupt store
+        let mut corrupt = previous;
+        corrupt[3] = 0;
+        assert!(compacted(&store(&[]), &corrupt));
+    }
+}
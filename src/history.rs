@@ -0,0 +1,118 @@
+//! Recent temperature history of each channel, for inspecting
+//! transient events after the fact.
+//!
+//! Declared globally, as it is too large for the stack in CCMRAM.
+
+use crate::{
+    channels::CHANNELS,
+    interrupt::{self, Mutex},
+};
+use core::cell::RefCell;
+
+/// Samples kept per channel
+pub const HISTORY_LEN: usize = 2048;
+/// ADC samples averaged into each history sample
+const DECIMATION: u32 = 8;
+
+const EMPTY: History = History::new();
+static HISTORY: Mutex<RefCell<[History; CHANNELS]>> = Mutex::new(RefCell::new([EMPTY; CHANNELS]));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Time of the last averaged ADC sample in milliseconds
+    pub time: u32,
+    /// Average temperature in degrees Celsius
+    pub temperature: f32,
+}
+
+impl Sample {
+    const EMPTY: Self = Sample {
+        time: 0,
+        temperature: 0.0,
+    };
+}
+
+pub struct History {
+    samples: [Sample; HISTORY_LEN],
+    /// Number of samples pushed in total, the sequence number of the
+    /// next sample
+    total: u32,
+    /// Sum of the ADC samples for the next history sample
+    sum: f64,
+    count: u32,
+}
+
+impl History {
+    const fn new() -> Self {
+        History {
+            samples: [Sample::EMPTY; HISTORY_LEN],
+            total: 0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn update(&mut self, time: u32, temperature: f64) {
+        self.sum += temperature;
+        self.count += 1;
+        if self.count >= DECIMATION {
+            let temperature = (self.sum / self.count as f64) as f32;
+            self.samples[self.total as usize % HISTORY_LEN] = Sample { time, temperature };
+            self.total = self.total.wrapping_add(1);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+
+    /// Sequence numbers of the last `n` samples still kept
+    fn last(&self, n: u32) -> (u32, u32) {
+        let kept = self.total.min(HISTORY_LEN as u32);
+        (self.total - n.min(kept), self.total)
+    }
+
+    /// Sample with sequence number `seq`, if it is still kept
+    fn get(&self, seq: u32) -> Option<Sample> {
+        if seq < self.total && self.total - seq <= HISTORY_LEN as u32 {
+            Some(self.samples[seq as usize % HISTORY_LEN])
+        } else {
+            None
+        }
+    }
+}
+
+/// Add an ADC sample of `channel`
+pub fn update(channel: usize, time: u32, temperature: f64) {
+    interrupt::free(|cs| HISTORY.borrow(cs).borrow_mut()[channel].update(time, temperature));
+}
+
+/// Sequence numbers of the last `n` samples of `channel`, as a range
+/// from the first up to, but not including, the end
+pub fn last(channel: usize, n: u32) -> (u32, u32) {
+    interrupt::free(|cs| HISTORY.borrow(cs).borrow()[channel].last(n))
+}
+
+/// Sample of `channel` with sequence number `seq`, unless it has been
+/// overwritten already
+pub fn get(channel: usize, seq: u32) -> Option<Sample> {
+    interrupt::free(|cs| HISTORY.borrow(cs).borrow()[channel].get(seq))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let mut history = History::new();
+        for i in 0..(HISTORY_LEN as u32 + 2) * DECIMATION {
+            history.update(i, i as f64);
+        }
+        let (start, end) = history.last(u32::MAX);
+        assert_eq!(end - start, HISTORY_LEN as u32);
+        assert_eq!(history.get(start - 1), None);
+        let sample = history.get(end - 1).unwrap();
+        assert_eq!(sample.time, end * DECIMATION - 1);
+        assert_eq!(sample.temperature, (end * DECIMATION) as f32 - 4.5);
+        assert_eq!(history.get(end), None);
+    }
+}
@@ -1,6 +1,6 @@
 use serde::Serialize;
 
-use crate::{command_handler::JsonBuffer, pins::HWRevPins};
+use crate::{command_handler::JsonBuffer, command_parser::DeviceName, config, pins::HWRevPins};
 
 #[derive(Serialize, Copy, Clone)]
 pub struct HWRev {
@@ -22,6 +22,7 @@ pub struct HWSettings {
 
 #[derive(Serialize, Clone)]
 struct HWSummary<'a> {
+    name: DeviceName,
     rev: &'a HWRev,
     settings: &'a HWSettings,
 }
@@ -78,6 +79,7 @@ impl HWRev {
     pub fn summary(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
         let settings = self.settings();
         let summary = HWSummary {
+            name: config::name(),
             rev: self,
             settings: &settings,
         };
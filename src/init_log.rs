@@ -1,7 +1,7 @@
-#[cfg(not(feature = "semihosting"))]
+#[cfg(not(any(feature = "semihosting", feature = "sim")))]
 use crate::usb;
 
-#[cfg(not(feature = "semihosting"))]
+#[cfg(not(any(feature = "semihosting", feature = "sim")))]
 pub fn init_log() {
     static USB_LOGGER: usb::Logger = usb::Logger;
     let _ = log::set_logger(&USB_LOGGER);
@@ -22,3 +22,35 @@ pub fn init_log() {
 
     init(logger).expect("set logger");
 }
+
+/// Log to stderr, and to the log buffer as on the hardware
+#[cfg(feature = "sim")]
+pub fn init_log() {
+    use crate::log_buffer;
+    use core::fmt::Write;
+    use log::{Log, Metadata, Record};
+
+    struct StderrLogger;
+
+    impl Log for StderrLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let _ = writeln!(
+                &mut log_buffer::Output,
+                "{} - {}",
+                record.level(),
+                record.args()
+            );
+            eprintln!("{} - {}", record.level(), record.args());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static STDERR_LOGGER: StderrLogger = StderrLogger;
+    let _ = log::set_logger(&STDERR_LOGGER);
+    log::set_max_level(log::LevelFilter::Info);
+}
@@ -0,0 +1,52 @@
+//! Critical sections around the state shared with the interrupt
+//! handlers. The firmware disables interrupts for them. The host
+//! builds, that of the tests and the simulation, take a lock instead,
+//! as they have no interrupts but may run tests in parallel.
+//! `singleton!` takes a critical section as well, and the host builds
+//! leak an allocation for it instead.
+
+pub use cortex_m::interrupt::{CriticalSection, Mutex};
+
+#[cfg(not(any(test, feature = "sim")))]
+pub use cortex_m::interrupt::free;
+
+#[cfg(any(test, feature = "sim"))]
+std::thread_local! {
+    /// Depth of the critical sections of this thread, which hold
+    /// `LOCK` while it is above 0
+    static DEPTH: core::cell::Cell<usize> = core::cell::Cell::new(0);
+}
+
+#[cfg(any(test, feature = "sim"))]
+static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Execute `f` in a critical section, which may be nested
+#[cfg(any(test, feature = "sim"))]
+pub fn free<F: FnOnce(&CriticalSection) -> R, R>(f: F) -> R {
+    let guard = if DEPTH.with(|depth| depth.get()) == 0 {
+        // A test that panicked in a critical section leaves the lock
+        // poisoned, but not the statics inconsistent
+        Some(LOCK.lock().unwrap_or_else(|e| e.into_inner()))
+    } else {
+        None
+    };
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let result = f(unsafe { &CriticalSection::new() });
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+    drop(guard);
+    result
+}
+
+#[cfg(not(any(test, feature = "sim")))]
+pub use cortex_m::singleton;
+
+/// `cortex_m::singleton!`, with a leaked allocation in place of the
+/// static on the host
+#[cfg(any(test, feature = "sim"))]
+macro_rules! singleton {
+    (: $ty:ty = $expr:expr) => {
+        Some::<&'static mut $ty>(std::boxed::Box::leak(std::boxed::Box::new($expr)))
+    };
+}
+#[cfg(any(test, feature = "sim"))]
+pub(crate) use singleton;
@@ -1,3 +1,8 @@
+use crate::{
+    interrupt::{self, Mutex},
+    timer,
+};
+use core::cell::RefCell;
 use stm32f4xx_hal::{
     gpio::{
         gpiod::{PD10, PD11, PD9},
@@ -6,6 +11,42 @@ use stm32f4xx_hal::{
     hal::digital::v2::OutputPin,
 };
 
+/// Duration of `identify` without an argument, in seconds
+pub const IDENTIFY_DEFAULT: u32 = 10;
+/// Half-period of the identify blink pattern, in milliseconds
+const IDENTIFY_PERIOD: u32 = 125;
+
+/// Duration of one flash or gap of a fault blink code, in milliseconds
+const FAULT_PERIOD: u32 = 250;
+/// Steps of a fault blink code, including the pause that ends it
+const FAULT_STEPS: u32 = 12;
+
+/// Faults indicated by L1, by the number of flashes before a pause.
+/// When several are present, the first one is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultCode {
+    /// Over- or under-temperature latched by the interlock
+    Interlock = 1,
+    SensorFault = 2,
+    NetworkDown = 3,
+    /// Last reset was by the watchdog, until `alarms clear`
+    WatchdogReset = 4,
+}
+
+/// End of the identify blink pattern
+static IDENTIFY_UNTIL: Mutex<RefCell<Option<u32>>> = Mutex::new(RefCell::new(None));
+
+/// Blink the LEDs for `seconds` so that the unit can be located,
+/// or stop blinking with 0
+pub fn identify(seconds: u32) {
+    let until = if seconds > 0 {
+        Some(timer::now().wrapping_add(seconds.saturating_mul(1000)))
+    } else {
+        None
+    };
+    interrupt::free(|cs| *IDENTIFY_UNTIL.borrow(cs).borrow_mut() = until);
+}
+
 pub struct Leds {
     /// Red LED L1
     pub r1: Led<PD9<Output<PushPull>>>,
@@ -23,6 +64,52 @@ impl Leds {
             g4: Led::new(g4.into_push_pull_output()),
         }
     }
+
+    /// Blink L1 with the code of `fault`, or turn it off
+    pub fn fault(&mut self, now: u32, fault: Option<FaultCode>) {
+        let flashes = fault.map_or(0, |fault| fault as u32);
+        let step = (now / FAULT_PERIOD) % FAULT_STEPS;
+        if step < 2 * flashes && step & 1 == 0 {
+            self.r1.on();
+        } else {
+            self.r1.off();
+        }
+    }
+
+    /// Drive L1 and L3 with the identify pattern, alternating twice
+    /// and pausing, while `identify` is active. Returns `false` when
+    /// not identifying, leaving L1 and L3 to their normal use.
+    pub fn identify(&mut self, now: u32) -> bool {
+        let active = interrupt::free(|cs| {
+            let mut until = IDENTIFY_UNTIL.borrow(cs).borrow_mut();
+            match *until {
+                Some(end) if (end.wrapping_sub(now) as i32) > 0 => true,
+                Some(_) => {
+                    *until = None;
+                    false
+                }
+                None => false,
+            }
+        });
+        if active {
+            // Steps of the 1 s pattern: r1, g3, r1, g3, then 4 dark
+            match (now / IDENTIFY_PERIOD) % 8 {
+                0 | 2 => {
+                    self.r1.on();
+                    self.g3.off();
+                }
+                1 | 3 => {
+                    self.r1.off();
+                    self.g3.on();
+                }
+                _ => {
+                    self.r1.off();
+                    self.g3.off();
+                }
+            }
+        }
+        active
+    }
 }
 
 pub struct Led<P> {
@@ -0,0 +1,155 @@
+//! Copy of the most recent log output in RAM, for retrieving
+//! messages that have been logged while no USB host was attached.
+
+use crate::interrupt::{self, Mutex};
+use core::cell::RefCell;
+use core::fmt::Write;
+use heapless::{consts::U256, String};
+
+/// Bytes of log output kept, the oldest are overwritten
+const LOG_BUFFER_LEN: usize = 4096;
+
+static BUFFER: Mutex<RefCell<LogBuffer>> = Mutex::new(RefCell::new(LogBuffer::new()));
+
+struct LogBuffer {
+    buf: [u8; LOG_BUFFER_LEN],
+    /// Bytes written in total, the end of the log
+    written: u32,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            buf: [0; LOG_BUFFER_LEN],
+            written: 0,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            self.buf[self.written as usize % LOG_BUFFER_LEN] = b;
+            self.written = self.written.wrapping_add(1);
+        }
+    }
+
+    /// Offset of the oldest byte kept
+    fn oldest(&self) -> u32 {
+        self.written.saturating_sub(LOG_BUFFER_LEN as u32)
+    }
+
+    fn get(&self, offset: u32) -> u8 {
+        self.buf[offset as usize % LOG_BUFFER_LEN]
+    }
+
+    /// Offset of the first whole line kept at or after `offset`
+    fn line_start(&self, offset: u32) -> u32 {
+        let oldest = self.oldest();
+        if offset >= oldest {
+            return offset;
+        }
+        // Skip the rest of a line that has been partly overwritten
+        let mut offset = oldest;
+        while offset < self.written {
+            offset += 1;
+            if self.get(offset - 1) == b'\n' {
+                break;
+            }
+        }
+        offset
+    }
+
+    /// Number of whole lines kept
+    fn lines(&self) -> u32 {
+        let start = self.line_start(0);
+        (start..self.written)
+            .filter(|&offset| self.get(offset) == b'\n')
+            .count() as u32
+    }
+
+    /// Line at `offset` as a JSON string, and the offset of the next
+    /// line
+    fn line(&self, offset: u32, end: u32) -> (String<U256>, u32) {
+        let mut line = String::new();
+        let _ = line.push('"');
+        let mut offset = offset;
+        while offset < end {
+            let b = self.get(offset);
+            offset += 1;
+            if b == b'\n' {
+                break;
+            }
+            // Leave room for an escape and the closing quote
+            if line.len() + 7 > line.capacity() {
+                continue;
+            }
+            let _ = match b {
+                b'"' => line.push_str("\\\""),
+                b'\\' => line.push_str("\\\\"),
+                0..=0x1F => write!(line, "\\u{:04x}", b).map_err(|_| ()),
+                0x7F..=0xFF => line.push('?'),
+                _ => line.push(b as char),
+            };
+        }
+        let _ = line.push('"');
+        (line, offset)
+    }
+}
+
+/// Appends log output
+pub struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        interrupt::free(|cs| BUFFER.borrow(cs).borrow_mut().write(s.as_bytes()));
+        Ok(())
+    }
+}
+
+/// Number of whole lines kept, and the offset after the last of them
+pub fn lines() -> (u32, u32) {
+    interrupt::free(|cs| {
+        let buffer = BUFFER.borrow(cs).borrow();
+        (buffer.lines(), buffer.written)
+    })
+}
+
+/// Line at `offset` before `end` as a JSON string, and the offset of
+/// the next line. Lines that have been overwritten since the dump has
+/// started are skipped.
+pub fn line(offset: u32, end: u32) -> Option<(String<U256>, u32)> {
+    interrupt::free(|cs| {
+        let buffer = BUFFER.borrow(cs).borrow();
+        let offset = buffer.line_start(offset);
+        if offset < end {
+            Some(buffer.line(offset, end))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_buffer() {
+        let mut buffer = LogBuffer::new();
+        buffer.write(b"INFO - \"a\"\n");
+        assert_eq!(buffer.lines(), 1);
+        let (line, next) = buffer.line(0, buffer.written);
+        assert_eq!(line.as_str(), "\"INFO - \\\"a\\\"\"");
+        assert_eq!(next, buffer.written);
+
+        for _ in 0..LOG_BUFFER_LEN / 8 {
+            buffer.write(b"WARN - b\n");
+        }
+        let start = buffer.line_start(0);
+        assert!(start > buffer.oldest());
+        assert_eq!(
+            buffer.line(start, buffer.written).0.as_str(),
+            "\"WARN - b\""
+        );
+        assert_eq!(buffer.lines(), (buffer.written - start) / 9);
+    }
+}
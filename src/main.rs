@@ -1,56 +1,98 @@
-#![cfg_attr(not(test), no_std)]
-#![cfg_attr(not(test), no_main)]
-#![cfg_attr(test, allow(unused))]
+#![cfg_attr(not(any(test, feature = "sim")), no_std)]
+#![cfg_attr(not(any(test, feature = "sim")), no_main)]
+// The host builds leave the setup of the hardware unused
+#![cfg_attr(any(test, feature = "sim"), allow(unused))]
 // TODO: #![deny(warnings, unused)]
 
-#[cfg(not(any(feature = "semihosting", test)))]
+#[cfg(all(feature = "semihosting", feature = "sim"))]
+compile_error!("features semihosting and sim are exclusive");
+
+#[cfg(not(any(feature = "semihosting", feature = "sim", test)))]
 use panic_halt as _;
 #[cfg(all(feature = "semihosting", not(test)))]
 use panic_semihosting as _;
 
 use cortex_m::asm::wfi;
 use cortex_m_rt::entry;
+use heapless::Vec;
 use log::{error, info, warn};
 use smoltcp::{socket::TcpSocket, time::Instant, wire::EthernetAddress};
 use stm32f4xx_hal::{
     hal::watchdog::{Watchdog, WatchdogEnable},
     rcc::RccExt,
-    stm32::{CorePeripherals, Peripherals, SCB},
+    stm32::{CorePeripherals, Peripherals},
     time::{MegaHertz, U32Ext},
     watchdog::IndependentWatchdog,
 };
+use uom::si::f64::ElectricPotential;
 
 mod init_log;
+mod interrupt;
+mod log_buffer;
 use init_log::init_log;
 mod leds;
+#[cfg_attr(feature = "sim", path = "sim/pins.rs")]
 mod pins;
 mod usb;
 use pins::Pins;
 mod ad5680;
+mod board;
+use board::{Board, Mcu, Stm32f4};
+mod ad7124;
 mod ad7172;
+mod adc;
+mod dac;
+mod dac8560;
+mod mdns;
 mod net;
 mod server;
-use server::Server;
+use server::{Server, SocketCount};
 mod session;
-use session::{Session, SessionInput};
+use session::{ClientSummary, Session, SessionInput, LINES_PER_CYCLE};
 mod command_parser;
-use command_parser::Ipv4Config;
+use command_parser::{Framing, Ipv4Config, PowerOn};
 mod b_parameter;
 mod channels;
 mod pid;
+mod rtc;
+mod rtd;
+mod scheduler;
+mod selftest;
+mod stable_output;
+use stable_output::StableOutput;
+mod stats;
+mod steinhart_hart;
+mod step_response;
+mod supervisor;
+use supervisor::{Supervisor, Task};
 mod timer;
-use channels::{Channels, CHANNELS};
+use channels::{Channels, ChannelsInterface, CHANNELS};
 mod channel;
 mod channel_state;
-mod config;
-use config::ChannelConfig;
 mod command_handler;
+mod config;
 mod dfu;
 mod flash_store;
-use command_handler::Handler;
+use command_handler::{
+    send_clients, send_dump, send_echo, send_eeprom, send_error, send_line, send_self_test, Error,
+    FramedSocket, Handler, QueuedResponse,
+};
+use flash_store::FlashStore;
 mod fan_ctrl;
 use fan_ctrl::FanCtrl;
+mod alarms;
+mod autosave;
+mod capture;
+mod deadman;
+mod event_log;
+mod fan_tach;
+mod faults;
+mod history;
 mod hw_rev;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "sim")]
+mod sim;
 
 const HSE: MegaHertz = MegaHertz(8);
 #[cfg(not(feature = "semihosting"))]
@@ -58,38 +100,129 @@ const WATCHDOG_INTERVAL: u32 = 1_000;
 #[cfg(feature = "semihosting")]
 const WATCHDOG_INTERVAL: u32 = 30_000;
 
-const CHANNEL_CONFIG_KEY: [&str; 2] = ["ch0", "ch1"];
+const CHANNEL_CONFIG_KEY: [&str; 2] = ["ch0v2", "ch1v2"];
+/// Of `config::ChannelConfigV1`, read while there is none under
+/// `CHANNEL_CONFIG_KEY`
+const CHANNEL_CONFIG_V1_KEY: [&str; 2] = ["ch0", "ch1"];
+const ADC_CALIBRATION_KEY: [&str; 2] = ["adc_cal0", "adc_cal1"];
+const TEC_CALIBRATION_KEY: [&str; 2] = ["tec_cal0", "tec_cal1"];
+const DAC_CALIBRATION_KEY: [&str; 2] = ["dac_cal0", "dac_cal1"];
+const DEVICE_CONFIG_KEY: &str = "devicev2";
+/// Of `config::DeviceConfigV1`, read while there is none under
+/// `DEVICE_CONFIG_KEY`
+const DEVICE_CONFIG_V1_KEY: &str = "device";
+const DEVICE_NAME_KEY: &str = "name";
+const CHANNEL_ALIAS_KEY: &str = "alias";
 
 const TCP_PORT: u16 = 23;
 
-fn send_line(socket: &mut TcpSocket, data: &[u8]) -> bool {
-    let send_free = socket.send_capacity() - socket.send_queue();
-    if data.len() > send_free + 1 {
-        // Not enough buffer space, skip report for now,
-        // instead of sending incomplete line
-        warn!(
-            "TCP socket has only {}/{} needed {}",
-            send_free + 1,
-            socket.send_capacity(),
-            data.len(),
-        );
-    } else {
-        match socket.send_slice(data) {
-            Ok(sent) if sent == data.len() => {
-                let _ = socket.send_slice(b"\n");
-                // success
-                return true;
+/// Interval between ADC polls in milliseconds while idle, that is
+/// with both channels powered down and no client connected
+const IDLE_POLL_INTERVAL: u32 = 1_000;
+/// Interval between reads of the Ethernet link status in milliseconds
+const LINK_POLL_INTERVAL: u32 = 1_000;
+
+/// The socket buffers and the client sessions are on the stack
+const _: () = assert!(
+    server::SOCKET_BUFFERS_SIZE + server::SOCKET_COUNT * core::mem::size_of::<Session>()
+        <= server::STACK_BUDGET,
+    "too many sockets for their buffer size"
+);
+
+/// Connected TCP clients, for the `clients` command
+fn client_summaries(server: &mut Server<Session>) -> Vec<ClientSummary, SocketCount> {
+    let now = timer::now();
+    let mut clients = Vec::new();
+    let mut index = 0;
+    server.for_each(|socket, session| {
+        if let Some(client) = session.summary(index, &socket, now) {
+            let _ = clients.push(client);
+        }
+        index += 1;
+    });
+    clients
+}
+
+/// Setting of the outputs at boot
+fn read_power_on(store: &FlashStore) -> PowerOn {
+    match store.read_value("power_on") {
+        Ok(Some(power_on)) => power_on,
+        Ok(None) => PowerOn::Restore,
+        Err(e) => {
+            error!("cannot read power_on: {:?}", e);
+            // Err on the safe side
+            PowerOn::Off
+        }
+    }
+}
+
+/// Saved `vref_meas` of each channel, `None` for those whose DAC is to
+/// be calibrated
+fn read_dac_calibrations(store: &FlashStore) -> [Option<ElectricPotential>; CHANNELS] {
+    core::array::from_fn(|c| match store.read_value(DAC_CALIBRATION_KEY[c]) {
+        Ok(vref_meas) => vref_meas,
+        Err(e) => {
+            error!("unable to load DAC calibration {} from flash: {:?}", c, e);
+            None
+        }
+    })
+}
+
+/// Apply the saved calibrations and configs of the channels, and the
+/// device name and channel aliases. The outputs are shut down with
+/// `outputs_off`.
+fn load_channels(store: &FlashStore, channels: &mut Channels, outputs_off: bool) {
+    for (c, key) in ADC_CALIBRATION_KEY.iter().enumerate().take(CHANNELS) {
+        match store.read_value(key) {
+            Ok(Some(calibration)) => channels.set_adc_calibration(c, &calibration),
+            Ok(None) => {}
+            Err(e) => error!("unable to load ADC calibration {} from flash: {:?}", c, e),
+        }
+    }
+    for (c, key) in TEC_CALIBRATION_KEY.iter().enumerate().take(CHANNELS) {
+        match store.read_value(key) {
+            Ok(Some(calibration)) => channels.channel_state(c).tec_cal = calibration,
+            Ok(None) => {}
+            Err(e) => error!("unable to load TEC calibration {} from flash: {:?}", c, e),
+        }
+    }
+    for c in 0..CHANNELS {
+        match config::read_channel_config(store, channels, c) {
+            Ok(Some(config)) => {
+                config.apply(channels, c);
+                if outputs_off {
+                    channels.shut_down(c);
+                }
             }
-            Ok(sent) => warn!("sent only {}/{} bytes", sent, data.len()),
-            Err(e) => error!("error sending line: {:?}", e),
+            Ok(None) => error!("flash config not found for channel {}", c),
+            Err(e) => error!("unable to load config {} from flash: {:?}", c, e),
         }
     }
-    // not success
-    false
+
+    match store.read_value(DEVICE_NAME_KEY) {
+        Ok(Some(name)) => config::set_name(name),
+        Ok(None) => {}
+        Err(e) => error!("cannot read name: {:?}", e),
+    }
+    match store.read_value(CHANNEL_ALIAS_KEY) {
+        Ok(Some(aliases)) => config::set_aliases(aliases),
+        Ok(None) => {}
+        Err(e) => error!("cannot read channel aliases: {:?}", e),
+    }
+}
+
+/// Send the echo of an interactive client, after any queued lines
+fn send_session_echo(socket: &mut TcpSocket, session: &mut Session) {
+    let framing = session.framing();
+    let (echo, send_queue) = session.take_echo_queued();
+    send_echo(
+        &mut FramedSocket::new(&mut QueuedResponse::new(socket, send_queue), framing),
+        echo,
+    );
 }
 
 /// Initialization and main loop
-#[cfg(not(test))]
+#[cfg(not(any(test, feature = "sim")))]
 #[entry]
 fn main() -> ! {
     init_log();
@@ -115,19 +248,21 @@ fn main() -> ! {
     wd.start(WATCHDOG_INTERVAL.ms());
     wd.feed();
 
-    timer::setup(cp.SYST, clocks);
+    Stm32f4::setup_timer(cp.SYST, clocks);
+    rtc::setup(dp.RTC, dp.PWR);
 
-    let (pins, mut leds, mut eeprom, eth_pins, usb, fan, hwrev, hw_settings) = Pins::setup(
-        clocks,
-        (dp.TIM1, dp.TIM3, dp.TIM8),
-        (
-            dp.GPIOA, dp.GPIOB, dp.GPIOC, dp.GPIOD, dp.GPIOE, dp.GPIOF, dp.GPIOG,
-        ),
-        dp.I2C1,
-        (dp.SPI2, dp.SPI4, dp.SPI5),
-        dp.ADC1,
-        (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
-    );
+    let (pins, mut leds, mut eeprom, eth_pins, usb, fan, hwrev, hw_settings, stable_pin) =
+        Pins::setup(
+            clocks,
+            (dp.TIM1, dp.TIM3, dp.TIM4, dp.TIM8),
+            (
+                dp.GPIOA, dp.GPIOB, dp.GPIOC, dp.GPIOD, dp.GPIOE, dp.GPIOF, dp.GPIOG,
+            ),
+            dp.I2C1,
+            (dp.SPI2, dp.SPI4, dp.SPI5),
+            dp.ADC1,
+            (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
+        );
 
     leds.r1.on();
     leds.g3.off();
@@ -136,17 +271,41 @@ fn main() -> ! {
     usb::State::setup(usb);
 
     let mut store = flash_store::store(dp.FLASH);
+    let watchdog_reset = event_log::load(&mut store);
+    if watchdog_reset {
+        alarms::watchdog_reset();
+    }
+    autosave::load(&mut store);
+    deadman::load(&mut store);
+    stable_output::load(&mut store);
+    alarms::load(&mut store);
 
-    let mut channels = Channels::new(pins);
-    for (c, key) in CHANNEL_CONFIG_KEY.iter().enumerate().take(CHANNELS) {
-        match store.read_value::<ChannelConfig>(key) {
-            Ok(Some(config)) => config.apply(&mut channels, c),
-            Ok(None) => error!("flash config not found for channel {}", c),
-            Err(e) => error!("unable to load config {} from flash: {:?}", c, e),
+    let power_on = read_power_on(&store);
+
+    let selftest_boot = match store.read_value("selftest_boot") {
+        Ok(Some(selftest_boot)) => selftest_boot,
+        Ok(None) => false,
+        Err(e) => {
+            error!("cannot read selftest_boot: {:?}", e);
+            false
         }
-    }
+    };
 
+    let vref_meas = read_dac_calibrations(&store);
+    // Before the channel configs, for the safe start flag
     let mut fan_ctrl = FanCtrl::new(fan, hw_settings);
+    match config::read_device_config(&store) {
+        Ok(Some(config)) => config.apply(&mut fan_ctrl),
+        Ok(None) => {}
+        Err(e) => error!("unable to load device config from flash: {:?}", e),
+    }
+    let outputs_off = config::outputs_off_at_boot(power_on, config::safe_start(), watchdog_reset);
+
+    let dac_chip = dac::load(&store);
+    let mut channels = Channels::new(pins, dac_chip, vref_meas);
+    load_channels(&store, &mut channels, outputs_off);
+
+    let mut stable_output = StableOutput::new(stable_pin);
 
     // default net config:
     let mut ipv4_config = Ipv4Config {
@@ -175,47 +334,316 @@ fn main() -> ! {
         ipv4_config.clone(),
         |iface| {
             Server::<Session>::run(iface, |server| {
+                if selftest_boot {
+                    let selftest = selftest::run(&mut channels, &mut eeprom, &mut fan_ctrl);
+                    match selftest.json() {
+                        Ok(buf) if selftest.pass() => {
+                            info!("self-test: {}", core::str::from_utf8(&buf).unwrap_or(""))
+                        }
+                        Ok(buf) => {
+                            error!("self-test: {}", core::str::from_utf8(&buf).unwrap_or(""))
+                        }
+                        Err(e) => error!("unable to serialize self-test: {:?}", e),
+                    }
+                }
                 leds.r1.off();
                 let mut should_reset = false;
+                let mut usb_session = Session::new();
+                let mut idle = false;
+                let mut last_idle_poll = 0;
+                let mut link_up = net::link_up();
+                let mut last_link_poll = timer::now();
+                let mut supervisor = Supervisor::new(timer::now());
 
                 loop {
                     let mut new_ipv4_config = None;
-                    let instant = Instant::from_millis(i64::from(timer::now()));
-                    channels.poll_adc(instant);
+                    let mut clients_request = None;
+                    let mut kick = None;
+                    let now_u64 = timer::now_u64();
+                    let now = now_u64 as u32;
+                    if !idle || now.wrapping_sub(last_idle_poll) >= IDLE_POLL_INTERVAL {
+                        last_idle_poll = now;
+                        if channels
+                            .poll_adc(Instant::from_millis(now_u64 as i64))
+                            .is_some()
+                        {
+                            supervisor.check_in(Task::Adc, now);
+                        }
+                        channels.poll_pins_adc();
+                    }
+                    if !idle {
+                        channels.check_adc_silence(Instant::from_millis(now_u64 as i64));
+                    }
+                    // A silent ADC is handled by its latched fault, with
+                    // the outputs off, rather than by resets
+                    if channels.adc_silent() {
+                        supervisor.check_in(Task::Adc, now);
+                    }
 
-                    fan_ctrl.cycle(channels.current_abs_max_tec_i());
+                    for alarm in alarms::check(&mut channels, &fan_ctrl) {
+                        match alarm.json() {
+                            Ok(alarm) => server.for_each(|mut socket, session| {
+                                if socket.can_send() {
+                                    let framing = session.framing();
+                                    let mut queued =
+                                        QueuedResponse::new(&mut *socket, session.send_queue_mut());
+                                    send_line(&mut FramedSocket::new(&mut queued, framing), &alarm);
+                                }
+                            }),
+                            Err(e) => error!("unable to serialize alarm: {:?}", e),
+                        }
+                    }
 
-                    if channels.pid_engaged() {
-                        leds.g3.on();
+                    if deadman::check() {
+                        // Nor may a scheduled command power the outputs
+                        // up again
+                        scheduler::clear(None);
+                        // Only an output that is on is worth a flash
+                        // write for the event
+                        if channels.any_powered() || channels.pid_engaged() {
+                            warn!("no commands within the deadman timeout, shutting down");
+                            for c in 0..CHANNELS {
+                                channels.shut_down(c);
+                            }
+                            event_log::record(event_log::Event::new(
+                                event_log::EventKind::Deadman,
+                                None,
+                            ));
+                        }
+                    }
+                    stable_output.update(&mut channels);
+
+                    if idle {
+                        fan_ctrl.idle();
                     } else {
-                        leds.g3.off();
+                        fan_ctrl.cycle(
+                            channels.current_abs_max_tec_i(),
+                            channels.get_mcu_temperature(),
+                        );
                     }
 
-                    let instant = Instant::from_millis(i64::from(timer::now()));
-                    cortex_m::interrupt::free(net::clear_pending);
-                    server.poll(instant).unwrap_or_else(|e| {
-                        warn!("poll: {:?}", e);
-                    });
+                    if now.wrapping_sub(last_link_poll) >= LINK_POLL_INTERVAL {
+                        last_link_poll = now;
+                        link_up = net::link_up();
+                    }
+
+                    // L1 and L3 blink while identifying
+                    if !leds.identify(now) {
+                        let fault = alarms::fault_code(&mut channels, link_up);
+                        leds.fault(now, fault);
+                        if channels.pid_engaged() {
+                            leds.g3.on();
+                        } else {
+                            leds.g3.off();
+                        }
+                    }
+
+                    for command in scheduler::take_due() {
+                        let mut discard = scheduler::Discard;
+                        let mut framed = FramedSocket::new(&mut discard, Framing::Newline);
+                        match Handler::run_command(
+                            command,
+                            &mut framed,
+                            &mut channels,
+                            &mut store,
+                            &mut ipv4_config,
+                            &mut fan_ctrl,
+                            hwrev,
+                        ) {
+                            Ok(Handler::NewIPV4(ip)) => new_ipv4_config = Some(ip),
+                            Ok(Handler::Reset) => should_reset = true,
+                            Ok(_) => {}
+                            Err(e) => warn!("scheduled command: {:?}", e),
+                        }
+                    }
+
+                    let instant = Instant::from_millis(timer::now_u64() as i64);
+                    interrupt::free(net::clear_pending);
+                    match server.poll(instant) {
+                        Ok(()) => supervisor.check_in(Task::Network, now),
+                        Err(e) => warn!("poll: {:?}", e),
+                    }
+                    server.poll_mdns(now);
 
                     if !should_reset {
                         // TCP protocol handling
+                        let mut next_index = 0;
                         server.for_each(|mut socket, session| {
+                            let index = next_index;
+                            next_index += 1;
+                            if socket.is_active() {
+                                session.connect(instant.total_millis() as u32);
+                            }
                             if !socket.is_active() {
                                 let _ = socket.listen(TCP_PORT);
                                 session.reset();
                             } else if socket.may_send() && !socket.may_recv() {
                                 socket.close()
+                            } else if socket.can_send()
+                                && !session.send_queue_mut().flush(&mut *socket)
+                            {
+                                // Samples and commands wait until the
+                                // client has taken the queued lines
+                            } else if socket.can_send() && session.dump_mut().is_some() {
+                                let framing = session.framing();
+                                let tag = session.tag();
+                                if let Some(dump) = session.dump_mut() {
+                                    let mut framed = FramedSocket::new(&mut *socket, framing);
+                                    framed.set_tag(tag);
+                                    if send_dump(&mut framed, dump, &mut channels) {
+                                        session.set_dump(None);
+                                    }
+                                }
                             } else if socket.can_send() && socket.can_recv() {
-                                match socket.recv(|buf| session.feed(buf)) {
-                                    // SessionInput::Nothing happens when the line reader parses a string of characters that is not
-                                    // followed by a newline character. Could be due to partial commands not terminated with newline,
-                                    // socket RX ring buffer wraps around, or when the command is sent as seperate TCP packets etc.
-                                    // Do nothing and feed more data to the line reader in the next loop cycle.
-                                    Ok(SessionInput::Nothing) => {}
-                                    Ok(SessionInput::Command(command)) => {
+                                let mut input = socket.recv(|buf| session.feed(buf));
+                                let mut lines = 1;
+                                send_session_echo(&mut socket, session);
+                                // Further commands on the same line, and a few
+                                // further lines received, are handled in the same
+                                // cycle, before the next PID update
+                                loop {
+                                    // Only a complete line is followed by more
+                                    let line_read = matches!(
+                                        input,
+                                        Ok(SessionInput::Command(_)) | Ok(SessionInput::Error(_))
+                                    );
+                                    match input {
+                                        // SessionInput::Nothing happens when the line reader parses a string of characters that is not
+                                        // followed by a newline character. Could be due to partial commands not terminated with newline,
+                                        // socket RX ring buffer wraps around, or when the command is sent as seperate TCP packets etc.
+                                        // Do nothing and feed more data to the line reader in the next loop cycle.
+                                        Ok(SessionInput::Nothing) => {}
+                                        Ok(SessionInput::Command(command)) => {
+                                            let framing = session.framing();
+                                            let tag = session.tag();
+                                            let report_format = session.report_format();
+                                            let precision = session.precision();
+                                            let mut queued = QueuedResponse::new(
+                                                &mut *socket,
+                                                session.send_queue_mut(),
+                                            );
+                                            let mut framed =
+                                                FramedSocket::new(&mut queued, framing);
+                                            framed.set_tag(tag);
+                                            framed.set_report_format(report_format);
+                                            framed.set_precision(precision);
+                                            match Handler::handle_command(
+                                                command,
+                                                &mut framed,
+                                                &mut channels,
+                                                &mut store,
+                                                &mut ipv4_config,
+                                                &mut fan_ctrl,
+                                                hwrev,
+                                            ) {
+                                                Ok(Handler::NewIPV4(ip)) => {
+                                                    new_ipv4_config = Some(ip)
+                                                }
+                                                Ok(Handler::NewFraming(framing)) => {
+                                                    session.set_framing(framing)
+                                                }
+                                                Ok(Handler::NewReportFormat(report_format)) => {
+                                                    session.set_report_format(report_format)
+                                                }
+                                                Ok(Handler::NewPrecision(precision)) => {
+                                                    session.set_precision(precision)
+                                                }
+                                                Ok(Handler::Interactive(enabled)) => {
+                                                    session.set_interactive(enabled)
+                                                }
+                                                Ok(Handler::NewDump(dump)) => {
+                                                    session.set_dump(Some(dump))
+                                                }
+                                                Ok(Handler::SelfTest) => {
+                                                    let selftest = selftest::run(
+                                                        &mut channels,
+                                                        &mut eeprom,
+                                                        &mut fan_ctrl,
+                                                    );
+                                                    send_self_test(&mut framed, &selftest);
+                                                }
+                                                Ok(Handler::Clients) => {
+                                                    clients_request = Some(index)
+                                                }
+                                                Ok(Handler::Kick(index)) => kick = Some(index),
+                                                Ok(Handler::ReceiveConfig) => {
+                                                    session.receive_document()
+                                                }
+                                                Ok(Handler::Eeprom(access)) => {
+                                                    send_eeprom(&mut framed, &mut eeprom, &access)
+                                                }
+                                                Ok(Handler::Handled) => {}
+                                                Ok(Handler::CloseSocket) => socket.close(),
+                                                Ok(Handler::Reset) => should_reset = true,
+                                                Err(_) => {}
+                                            }
+                                        }
+                                        Ok(SessionInput::Error(e)) => {
+                                            error!("session input: {:?}", e);
+                                            let framing = session.framing();
+                                            let tag = session.tag();
+                                            let mut queued = QueuedResponse::new(
+                                                &mut *socket,
+                                                session.send_queue_mut(),
+                                            );
+                                            let mut framed =
+                                                FramedSocket::new(&mut queued, framing);
+                                            framed.set_tag(tag);
+                                            send_error(
+                                                &mut framed,
+                                                Error::Parse,
+                                                format_args!("{}", e),
+                                                None,
+                                            );
+                                        }
+                                        Err(_) => socket.close(),
+                                    }
+                                    match session.next_command() {
+                                        Some(next) => input = Ok(next),
+                                        None if line_read
+                                            && lines < LINES_PER_CYCLE
+                                            && socket.can_send()
+                                            && socket.can_recv()
+                                            && session.reads_lines() =>
+                                        {
+                                            lines += 1;
+                                            send_session_echo(&mut socket, session);
+                                            input = socket.recv(|buf| session.feed(buf));
+                                        }
+                                        None => break,
+                                    }
+                                }
+                                send_session_echo(&mut socket, session);
+                            }
+                        });
+
+                        // USB serial port handling, as for a TCP client
+                        usb::set_log_output(usb_session.framing() == Framing::Newline);
+                        let mut serial = usb::SerialResponse;
+                        let mut framed = FramedSocket::new(&mut serial, usb_session.framing());
+                        framed.set_tag(usb_session.tag());
+                        if let Some(dump) = usb_session.dump_mut() {
+                            if send_dump(&mut framed, dump, &mut channels) {
+                                usb_session.set_dump(None);
+                            }
+                        } else {
+                            let mut input = usb::recv(|buf| usb_session.feed(buf));
+                            let mut lines = 1;
+                            send_echo(&mut framed, usb_session.take_echo());
+                            loop {
+                                framed.set_tag(usb_session.tag());
+                                framed.set_report_format(usb_session.report_format());
+                                framed.set_precision(usb_session.precision());
+                                let line_read = matches!(
+                                    input,
+                                    Some(SessionInput::Command(_)) | Some(SessionInput::Error(_))
+                                );
+                                match input {
+                                    None | Some(SessionInput::Nothing) => {}
+                                    Some(SessionInput::Command(command)) => {
                                         match Handler::handle_command(
                                             command,
-                                            &mut socket,
+                                            &mut framed,
                                             &mut channels,
                                             &mut store,
                                             &mut ipv4_config,
@@ -223,20 +651,71 @@ fn main() -> ! {
                                             hwrev,
                                         ) {
                                             Ok(Handler::NewIPV4(ip)) => new_ipv4_config = Some(ip),
+                                            Ok(Handler::NewFraming(framing)) => {
+                                                usb::set_log_output(framing == Framing::Newline);
+                                                usb_session.set_framing(framing)
+                                            }
+                                            Ok(Handler::NewReportFormat(report_format)) => {
+                                                usb_session.set_report_format(report_format)
+                                            }
+                                            Ok(Handler::NewPrecision(precision)) => {
+                                                usb_session.set_precision(precision)
+                                            }
+                                            Ok(Handler::Interactive(enabled)) => {
+                                                usb_session.set_interactive(enabled)
+                                            }
+                                            Ok(Handler::NewDump(dump)) => {
+                                                usb_session.set_dump(Some(dump))
+                                            }
+                                            Ok(Handler::SelfTest) => {
+                                                let selftest = selftest::run(
+                                                    &mut channels,
+                                                    &mut eeprom,
+                                                    &mut fan_ctrl,
+                                                );
+                                                send_self_test(&mut framed, &selftest);
+                                            }
+                                            Ok(Handler::Clients) => {
+                                                send_clients(&mut framed, &client_summaries(server))
+                                            }
+                                            Ok(Handler::Kick(index)) => kick = Some(index),
+                                            Ok(Handler::ReceiveConfig) => {
+                                                usb_session.receive_document()
+                                            }
+                                            Ok(Handler::Eeprom(access)) => {
+                                                send_eeprom(&mut framed, &mut eeprom, &access)
+                                            }
                                             Ok(Handler::Handled) => {}
-                                            Ok(Handler::CloseSocket) => socket.close(),
+                                            Ok(Handler::CloseSocket) => usb_session.reset(),
                                             Ok(Handler::Reset) => should_reset = true,
                                             Err(_) => {}
                                         }
                                     }
-                                    Ok(SessionInput::Error(e)) => {
+                                    Some(SessionInput::Error(e)) => {
                                         error!("session input: {:?}", e);
-                                        send_line(&mut socket, b"{ \"error\": \"invalid input\" }");
+                                        send_error(
+                                            &mut framed,
+                                            Error::Parse,
+                                            format_args!("{}", e),
+                                            None,
+                                        );
                                     }
-                                    Err(_) => socket.close(),
+                                }
+                                match usb_session.next_command() {
+                                    Some(next) => input = Some(next),
+                                    None if line_read
+                                        && lines < LINES_PER_CYCLE
+                                        && usb_session.reads_lines() =>
+                                    {
+                                        lines += 1;
+                                        send_echo(&mut framed, usb_session.take_echo());
+                                        input = usb::recv(|buf| usb_session.feed(buf));
+                                    }
+                                    None => break,
                                 }
                             }
-                        });
+                            send_echo(&mut framed, usb_session.take_echo());
+                        }
                     } else {
                         // Should reset, close all TCP sockets.
                         let mut any_socket_alive = false;
@@ -249,7 +728,36 @@ fn main() -> ! {
                         // Must let loop run for one more cycle to poll server for RST to be sent,
                         // this makes sure system does not reset right after socket.abort() is called.
                         if !any_socket_alive {
-                            SCB::sys_reset();
+                            event_log::flush_now(&mut store);
+                            Mcu::reset();
+                        }
+                    }
+
+                    // Network or USB activity ends idle mode in the
+                    // same cycle that it wakes us up in
+                    let was_idle = idle;
+                    idle = !channels.any_powered() && !server.any_active() && !usb::active();
+                    if idle != was_idle {
+                        info!("idle: {}", idle);
+                    }
+
+                    if let Some(index) = clients_request.take() {
+                        let clients = client_summaries(server);
+                        if let Some((mut socket, session)) = server.get(index) {
+                            let framing = session.framing();
+                            let tag = session.tag();
+                            let mut queued =
+                                QueuedResponse::new(&mut *socket, session.send_queue_mut());
+                            let mut framed = FramedSocket::new(&mut queued, framing);
+                            framed.set_tag(tag);
+                            send_clients(&mut framed, &clients);
+                        }
+                    }
+
+                    if let Some(index) = kick.take() {
+                        if let Some((mut socket, _)) = server.get(index) {
+                            info!("kick client {}", index);
+                            socket.abort();
                         }
                     }
 
@@ -259,11 +767,27 @@ fn main() -> ! {
                         ipv4_config = config;
                     };
 
-                    // Update watchdog
-                    wd.feed();
+                    if let Some((channel, vref_meas)) = channels.poll_dac_calibration() {
+                        match store.write_value(DAC_CALIBRATION_KEY[channel], &vref_meas, [0; 16]) {
+                            Ok(()) => event_log::record(event_log::Event::new(
+                                event_log::EventKind::DacCalibrated,
+                                Some(channel),
+                            )),
+                            Err(e) => error!("unable to save DAC calibration to flash: {:?}", e),
+                        }
+                    }
+
+                    event_log::flush(&mut store);
+                    autosave::flush(&mut store, &mut channels);
+
+                    // Update watchdog, unless a part of the loop has
+                    // stopped making progress
+                    if supervisor.healthy(timer::now()) {
+                        wd.feed();
+                    }
 
                     leds.g4.off();
-                    cortex_m::interrupt::free(|cs| {
+                    interrupt::free(|cs| {
                         if !net::is_pending(cs) {
                             // Wait for interrupts
                             // (Ethernet, SysTick, or USB)
@@ -278,3 +802,9 @@ fn main() -> ! {
 
     unreachable!()
 }
+
+/// Run the simulation, see `sim`
+#[cfg(all(feature = "sim", not(test)))]
+fn main() {
+    sim::run()
+}
@@ -0,0 +1,326 @@
+//! Multicast DNS responder (RFC 6762), so that a device can be found
+//! as `<name>.local` by the name set with `name`.
+//!
+//! It answers queries for the A record of that host name, and
+//! announces the record at boot and whenever the name or the IPv4
+//! address has changed. Other record types and service discovery are
+//! not answered.
+
+use crate::{command_parser::DeviceName, config};
+use byteorder::{BigEndian, ByteOrder};
+use heapless::{consts::U128, Vec};
+use log::warn;
+use smoltcp::{
+    socket::UdpSocket,
+    wire::{IpEndpoint, Ipv4Address},
+};
+
+pub const PORT: u16 = 5353;
+/// Multicast group of mDNS, which the interface joins
+pub const GROUP: Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+/// Size of the socket buffers, for the queries of a few hosts at once
+pub const BUFFER_SIZE: usize = 1024;
+
+/// Domain of the host names
+const LOCAL: &[u8] = b"local";
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// In the class of a question, asks for a unicast response
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+/// In the class of a record, replaces those cached for the name
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+/// Seconds to cache the record, as recommended for host names
+const TTL: u32 = 120;
+/// At most for responses to legacy unicast queries
+const LEGACY_TTL: u32 = 10;
+/// Announcements after a change, `ANNOUNCE_INTERVAL` apart
+const ANNOUNCEMENTS: u8 = 2;
+/// Milliseconds between announcements
+const ANNOUNCE_INTERVAL: u32 = 1_000;
+/// Labels followed in a name, bounding compression pointer loops
+const MAX_LABELS: usize = 32;
+
+/// A response: the header, the question of a legacy unicast query,
+/// and the A record, each with a name of at most 40 bytes
+type Packet = Vec<u8, U128>;
+
+/// Query for the A record of the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Query {
+    id: u16,
+    /// Asked for a unicast response
+    unicast: bool,
+}
+
+/// Compare the name at `offset` of `packet` with `labels`, regardless
+/// of case. Returns whether it matches, and the offset after the name.
+fn read_name(packet: &[u8], offset: usize, labels: &[&[u8]]) -> Option<(bool, usize)> {
+    let mut pos = offset;
+    let mut end = None;
+    let mut label = 0;
+    let mut matches = true;
+    for _ in 0..MAX_LABELS {
+        let len = usize::from(*packet.get(pos)?);
+        if len & 0xC0 == 0xC0 {
+            // Compression pointer to the rest of the name
+            let pointer = (len & 0x3F) << 8 | usize::from(*packet.get(pos + 1)?);
+            end.get_or_insert(pos + 2);
+            pos = pointer;
+            continue;
+        }
+        if len == 0 {
+            return Some((matches && label == labels.len(), end.unwrap_or(pos + 1)));
+        }
+        let text = packet.get(pos + 1..pos + 1 + len)?;
+        matches &=
+            matches!(labels.get(label), Some(expected) if expected.eq_ignore_ascii_case(text));
+        label += 1;
+        pos += 1 + len;
+    }
+    None
+}
+
+/// The query in `packet` if it asks for the A record of `host`.local
+fn parse_query(packet: &[u8], host: &[u8]) -> Option<Query> {
+    let header = packet.get(..12)?;
+    // QR and opcode of a standard query
+    if header[2] & 0xF8 != 0 {
+        return None;
+    }
+    let questions = BigEndian::read_u16(&header[4..6]);
+    let mut offset = 12;
+    for _ in 0..questions {
+        let (matches, end) = read_name(packet, offset, &[host, LOCAL])?;
+        let question = packet.get(end..end + 4)?;
+        let qtype = BigEndian::read_u16(&question[0..2]);
+        let qclass = BigEndian::read_u16(&question[2..4]);
+        offset = end + 4;
+        if matches
+            && (qtype == TYPE_A || qtype == TYPE_ANY)
+            && qclass & !CLASS_UNICAST_RESPONSE == CLASS_IN
+        {
+            return Some(Query {
+                id: BigEndian::read_u16(&header[0..2]),
+                unicast: qclass & CLASS_UNICAST_RESPONSE != 0,
+            });
+        }
+    }
+    None
+}
+
+fn push_u16(packet: &mut Packet, value: u16) -> Option<()> {
+    packet.extend_from_slice(&value.to_be_bytes()).ok()
+}
+
+fn push_name(packet: &mut Packet, host: &[u8]) -> Option<()> {
+    for label in [host, LOCAL] {
+        packet.push(label.len() as u8).ok()?;
+        packet.extend_from_slice(label).ok()?;
+    }
+    packet.push(0).ok()
+}
+
+/// Response with the A record of `host`.local. A legacy unicast query,
+/// from another port than `PORT`, is answered with its `id` and
+/// question, as its resolver expects.
+fn response(host: &[u8], address: Ipv4Address, legacy: Option<u16>) -> Option<Packet> {
+    let mut packet = Packet::new();
+    push_u16(&mut packet, legacy.unwrap_or(0))?;
+    // Authoritative answer
+    push_u16(&mut packet, 0x8400)?;
+    push_u16(&mut packet, u16::from(legacy.is_some()))?;
+    push_u16(&mut packet, 1)?;
+    push_u16(&mut packet, 0)?;
+    push_u16(&mut packet, 0)?;
+    if legacy.is_some() {
+        push_name(&mut packet, host)?;
+        push_u16(&mut packet, TYPE_A)?;
+        push_u16(&mut packet, CLASS_IN)?;
+    }
+    push_name(&mut packet, host)?;
+    push_u16(&mut packet, TYPE_A)?;
+    let (class, ttl) = match legacy {
+        Some(_) => (CLASS_IN, LEGACY_TTL),
+        None => (CLASS_IN | CLASS_CACHE_FLUSH, TTL),
+    };
+    push_u16(&mut packet, class)?;
+    packet.extend_from_slice(&ttl.to_be_bytes()).ok()?;
+    push_u16(&mut packet, 4)?;
+    packet.extend_from_slice(address.as_bytes()).ok()?;
+    Some(packet)
+}
+
+#[derive(Default)]
+pub struct Responder {
+    /// Name and address of the announcements under way
+    announced: Option<(DeviceName, Ipv4Address)>,
+    announcements_left: u8,
+    last_announcement: u32,
+}
+
+impl Responder {
+    /// Whether to announce `name` at `address` now, starting over
+    /// after a change of either
+    fn announcement_due(&mut self, name: &DeviceName, address: Ipv4Address, now: u32) -> bool {
+        if !matches!(&self.announced, Some((n, a)) if n == name && *a == address) {
+            self.announced = Some((name.clone(), address));
+            self.announcements_left = ANNOUNCEMENTS;
+            self.last_announcement = now.wrapping_sub(ANNOUNCE_INTERVAL);
+        }
+        if self.announcements_left == 0
+            || now.wrapping_sub(self.last_announcement) < ANNOUNCE_INTERVAL
+        {
+            return false;
+        }
+        self.announcements_left -= 1;
+        self.last_announcement = now;
+        true
+    }
+
+    /// Answer the queries received, and send the announcements due.
+    /// To be called from the main loop after polling the interface.
+    pub fn poll(&mut self, socket: &mut UdpSocket, address: Ipv4Address, now: u32) {
+        if !socket.is_open() {
+            if let Err(e) = socket.bind(PORT) {
+                warn!("mdns bind: {:?}", e);
+                return;
+            }
+        }
+        if address.is_unspecified() {
+            return;
+        }
+        let name = config::name();
+        let host = name.as_bytes();
+        let group = IpEndpoint::from((GROUP, PORT));
+
+        while socket.can_recv() {
+            let (packet, source) = match socket.recv() {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+            let reply = parse_query(packet, host).and_then(|query| {
+                let legacy = (source.port != PORT).then_some(query.id);
+                let destination = if legacy.is_some() || query.unicast {
+                    source
+                } else {
+                    group
+                };
+                Some((response(host, address, legacy)?, destination))
+            });
+            if let Some((packet, destination)) = reply {
+                if let Err(e) = socket.send_slice(&packet, destination) {
+                    warn!("mdns send: {:?}", e);
+                }
+            }
+        }
+
+        if socket.can_send() && self.announcement_due(&name, address, now) {
+            if let Some(packet) = response(host, address, None) {
+                if let Err(e) = socket.send_slice(&packet, group) {
+                    warn!("mdns announce: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Query of `dig -p 5353 @224.0.0.251 coldtrap-lab2.local`
+    #[rustfmt::skip]
+    const LEGACY_QUERY: [u8; 37] = [
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0,
+        13, b'c', b'o', b'l', b'd', b't', b'r', b'a', b'p', b'-', b'l', b'a', b'b', b'2',
+        5, b'l', b'o', b'c', b'a', b'l', 0,
+        0, 1, 0, 1,
+    ];
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            parse_query(&LEGACY_QUERY, b"ColdTrap-Lab2"),
+            Some(Query {
+                id: 0x1234,
+                unicast: false
+            })
+        );
+        assert_eq!(parse_query(&LEGACY_QUERY, b"coldtrap-lab"), None);
+        assert_eq!(parse_query(&LEGACY_QUERY[..30], b"coldtrap-lab2"), None);
+
+        // The second question points into the first, asking for a
+        // unicast response
+        #[rustfmt::skip]
+        let query = [
+            0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0,
+            4, b'h', b'o', b's', b't', 5, b'l', b'o', b'c', b'a', b'l', 0, 0, 28, 0, 1,
+            0xC0, 12, 0, 255, 0x80, 1,
+        ];
+        assert_eq!(
+            parse_query(&query, b"host"),
+            Some(Query {
+                id: 0,
+                unicast: true
+            })
+        );
+        // AAAA only
+        let mut aaaa = query;
+        aaaa[5] = 1;
+        assert_eq!(parse_query(&aaaa, b"host"), None);
+
+        // A pointer to itself
+        let mut looped = query;
+        looped[12] = 0xC0;
+        looped[13] = 12;
+        assert_eq!(parse_query(&looped, b"host"), None);
+
+        // A response
+        let mut response = LEGACY_QUERY;
+        response[2] = 0x84;
+        assert_eq!(parse_query(&response, b"coldtrap-lab2"), None);
+    }
+
+    #[test]
+    fn test_response() {
+        let address = Ipv4Address::new(192, 168, 1, 26);
+        let packet = response(b"host", address, None).unwrap();
+        #[rustfmt::skip]
+        let expected: [u8; 37] = [
+            0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+            4, b'h', b'o', b's', b't', 5, b'l', b'o', b'c', b'a', b'l', 0,
+            0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 26,
+        ];
+        assert_eq!(&packet[..], &expected[..]);
+
+        let packet = response(b"coldtrap-lab2", address, Some(0x1234)).unwrap();
+        assert_eq!(&packet[..4], [0x12, 0x34, 0x84, 0]);
+        assert_eq!(&packet[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&packet[12..37], &LEGACY_QUERY[12..]);
+        assert_eq!(parse_query(&packet[..], b"coldtrap-lab2"), None);
+        assert_eq!(&packet[packet.len() - 10..packet.len() - 6], [0, 0, 0, 10]);
+
+        // The longest name
+        let host = [b'a'; 32];
+        assert!(response(&host, address, Some(0)).is_some());
+    }
+
+    #[test]
+    fn test_announcement_due() {
+        let mut responder = Responder::default();
+        let name = DeviceName::from("host");
+        let address = Ipv4Address::new(192, 168, 1, 26);
+        assert!(responder.announcement_due(&name, address, 0));
+        assert!(!responder.announcement_due(&name, address, 999));
+        assert!(responder.announcement_due(&name, address, 1000));
+        assert!(!responder.announcement_due(&name, address, 5000));
+
+        // Again after a change
+        let address = Ipv4Address::new(192, 168, 1, 27);
+        assert!(responder.announcement_due(&name, address, 5001));
+        let name = DeviceName::from("other");
+        assert!(responder.announcement_due(&name, address, 5002));
+        assert!(!responder.announcement_due(&name, address, 5003));
+    }
+}
@@ -0,0 +1,576 @@
+//! Mocks of the hardware interfaces, so that the command handler can
+//! be run in the host tests.
+//!
+//! `MockChannels` keeps the channel states in memory without an ADC or
+//! DACs behind them, and `MockFlash` records the keys written instead of
+//! storing the values, and reads back those given to it encoded.
+//! `MockAdc` answers the SPI transfers of the ADC drivers from a
+//! register file, and `MockDac` records the frames of the DAC drivers.
+//! `MockBoard` is the `Mcu` of the host tests.
+
+use crate::{
+    ad5680,
+    ad7172::{self, ChannelCalibration, DataRate, DigitalFilterOrder, PostFilter},
+    board::Board,
+    channel_state::ChannelState,
+    channels::{AdcError, ChannelsInterface, CHANNELS, MAX_TEC_I, MAX_TEC_V},
+    command_handler::JsonBuffer,
+    command_parser::Polarity,
+    dac::DacChip,
+    dac8560,
+    flash_store::{FlashInterface, StoreError},
+    stats,
+};
+use core::{cell::RefCell, fmt};
+use heapless::{
+    consts::{U16, U24, U256, U4, U512, U8},
+    String, Vec,
+};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use stm32f4xx_hal::hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+use uom::{
+    si::{
+        electric_potential::volt,
+        f64::{ElectricCurrent, ElectricPotential, Energy},
+    },
+    ConstZero,
+};
+
+pub struct MockChannels {
+    pub states: [ChannelState; CHANNELS],
+    pub powered: [bool; CHANNELS],
+    pub data_rate: [DataRate; CHANNELS],
+    pub filter_order: [DigitalFilterOrder; CHANNELS],
+    pub postfilter: [Option<PostFilter>; CHANNELS],
+    pub reports_dropped: u32,
+    /// Channel of a DAC calibration started
+    pub dac_calibration: Option<usize>,
+    pub dac_chip: DacChip,
+    /// Sent for each report and summary
+    pub json: &'static [u8],
+}
+
+impl MockChannels {
+    pub fn new() -> Self {
+        MockChannels {
+            states: core::array::from_fn(|_| ChannelState::new(ChannelCalibration::default())),
+            powered: [false; CHANNELS],
+            data_rate: [DataRate::DEFAULT; CHANNELS],
+            filter_order: [DigitalFilterOrder::Sinc5Sinc1; CHANNELS],
+            postfilter: [None; CHANNELS],
+            reports_dropped: 0,
+            dac_calibration: None,
+            dac_chip: DacChip::Ad5680,
+            json: b"[]",
+        }
+    }
+
+    fn json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        let mut buf = JsonBuffer::new();
+        buf.extend_from_slice(self.json)
+            .map_err(|_| serde_json_core::ser::Error::BufferFull)?;
+        Ok(buf)
+    }
+}
+
+impl ChannelsInterface for MockChannels {
+    fn channel_state(&mut self, channel: usize) -> &mut ChannelState {
+        &mut self.states[channel]
+    }
+
+    fn is_powered(&self, channel: usize) -> bool {
+        self.powered[channel]
+    }
+
+    fn enable_output(&mut self, channel: usize) {
+        self.powered[channel] = true;
+    }
+
+    fn power_down(&mut self, channel: usize) {
+        self.powered[channel] = false;
+    }
+
+    fn get_i_set(&mut self, channel: usize) -> ElectricCurrent {
+        self.states[channel].i_set
+    }
+
+    fn set_i(&mut self, channel: usize, i_set: ElectricCurrent) -> ElectricCurrent {
+        self.states[channel].i_set = i_set;
+        i_set
+    }
+
+    fn set_i_trim(&mut self, channel: usize, enabled: bool) {
+        self.states[channel].i_trim = if enabled { Some(0.0) } else { None };
+    }
+
+    fn set_polarity(&mut self, channel: usize, polarity: Polarity) {
+        self.states[channel].polarity = polarity;
+    }
+
+    fn get_max_v(&mut self, channel: usize) -> ElectricPotential {
+        self.states[channel].output_limits.max_v
+    }
+
+    fn set_max_v(
+        &mut self,
+        channel: usize,
+        max_v: ElectricPotential,
+    ) -> (ElectricPotential, ElectricPotential) {
+        self.states[channel].output_limits.max_v = max_v;
+        (max_v, MAX_TEC_V)
+    }
+
+    fn get_max_i_pos(&mut self, channel: usize) -> ElectricCurrent {
+        self.states[channel].output_limits.max_i_pos
+    }
+
+    fn set_max_i_pos(
+        &mut self,
+        channel: usize,
+        max_i_pos: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent) {
+        self.states[channel].output_limits.max_i_pos = max_i_pos;
+        (max_i_pos, MAX_TEC_I)
+    }
+
+    fn get_max_i_neg(&mut self, channel: usize) -> ElectricCurrent {
+        self.states[channel].output_limits.max_i_neg
+    }
+
+    fn set_max_i_neg(
+        &mut self,
+        channel: usize,
+        max_i_neg: ElectricCurrent,
+    ) -> (ElectricCurrent, ElectricCurrent) {
+        self.states[channel].output_limits.max_i_neg = max_i_neg;
+        (max_i_neg, MAX_TEC_I)
+    }
+
+    fn start_dac_calibration(&mut self, channel: usize) {
+        self.dac_calibration = Some(channel);
+    }
+
+    fn dac_calibration_running(&self, channel: usize) -> bool {
+        self.dac_calibration == Some(channel)
+    }
+
+    fn any_dac_calibration_running(&self) -> bool {
+        self.dac_calibration.is_some()
+    }
+
+    fn set_dac_code(&mut self, channel: usize, code: u32) -> ElectricPotential {
+        // An ideal DAC with a 3 V reference
+        ElectricPotential::new::<volt>(3.0 * code as f64 / self.dac_max_value(channel) as f64)
+    }
+
+    fn dac_max_value(&self, _channel: usize) -> u32 {
+        match self.dac_chip {
+            DacChip::Ad5680 => ad5680::MAX_VALUE,
+            DacChip::Dac8560 => dac8560::MAX_VALUE,
+        }
+    }
+
+    fn dac_chip(&self, _channel: usize) -> DacChip {
+        self.dac_chip
+    }
+
+    fn get_data_rate(&mut self, index: u8) -> DataRate {
+        self.data_rate[usize::from(index)]
+    }
+
+    fn set_data_rate(&mut self, index: u8, rate: DataRate) {
+        self.data_rate[usize::from(index)] = rate;
+    }
+
+    fn get_filter_order(&mut self, index: u8) -> DigitalFilterOrder {
+        self.filter_order[usize::from(index)]
+    }
+
+    fn set_filter_order(&mut self, index: u8, order: DigitalFilterOrder) {
+        self.filter_order[usize::from(index)] = order;
+    }
+
+    fn get_postfilter(&mut self, index: u8) -> Option<PostFilter> {
+        self.postfilter[usize::from(index)]
+    }
+
+    fn set_postfilter(&mut self, index: u8, filter: Option<PostFilter>) {
+        self.postfilter[usize::from(index)] = filter;
+    }
+
+    fn calibrate_adc(
+        &mut self,
+        channel: usize,
+        _mode: ad7172::Mode,
+    ) -> Result<ChannelCalibration, AdcError> {
+        Ok(self.states[channel].adc_calibration.clone())
+    }
+
+    fn reset_stats(&mut self) {
+        for state in self.states.iter_mut() {
+            state.temperature_stats = stats::Statistics::default();
+            state.tec_i_stats = stats::Statistics::default();
+        }
+    }
+
+    fn reset_energy(&mut self) {
+        for state in self.states.iter_mut() {
+            state.tec_energy = Energy::ZERO;
+        }
+    }
+
+    fn step_test_running(&mut self) -> bool {
+        self.states.iter().any(|state| state.step_test.is_some())
+    }
+
+    fn report_dropped(&mut self) {
+        self.reports_dropped += 1;
+    }
+
+    fn report_csv(
+        &mut self,
+        _channel: usize,
+        _precision: Option<u8>,
+    ) -> Result<String<U512>, fmt::Error> {
+        Ok(String::new())
+    }
+
+    fn reports_json(
+        &mut self,
+        _precision: Option<u8>,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn reports_milli_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn raw_reports_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn tec_check_json(
+        &mut self,
+        _channel: usize,
+    ) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn pid_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn output_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn data_rate_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn postfilter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn b_parameter_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn cascade_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn interlock_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn stats_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn tec_cal_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn stable_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn dtdt_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn locked_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn steinhart_hart_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+
+    fn sensor_summaries_json(&mut self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        self.json()
+    }
+}
+
+#[derive(Default)]
+pub struct MockFlash {
+    /// Keys written or removed, in order
+    pub written: Vec<String<U24>, U16>,
+    /// Fail every write, as with a full store
+    pub full: bool,
+    /// Values to read by key, encoded as in flash
+    pub values: Vec<(String<U24>, Vec<u8, U256>), U4>,
+}
+
+impl MockFlash {
+    fn record(&mut self, key: &str) -> Result<(), StoreError> {
+        if self.full {
+            return Err(sfkv::Error::Write(sfkv::WriteError::SpaceExhausted));
+        }
+        let _ = self.written.push(String::from(key));
+        Ok(())
+    }
+}
+
+impl FlashInterface for MockFlash {
+    fn read_value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<Option<T>, StoreError> {
+        self.values
+            .iter()
+            .find(|(saved, _)| saved == key)
+            .map(|(_, value)| {
+                postcard::from_bytes(value)
+                    .map_err(|_| sfkv::Error::Read(sfkv::ReadError::Truncated { offset: 0 }))
+            })
+            .transpose()
+    }
+
+    fn write_value<T: Serialize, V: AsMut<[u8]>>(
+        &mut self,
+        key: &str,
+        _value: &T,
+        _value_buf: V,
+    ) -> Result<(), StoreError> {
+        self.record(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), StoreError> {
+        self.record(key)
+    }
+}
+
+/// ADC register file behind `MockSpi`. Registers read back what was
+/// written, and the ID register its chip. A read followed by one more
+/// byte, as with the SPI checksums of the AD7172, gets its CRC.
+pub struct MockAdc {
+    /// By address, as after a reset
+    defaults: [[u8; 3]; 64],
+    pub registers: [[u8; 3]; 64],
+    /// Nothing on the bus, every byte reads as all ones
+    pub absent: bool,
+    /// Reads still to answer with a wrong checksum
+    pub bad_checksums: usize,
+    /// Communications byte of the frame under way
+    command: Option<u8>,
+    /// Data transferred in the frame under way
+    data: Option<([u8; 3], usize)>,
+}
+
+impl MockAdc {
+    fn new(id_address: u8, id: [u8; 3]) -> Self {
+        let mut defaults = [[0; 3]; 64];
+        defaults[usize::from(id_address)] = id;
+        MockAdc {
+            defaults,
+            registers: defaults,
+            absent: false,
+            bad_checksums: 0,
+            command: None,
+            data: None,
+        }
+    }
+
+    pub fn ad7172() -> Self {
+        MockAdc::new(0x07, [0x00, 0xD4, 0])
+    }
+
+    pub fn ad7124() -> Self {
+        MockAdc::new(0x05, [0x14, 0, 0])
+    }
+
+    pub fn absent() -> Self {
+        MockAdc {
+            absent: true,
+            ..MockAdc::ad7172()
+        }
+    }
+
+    /// Bus and chip select for a driver, keeping the ADC to look at
+    pub fn bus(self) -> (Rc<RefCell<MockAdc>>, MockSpi, MockNss) {
+        let adc = Rc::new(RefCell::new(self));
+        (adc.clone(), MockSpi(adc.clone()), MockNss(adc))
+    }
+
+    fn transfer(&mut self, words: &mut [u8]) {
+        if self.absent {
+            words.fill(0xFF);
+            return;
+        }
+        match (self.command, self.data) {
+            (None, _) if words.len() == 8 && words.iter().all(|&word| word == 0xFF) => {
+                self.registers = self.defaults;
+            }
+            (None, _) => self.command = Some(words[0]),
+            (Some(command), None) => {
+                let register = &mut self.registers[usize::from(command & 0x3F)];
+                if command & 0x40 != 0 {
+                    words.copy_from_slice(&register[..words.len()]);
+                } else {
+                    register[..words.len()].copy_from_slice(words);
+                }
+                let mut data = [0; 3];
+                data[..words.len()].copy_from_slice(words);
+                self.data = Some((data, words.len()));
+            }
+            (Some(command), Some((data, len))) => {
+                if command & 0x40 != 0 {
+                    words[0] = crc8(&[&[command], &data[..len]].concat());
+                    if self.bad_checksums > 0 {
+                        self.bad_checksums -= 1;
+                        words[0] ^= 0xFF;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// CRC-8 with the polynomial x8 + x2 + x + 1 of the AD7172
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+pub struct MockSpi(Rc<RefCell<MockAdc>>);
+
+impl Transfer<u8> for MockSpi {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        self.0.borrow_mut().transfer(words);
+        Ok(words)
+    }
+}
+
+/// Ends the frame under way when raised
+pub struct MockNss(Rc<RefCell<MockAdc>>);
+
+impl OutputPin for MockNss {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), ()> {
+        let mut adc = self.0.borrow_mut();
+        adc.command = None;
+        adc.data = None;
+        Ok(())
+    }
+}
+
+/// A DAC that records the frames written to it
+#[derive(Default)]
+pub struct MockDac {
+    /// Frames started by a pulse of SYNC, in order
+    pub frames: Vec<[u8; 3], U8>,
+    sync_high: bool,
+    /// SYNC went high and low again since the last frame
+    pulsed: bool,
+}
+
+impl MockDac {
+    /// Bus and SYNC for a driver, keeping the DAC to look at
+    pub fn bus(self) -> (Rc<RefCell<MockDac>>, MockDacSpi, MockDacSync) {
+        let dac = Rc::new(RefCell::new(self));
+        (dac.clone(), MockDacSpi(dac.clone()), MockDacSync(dac))
+    }
+}
+
+pub struct MockDacSpi(Rc<RefCell<MockDac>>);
+
+impl Transfer<u8> for MockDacSpi {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        let mut dac = self.0.borrow_mut();
+        if dac.pulsed && !dac.sync_high && words.len() == 3 {
+            dac.pulsed = false;
+            let _ = dac.frames.push([words[0], words[1], words[2]]);
+        }
+        Ok(words)
+    }
+}
+
+pub struct MockDacSync(Rc<RefCell<MockDac>>);
+
+impl OutputPin for MockDacSync {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), ()> {
+        let mut dac = self.0.borrow_mut();
+        if dac.sync_high {
+            dac.sync_high = false;
+            dac.pulsed = true;
+        }
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), ()> {
+        self.0.borrow_mut().sync_high = true;
+        Ok(())
+    }
+}
+
+std::thread_local! {
+    static NOW_MS: core::cell::Cell<u64> = core::cell::Cell::new(0);
+}
+
+/// The board of the host tests, whose clock advances by a millisecond
+/// each time it is read, so that `timer::sleep()` returns
+pub struct MockBoard;
+
+/// `Channels` is built but not run in the host tests, so it keeps the
+/// ADC and SPI of the STM32F4
+impl Board for MockBoard {
+    type PinsAdc = crate::pins::PinsAdc;
+    type SpiError = <crate::pins::AdcSpi as Transfer<u8>>::Error;
+
+    fn now_u64() -> u64 {
+        NOW_MS.with(|now| {
+            let ms = now.get();
+            now.set(ms + 1);
+            ms
+        })
+    }
+
+    fn reset() -> ! {
+        panic!("reset")
+    }
+
+    fn set_dfu_trigger() {}
+}
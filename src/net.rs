@@ -2,10 +2,14 @@
 //! declared once and globally.
 
 use crate::command_parser::Ipv4Config;
+use crate::interrupt::{CriticalSection, Mutex};
+use crate::mdns;
 use crate::pins::EthernetPins;
+use crate::timer;
 use core::cell::RefCell;
-use cortex_m::interrupt::{CriticalSection, Mutex};
+use log::warn;
 use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::time::Instant;
 use smoltcp::wire::{EthernetAddress, Ipv4Address, Ipv4Cidr};
 use stm32_eth::{Eth, RingEntry, RxDescriptor, TxDescriptor};
 use stm32f4xx_hal::{
@@ -58,12 +62,21 @@ pub fn run<F>(
     let mut routes_storage = [None; 1];
     let mut routes = Routes::new(&mut routes_storage[..]);
     gateway.map(|gateway| routes.add_default_ipv4_route(gateway).unwrap());
-    let iface = EthernetInterfaceBuilder::new(&mut eth_dev)
+    let mut multicast_storage = [None; 1];
+    let mut iface = EthernetInterfaceBuilder::new(&mut eth_dev)
         .ethernet_addr(ethernet_addr)
         .ip_addrs(&mut ip_addrs[..])
         .neighbor_cache(neighbor_cache)
         .routes(routes)
+        .ipv4_multicast_groups(&mut multicast_storage[..])
         .finalize();
+    // Reports the membership with IGMP if the link is up already, and
+    // on queries of the routers
+    if let Err(e) =
+        iface.join_multicast_group(mdns::GROUP, Instant::from_millis(timer::now_u64() as i64))
+    {
+        warn!("cannot join the mDNS group: {:?}", e);
+    }
 
     f(iface);
 }
@@ -72,7 +85,7 @@ pub fn run<F>(
 /// clear interrupt flags.
 #[interrupt]
 fn ETH() {
-    cortex_m::interrupt::free(|cs| {
+    crate::interrupt::free(|cs| {
         *NET_PENDING.borrow(cs).borrow_mut() = true;
     });
 
@@ -91,6 +104,48 @@ pub fn clear_pending(cs: &CriticalSection) {
     *NET_PENDING.borrow(cs).borrow_mut() = false;
 }
 
+/// Address of the PHY on the MDIO bus
+const PHY_ADDR: u32 = 0;
+/// PHY basic status register
+const PHY_REG_BSR: u32 = 1;
+/// PHY identifier 1 register
+const PHY_REG_ID1: u32 = 2;
+const PHY_BSR_LINK_STATUS: u32 = 1 << 2;
+const MACMIIAR_MB: u32 = 1 << 0;
+const MACMIIAR_CR: u32 = 0b111 << 2;
+
+/// Read a PHY register over MDIO, once the MAC has been set up by
+/// `run()`. `None` if the read times out.
+fn phy_read(reg: u32) -> Option<u32> {
+    // Not used by the driver after its setup
+    let mac = unsafe { &*ETHERNET_MAC::ptr() };
+    // Keep the MDC clock range
+    let cr = mac.macmiiar.read().bits() & MACMIIAR_CR;
+    mac.macmiiar
+        .write(|w| unsafe { w.bits(PHY_ADDR << 11 | reg << 6 | cr | MACMIIAR_MB) });
+    let start = timer::now();
+    while mac.macmiiar.read().bits() & MACMIIAR_MB != 0 {
+        if timer::now().wrapping_sub(start) > 10 {
+            return None;
+        }
+    }
+    Some(mac.macmiidr.read().bits() & 0xFFFF)
+}
+
+/// Whether the PHY answers on the MDIO bus
+pub fn phy_present() -> bool {
+    // Nothing pulls MDIO low without a PHY
+    matches!(phy_read(PHY_REG_ID1), Some(id1) if id1 != 0xFFFF && id1 != 0)
+}
+
+/// Whether the PHY reports an established Ethernet link
+pub fn link_up() -> bool {
+    // The link status bit latches low, so read it twice for the
+    // current state
+    let _ = phy_read(PHY_REG_BSR);
+    matches!(phy_read(PHY_REG_BSR), Some(bsr) if bsr != 0xFFFF && bsr & PHY_BSR_LINK_STATUS != 0)
+}
+
 /// utility for destructuring into smoltcp types
 pub fn split_ipv4_config(config: Ipv4Config) -> (Ipv4Cidr, Option<Ipv4Address>) {
     let cidr = Ipv4Cidr::new(Ipv4Address(config.address), config.mask_len);
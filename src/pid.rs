@@ -12,6 +12,20 @@ pub struct Parameters {
     pub output_min: f32,
     /// Output limit maximum
     pub output_max: f32,
+    /// Target slew rate in K/s, `0` to step immediately
+    pub ramp_rate: f32,
+    /// Time constant of the derivative low-pass filter in seconds, `0`
+    /// for no filtering
+    pub d_filter: f32,
+    /// Feedforward output offset
+    pub ff_offset: f32,
+    /// Feedforward gain per kelvin of target above `ff_reference`
+    pub ff_gain: f32,
+    /// Reference temperature for the feedforward gain, as configured
+    /// rather than measured, such as the usual ambient
+    pub ff_reference: f32,
+    /// Run the controller on the average of this many ADC samples
+    pub divisor: u32,
 }
 
 impl Default for Parameters {
@@ -22,6 +36,12 @@ impl Default for Parameters {
             kd: 0.0,
             output_min: -2.0,
             output_max: 2.0,
+            ramp_rate: 0.0,
+            d_filter: 0.0,
+            ff_offset: 0.0,
+            ff_gain: 0.0,
+            ff_reference: 25.0,
+            divisor: 1,
         }
     }
 }
@@ -30,9 +50,12 @@ impl Default for Parameters {
 pub struct Controller {
     pub parameters: Parameters,
     pub target: f64,
+    /// Target in effect while slewing towards `target`
+    ramp_target: Option<f64>,
     u1: f64,
     x1: f64,
-    x2: f64,
+    /// Filtered first difference of the input
+    d1: f64,
     pub y1: f64,
 }
 
@@ -41,44 +64,98 @@ impl Controller {
         Controller {
             parameters,
             target: 0.0,
+            ramp_target: None,
             u1: 0.0,
             x1: 0.0,
-            x2: 0.0,
+            d1: 0.0,
             y1: 0.0,
         }
     }
 
     // Based on https://hackmd.io/IACbwcOTSt6Adj3_F9bKuw PID implementation
-    // Input x(t), target u(t), output y(t)
-    // y0' =   y1 - ki * u0
-    //       + x0 * (kp + ki + kd)
-    //       - x1 * (kp + 2kd)
-    //       + x2 * kd
-    // y0  = clip(y0', ymin, ymax)
-    pub fn update(&mut self, input: f64) -> f64 {
+    // Input x(t), target u(t), output y(t), sampling interval dt
+    // a   = dt / (d_filter + dt)
+    // d0  = d1 + a * (x0 - x1 - d1)
+    // y0' =   y1 + kp * (x0 - x1)
+    //       + ki * (x0 - u0)
+    //       + kd * (d0 - d1)
+    // y0  = clip(y0' + ff(u0), ymin, ymax) - ff(u0)
+    // Without filtering (a = 1), d0 - d1 = x0 - 2 * x1 + x2.
+    // The output is y0 + ff(u0), so that y0 is the clipped output
+    // less the feedforward term.
+    pub fn update(&mut self, input: f64, dt: f64) -> f64 {
+        self.ramp(input, dt);
+
         let kp = self.parameters.kp as f64;
         let ki = self.parameters.ki as f64;
         let kd = self.parameters.kd as f64;
+        let target = self.setpoint();
+
+        let d_filter = self.parameters.d_filter as f64;
+        let a = if d_filter > 0.0 {
+            dt / (d_filter + dt)
+        } else {
+            1.0
+        };
+        let d0 = self.d1 + a * (input - self.x1 - self.d1);
 
+        let feedforward = self.feedforward(target);
         #[rustfmt::skip]
-        let mut output = self.y1 - ki * self.target
-            + input * (kp + ki + kd)
-            - self.x1 * (kp + 2.0 * kd)
-            + self.x2 * kd;
-        output = output.clamp(self.parameters.output_min.into(), self.parameters.output_max.into());
+        let output = self.y1
+            + kp * (input - self.x1)
+            + ki * (input - target)
+            + kd * (d0 - self.d1)
+            + feedforward;
+        let output = output.clamp(
+            self.parameters.output_min.into(),
+            self.parameters.output_max.into(),
+        );
 
-        self.x2 = self.x1;
+        self.d1 = d0;
         self.x1 = input;
-        self.u1 = self.target;
-        self.y1 = output;
+        self.u1 = target;
+        self.y1 = output - feedforward;
         output
     }
 
+    /// Target in effect, while slewing or not
+    pub fn setpoint(&self) -> f64 {
+        self.ramp_target.unwrap_or(self.target)
+    }
+
+    /// Known heat load contribution to the output, for the given target
+    fn feedforward(&self, target: f64) -> f64 {
+        let ff_offset = self.parameters.ff_offset as f64;
+        let ff_gain = self.parameters.ff_gain as f64;
+        let ff_reference = self.parameters.ff_reference as f64;
+        ff_offset + ff_gain * (target - ff_reference)
+    }
+
+    /// Slew the target in effect towards `target` by at most
+    /// `ramp_rate * dt`, starting from `input` after a reset
+    fn ramp(&mut self, input: f64, dt: f64) {
+        let ramp_rate = self.parameters.ramp_rate as f64;
+        if ramp_rate > 0.0 {
+            let current = self.ramp_target.unwrap_or(input);
+            let max_step = ramp_rate * dt;
+            let step = (self.target - current).clamp(-max_step, max_step);
+            self.ramp_target = Some(current + step);
+        } else {
+            self.ramp_target = None;
+        }
+    }
+
+    /// Restart the ramp from the next input
+    pub fn reset_ramp(&mut self) {
+        self.ramp_target = None;
+    }
+
     pub fn summary(&self, channel: usize) -> Summary {
         Summary {
             channel,
             parameters: self.parameters.clone(),
             target: self.target,
+            ramp_target: self.ramp_target,
         }
     }
 }
@@ -88,6 +165,7 @@ pub struct Summary {
     channel: usize,
     parameters: Parameters,
     target: f64,
+    ramp_target: Option<f64>,
 }
 
 #[cfg(test)]
@@ -100,6 +178,12 @@ mod test {
         kd: 0.15,
         output_min: -10.0,
         output_max: 10.0,
+        ramp_rate: 0.0,
+        d_filter: 0.0,
+        ff_offset: 0.0,
+        ff_gain: 0.0,
+        ff_reference: 25.0,
+        divisor: 1,
     };
 
     #[test]
@@ -128,7 +212,7 @@ mod test {
         while !values.iter().all(|value| target.contains(value)) && total_t < CYCLE_LIMIT {
             let next_t = (t + 1) % DELAY;
             // Feed the oldest temperature
-            output = pid.update(values[next_t]);
+            output = pid.update(values[next_t], 1.0);
             // Overwrite oldest with previous temperature - output
             values[next_t] = values[t] - output - (values[t] - DEFAULT) * LOSS;
             t = next_t;
@@ -137,4 +221,84 @@ mod test {
         }
         assert_ne!(CYCLE_LIMIT, total_t);
     }
+
+    #[test]
+    fn test_ramp() {
+        let mut pid = Controller::new(Parameters {
+            ramp_rate: 0.5,
+            ..PARAMETERS
+        });
+        pid.target = 30.0;
+
+        pid.ramp(20.0, 1.0);
+        assert_eq!(pid.ramp_target, Some(20.5));
+        pid.ramp(25.0, 2.0);
+        assert_eq!(pid.ramp_target, Some(21.5));
+        for _ in 0..20 {
+            pid.ramp(25.0, 1.0);
+        }
+        assert_eq!(pid.ramp_target, Some(30.0));
+
+        pid.parameters.ramp_rate = 0.0;
+        pid.ramp(25.0, 1.0);
+        assert_eq!(pid.ramp_target, None);
+    }
+
+    #[test]
+    fn test_d_filter() {
+        let parameters = Parameters {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 1.0,
+            ..PARAMETERS
+        };
+        let mut unfiltered = Controller::new(parameters.clone());
+        let mut filtered = Controller::new(Parameters {
+            d_filter: 0.9,
+            ..parameters
+        });
+        // Step of the input by 1.0 within 0.1 s
+        assert_eq!(unfiltered.update(1.0, 0.1), 1.0);
+        assert!((filtered.update(1.0, 0.1) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_feedforward() {
+        let mut pid = Controller::new(Parameters {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            ff_offset: 0.1,
+            ff_gain: 0.05,
+            ff_reference: 20.0,
+            ..PARAMETERS
+        });
+        pid.target = 30.0;
+        let output = pid.update(30.0, 0.1);
+        assert!((output - 0.6).abs() < 1e-6);
+        // Feedforward is not accumulated into the PID state
+        assert_eq!(pid.y1, 0.0);
+    }
+
+    #[test]
+    fn test_feedforward_clamp() {
+        let mut pid = Controller::new(Parameters {
+            kp: 0.0,
+            ki: 1.0,
+            kd: 0.0,
+            output_min: -1.0,
+            output_max: 1.0,
+            ff_offset: 0.8,
+            ..PARAMETERS
+        });
+        pid.target = 20.0;
+        // Clipped once, with the feedforward term
+        let output = pid.update(22.0, 1.0);
+        assert_eq!(output, 1.0);
+        assert!((pid.y1 + 0.8 - output).abs() < 1e-6);
+        // Without windup, the output leaves the limit as soon as the
+        // error changes sign
+        let output = pid.update(19.5, 1.0);
+        assert!((output - 0.5).abs() < 1e-6);
+    }
 }
@@ -1,13 +1,14 @@
 use crate::{
     channel::{Channel0, Channel1},
     fan_ctrl::FanPin,
+    fan_tach,
     hw_rev::{HWRev, HWSettings},
     leds::Leds,
 };
 use eeprom24x::{self, Eeprom24x};
 use stm32_eth::EthPins;
 use stm32f4xx_hal::{
-    adc::Adc,
+    adc::{config::SampleTime, Adc},
     gpio::{
         gpioa::*, gpiob::*, gpioc::*, gpioe::*, gpiof::*, gpiog::*, Alternate, AlternateOD, Analog,
         Floating, GpioExt, Input, Output, PushPull, AF5,
@@ -17,7 +18,7 @@ use stm32f4xx_hal::{
     otg_fs::USB,
     pac::{
         ADC1, GPIOA, GPIOB, GPIOC, GPIOD, GPIOE, GPIOF, GPIOG, I2C1, OTG_FS_DEVICE, OTG_FS_GLOBAL,
-        OTG_FS_PWRCLK, SPI2, SPI4, SPI5, TIM1, TIM3, TIM8,
+        OTG_FS_PWRCLK, SPI2, SPI4, SPI5, TIM1, TIM3, TIM4, TIM8,
     },
     pwm::{self, PwmChannels},
     rcc::Clocks,
@@ -52,40 +53,92 @@ pub trait ChannelPins {
     type DacSpi: Transfer<u8>;
     type DacSync: OutputPin;
     type Shdn: OutputPin;
-    type VRefPin;
-    type ITecPin;
-    type DacFeedbackPin;
-    type TecUMeasPin;
+    type VRefPin: PinsAdcInput;
+    type ITecPin: PinsAdcInput;
+    type DacFeedbackPin: PinsAdcInput;
+    type TecUMeasPin: PinsAdcInput;
+    type MaxV: hal::PwmPin<Duty = u16>;
+    type MaxIPos: hal::PwmPin<Duty = u16>;
+    type MaxINeg: hal::PwmPin<Duty = u16>;
 }
 
-pub enum Channel0VRef {
-    Analog(PA0<Analog>),
-    Disabled(PA0<Input<Floating>>),
+/// Input of the STM32 ADC
+pub trait PinsAdcInput {
+    /// Convert the input, `None` if it is not connected
+    fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16>;
+
+    fn connected(&self) -> bool {
+        true
+    }
+}
+
+macro_rules! pins_adc_input {
+    ($($pin: ty),*) => {
+        $(
+            impl PinsAdcInput for $pin {
+                fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16> {
+                    Some(pins_adc.convert(self, SampleTime::Cycles_480))
+                }
+            }
+        )*
+    };
+}
+
+pins_adc_input!(
+    PA0<Analog>,
+    PA3<Analog>,
+    PA4<Analog>,
+    PA5<Analog>,
+    PA6<Analog>,
+    PB0<Analog>,
+    PC2<Analog>,
+    PC3<Analog>
+);
+
+/// VREF of the MAX driver, connected to the STM32 ADC from hardware
+/// revision 3 on
+pub enum VRef<A, D> {
+    Analog(A),
+    Disabled(D),
+}
+
+impl<A: PinsAdcInput, D> PinsAdcInput for VRef<A, D> {
+    fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16> {
+        match self {
+            VRef::Analog(pin) => pin.convert(pins_adc),
+            VRef::Disabled(_) => None,
+        }
+    }
+
+    fn connected(&self) -> bool {
+        matches!(self, VRef::Analog(_))
+    }
 }
 
 impl ChannelPins for Channel0 {
     type DacSpi = Dac0Spi;
     type DacSync = PE4<Output<PushPull>>;
     type Shdn = PE10<Output<PushPull>>;
-    type VRefPin = Channel0VRef;
+    type VRefPin = VRef<PA0<Analog>, PA0<Input<Floating>>>;
     type ITecPin = PA6<Analog>;
     type DacFeedbackPin = PA4<Analog>;
     type TecUMeasPin = PC2<Analog>;
-}
-
-pub enum Channel1VRef {
-    Analog(PA3<Analog>),
-    Disabled(PA3<Input<Floating>>),
+    type MaxV = PwmChannels<TIM3, pwm::C1>;
+    type MaxIPos = PwmChannels<TIM1, pwm::C1>;
+    type MaxINeg = PwmChannels<TIM1, pwm::C3>;
 }
 
 impl ChannelPins for Channel1 {
     type DacSpi = Dac1Spi;
     type DacSync = PF6<Output<PushPull>>;
     type Shdn = PE15<Output<PushPull>>;
-    type VRefPin = Channel1VRef;
+    type VRefPin = VRef<PA3<Analog>, PA3<Input<Floating>>>;
     type ITecPin = PB0<Analog>;
     type DacFeedbackPin = PA5<Analog>;
     type TecUMeasPin = PC3<Analog>;
+    type MaxV = PwmChannels<TIM3, pwm::C2>;
+    type MaxIPos = PwmChannels<TIM1, pwm::C2>;
+    type MaxINeg = PwmChannels<TIM1, pwm::C4>;
 }
 
 /// SPI peripheral used for communication with the ADC
@@ -99,6 +152,8 @@ pub type AdcSpi = Spi<
     TransferModeNormal,
 >;
 pub type AdcNss = PB12<Output<PushPull>>;
+/// Output of `stable_output`
+pub type StablePin = stm32f4xx_hal::gpio::gpiod::PD8<Output<PushPull>>;
 type Dac0Spi = Spi<SPI4, (PE2<Alternate<AF5>>, NoMiso, PE6<Alternate<AF5>>), TransferModeNormal>;
 type Dac1Spi = Spi<SPI5, (PF7<Alternate<AF5>>, NoMiso, PF9<Alternate<AF5>>), TransferModeNormal>;
 pub type PinsAdc = Adc<ADC1>;
@@ -111,6 +166,9 @@ pub struct ChannelPinSet<C: ChannelPins> {
     pub itec_pin: C::ITecPin,
     pub dac_feedback_pin: C::DacFeedbackPin,
     pub tec_u_meas_pin: C::TecUMeasPin,
+    pub max_v: C::MaxV,
+    pub max_i_pos: C::MaxIPos,
+    pub max_i_neg: C::MaxINeg,
 }
 
 pub struct HWRevPins {
@@ -124,7 +182,6 @@ pub struct Pins {
     pub adc_spi: AdcSpi,
     pub adc_nss: AdcNss,
     pub pins_adc: PinsAdc,
-    pub pwm: PwmPins,
     pub channel0: ChannelPinSet<Channel0>,
     pub channel1: ChannelPinSet<Channel1>,
 }
@@ -133,7 +190,7 @@ impl Pins {
     /// Setup GPIO pins and configure MCU peripherals
     pub fn setup(
         clocks: Clocks,
-        (tim1, tim3, tim8): (TIM1, TIM3, TIM8),
+        (tim1, tim3, tim4, tim8): (TIM1, TIM3, TIM4, TIM8),
         (gpioa, gpiob, gpioc, gpiod, gpioe, gpiof, gpiog): (
             GPIOA,
             GPIOB,
@@ -160,6 +217,7 @@ impl Pins {
         Option<FanPin>,
         HWRev,
         HWSettings,
+        StablePin,
     ) {
         let gpioa = gpioa.split();
         let gpiob = gpiob.split();
@@ -174,7 +232,14 @@ impl Pins {
 
         let pins_adc = Adc::adc1(adc1, true, Default::default());
 
-        let pwm = PwmPins::setup(
+        let PwmPins {
+            max_v0,
+            max_v1,
+            max_i_pos0,
+            max_i_pos1,
+            max_i_neg0,
+            max_i_neg1,
+        } = PwmPins::setup(
             clocks,
             (tim1, tim3),
             (gpioc.pc6, gpioc.pc7),
@@ -194,9 +259,9 @@ impl Pins {
         let mut shdn0 = gpioe.pe10.into_push_pull_output();
         shdn0.set_low();
         let vref0_pin = if hwrev.major > 2 {
-            Channel0VRef::Analog(gpioa.pa0.into_analog())
+            VRef::Analog(gpioa.pa0.into_analog())
         } else {
-            Channel0VRef::Disabled(gpioa.pa0)
+            VRef::Disabled(gpioa.pa0)
         };
         let itec0_pin = gpioa.pa6.into_analog();
         let dac_feedback0_pin = gpioa.pa4.into_analog();
@@ -209,15 +274,18 @@ impl Pins {
             itec_pin: itec0_pin,
             dac_feedback_pin: dac_feedback0_pin,
             tec_u_meas_pin: tec_u_meas0_pin,
+            max_v: max_v0,
+            max_i_pos: max_i_pos0,
+            max_i_neg: max_i_neg0,
         };
 
         let (dac1_spi, dac1_sync) = Self::setup_dac1(clocks, spi5, gpiof.pf7, gpiof.pf6, gpiof.pf9);
         let mut shdn1 = gpioe.pe15.into_push_pull_output();
         shdn1.set_low();
         let vref1_pin = if hwrev.major > 2 {
-            Channel1VRef::Analog(gpioa.pa3.into_analog())
+            VRef::Analog(gpioa.pa3.into_analog())
         } else {
-            Channel1VRef::Disabled(gpioa.pa3)
+            VRef::Disabled(gpioa.pa3)
         };
         let itec1_pin = gpiob.pb0.into_analog();
         let dac_feedback1_pin = gpioa.pa5.into_analog();
@@ -230,13 +298,15 @@ impl Pins {
             itec_pin: itec1_pin,
             dac_feedback_pin: dac_feedback1_pin,
             tec_u_meas_pin: tec_u_meas1_pin,
+            max_v: max_v1,
+            max_i_pos: max_i_pos1,
+            max_i_neg: max_i_neg1,
         };
 
         let pins = Pins {
             adc_spi,
             adc_nss,
             pins_adc,
-            pwm,
             channel0,
             channel1,
         };
@@ -272,6 +342,7 @@ impl Pins {
         };
 
         let fan = if hw_settings.fan_available {
+            fan_tach::setup(tim4, gpiod.pd12.into_alternate(), &clocks);
             Some(
                 Timer::new(tim8, &clocks)
                     .pwm(gpioc.pc9.into_alternate(), hw_settings.fan_pwm_freq_hz.hz()),
@@ -280,7 +351,19 @@ impl Pins {
             None
         };
 
-        (pins, leds, eeprom, eth_pins, usb, fan, hwrev, hw_settings)
+        let stable_pin = gpiod.pd8.into_push_pull_output();
+
+        (
+            pins,
+            leds,
+            eeprom,
+            eth_pins,
+            usb,
+            fan,
+            hwrev,
+            hw_settings,
+            stable_pin,
+        )
     }
 
     /// Configure the GPIO pins for SPI operation, and initialize SPI
@@ -346,13 +429,13 @@ impl Pins {
     }
 }
 
-pub struct PwmPins {
-    pub max_v0: PwmChannels<TIM3, pwm::C1>,
-    pub max_v1: PwmChannels<TIM3, pwm::C2>,
-    pub max_i_pos0: PwmChannels<TIM1, pwm::C1>,
-    pub max_i_pos1: PwmChannels<TIM1, pwm::C2>,
-    pub max_i_neg0: PwmChannels<TIM1, pwm::C3>,
-    pub max_i_neg1: PwmChannels<TIM1, pwm::C4>,
+struct PwmPins {
+    max_v0: PwmChannels<TIM3, pwm::C1>,
+    max_v1: PwmChannels<TIM3, pwm::C2>,
+    max_i_pos0: PwmChannels<TIM1, pwm::C1>,
+    max_i_pos1: PwmChannels<TIM1, pwm::C2>,
+    max_i_neg0: PwmChannels<TIM1, pwm::C3>,
+    max_i_neg1: PwmChannels<TIM1, pwm::C4>,
 }
 
 impl PwmPins {
@@ -0,0 +1,307 @@
+//! Wall-clock time from the RTC, which keeps running across resets in
+//! the backup domain.
+//!
+//! The RTC is clocked from the LSE crystal if one starts up, or the
+//! less accurate LSI oscillator otherwise.
+
+use crate::timer;
+use core::fmt;
+use log::{info, warn};
+use stm32f4xx_hal::stm32::{PWR, RCC, RTC};
+
+/// Asynchronous and synchronous prescalers down to 1 Hz, from
+/// 32768 Hz for the LSE and about 32 kHz for the LSI
+const PREDIV_A: u32 = 127;
+const LSE_PREDIV_S: u32 = 255;
+const LSI_PREDIV_S: u32 = 249;
+/// Time in milliseconds to wait for an oscillator to become ready
+const LSE_TIMEOUT: u32 = 250;
+const LSI_TIMEOUT: u32 = 10;
+/// Time in milliseconds to wait for the RTC to enter initialization
+/// mode, or to synchronize its shadow registers
+const RTC_TIMEOUT: u32 = 10;
+
+const RCC_APB1ENR_PWREN: u32 = 1 << 28;
+const RCC_BDCR_LSEON: u32 = 1 << 0;
+const RCC_BDCR_LSERDY: u32 = 1 << 1;
+const RCC_BDCR_RTCSEL: u32 = 0b11 << 8;
+const RCC_BDCR_RTCSEL_LSE: u32 = 0b01 << 8;
+const RCC_BDCR_RTCSEL_LSI: u32 = 0b10 << 8;
+const RCC_BDCR_RTCEN: u32 = 1 << 15;
+const RCC_CSR_LSION: u32 = 1 << 0;
+const RCC_CSR_LSIRDY: u32 = 1 << 1;
+const PWR_CR_DBP: u32 = 1 << 8;
+const RTC_ISR_INITS: u32 = 1 << 4;
+const RTC_ISR_RSF: u32 = 1 << 5;
+const RTC_ISR_INITF: u32 = 1 << 6;
+const RTC_ISR_INIT: u32 = 1 << 7;
+
+/// Range of times the RTC calendar can hold, in seconds since the
+/// Unix epoch. The year 2000 is left out as it cannot be told apart
+/// from a calendar that has never been set.
+pub const EPOCH_MIN: u32 = 978_307_200;
+pub const EPOCH_MAX: u32 = 4_102_444_799;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+}
+
+impl DateTime {
+    pub fn from_epoch_millis(millis: u64) -> Self {
+        let days = (millis / 86_400_000) as i64;
+        let millis = (millis % 86_400_000) as u32;
+        // Howard Hinnant's civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400) as u32 + u32::from(month <= 2);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: millis / 3_600_000,
+            minute: millis / 60_000 % 60,
+            second: millis / 1000 % 60,
+            millis: millis % 1000,
+        }
+    }
+
+    /// Days since the Unix epoch
+    fn days(&self) -> i64 {
+        // Howard Hinnant's days_from_civil
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = i64::from(self.month);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    pub fn to_epoch_millis(self) -> u64 {
+        let seconds = self.days() as u64 * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        seconds * 1000 + u64::from(self.millis)
+    }
+
+    /// ISO 8601 day of the week, from 1 for Monday to 7 for Sunday
+    fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((self.days() + 3).rem_euclid(7) + 1) as u32
+    }
+}
+
+/// ISO 8601 in UTC
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+fn wait_for<F: Fn() -> bool>(timeout: u32, ready: F) -> bool {
+    let start = timer::now();
+    while !ready() {
+        if timer::now().wrapping_sub(start) > timeout {
+            return false;
+        }
+    }
+    true
+}
+
+fn enable_lsi(rcc: &stm32f4xx_hal::stm32::rcc::RegisterBlock) -> bool {
+    rcc.csr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_CSR_LSION) });
+    wait_for(LSI_TIMEOUT, || rcc.csr.read().bits() & RCC_CSR_LSIRDY != 0)
+}
+
+/// Run `f` in initialization mode, in which the prescalers and the
+/// calendar can be written
+fn init_mode<F: FnOnce(&stm32f4xx_hal::stm32::rtc::RegisterBlock)>(f: F) {
+    let rtc = unsafe { &*RTC::ptr() };
+    // Remove write protection
+    rtc.wpr.write(|w| unsafe { w.bits(0xCA) });
+    rtc.wpr.write(|w| unsafe { w.bits(0x53) });
+    rtc.isr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RTC_ISR_INIT) });
+    if wait_for(RTC_TIMEOUT, || rtc.isr.read().bits() & RTC_ISR_INITF != 0) {
+        f(rtc);
+    } else {
+        warn!("RTC does not enter initialization mode");
+    }
+    rtc.isr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(RTC_ISR_INIT | RTC_ISR_RSF)) });
+    rtc.wpr.write(|w| unsafe { w.bits(0xFF) });
+    // Shadow registers are read after the calendar has been updated
+    wait_for(RTC_TIMEOUT, || rtc.isr.read().bits() & RTC_ISR_RSF != 0);
+}
+
+/// Start the RTC unless it has kept running since before the reset.
+/// The peripherals are taken here, but accessed through their
+/// pointers afterwards so that the time can be read anywhere.
+pub fn setup(_rtc: RTC, pwr: PWR) {
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.apb1enr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_APB1ENR_PWREN) });
+    // Allow writes to the backup domain
+    pwr.cr
+        .modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR_DBP) });
+
+    let bdcr = rcc.bdcr.read().bits();
+    if bdcr & RCC_BDCR_RTCEN != 0 {
+        // The LSI is not in the backup domain
+        if bdcr & RCC_BDCR_RTCSEL == RCC_BDCR_RTCSEL_LSI && !enable_lsi(rcc) {
+            warn!("RTC: LSI not ready");
+        }
+        return;
+    }
+
+    rcc.bdcr
+        .modify(|r, w| unsafe { w.bits(r.bits() | RCC_BDCR_LSEON) });
+    let (rtcsel, prediv_s) = if wait_for(LSE_TIMEOUT, || {
+        rcc.bdcr.read().bits() & RCC_BDCR_LSERDY != 0
+    }) {
+        info!("RTC clocked from LSE");
+        (RCC_BDCR_RTCSEL_LSE, LSE_PREDIV_S)
+    } else {
+        rcc.bdcr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !RCC_BDCR_LSEON) });
+        if !enable_lsi(rcc) {
+            warn!("RTC: neither LSE nor LSI ready");
+            return;
+        }
+        info!("RTC clocked from LSI");
+        (RCC_BDCR_RTCSEL_LSI, LSI_PREDIV_S)
+    };
+    rcc.bdcr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !RCC_BDCR_RTCSEL | rtcsel | RCC_BDCR_RTCEN) });
+    init_mode(|rtc| {
+        // Synchronous prescaler must be written first
+        rtc.prer.write(|w| unsafe { w.bits(prediv_s) });
+        rtc.prer
+            .write(|w| unsafe { w.bits(PREDIV_A << 16 | prediv_s) });
+    });
+}
+
+fn bcd(value: u32) -> u32 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u32) -> u32 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+/// Current time, if it has been set since the backup domain has last
+/// lost power
+#[cfg(not(feature = "sim"))]
+pub fn now() -> Option<DateTime> {
+    let rtc = unsafe { &*RTC::ptr() };
+    if rtc.isr.read().bits() & RTC_ISR_INITS == 0 {
+        return None;
+    }
+    // Reading SSR and TR locks DR until it is read
+    let ssr = rtc.ssr.read().bits() & 0xFFFF;
+    let tr = rtc.tr.read().bits();
+    let dr = rtc.dr.read().bits();
+    let prediv_s = rtc.prer.read().bits() & 0x7FFF;
+    Some(DateTime {
+        year: 2000 + from_bcd(dr >> 16 & 0xFF),
+        month: from_bcd(dr >> 8 & 0x1F),
+        day: from_bcd(dr & 0x3F),
+        hour: from_bcd(tr >> 16 & 0x3F),
+        minute: from_bcd(tr >> 8 & 0x7F),
+        second: from_bcd(tr & 0x7F),
+        millis: prediv_s.saturating_sub(ssr) * 1000 / (prediv_s + 1),
+    })
+}
+
+/// Set the current time in seconds since the Unix epoch, within
+/// `EPOCH_MIN..=EPOCH_MAX`
+#[cfg(not(feature = "sim"))]
+pub fn set(epoch: u32) {
+    let time = DateTime::from_epoch_millis(u64::from(epoch) * 1000);
+    init_mode(|rtc| {
+        let tr = bcd(time.hour) << 16 | bcd(time.minute) << 8 | bcd(time.second);
+        let dr = bcd(time.year - 2000) << 16
+            | time.weekday() << 13
+            | bcd(time.month) << 8
+            | bcd(time.day);
+        rtc.tr.write(|w| unsafe { w.bits(tr) });
+        rtc.dr.write(|w| unsafe { w.bits(dr) });
+    });
+}
+
+/// Current time of the host, moved by `set()`
+#[cfg(feature = "sim")]
+pub fn now() -> Option<DateTime> {
+    crate::sim::epoch_millis().map(DateTime::from_epoch_millis)
+}
+
+#[cfg(feature = "sim")]
+pub fn set(epoch: u32) {
+    crate::sim::set_epoch(epoch);
+}
+
+/// Seconds since the Unix epoch at `uptime` milliseconds since boot
+pub fn epoch_at(uptime: u64) -> Option<f64> {
+    let now = now()?.to_epoch_millis();
+    let age = timer::now_u64().saturating_sub(uptime);
+    Some(now.saturating_sub(age) as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_date_time() {
+        let time = DateTime::from_epoch_millis(1_709_214_307_250);
+        assert_eq!(
+            time,
+            DateTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                hour: 13,
+                minute: 45,
+                second: 7,
+                millis: 250,
+            }
+        );
+        assert_eq!(time.to_epoch_millis(), 1_709_214_307_250);
+        let time = DateTime::from_epoch_millis(1_760_572_800_000);
+        assert_eq!(time.weekday(), 4);
+        assert_eq!(
+            DateTime::from_epoch_millis(u64::from(EPOCH_MIN) * 1000).year,
+            2001
+        );
+        assert_eq!(
+            DateTime::from_epoch_millis(u64::from(EPOCH_MAX) * 1000).year,
+            2099
+        );
+    }
+
+    #[test]
+    fn test_bcd() {
+        assert_eq!(bcd(59), 0x59);
+        assert_eq!(from_bcd(0x59), 59);
+    }
+}
@@ -0,0 +1,75 @@
+use core::marker::PhantomData;
+use num_traits::float::Float;
+use uom::si::{
+    electrical_resistance::ohm,
+    f64::{ElectricalResistance, ThermodynamicTemperature},
+    thermodynamic_temperature::degree_celsius,
+};
+
+/// Callendar-Van Dusen coefficients of IEC 60751 platinum RTDs
+const A: f64 = 3.9083e-3;
+const B: f64 = -5.775e-7;
+const C: f64 = -4.183e-12;
+
+/// Resistance of a PT100 at 0 °C
+pub const PT100: ElectricalResistance = ElectricalResistance {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 100.0,
+};
+/// Resistance of a PT1000 at 0 °C
+pub const PT1000: ElectricalResistance = ElectricalResistance {
+    dimension: PhantomData,
+    units: PhantomData,
+    value: 1000.0,
+};
+
+/// Resistance of an RTD with resistance `r0` at 0 °C, at temperature
+/// `t` in °C
+fn resistance(r0: f64, t: f64) -> f64 {
+    let r = 1.0 + A * t + B * t * t;
+    if t < 0.0 {
+        r0 * (r + C * (t - 100.0) * t * t * t)
+    } else {
+        r0 * r
+    }
+}
+
+/// Perform the resistance to temperature conversion for an RTD with
+/// resistance `r0` at 0 °C.
+///
+/// Above 0 °C the quadratic Callendar-Van Dusen equation is solved
+/// directly. Below, its solution is refined by Newton's method.
+pub fn get_temperature(
+    r0: ElectricalResistance,
+    r: ElectricalResistance,
+) -> ThermodynamicTemperature {
+    let r0 = r0.get::<ohm>();
+    let r = r.get::<ohm>();
+    let mut t = (-A + (A * A - 4.0 * B * (1.0 - r / r0)).sqrt()) / (2.0 * B);
+    if t < 0.0 {
+        for _ in 0..4 {
+            let slope = r0 * (A + 2.0 * B * t + C * (4.0 * t - 300.0) * t * t);
+            t -= (resistance(r0, t) - r) / slope;
+        }
+    }
+    ThermodynamicTemperature::new::<degree_celsius>(t)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pt100(r: f64) -> f64 {
+        get_temperature(PT100, ElectricalResistance::new::<ohm>(r)).get::<degree_celsius>()
+    }
+
+    #[test]
+    fn test_pt100() {
+        // IEC 60751 table values
+        assert!((pt100(100.0) - 0.0).abs() < 1e-3);
+        assert!((pt100(138.5055) - 100.0).abs() < 1e-3);
+        assert!((pt100(60.2558) - -100.0).abs() < 1e-3);
+        assert!((pt100(18.5201) - -200.0).abs() < 1e-3);
+    }
+}
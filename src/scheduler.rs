@@ -0,0 +1,229 @@
+//! Commands deferred with `at <seconds> <command>`, or repeated with
+//! `every <seconds> <command>`, such as engaging the PID after a
+//! warm-up or a periodic calibration.
+//!
+//! The main loop runs the commands that are due with `take_due()`,
+//! outside of any session, so their responses are dropped. The queue
+//! is kept in RAM only, and is empty after a reset. The main loop also
+//! clears it when the deadman timeout expires.
+
+use crate::{
+    command_handler::{JsonBuffer, Response},
+    command_parser::{Command, ScheduledLine},
+    interrupt::{self, Mutex},
+    timer,
+};
+use core::cell::RefCell;
+use heapless::{consts::U8, Vec};
+use serde::Serialize;
+
+/// Commands that can be queued at a time
+pub const SLOTS: usize = 8;
+/// Longest delay or period, well within the wrap of `timer::now()`
+pub const MAX_SECONDS: u32 = 7 * 24 * 3600;
+
+/// Commands due in one cycle of the main loop, at most all slots
+pub type Due = Vec<Command, U8>;
+
+struct Entry {
+    /// As entered, for `schedule`
+    line: ScheduledLine,
+    command: Command,
+    /// `timer::now()` at which it is due
+    due: u32,
+    /// Period in milliseconds of `every`
+    period: Option<u32>,
+}
+
+struct Schedule {
+    entries: [Option<Entry>; SLOTS],
+}
+
+impl Schedule {
+    const fn new() -> Self {
+        const NO_ENTRY: Option<Entry> = None;
+        Schedule {
+            entries: [NO_ENTRY; SLOTS],
+        }
+    }
+
+    /// Queue `command` in the first free slot, returns its index
+    fn add(
+        &mut self,
+        now: u32,
+        line: ScheduledLine,
+        command: Command,
+        seconds: u32,
+        every: bool,
+    ) -> Option<usize> {
+        let index = self.entries.iter().position(Option::is_none)?;
+        let delay = seconds.min(MAX_SECONDS) * 1000;
+        self.entries[index] = Some(Entry {
+            line,
+            command,
+            due: now.wrapping_add(delay),
+            period: every.then_some(delay),
+        });
+        Some(index)
+    }
+
+    /// Commands that are due, in the order of their slots. Those of
+    /// `at` are removed, those of `every` are due again a period
+    /// later, without catching up on the periods missed.
+    fn take_due(&mut self, now: u32) -> Due {
+        let mut due = Due::new();
+        for slot in self.entries.iter_mut() {
+            let entry = match slot {
+                Some(entry) if (now.wrapping_sub(entry.due) as i32) >= 0 => entry,
+                _ => continue,
+            };
+            let _ = due.push(entry.command.clone());
+            match entry.period {
+                Some(period) => entry.due = now.wrapping_add(period),
+                None => *slot = None,
+            }
+        }
+        due
+    }
+
+    /// Remove the command in slot `index`, or all with `None`. Returns
+    /// `false` if there is none to remove.
+    fn clear(&mut self, index: Option<usize>) -> bool {
+        match index {
+            Some(index) => self.entries.get_mut(index).and_then(Option::take).is_some(),
+            None => {
+                self.entries = Schedule::new().entries;
+                true
+            }
+        }
+    }
+}
+
+static SCHEDULE: Mutex<RefCell<Schedule>> = Mutex::new(RefCell::new(Schedule::new()));
+
+/// Queue `command`, entered as `line`, to run in `seconds`, and every
+/// `seconds` after with `every`. Returns its index, or `None` if all
+/// slots are taken.
+pub fn add(line: ScheduledLine, command: Command, seconds: u32, every: bool) -> Option<usize> {
+    let now = timer::now();
+    interrupt::free(|cs| {
+        SCHEDULE
+            .borrow(cs)
+            .borrow_mut()
+            .add(now, line, command, seconds, every)
+    })
+}
+
+/// Commands to run in this cycle of the main loop
+pub fn take_due() -> Due {
+    let now = timer::now();
+    interrupt::free(|cs| SCHEDULE.borrow(cs).borrow_mut().take_due(now))
+}
+
+/// Remove the command in slot `index`, or all with `None`
+pub fn clear(index: Option<usize>) -> bool {
+    interrupt::free(|cs| SCHEDULE.borrow(cs).borrow_mut().clear(index))
+}
+
+#[derive(Serialize)]
+struct EntrySummary<'a> {
+    index: usize,
+    command: &'a str,
+    /// Whole seconds until it is due
+    due_in: u32,
+    every: Option<u32>,
+}
+
+pub fn summary_json() -> Result<JsonBuffer, serde_json_core::ser::Error> {
+    let now = timer::now();
+    interrupt::free(|cs| {
+        let schedule = SCHEDULE.borrow(cs).borrow();
+        let summaries: Vec<EntrySummary, U8> = schedule
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let entry = entry.as_ref()?;
+                let due_in = (entry.due.wrapping_sub(now) as i32).max(0) as u32;
+                Some(EntrySummary {
+                    index,
+                    command: &entry.line,
+                    due_in: due_in / 1000,
+                    every: entry.period.map(|period| period / 1000),
+                })
+            })
+            .collect();
+        serde_json_core::to_vec(&summaries)
+    })
+}
+
+/// Drops the responses of scheduled commands
+pub struct Discard;
+
+impl Response for Discard {
+    fn send_capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    fn send_queue(&self) -> usize {
+        0
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        data.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(line: &str) -> ScheduledLine {
+        ScheduledLine::from(line)
+    }
+
+    #[test]
+    fn test_take_due() {
+        let mut schedule = Schedule::new();
+        let pid = Command::parse(b"output 0 pid").unwrap();
+        let ping = Command::Ping;
+        assert_eq!(
+            schedule.add(u32::MAX - 500, line("output 0 pid"), pid.clone(), 2, false),
+            Some(0)
+        );
+        assert_eq!(
+            schedule.add(0, line("ping"), ping.clone(), 1, true),
+            Some(1)
+        );
+
+        assert!(schedule.take_due(999).is_empty());
+        assert_eq!(&schedule.take_due(1000)[..], [ping.clone()]);
+        assert_eq!(&schedule.take_due(1499)[..], [pid]);
+        assert!(schedule.take_due(1999).is_empty());
+        // Periods missed are skipped
+        assert_eq!(&schedule.take_due(5000)[..], [ping]);
+        assert!(schedule.take_due(5999).is_empty());
+        assert!(schedule.entries[0].is_none());
+
+        assert!(!schedule.clear(Some(0)));
+        assert!(schedule.clear(Some(1)));
+        assert!(schedule.take_due(10_000).is_empty());
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut schedule = Schedule::new();
+        for index in 0..SLOTS {
+            assert_eq!(
+                schedule.add(0, line("ping"), Command::Ping, 1, false),
+                Some(index)
+            );
+        }
+        assert_eq!(schedule.add(0, line("ping"), Command::Ping, 1, false), None);
+        assert!(schedule.clear(None));
+        assert_eq!(
+            schedule.add(0, line("ping"), Command::Ping, 1, false),
+            Some(0)
+        );
+    }
+}
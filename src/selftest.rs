@@ -0,0 +1,98 @@
+//! Self-test of the peripherals around the MCU, for telling hardware
+//! faults from configuration problems.
+
+use crate::{
+    channels::{Channels, CHANNELS},
+    command_handler::JsonBuffer,
+    fan_ctrl::FanCtrl,
+    net,
+    pins::Eeprom,
+};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Pass,
+    Fail,
+    /// Not applicable, or would disturb operation
+    Skip,
+}
+
+impl From<bool> for Check {
+    fn from(pass: bool) -> Self {
+        if pass {
+            Check::Pass
+        } else {
+            Check::Fail
+        }
+    }
+}
+
+impl From<Option<bool>> for Check {
+    fn from(pass: Option<bool>) -> Self {
+        pass.map(Check::from).unwrap_or(Check::Skip)
+    }
+}
+
+#[derive(Serialize)]
+pub struct SelfTest {
+    /// AD7172 identification over SPI
+    adc: Check,
+    /// DAC output loopback through its feedback, per channel
+    dac: [Check; CHANNELS],
+    /// EEPROM read of the EUI-48
+    eeprom: Check,
+    /// Ethernet PHY identification over MDIO
+    phy: Check,
+    /// Fan PWM duty cycle
+    fan: Check,
+    /// No check has failed
+    pass: bool,
+}
+
+impl SelfTest {
+    pub fn pass(&self) -> bool {
+        self.pass
+    }
+
+    pub fn json(&self) -> Result<JsonBuffer, serde_json_core::ser::Error> {
+        serde_json_core::to_vec(self)
+    }
+}
+
+fn eeprom_self_test(eeprom: &mut Eeprom) -> bool {
+    let mut eui48 = [0; 6];
+    match eeprom.read_data(0xFA, &mut eui48) {
+        Ok(()) => eui48 != [0xFF; 6] && eui48 != [0; 6],
+        Err(_) => false,
+    }
+}
+
+/// Run all checks. Blocks for a few tens of milliseconds.
+pub fn run(channels: &mut Channels, eeprom: &mut Eeprom, fan_ctrl: &mut FanCtrl) -> SelfTest {
+    let adc = channels.adc_self_test().into();
+    let mut dac = [Check::Skip; CHANNELS];
+    for (channel, check) in dac.iter_mut().enumerate() {
+        *check = channels.dac_self_test(channel).into();
+    }
+    let eeprom = eeprom_self_test(eeprom).into();
+    #[cfg(not(feature = "sim"))]
+    let phy = net::phy_present().into();
+    // The simulation has no Ethernet
+    #[cfg(feature = "sim")]
+    let phy = Check::Skip;
+    let fan = fan_ctrl.self_test().into();
+    let pass = [adc, eeprom, phy, fan]
+        .iter()
+        .chain(dac.iter())
+        .all(|&check| check != Check::Fail);
+    SelfTest {
+        adc,
+        dac,
+        eeprom,
+        phy,
+        fan,
+        pass,
+    }
+}
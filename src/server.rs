@@ -1,8 +1,12 @@
 use crate::command_parser::Ipv4Config;
+use crate::mdns;
 use crate::net::split_ipv4_config;
 use smoltcp::{
     iface::EthernetInterface,
-    socket::{SocketHandle, SocketRef, SocketSet, TcpSocket, TcpSocketBuffer},
+    socket::{
+        SocketHandle, SocketRef, SocketSet, TcpSocket, TcpSocketBuffer, UdpPacketMetadata,
+        UdpSocket, UdpSocketBuffer,
+    },
     time::Instant,
     wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr},
 };
@@ -28,13 +32,50 @@ impl<'a, S: Default> SocketState<S> {
     }
 }
 
+#[cfg(all(feature = "sockets-2", feature = "sockets-8"))]
+compile_error!("features sockets-2 and sockets-8 are exclusive");
+#[cfg(all(feature = "tcp-buffers-1k", feature = "tcp-buffers-4k"))]
+compile_error!("features tcp-buffers-1k and tcp-buffers-4k are exclusive");
+
 /// Number of server sockets and therefore concurrent client
 /// sessions. Many data structures in `Server::run()` correspond to
 /// this const.
-const SOCKET_COUNT: usize = 4;
+#[cfg(feature = "sockets-2")]
+pub const SOCKET_COUNT: usize = 2;
+#[cfg(feature = "sockets-8")]
+pub const SOCKET_COUNT: usize = 8;
+#[cfg(not(any(feature = "sockets-2", feature = "sockets-8")))]
+pub const SOCKET_COUNT: usize = 4;
+
+/// `SOCKET_COUNT` for the capacity of `heapless::Vec`
+#[cfg(feature = "sockets-2")]
+pub type SocketCount = heapless::consts::U2;
+#[cfg(feature = "sockets-8")]
+pub type SocketCount = heapless::consts::U8;
+#[cfg(not(any(feature = "sockets-2", feature = "sockets-8")))]
+pub type SocketCount = heapless::consts::U4;
+
+#[cfg(feature = "tcp-buffers-1k")]
+pub const TCP_BUFFER_SIZE: usize = 1024;
+#[cfg(feature = "tcp-buffers-4k")]
+pub const TCP_BUFFER_SIZE: usize = 4096;
+#[cfg(not(any(feature = "tcp-buffers-1k", feature = "tcp-buffers-4k")))]
+pub const TCP_BUFFER_SIZE: usize = 2048;
+
+const TCP_RX_BUFFER_SIZE: usize = TCP_BUFFER_SIZE;
+const TCP_TX_BUFFER_SIZE: usize = TCP_BUFFER_SIZE;
 
-const TCP_RX_BUFFER_SIZE: usize = 2048;
-const TCP_TX_BUFFER_SIZE: usize = 2048;
+/// Packets queued in each direction of the mDNS socket
+const MDNS_PACKETS: usize = 4;
+
+/// Stack for the socket buffers and the client sessions, in the 64 KiB
+/// of CCM RAM, leaving room for everything else
+pub const STACK_BUDGET: usize = 40 * 1024;
+
+/// Stack taken by the socket buffers in `Server::run()`, including
+/// those of the mDNS socket
+pub const SOCKET_BUFFERS_SIZE: usize =
+    SOCKET_COUNT * (TCP_RX_BUFFER_SIZE + TCP_TX_BUFFER_SIZE) + 2 * mdns::BUFFER_SIZE;
 
 /// Contains a number of server sockets that get all sent the same
 /// data (through `fmt::Write`).
@@ -42,6 +83,8 @@ pub struct Server<'a, 'b, S> {
     net: EthernetInterface<'a, &'a mut stm32_eth::Eth<'static, 'static>>,
     sockets: SocketSet<'b>,
     states: [SocketState<S>; SOCKET_COUNT],
+    mdns_handle: SocketHandle,
+    mdns: mdns::Responder,
 }
 
 impl<'a, 'b, S: Default> Server<'a, 'b, S> {
@@ -50,32 +93,34 @@ impl<'a, 'b, S: Default> Server<'a, 'b, S> {
     where
         F: FnOnce(&mut Server<'a, '_, S>),
     {
-        macro_rules! create_rtx_storage {
-            ($rx_storage:ident, $tx_storage:ident) => {
-                let mut $rx_storage = [0; TCP_RX_BUFFER_SIZE];
-                let mut $tx_storage = [0; TCP_TX_BUFFER_SIZE];
-            };
-        }
+        let mut tcp_rx_storage = [[0; TCP_RX_BUFFER_SIZE]; SOCKET_COUNT];
+        let mut tcp_tx_storage = [[0; TCP_TX_BUFFER_SIZE]; SOCKET_COUNT];
+        let mut rtx_storage = tcp_rx_storage.iter_mut().zip(tcp_tx_storage.iter_mut());
 
-        create_rtx_storage!(tcp_rx_storage0, tcp_tx_storage0);
-        create_rtx_storage!(tcp_rx_storage1, tcp_tx_storage1);
-        create_rtx_storage!(tcp_rx_storage2, tcp_tx_storage2);
-        create_rtx_storage!(tcp_rx_storage3, tcp_tx_storage3);
+        let mut mdns_rx_metadata = [UdpPacketMetadata::EMPTY; MDNS_PACKETS];
+        let mut mdns_tx_metadata = [UdpPacketMetadata::EMPTY; MDNS_PACKETS];
+        let mut mdns_rx_storage = [0; mdns::BUFFER_SIZE];
+        let mut mdns_tx_storage = [0; mdns::BUFFER_SIZE];
 
-        let mut sockets_storage: [_; SOCKET_COUNT] = Default::default();
+        let mut sockets_storage: [_; SOCKET_COUNT + 1] = Default::default();
         let mut sockets = SocketSet::new(&mut sockets_storage[..]);
 
-        let states: [SocketState<S>; SOCKET_COUNT] = [
-            SocketState::<S>::new(&mut sockets, &mut tcp_rx_storage0, &mut tcp_tx_storage0),
-            SocketState::<S>::new(&mut sockets, &mut tcp_rx_storage1, &mut tcp_tx_storage1),
-            SocketState::<S>::new(&mut sockets, &mut tcp_rx_storage2, &mut tcp_tx_storage2),
-            SocketState::<S>::new(&mut sockets, &mut tcp_rx_storage3, &mut tcp_tx_storage3),
-        ];
+        let states: [SocketState<S>; SOCKET_COUNT] = core::array::from_fn(|_| {
+            let (tcp_rx_storage, tcp_tx_storage) = rtx_storage.next().unwrap();
+            SocketState::<S>::new(&mut sockets, tcp_rx_storage, tcp_tx_storage)
+        });
+        let mdns_socket = UdpSocket::new(
+            UdpSocketBuffer::new(&mut mdns_rx_metadata[..], &mut mdns_rx_storage[..]),
+            UdpSocketBuffer::new(&mut mdns_tx_metadata[..], &mut mdns_tx_storage[..]),
+        );
+        let mdns_handle = sockets.add(mdns_socket);
 
         let mut server = Server {
             states,
             sockets,
             net,
+            mdns_handle,
+            mdns: mdns::Responder::default(),
         };
         f(&mut server);
     }
@@ -92,6 +137,23 @@ impl<'a, 'b, S: Default> Server<'a, 'b, S> {
         }
     }
 
+    /// Answer mDNS queries and send the announcements due, after
+    /// `poll()`
+    pub fn poll_mdns(&mut self, now: u32) {
+        let address = self
+            .net
+            .ip_addrs()
+            .iter()
+            .find_map(|addr| match addr {
+                IpCidr::Ipv4(cidr) => Some(cidr.address()),
+                #[allow(unreachable_patterns)]
+                _ => None,
+            })
+            .unwrap_or(Ipv4Address::UNSPECIFIED);
+        let mut socket = self.sockets.get::<UdpSocket>(self.mdns_handle);
+        self.mdns.poll(&mut socket, address, now);
+    }
+
     /// Iterate over all sockets managed by this server
     pub fn for_each<F: FnMut(SocketRef<TcpSocket>, &mut S)>(&mut self, mut callback: F) {
         for state in &mut self.states {
@@ -100,6 +162,21 @@ impl<'a, 'b, S: Default> Server<'a, 'b, S> {
         }
     }
 
+    /// Socket and state of client `index`
+    pub fn get(&mut self, index: usize) -> Option<(SocketRef<'_, TcpSocket<'b>>, &mut S)> {
+        let state = self.states.get_mut(index)?;
+        let socket = self.sockets.get::<TcpSocket>(state.handle);
+        Some((socket, &mut state.state))
+    }
+
+    /// Whether any socket has a client connected
+    pub fn any_active(&mut self) -> bool {
+        let sockets = &mut self.sockets;
+        self.states
+            .iter()
+            .any(|state| sockets.get::<TcpSocket>(state.handle).is_active())
+    }
+
     fn set_ipv4_address(&mut self, ipv4_address: Ipv4Cidr) {
         self.net.update_ip_addrs(|addrs| {
             for addr in addrs.iter_mut() {
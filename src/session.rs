@@ -1,10 +1,30 @@
-use super::command_parser::{Command, Error as ParserError};
+use super::command_handler::{Dump, SendQueue};
+use super::command_parser::{Command, Error as ParserError, Framing, ReportFormat, RequestTag};
+use super::config;
+use core::fmt::{self, Write};
+use heapless::{consts::U24, String};
+use serde::Serialize;
+use smoltcp::socket::TcpSocket;
 
-const MAX_LINE_LEN: usize = 64;
+const MAX_LINE_LEN: usize = 128;
+/// Echo of the input received in one `Session::feed()`
+const ECHO_LEN: usize = 64;
+/// Sent to interactive sessions when ready for the next line
+const PROMPT: &[u8] = b"> ";
+/// Lines handled for a client in one cycle of the main loop, further
+/// ones wait in its receive buffer for the next cycles
+pub const LINES_PER_CYCLE: usize = 4;
 
 struct LineReader {
     buf: [u8; MAX_LINE_LEN],
     pos: usize,
+    /// Input beyond `MAX_LINE_LEN` is being discarded until the end of
+    /// the line
+    overflow: bool,
+    /// Echo input back, for terminals without a local echo
+    interactive: bool,
+    echo: [u8; ECHO_LEN],
+    echo_len: usize,
 }
 
 impl LineReader {
@@ -12,29 +32,64 @@ impl LineReader {
         LineReader {
             buf: [0; MAX_LINE_LEN],
             pos: 0,
+            overflow: false,
+            interactive: false,
+            echo: [0; ECHO_LEN],
+            echo_len: 0,
         }
     }
 
-    pub fn feed(&mut self, c: u8) -> Option<&[u8]> {
+    pub fn feed(&mut self, c: u8) -> Option<Result<&[u8], ParserError>> {
         if c == 13 || c == 10 {
             // Enter
-            if self.pos > 0 {
+            if self.overflow {
+                self.echo(b"\r\n");
+                self.overflow = false;
+                self.pos = 0;
+                Some(Err(ParserError::LineTooLong))
+            } else if self.pos > 0 {
+                self.echo(b"\r\n");
                 let len = self.pos;
                 self.pos = 0;
-                Some(&self.buf[..len])
+                Some(Ok(&self.buf[..len]))
             } else {
                 None
             }
+        } else if self.overflow {
+            // Rest of a line that is too long
+            None
+        } else if c == 8 || c == 127 {
+            // Backspace or DEL
+            if self.pos > 0 {
+                self.pos -= 1;
+                self.echo(b"\x08 \x08");
+            }
+            None
         } else if self.pos < self.buf.len() {
             // Add input
             self.buf[self.pos] = c;
             self.pos += 1;
+            self.echo(&[c]);
             None
         } else {
-            // Buffer is full, ignore
+            // Buffer is full, discard the line
+            self.overflow = true;
             None
         }
     }
+
+    fn echo(&mut self, data: &[u8]) {
+        let end = self.echo_len + data.len();
+        if self.interactive && end <= ECHO_LEN {
+            self.echo[self.echo_len..end].copy_from_slice(data);
+            self.echo_len = end;
+        }
+    }
+
+    /// Whether the echo of any further input might not fit
+    fn echo_full(&self) -> bool {
+        self.echo_len + 4 > ECHO_LEN
+    }
 }
 
 pub enum SessionInput {
@@ -51,8 +106,42 @@ impl From<Result<Command, ParserError>> for SessionInput {
     }
 }
 
+/// Connected client, as listed by the `clients` command
+#[derive(Serialize)]
+pub struct ClientSummary {
+    index: usize,
+    /// Remote IP address and port
+    remote: String<U24>,
+    /// Time since connecting in seconds
+    age: f64,
+    framing: &'static str,
+    /// Samples are being sent to the client
+    dump: bool,
+}
+
 pub struct Session {
     reader: LineReader,
+    framing: Framing,
+    report_format: ReportFormat,
+    precision: Option<u8>,
+    /// Samples being sent, before further commands are read
+    dump: Option<Dump>,
+    /// Time of connecting in milliseconds since boot
+    connected: Option<u32>,
+    /// Bytes received of a config document, while reading one instead
+    /// of commands
+    document: Option<usize>,
+    /// Rest of the current line, with further commands separated by
+    /// `;`
+    pending: [u8; MAX_LINE_LEN],
+    pending_len: usize,
+    /// The prompt is due once the commands of the current line have
+    /// been read
+    prompt: bool,
+    /// Tag of the last command, for its responses
+    tag: Option<RequestTag>,
+    /// Lines waiting for room in the send buffer
+    send_queue: SendQueue,
 }
 
 impl Default for Session {
@@ -65,23 +154,302 @@ impl Session {
     pub fn new() -> Self {
         Session {
             reader: LineReader::new(),
+            framing: Framing::Newline,
+            report_format: ReportFormat::Json,
+            precision: None,
+            dump: None,
+            connected: None,
+            document: None,
+            pending: [0; MAX_LINE_LEN],
+            pending_len: 0,
+            prompt: false,
+            tag: None,
+            send_queue: SendQueue::new(),
         }
     }
 
     pub fn reset(&mut self) {
         self.reader = LineReader::new();
+        self.framing = Framing::Newline;
+        self.report_format = ReportFormat::Json;
+        self.precision = None;
+        self.dump = None;
+        self.connected = None;
+        self.pending_len = 0;
+        self.prompt = false;
+        self.tag = None;
+        self.send_queue.clear();
+        if self.document.take().is_some() {
+            config::receive_cancel();
+        }
+    }
+
+    /// Note the time of connecting, at the first poll after it
+    pub fn connect(&mut self, now: u32) {
+        self.connected.get_or_insert(now);
+    }
+
+    /// Summary of the client on socket `index`, if connected
+    pub fn summary(&self, index: usize, socket: &TcpSocket, now: u32) -> Option<ClientSummary> {
+        if !socket.is_active() {
+            return None;
+        }
+        Some(self.summary_of(index, socket.remote_endpoint(), now))
+    }
+
+    /// Summary of the session with a client at `endpoint`
+    pub fn summary_of<R: fmt::Display>(
+        &self,
+        index: usize,
+        endpoint: R,
+        now: u32,
+    ) -> ClientSummary {
+        let mut remote = String::new();
+        let _ = write!(remote, "{}", endpoint);
+        let age = self
+            .connected
+            .map(|connected| now.wrapping_sub(connected))
+            .unwrap_or(0);
+        ClientSummary {
+            index,
+            remote,
+            age: f64::from(age) / 1000.0,
+            framing: match self.framing {
+                Framing::Newline => "newline",
+                Framing::LengthPrefix => "length_prefix",
+            },
+            dump: self.dump.is_some(),
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn report_format(&self) -> ReportFormat {
+        self.report_format
+    }
+
+    pub fn set_report_format(&mut self, report_format: ReportFormat) {
+        self.report_format = report_format;
+    }
+
+    pub fn precision(&self) -> Option<u8> {
+        self.precision
+    }
+
+    pub fn set_precision(&mut self, precision: Option<u8>) {
+        self.precision = precision;
+    }
+
+    /// Tag of the command whose responses are being sent
+    pub fn tag(&self) -> Option<RequestTag> {
+        self.tag.clone()
+    }
+
+    /// Whether further lines received can be read, rather than once
+    /// samples or queued lines have been sent
+    pub fn reads_lines(&self) -> bool {
+        self.dump.is_none() && self.send_queue.is_empty()
+    }
+
+    pub fn send_queue_mut(&mut self) -> &mut SendQueue {
+        &mut self.send_queue
     }
 
+    pub fn dump_mut(&mut self) -> Option<&mut Dump> {
+        self.dump.as_mut()
+    }
+
+    pub fn set_dump(&mut self, dump: Option<Dump>) {
+        self.dump = dump;
+    }
+
+    /// Echo input, and prompt for each line
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.reader.interactive = interactive;
+    }
+
+    /// Echo and prompt to send to the client, without framing
+    pub fn take_echo(&mut self) -> &[u8] {
+        let len = self.reader.echo_len;
+        self.reader.echo_len = 0;
+        &self.reader.echo[..len]
+    }
+
+    /// Echo and prompt as with `take_echo()`, with the send queue that
+    /// they go after
+    pub fn take_echo_queued(&mut self) -> (&[u8], &mut SendQueue) {
+        let len = self.reader.echo_len;
+        self.reader.echo_len = 0;
+        (&self.reader.echo[..len], &mut self.send_queue)
+    }
+
+    /// Read the next line as a config document for `config load`
+    pub fn receive_document(&mut self) {
+        self.document = Some(0);
+    }
+
+    fn feed_document(&mut self, received: usize, buf: &[u8]) -> (usize, SessionInput) {
+        match buf.iter().position(|&c| c == 13 || c == 10) {
+            // Enter, after an empty line
+            Some(0) if received == 0 => (1, SessionInput::Nothing),
+            Some(len) => {
+                config::receive_data(&buf[..len]);
+                self.document = None;
+                (len + 1, SessionInput::Command(Command::ConfigDocument))
+            }
+            None => {
+                config::receive_data(buf);
+                self.document = Some(received + buf.len());
+                (buf.len(), SessionInput::Nothing)
+            }
+        }
+    }
+
+    /// Read `buf` up to the end of its first complete line. Returns
+    /// how many bytes were taken, for the rest to be fed again.
     pub fn feed(&mut self, buf: &[u8]) -> (usize, SessionInput) {
+        if let Some(received) = self.document {
+            return self.feed_document(received, buf);
+        }
         let mut buf_bytes = 0;
         for (i, b) in buf.iter().enumerate() {
+            if self.reader.echo_full() {
+                // Leave the rest until the echo has been sent
+                break;
+            }
             buf_bytes = i + 1;
-            let line = self.reader.feed(*b);
-            if let Some(line) = line {
-                let command = Command::parse(line);
-                return (buf_bytes, command.into());
+            match self.reader.feed(*b) {
+                Some(Ok(line)) => {
+                    self.pending[..line.len()].copy_from_slice(line);
+                    self.pending_len = line.len();
+                    self.prompt = true;
+                    let input = self.next_command().unwrap_or(SessionInput::Nothing);
+                    return (buf_bytes, input);
+                }
+                Some(Err(e)) => {
+                    self.tag = None;
+                    self.prompt = true;
+                    return (buf_bytes, SessionInput::Error(e));
+                }
+                None => {}
             }
         }
         (buf_bytes, SessionInput::Nothing)
     }
+
+    /// The next of several commands on the last line, separated by
+    /// `;`. The rest of the line is dropped once samples are being
+    /// sent, or a config document is read.
+    pub fn next_command(&mut self) -> Option<SessionInput> {
+        if self.dump.is_some() || self.document.is_some() {
+            self.pending_len = 0;
+        }
+        while self.pending_len > 0 {
+            let line = &self.pending[..self.pending_len];
+            let len = line.iter().position(|&c| c == b';').unwrap_or(line.len());
+            let start = line[..len].iter().position(|&c| c != b' ').unwrap_or(len);
+            let input = if start < len {
+                self.tag = None;
+                let command = Command::split_tag(&line[start..len]).and_then(|(tag, line)| {
+                    self.tag = tag;
+                    Command::parse(line).or_else(|e| {
+                        Command::parse_aliased(line, &config::aliases()).unwrap_or(Err(e))
+                    })
+                });
+                Some(command.into())
+            } else {
+                None
+            };
+            let rest = (len + 1).min(self.pending_len);
+            self.pending.copy_within(rest..self.pending_len, 0);
+            self.pending_len -= rest;
+            if input.is_some() {
+                return input;
+            }
+        }
+        if self.prompt && self.dump.is_none() && self.document.is_none() {
+            self.reader.echo(PROMPT);
+        }
+        self.prompt = false;
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_handler::{SEND_QUEUE_LEN, SESSION_BASE_SIZE};
+
+    #[test]
+    fn test_session_size() {
+        let size = core::mem::size_of::<Session>();
+        assert!(size - SEND_QUEUE_LEN <= SESSION_BASE_SIZE);
+    }
+
+    #[test]
+    fn test_feed_several_commands() {
+        let mut session = Session::new();
+        let line = b"pid 0 kp 1.5; pid 0 ki 0.2;;report\n";
+        let (len, input) = session.feed(line);
+        assert_eq!(len, line.len());
+        assert!(matches!(input, SessionInput::Command(Command::Pid { .. })));
+        assert!(matches!(
+            session.next_command(),
+            Some(SessionInput::Command(Command::Pid { .. }))
+        ));
+        assert!(matches!(
+            session.next_command(),
+            Some(SessionInput::Command(Command::Show(_)))
+        ));
+        assert!(session.next_command().is_none());
+    }
+
+    #[test]
+    fn test_feed_several_lines() {
+        let mut session = Session::new();
+        let buf = b"report\npid\nrepo";
+        let (len, input) = session.feed(buf);
+        assert_eq!(len, 7);
+        assert!(matches!(input, SessionInput::Command(Command::Show(_))));
+        let (len, input) = session.feed(&buf[7..]);
+        assert_eq!(len, 4);
+        assert!(matches!(input, SessionInput::Command(Command::Show(_))));
+        let (len, input) = session.feed(&buf[11..]);
+        assert_eq!(len, 4);
+        assert!(matches!(input, SessionInput::Nothing));
+        assert!(session.reads_lines());
+    }
+
+    #[test]
+    fn test_feed_line_too_long() {
+        let mut session = Session::new();
+        let mut line = [b'x'; MAX_LINE_LEN + 10];
+        line[MAX_LINE_LEN + 9] = b'\n';
+        let (len, input) = session.feed(&line);
+        assert_eq!(len, line.len());
+        assert!(matches!(
+            input,
+            SessionInput::Error(ParserError::LineTooLong)
+        ));
+        // The next line is read from its start
+        let (_, input) = session.feed(b"report\n");
+        assert!(matches!(input, SessionInput::Command(Command::Show(_))));
+    }
+
+    #[test]
+    fn test_feed_interactive() {
+        let mut session = Session::new();
+        session.set_interactive(true);
+        let (_, input) = session.feed(b"reporx\x7ft\r\n");
+        assert!(matches!(input, SessionInput::Command(Command::Show(_))));
+        assert_eq!(session.take_echo(), b"reporx\x08 \x08t\r\n");
+        assert!(session.next_command().is_none());
+        assert_eq!(session.take_echo(), b"> ");
+    }
 }
@@ -0,0 +1,379 @@
+//! AD7172 of the simulation, emulated at the level of its SPI transfers
+//! so that `ad7172::Adc` drives it as it would the chip: its registers
+//! with their checksums, and the conversions of the enabled channels
+//! in turn, each at the rate of its filter, from the thermistors of
+//! the `Plant` and the 5 V supply.
+
+use super::plant::Plant;
+use crate::{
+    ad7172::{
+        regs::{self, Register, RegisterData},
+        Checksum, ChecksumMode, DataRate, Input, Mode, MAX_VALUE,
+    },
+    channel_state::{R_INNER, VREF_SENS},
+    timer,
+};
+use std::{cell::RefCell, rc::Rc};
+use uom::si::{electric_potential::volt, electrical_resistance::ohm};
+
+/// Channels and setups of the AD7172-2
+const CHANNELS: u8 = 4;
+/// AVDD1 in Volts, sampled divided by 5 between the analog supply
+/// inputs
+const AVDD1: f64 = 5.0;
+const AVDD1_DIVIDER: f64 = 5.0;
+/// Bytes of 1s on DIN that reset the serial interface and registers
+const RESET_BYTES: usize = 8;
+/// Identification with the X of 0x00DX for the AD7172-2
+const ID: u16 = 0x00D0;
+
+/// Size of the register at `address` in bytes
+fn size(address: u8) -> usize {
+    match address {
+        0x00 => 1,
+        0x03 | 0x04 | 0x30..=0x3B => 3,
+        _ => 2,
+    }
+}
+
+/// Progress of the transfer since the last write to the communications
+/// register
+#[derive(Clone, Copy)]
+enum Frame {
+    /// Next byte is a write to the communications register
+    Comms,
+    /// Data of the register that `comms` addresses, up to `pos`
+    Data {
+        comms: u8,
+        data: [u8; 3],
+        pos: usize,
+    },
+    /// Checksum byte after the data
+    Checksum { comms: u8, data: [u8; 3] },
+}
+
+pub struct Ad7172 {
+    plant: Rc<RefCell<Plant>>,
+    regs: [[u8; 3]; 0x40],
+    frame: Frame,
+    /// Bytes of 1s received in a row
+    ones: usize,
+    /// Channel being converted and the time its data is ready, while
+    /// converting continuously
+    next: Option<(u8, u32)>,
+}
+
+impl Ad7172 {
+    pub fn new(plant: Rc<RefCell<Plant>>) -> Self {
+        let mut adc = Ad7172 {
+            plant,
+            regs: [[0; 3]; 0x40],
+            frame: Frame::Comms,
+            ones: 0,
+            next: None,
+        };
+        adc.reset();
+        adc
+    }
+
+    /// Register values after power-up, with offset and gain in the
+    /// range of the factory calibration
+    fn reset(&mut self) {
+        self.regs = [[0; 3]; 0x40];
+        self.regs[0x00] = [0x80, 0, 0];
+        self.regs[0x01] = [0x20, 0x00, 0];
+        self.regs[0x06] = [0x08, 0x00, 0];
+        self.regs[0x07] = [(ID >> 8) as u8, ID as u8, 0];
+        for index in 0..CHANNELS {
+            let enabled = if index == 0 { 0x80 } else { 0x00 };
+            self.regs[usize::from(0x10 + index)] = [enabled, 0x01, 0];
+            self.regs[usize::from(0x20 + index)] = [0x10, 0x00, 0];
+            self.regs[usize::from(0x28 + index)] = [0x05, 0x00, 0];
+            self.regs[usize::from(0x30 + index)] = [0x80, 0x00, 0x00];
+            self.regs[usize::from(0x38 + index)] = [0x55, 0x55, 0x55];
+        }
+        self.next = None;
+    }
+
+    fn reg<R: Register>(&self, reg: &R) -> R::Data {
+        let mut data = R::Data::empty();
+        let len = data.len();
+        data.copy_from_slice(&self.regs[usize::from(reg.address())][..len]);
+        data
+    }
+
+    fn checksum_mode(&self) -> ChecksumMode {
+        self.reg(&regs::IfMode).crc()
+    }
+
+    fn mode(&self) -> Mode {
+        self.reg(&regs::AdcMode).mode()
+    }
+
+    /// CS asserted
+    pub fn select(&mut self) {
+        self.frame = Frame::Comms;
+        self.ones = 0;
+    }
+
+    /// CS deasserted, ending the transfer
+    pub fn deselect(&mut self) {
+        self.frame = Frame::Comms;
+    }
+
+    /// Clock `input` into DIN, returning the byte on DOUT
+    pub fn exchange(&mut self, input: u8) -> u8 {
+        if input == 0xFF {
+            self.ones += 1;
+            if self.ones == RESET_BYTES {
+                self.ones = 0;
+                self.reset();
+                self.frame = Frame::Comms;
+                return 0xFF;
+            }
+        } else {
+            self.ones = 0;
+        }
+
+        match self.frame {
+            Frame::Comms => {
+                // WEN must be low for the write to take effect
+                if input & 0x80 == 0 {
+                    let address = input & 0x3F;
+                    let data = if input & 0x40 != 0 {
+                        self.read(address)
+                    } else {
+                        [0; 3]
+                    };
+                    self.frame = Frame::Data {
+                        comms: input,
+                        data,
+                        pos: 0,
+                    };
+                }
+                0xFF
+            }
+            Frame::Data {
+                comms,
+                mut data,
+                pos,
+            } => {
+                let read = comms & 0x40 != 0;
+                let output = if read {
+                    data[pos]
+                } else {
+                    data[pos] = input;
+                    0xFF
+                };
+                if pos + 1 < size(comms & 0x3F) {
+                    self.frame = Frame::Data {
+                        comms,
+                        data,
+                        pos: pos + 1,
+                    };
+                } else if self.checksum_mode() != ChecksumMode::Off {
+                    self.frame = Frame::Checksum { comms, data };
+                } else {
+                    if !read {
+                        self.write(comms & 0x3F, data);
+                    }
+                    self.frame = Frame::Comms;
+                }
+                output
+            }
+            Frame::Checksum { comms, data } => {
+                self.frame = Frame::Comms;
+                let read = comms & 0x40 != 0;
+                // Writes are always checked by CRC
+                let mode = match self.checksum_mode() {
+                    ChecksumMode::Xor if read => ChecksumMode::Xor,
+                    _ => ChecksumMode::Crc,
+                };
+                let mut checksum = Checksum::new(mode);
+                checksum.feed(&[comms]);
+                checksum.feed(&data[..size(comms & 0x3F)]);
+                let checksum = checksum.result().unwrap_or(0);
+                if read {
+                    checksum
+                } else {
+                    // A write with a wrong checksum is discarded
+                    if input == checksum {
+                        self.write(comms & 0x3F, data);
+                    }
+                    0xFF
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, address: u8) -> [u8; 3] {
+        match address {
+            0x00 => {
+                let ready = match self.mode() {
+                    Mode::ContinuousConversion => {
+                        matches!(self.next, Some((_, ready)) if ready <= timer::now())
+                    }
+                    // Calibrations are done at once
+                    Mode::InternalOffsetCalibration
+                    | Mode::SystemOffsetCalibration
+                    | Mode::SystemGainCalibration => true,
+                    _ => false,
+                };
+                let channel = self.next.map_or(0, |(index, _)| index);
+                [if ready { channel } else { 0x80 | channel }, 0, 0]
+            }
+            0x04 => {
+                let data = self.take_data();
+                [(data >> 16) as u8, (data >> 8) as u8, data as u8]
+            }
+            _ => self.regs[usize::from(address)],
+        }
+    }
+
+    fn write(&mut self, address: u8, data: [u8; 3]) {
+        match address {
+            // Read-only
+            0x00 | 0x03 | 0x04 | 0x07 => {}
+            0x01 => {
+                self.regs[0x01] = data;
+                self.next = match self.mode() {
+                    Mode::ContinuousConversion => {
+                        let now = timer::now();
+                        self.after(CHANNELS - 1)
+                            .map(|(index, period)| (index, now + period))
+                    }
+                    _ => None,
+                };
+            }
+            _ => self.regs[usize::from(address)] = data,
+        }
+    }
+
+    /// The enabled channel after `index`, and the time in milliseconds
+    /// its conversion takes
+    fn after(&self, index: u8) -> Option<(u8, u32)> {
+        (1..=CHANNELS)
+            .map(|step| (index + step) % CHANNELS)
+            .find(|&index| self.reg(&regs::Channel { index }).enabled())
+            .map(|index| (index, self.period(index)))
+    }
+
+    fn period(&self, index: u8) -> u32 {
+        let setup = self.reg(&regs::Channel { index }).setup();
+        let filter = self.reg(&regs::FiltCon { index: setup });
+        let postfilter = if filter.enh_filt_en() {
+            filter.enh_filt().output_rate()
+        } else {
+            None
+        };
+        let rate =
+            postfilter.unwrap_or_else(|| DataRate::from(filter.odr()).output_rate(filter.order()));
+        (1000.0 / rate).max(1.0) as u32
+    }
+
+    /// Data of the conversion that is ready, starting on the next one.
+    /// Conversions missed by a slow reader are lost, as the AD7172
+    /// overwrites its data register.
+    fn take_data(&mut self) -> u32 {
+        let now = timer::now();
+        let (index, ready) = match self.next {
+            Some(next) => next,
+            None => return 0,
+        };
+        let data = self.conversion(index);
+        self.next = self
+            .after(index)
+            .map(|(next, period)| (next, ready.max(now.saturating_sub(period)) + period));
+        data
+    }
+
+    /// Voltage between the inputs of channel `index`
+    fn input(&self, index: u8) -> f64 {
+        let mut plant = self.plant.borrow_mut();
+        plant.advance(timer::now_u64());
+        let sensor = |load: usize| {
+            let r = plant.loads[load].sensor_resistance();
+            let r_inner = R_INNER.get::<ohm>();
+            VREF_SENS.get::<volt>() * r / (r_inner + r)
+        };
+        match self.reg(&regs::Channel { index }).a_in_pos() {
+            Input::Ain2 => sensor(0),
+            Input::Ain0 => sensor(1),
+            Input::AnalogSupplyPos => AVDD1 / AVDD1_DIVIDER,
+            _ => 0.0,
+        }
+    }
+
+    /// Data of a conversion on channel `index`, inverting
+    /// `ChannelCalibration::convert_data()` with the offset and gain
+    /// of its setup
+    fn conversion(&self, index: u8) -> u32 {
+        let setup = self.reg(&regs::Channel { index }).setup();
+        let offset = self.reg(&regs::Offset { index: setup }).offset();
+        let gain = self.reg(&regs::Gain { index: setup }).gain();
+        let bipolar = self.reg(&regs::SetupCon { index: setup }).bipolar();
+
+        let data = self.input(index) * 0.75 / 3.3 * f64::from(1u32 << 24);
+        let data = data - f64::from(offset as i32 - 0x80_0000);
+        let data = data * f64::from(gain) / f64::from(0x40_0000);
+        let data = if bipolar {
+            data + f64::from(0x80_0000)
+        } else {
+            data * 2.0
+        };
+        data.round().clamp(0.0, f64::from(MAX_VALUE)) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ad7172::{Adc, DigitalFilterOrder},
+        pins::Pins,
+        sim::plant::Load,
+    };
+
+    #[test]
+    fn test_ad7172() {
+        let plant = Rc::new(RefCell::new(Plant::new(timer::now_u64())));
+        let (pins, ..) = Pins::setup(plant);
+        let mut adc = match Adc::new(pins.adc_spi, pins.adc_nss) {
+            Ok(adc) => adc,
+            Err(_) => panic!("no AD7172 found"),
+        };
+        assert_eq!(adc.identify().unwrap(), ID);
+
+        adc.setup_channel(0, Input::Ain2, Input::Ain3).unwrap();
+        adc.setup_channel(1, Input::Ain0, Input::Ain1).unwrap();
+        adc.setup_channel(2, Input::AnalogSupplyPos, Input::AnalogSupplyNeg)
+            .unwrap();
+        adc.set_data_rate(2, DataRate::closest(1000.0, DigitalFilterOrder::Sinc5Sinc1))
+            .unwrap();
+        let calibrations = [0, 1, 2].map(|index| adc.get_calibration(index).unwrap());
+        adc.start_continuous_conversion().unwrap();
+        assert_eq!(adc.checksum_errors(), 0);
+
+        // Both channels at ambient temperature, and the supply which
+        // converts faster but only in its turn
+        let r = Load::new().sensor_resistance();
+        let ambient = VREF_SENS.get::<volt>() * r / (R_INNER.get::<ohm>() + r);
+        let mut order = Vec::new();
+        while order.len() < 6 {
+            if let Some(index) = adc.data_ready().unwrap() {
+                let data = adc.read_data().unwrap();
+                let voltage = calibrations[usize::from(index)]
+                    .convert_data(data)
+                    .get::<volt>();
+                let expected = if index == 2 {
+                    AVDD1 / AVDD1_DIVIDER
+                } else {
+                    ambient
+                };
+                assert!((voltage - expected).abs() < 1e-3);
+                order.push(index);
+            }
+        }
+        assert_eq!(order, [0, 1, 2, 0, 1, 2]);
+    }
+}
@@ -0,0 +1,591 @@
+//! Host simulation, with feature `sim`: the firmware runs as a process
+//! of the host, with the chips behind its pins emulated against a
+//! thermal model of the channels, the `Plant`. It serves the TCP
+//! interface on a local port. There is no USB or Ethernet, and
+//! `reset` ends the process.
+
+pub mod ad7172;
+pub mod plant;
+mod store;
+
+pub use store::FileBackend;
+
+use crate::{
+    alarms, autosave,
+    board::Board,
+    channels::{Channels, ChannelsInterface, CHANNELS},
+    command_handler::{
+        send_clients, send_dump, send_echo, send_eeprom, send_error, send_line, send_self_test,
+        Error, FramedSocket, Handler, QueuedResponse, Response,
+    },
+    command_parser::{Framing, Ipv4Config},
+    config, dac, deadman, event_log,
+    fan_ctrl::FanCtrl,
+    flash_store::{self, FlashStore},
+    hw_rev::HWRev,
+    init_log::init_log,
+    load_channels,
+    pins::{Eeprom, Pins},
+    read_dac_calibrations, read_power_on, scheduler, selftest,
+    server::{SOCKET_COUNT, TCP_BUFFER_SIZE},
+    session::{ClientSummary, Session, SessionInput, LINES_PER_CYCLE},
+    stable_output::{self, StableOutput},
+    timer, DAC_CALIBRATION_KEY, IDLE_POLL_INTERVAL,
+};
+use log::{error, info, warn};
+use plant::Plant;
+use smoltcp::time::Instant;
+use std::{
+    cell::RefCell,
+    env,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process,
+    rc::Rc,
+    sync::atomic::{AtomicI64, Ordering},
+    thread,
+    time::{self, Duration, SystemTime},
+};
+use stm32f4xx_hal::hal::blocking::spi::Transfer;
+
+/// Address served without `--listen`, on a port that needs no
+/// privileges
+const DEFAULT_LISTEN: &str = "127.0.0.1:2323";
+/// Size of the send and receive buffers of a client, as of the TCP
+/// sockets of the firmware
+const BUFFER_SIZE: usize = TCP_BUFFER_SIZE;
+/// Sleep of each cycle of the main loop, standing in for `wfi()`
+const CYCLE: Duration = Duration::from_millis(1);
+
+std::thread_local! {
+    /// Time of the start of the simulation
+    static BOOT: time::Instant = time::Instant::now();
+}
+
+/// Milliseconds from the clock of the host to the wall clock, as set
+/// with `time set`
+static EPOCH_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+fn system_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as i64)
+}
+
+/// The host, as the board of the simulation
+pub struct Host;
+
+impl Board for Host {
+    type PinsAdc = crate::pins::PinsAdc;
+    type SpiError = <crate::pins::AdcSpi as Transfer<u8>>::Error;
+
+    /// Milliseconds since the start of the simulation
+    fn now_u64() -> u64 {
+        BOOT.with(|boot| boot.elapsed().as_millis() as u64)
+    }
+
+    /// Ends the simulation
+    fn reset() -> ! {
+        process::exit(0)
+    }
+
+    /// There is no bootloader to enter, `reset` only ends the
+    /// simulation
+    fn set_dfu_trigger() {
+        warn!("no bootloader in the simulation");
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub fn epoch_millis() -> Option<u64> {
+    u64::try_from(system_millis() + EPOCH_OFFSET.load(Ordering::Relaxed)).ok()
+}
+
+/// Set the wall clock to `epoch` in seconds since the Unix epoch
+pub fn set_epoch(epoch: u32) {
+    EPOCH_OFFSET.store(i64::from(epoch) * 1000 - system_millis(), Ordering::Relaxed);
+}
+
+/// Send buffer of a client, as the TX buffer of a socket
+struct SendBuffer(Vec<u8>);
+
+impl SendBuffer {
+    fn can_send(&self) -> bool {
+        self.0.len() < BUFFER_SIZE
+    }
+}
+
+impl Response for SendBuffer {
+    fn send_capacity(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    fn send_queue(&self) -> usize {
+        self.0.len()
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(BUFFER_SIZE - self.0.len());
+        self.0.extend_from_slice(&data[..len]);
+        len
+    }
+}
+
+/// Connection of a TCP client, in place of a socket of `Server`
+struct Client {
+    stream: TcpStream,
+    remote: SocketAddr,
+    session: Session,
+    /// Received bytes that the session has not taken yet
+    received: Vec<u8>,
+    send_buffer: SendBuffer,
+    closed: bool,
+}
+
+impl Client {
+    fn new(stream: TcpStream, remote: SocketAddr) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let mut session = Session::new();
+        session.connect(timer::now());
+        Ok(Client {
+            stream,
+            remote,
+            session,
+            received: Vec::new(),
+            send_buffer: SendBuffer(Vec::new()),
+            closed: false,
+        })
+    }
+
+    fn receive(&mut self) {
+        let mut buf = [0; BUFFER_SIZE];
+        let len = BUFFER_SIZE - self.received.len();
+        match self.stream.read(&mut buf[..len]) {
+            Ok(0) if len > 0 => self.closed = true,
+            Ok(received) => self.received.extend_from_slice(&buf[..received]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                warn!("receive from {}: {}", self.remote, e);
+                self.closed = true;
+            }
+        }
+    }
+
+    fn send(&mut self) {
+        let data = &mut self.send_buffer.0;
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(sent) => {
+                    data.drain(..sent);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("send to {}: {}", self.remote, e);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn can_send(&self) -> bool {
+        self.send_buffer.can_send()
+    }
+
+    fn summary(&self, index: usize, now: u32) -> ClientSummary {
+        self.session.summary_of(index, self.remote, now)
+    }
+}
+
+/// What the handler works on, as held by the main loop of the firmware
+struct Device {
+    channels: Channels,
+    store: FlashStore,
+    ipv4_config: Ipv4Config,
+    fan_ctrl: FanCtrl,
+    hwrev: HWRev,
+    eeprom: Eeprom,
+}
+
+/// Requests of the commands of a cycle, carried out after all clients
+/// have been served
+#[derive(Default)]
+struct Requests {
+    /// Index of the client to send the list of clients to
+    clients: Option<usize>,
+    kick: Option<usize>,
+    reset: bool,
+}
+
+/// Send the echo of an interactive client, after any queued lines
+fn send_session_echo(send_buffer: &mut SendBuffer, session: &mut Session) {
+    let framing = session.framing();
+    let (echo, send_queue) = session.take_echo_queued();
+    send_echo(
+        &mut FramedSocket::new(&mut QueuedResponse::new(send_buffer, send_queue), framing),
+        echo,
+    );
+}
+
+/// Serve the session of client `index`, as the firmware does a TCP
+/// socket
+fn serve(client: &mut Client, index: usize, device: &mut Device, requests: &mut Requests) {
+    if !client.can_send() {
+        return;
+    }
+    let Client {
+        session,
+        received,
+        send_buffer,
+        closed,
+        ..
+    } = client;
+    if !session.send_queue_mut().flush(&mut *send_buffer) {
+        // Samples and commands wait until the client has taken the
+        // queued lines
+        return;
+    }
+    if session.dump_mut().is_some() {
+        let framing = session.framing();
+        let tag = session.tag();
+        if let Some(dump) = session.dump_mut() {
+            let mut framed = FramedSocket::new(&mut *send_buffer, framing);
+            framed.set_tag(tag);
+            if send_dump(&mut framed, dump, &mut device.channels) {
+                session.set_dump(None);
+            }
+        }
+        return;
+    }
+    if received.is_empty() {
+        return;
+    }
+
+    let (len, mut input) = session.feed(received);
+    received.drain(..len);
+    let mut lines = 1;
+    send_session_echo(send_buffer, session);
+    // Further commands on the same line, and a few further lines
+    // received, are handled in the same cycle
+    loop {
+        // Only a complete line is followed by more
+        let line_read = matches!(input, SessionInput::Command(_) | SessionInput::Error(_));
+        let framing = session.framing();
+        let tag = session.tag();
+        let report_format = session.report_format();
+        let precision = session.precision();
+        let mut queued = QueuedResponse::new(&mut *send_buffer, session.send_queue_mut());
+        let mut framed = FramedSocket::new(&mut queued, framing);
+        framed.set_tag(tag);
+        match input {
+            SessionInput::Nothing => {}
+            SessionInput::Command(command) => {
+                framed.set_report_format(report_format);
+                framed.set_precision(precision);
+                match Handler::handle_command(
+                    command,
+                    &mut framed,
+                    &mut device.channels,
+                    &mut device.store,
+                    &mut device.ipv4_config,
+                    &mut device.fan_ctrl,
+                    device.hwrev,
+                ) {
+                    // Saved for the hardware, but the simulation keeps
+                    // its address
+                    Ok(Handler::NewIPV4(ip)) => device.ipv4_config = ip,
+                    Ok(Handler::NewFraming(framing)) => session.set_framing(framing),
+                    Ok(Handler::NewReportFormat(report_format)) => {
+                        session.set_report_format(report_format)
+                    }
+                    Ok(Handler::NewPrecision(precision)) => session.set_precision(precision),
+                    Ok(Handler::Interactive(enabled)) => session.set_interactive(enabled),
+                    Ok(Handler::NewDump(dump)) => session.set_dump(Some(dump)),
+                    Ok(Handler::SelfTest) => {
+                        let selftest = selftest::run(
+                            &mut device.channels,
+                            &mut device.eeprom,
+                            &mut device.fan_ctrl,
+                        );
+                        send_self_test(&mut framed, &selftest);
+                    }
+                    Ok(Handler::Clients) => requests.clients = Some(index),
+                    Ok(Handler::Kick(index)) => requests.kick = Some(index),
+                    Ok(Handler::ReceiveConfig) => session.receive_document(),
+                    Ok(Handler::Eeprom(access)) => {
+                        send_eeprom(&mut framed, &mut device.eeprom, &access)
+                    }
+                    Ok(Handler::Handled) => {}
+                    Ok(Handler::CloseSocket) => *closed = true,
+                    Ok(Handler::Reset) => requests.reset = true,
+                    Err(_) => {}
+                }
+            }
+            SessionInput::Error(e) => {
+                error!("session input: {:?}", e);
+                send_error(&mut framed, Error::Parse, format_args!("{}", e), None);
+            }
+        }
+        match session.next_command() {
+            Some(next) => input = next,
+            None if line_read
+                && lines < LINES_PER_CYCLE
+                && send_buffer.can_send()
+                && !received.is_empty()
+                && session.reads_lines() =>
+            {
+                lines += 1;
+                send_session_echo(send_buffer, session);
+                let (len, next) = session.feed(received);
+                received.drain(..len);
+                input = next;
+            }
+            None => break,
+        }
+    }
+    send_session_echo(send_buffer, session);
+}
+
+fn usage() -> ! {
+    eprintln!("usage: thermostat [--listen <address:port>] [--store <file>]");
+    process::exit(2)
+}
+
+/// Initialization and main loop of the simulation
+pub fn run() {
+    init_log();
+    info!("thermostat simulation");
+
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut store_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(address)) => listen = address,
+            ("--store", Some(path)) => store_path = Some(PathBuf::from(path)),
+            _ => usage(),
+        }
+    }
+
+    let plant = Rc::new(RefCell::new(Plant::new(timer::now_u64())));
+    let (pins, eeprom, hwrev, hw_settings, stable_pin) = Pins::setup(plant);
+
+    let mut store = flash_store::checked(FlashStore::new(FileBackend::new(store_path)));
+    let watchdog_reset = event_log::load(&mut store);
+    if watchdog_reset {
+        alarms::watchdog_reset();
+    }
+    autosave::load(&mut store);
+    deadman::load(&mut store);
+    stable_output::load(&mut store);
+    alarms::load(&mut store);
+
+    let power_on = read_power_on(&store);
+    let vref_meas = read_dac_calibrations(&store);
+    // Before the channel configs, for the safe start flag
+    let mut fan_ctrl = FanCtrl::new(None, hw_settings);
+    match config::read_device_config(&store) {
+        Ok(Some(config)) => config.apply(&mut fan_ctrl),
+        Ok(None) => {}
+        Err(e) => error!("unable to load device config from flash: {:?}", e),
+    }
+    let outputs_off = config::outputs_off_at_boot(power_on, config::safe_start(), watchdog_reset);
+
+    let dac_chip = dac::load(&store);
+    let mut channels = Channels::new(pins, dac_chip, vref_meas);
+    load_channels(&store, &mut channels, outputs_off);
+
+    let mut stable_output = StableOutput::new(stable_pin);
+
+    // default net config:
+    let mut ipv4_config = Ipv4Config {
+        address: [192, 168, 1, 26],
+        mask_len: 24,
+        gateway: None,
+    };
+    match store.read_value("ipv4") {
+        Ok(Some(config)) => ipv4_config = config,
+        Ok(None) => {}
+        Err(e) => error!("cannot read ipv4 config: {:?}", e),
+    }
+
+    let mut device = Device {
+        channels,
+        store,
+        ipv4_config,
+        fan_ctrl,
+        hwrev,
+        eeprom,
+    };
+
+    let listener = TcpListener::bind(&listen)
+        .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+        .unwrap_or_else(|e| {
+            error!("cannot listen on {}: {}", listen, e);
+            process::exit(1)
+        });
+    info!("listening on {}", listen);
+
+    let mut clients: Vec<Client> = Vec::new();
+    let mut idle = false;
+    let mut last_idle_poll = 0;
+    loop {
+        let mut requests = Requests::default();
+        let now_u64 = timer::now_u64();
+        let now = now_u64 as u32;
+        if !idle || now.wrapping_sub(last_idle_poll) >= IDLE_POLL_INTERVAL {
+            last_idle_poll = now;
+            device
+                .channels
+                .poll_adc(Instant::from_millis(now_u64 as i64));
+            device.channels.poll_pins_adc();
+        }
+        if !idle {
+            device
+                .channels
+                .check_adc_silence(Instant::from_millis(now_u64 as i64));
+        }
+
+        for alarm in alarms::check(&mut device.channels, &device.fan_ctrl) {
+            match alarm.json() {
+                Ok(alarm) => {
+                    for client in clients.iter_mut().filter(|client| client.can_send()) {
+                        let framing = client.session.framing();
+                        let mut queued = QueuedResponse::new(
+                            &mut client.send_buffer,
+                            client.session.send_queue_mut(),
+                        );
+                        send_line(&mut FramedSocket::new(&mut queued, framing), &alarm);
+                    }
+                }
+                Err(e) => error!("unable to serialize alarm: {:?}", e),
+            }
+        }
+
+        if deadman::check() {
+            // Nor may a scheduled command power the outputs up again
+            scheduler::clear(None);
+            // Only an output that is on is worth a flash write for the
+            // event
+            if device.channels.any_powered() || device.channels.pid_engaged() {
+                warn!("no commands within the deadman timeout, shutting down");
+                for c in 0..CHANNELS {
+                    device.channels.shut_down(c);
+                }
+                event_log::record(event_log::Event::new(event_log::EventKind::Deadman, None));
+            }
+        }
+        stable_output.update(&mut device.channels);
+
+        if idle {
+            device.fan_ctrl.idle();
+        } else {
+            let abs_max_tec_i = device.channels.current_abs_max_tec_i();
+            let mcu_temperature = device.channels.get_mcu_temperature();
+            device.fan_ctrl.cycle(abs_max_tec_i, mcu_temperature);
+        }
+
+        for command in scheduler::take_due() {
+            let mut discard = scheduler::Discard;
+            let mut framed = FramedSocket::new(&mut discard, Framing::Newline);
+            match Handler::run_command(
+                command,
+                &mut framed,
+                &mut device.channels,
+                &mut device.store,
+                &mut device.ipv4_config,
+                &mut device.fan_ctrl,
+                device.hwrev,
+            ) {
+                Ok(Handler::NewIPV4(ip)) => device.ipv4_config = ip,
+                Ok(Handler::Reset) => requests.reset = true,
+                Ok(_) => {}
+                Err(e) => warn!("scheduled command: {:?}", e),
+            }
+        }
+
+        // As many clients as the firmware has sockets
+        while clients.len() < SOCKET_COUNT {
+            match listener.accept() {
+                Ok((stream, remote)) => match Client::new(stream, remote) {
+                    Ok(client) => {
+                        info!("client {} connected", remote);
+                        clients.push(client);
+                    }
+                    Err(e) => warn!("client {}: {}", remote, e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("accept: {}", e);
+                    break;
+                }
+            }
+        }
+
+        for (index, client) in clients.iter_mut().enumerate() {
+            client.receive();
+            serve(client, index, &mut device, &mut requests);
+        }
+
+        if let Some(index) = requests.clients {
+            let clients_summary: Vec<ClientSummary> = clients
+                .iter()
+                .enumerate()
+                .map(|(index, client)| client.summary(index, now))
+                .collect();
+            if let Some(client) = clients.get_mut(index) {
+                let framing = client.session.framing();
+                let tag = client.session.tag();
+                let mut queued =
+                    QueuedResponse::new(&mut client.send_buffer, client.session.send_queue_mut());
+                let mut framed = FramedSocket::new(&mut queued, framing);
+                framed.set_tag(tag);
+                send_clients(&mut framed, &clients_summary);
+            }
+        }
+
+        if let Some(index) = requests.kick {
+            if let Some(client) = clients.get_mut(index) {
+                info!("kick client {}", index);
+                client.closed = true;
+            }
+        }
+
+        for client in clients.iter_mut() {
+            client.send();
+        }
+        clients.retain(|client| {
+            if client.closed {
+                info!("client {} disconnected", client.remote);
+            }
+            !client.closed
+        });
+
+        idle = !device.channels.any_powered() && clients.is_empty();
+
+        if let Some((channel, vref_meas)) = device.channels.poll_dac_calibration() {
+            match device
+                .store
+                .write_value(DAC_CALIBRATION_KEY[channel], &vref_meas, [0; 16])
+            {
+                Ok(()) => event_log::record(event_log::Event::new(
+                    event_log::EventKind::DacCalibrated,
+                    Some(channel),
+                )),
+                Err(e) => error!("unable to save DAC calibration to flash: {:?}", e),
+            }
+        }
+
+        event_log::flush(&mut device.store);
+        autosave::flush(&mut device.store, &mut device.channels);
+
+        if requests.reset {
+            info!("reset");
+            event_log::flush_now(&mut device.store);
+            Host::reset();
+        }
+
+        thread::sleep(CYCLE);
+    }
+}
@@ -0,0 +1,630 @@
+//! Pins of the simulation, in place of `pins.rs`: the same types for
+//! the core to drive, emulating the chips behind them against the
+//! `Plant`.
+
+use crate::{
+    board::McuAdc,
+    channel::{Channel0, Channel1},
+    hw_rev::{HWRev, HWSettings},
+    sim::{
+        ad7172::Ad7172,
+        plant::{Plant, VREF},
+    },
+    timer,
+};
+use core::{
+    cell::{RefCell, RefMut},
+    convert::Infallible,
+};
+use eeprom24x::{self, Eeprom24x};
+use log::info;
+use std::rc::Rc;
+use stm32_eth::EthPins;
+use stm32f4xx_hal::{
+    adc::config::SampleTime,
+    gpio::{gpioa::*, gpiob::*, gpioc::*, gpiog::*, Floating, Input},
+    hal::{
+        self,
+        blocking::{
+            i2c::{Read, Write, WriteRead},
+            spi::Transfer,
+        },
+        digital::v2::OutputPin,
+    },
+};
+use uom::si::{electric_potential::volt, f64::ElectricPotential};
+
+/// Full scale of the MCU ADC in Volts, which is VDDA, and its largest
+/// code
+const PINS_ADC_V_MAX: f64 = 3.3;
+const PINS_ADC_MAX: u16 = 4095;
+/// Die temperature of the MCU in °C
+const MCU_TEMPERATURE: f64 = 40.0;
+/// Full scale of the i_set DAC in Volts, after its divider
+const DAC_OUT_V_MAX: f64 = 3.0;
+/// Offset of the TEC voltage measurement in Volts
+const TEC_U_MEAS_OFFSET: f64 = 1.5;
+/// Resolution of the PWM timers at 20 kHz from 168 MHz
+const PWM_MAX_DUTY: u16 = 8400;
+/// MAC address in the EUI-48 of the EEPROM, locally administered
+const EUI48: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+/// Address of the EUI-48 in the EEPROM
+const EUI48_ADDRESS: usize = 0xFA;
+/// Size of the EEPROM and the start of its write-protected upper half
+const EEPROM_SIZE: usize = 256;
+const EEPROM_PROTECTED: usize = 0x80;
+
+fn plant_now(plant: &Rc<RefCell<Plant>>) -> RefMut<'_, Plant> {
+    let mut plant = plant.borrow_mut();
+    plant.advance(timer::now_u64());
+    plant
+}
+
+pub type Eeprom = Eeprom24x<EepromI2c, eeprom24x::page_size::B8, eeprom24x::addr_size::OneByte>;
+
+/// Not used by the simulation, which has no Ethernet
+pub type EthernetPins = EthPins<
+    PA1<Input<Floating>>,
+    PA7<Input<Floating>>,
+    PB11<Input<Floating>>,
+    PG13<Input<Floating>>,
+    PB13<Input<Floating>>,
+    PC4<Input<Floating>>,
+    PC5<Input<Floating>>,
+>;
+
+pub trait ChannelPins {
+    type DacSpi: Transfer<u8>;
+    type DacSync: OutputPin;
+    type Shdn: OutputPin;
+    type VRefPin: PinsAdcInput;
+    type ITecPin: PinsAdcInput;
+    type DacFeedbackPin: PinsAdcInput;
+    type TecUMeasPin: PinsAdcInput;
+    type MaxV: hal::PwmPin<Duty = u16>;
+    type MaxIPos: hal::PwmPin<Duty = u16>;
+    type MaxINeg: hal::PwmPin<Duty = u16>;
+}
+
+/// Input of the STM32 ADC
+pub trait PinsAdcInput {
+    /// Convert the input, `None` if it is not connected
+    fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16>;
+
+    fn connected(&self) -> bool {
+        true
+    }
+}
+
+/// VREF of the MAX driver. The simulated ones are connected to the
+/// MCU ADC whatever the hardware revision.
+pub enum VRef<A, D> {
+    Analog(A),
+    Disabled(D),
+}
+
+impl<A: PinsAdcInput, D> PinsAdcInput for VRef<A, D> {
+    fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16> {
+        match self {
+            VRef::Analog(pin) => pin.convert(pins_adc),
+            VRef::Disabled(_) => None,
+        }
+    }
+
+    fn connected(&self) -> bool {
+        matches!(self, VRef::Analog(_))
+    }
+}
+
+impl ChannelPins for Channel0 {
+    type DacSpi = DacSpi;
+    type DacSync = DacSync;
+    type Shdn = Shdn;
+    type VRefPin = VRef<AnalogPin, ()>;
+    type ITecPin = AnalogPin;
+    type DacFeedbackPin = AnalogPin;
+    type TecUMeasPin = AnalogPin;
+    type MaxV = Pwm;
+    type MaxIPos = Pwm;
+    type MaxINeg = Pwm;
+}
+
+impl ChannelPins for Channel1 {
+    type DacSpi = DacSpi;
+    type DacSync = DacSync;
+    type Shdn = Shdn;
+    type VRefPin = VRef<AnalogPin, ()>;
+    type ITecPin = AnalogPin;
+    type DacFeedbackPin = AnalogPin;
+    type TecUMeasPin = AnalogPin;
+    type MaxV = Pwm;
+    type MaxIPos = Pwm;
+    type MaxINeg = Pwm;
+}
+
+/// Signal of a channel sampled by the MCU ADC
+#[derive(Clone, Copy)]
+enum Signal {
+    VRef,
+    DacFeedback,
+    /// Through the 0.4 Ω of the ITEC output, around VREF
+    ITec,
+    TecUMeas,
+}
+
+/// Analog input of the MCU ADC from a channel
+pub struct AnalogPin {
+    channel: usize,
+    signal: Signal,
+    plant: Rc<RefCell<Plant>>,
+}
+
+/// Input of `PinsAdc`, with its voltage in Volts
+pub trait AdcInput {
+    fn voltage(&self) -> f64;
+}
+
+impl AdcInput for AnalogPin {
+    fn voltage(&self) -> f64 {
+        let plant = plant_now(&self.plant);
+        let driver = &plant.drivers[self.channel];
+        let load = &plant.loads[self.channel];
+        let current = driver.current(load);
+        match self.signal {
+            Signal::VRef => VREF,
+            Signal::DacFeedback => driver.i_set,
+            Signal::ITec => VREF + 0.4 * current,
+            Signal::TecUMeas => TEC_U_MEAS_OFFSET + load.tec_voltage(current) / 4.0,
+        }
+    }
+}
+
+impl PinsAdcInput for AnalogPin {
+    fn convert(&self, pins_adc: &mut PinsAdc) -> Option<u16> {
+        Some(pins_adc.convert(self, SampleTime::Cycles_480))
+    }
+}
+
+fn sample(voltage: f64) -> u16 {
+    let sample = voltage / PINS_ADC_V_MAX * f64::from(PINS_ADC_MAX);
+    sample.round().clamp(0.0, f64::from(PINS_ADC_MAX)) as u16
+}
+
+/// MCU ADC, at VDDA = 3.3 V
+pub struct PinsAdc;
+
+impl PinsAdc {
+    pub fn convert<P: AdcInput>(&mut self, pin: &P, _sample_time: SampleTime) -> u16 {
+        sample(pin.voltage())
+    }
+}
+
+/// The internal reference and temperature sensor read as the
+/// simulated values, without their conversion
+impl McuAdc for PinsAdc {
+    fn enable_internal(&mut self) {}
+
+    fn millivolts(&self, sample: u16) -> u16 {
+        (u32::from(sample) * 3300 / u32::from(PINS_ADC_MAX)) as u16
+    }
+
+    fn vdda(&mut self, _count: u32) -> ElectricPotential {
+        ElectricPotential::new::<volt>(PINS_ADC_V_MAX)
+    }
+
+    fn temperature(&mut self, _count: u32) -> f64 {
+        MCU_TEMPERATURE
+    }
+}
+
+/// AD5680 setting the i_set of a channel
+pub struct DacSpi {
+    channel: usize,
+    plant: Rc<RefCell<Plant>>,
+}
+
+impl Transfer<u8> for DacSpi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        if let [b0, b1, b2] = *words {
+            let value = ((u32::from(b0) << 14) | (u32::from(b1) << 6) | (u32::from(b2) >> 2))
+                & crate::ad5680::MAX_VALUE;
+            plant_now(&self.plant).drivers[self.channel].i_set =
+                f64::from(value) / f64::from(crate::ad5680::MAX_VALUE) * DAC_OUT_V_MAX;
+        }
+        Ok(words)
+    }
+}
+
+/// SYNC of an AD5680, whose frames `DacSpi` takes whole
+pub struct DacSync;
+
+impl OutputPin for DacSync {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// SHDN of a MAX1968, powering it when high
+pub struct Shdn {
+    channel: usize,
+    plant: Rc<RefCell<Plant>>,
+}
+
+impl OutputPin for Shdn {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        plant_now(&self.plant).drivers[self.channel].powered = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        plant_now(&self.plant).drivers[self.channel].powered = true;
+        Ok(())
+    }
+}
+
+/// SPI peripheral used for communication with the ADC
+pub struct AdcSpi(Rc<RefCell<Ad7172>>);
+
+impl Transfer<u8> for AdcSpi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut adc = self.0.borrow_mut();
+        for word in words.iter_mut() {
+            *word = adc.exchange(*word);
+        }
+        Ok(words)
+    }
+}
+
+pub struct AdcNss(Rc<RefCell<Ad7172>>);
+
+impl OutputPin for AdcNss {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().select();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().deselect();
+        Ok(())
+    }
+}
+
+/// Limit of a MAX1968 set by a PWM
+#[derive(Clone, Copy)]
+enum Limit {
+    MaxV,
+    MaxIPos,
+    MaxINeg,
+}
+
+pub struct Pwm {
+    channel: usize,
+    limit: Limit,
+    duty: u16,
+    enabled: bool,
+    plant: Rc<RefCell<Plant>>,
+}
+
+impl Pwm {
+    fn new(channel: usize, limit: Limit, plant: Rc<RefCell<Plant>>) -> Self {
+        Pwm {
+            channel,
+            limit,
+            duty: 0,
+            enabled: false,
+            plant,
+        }
+    }
+
+    fn apply(&mut self) {
+        let duty = if self.enabled {
+            f64::from(self.duty) / f64::from(PWM_MAX_DUTY)
+        } else {
+            0.0
+        };
+        let mut plant = plant_now(&self.plant);
+        let driver = &mut plant.drivers[self.channel];
+        match self.limit {
+            Limit::MaxV => driver.max_v = duty,
+            Limit::MaxIPos => driver.max_i_pos = duty,
+            Limit::MaxINeg => driver.max_i_neg = duty,
+        }
+    }
+}
+
+impl hal::PwmPin for Pwm {
+    type Duty = u16;
+
+    fn disable(&mut self) {
+        self.enabled = false;
+        self.apply();
+    }
+
+    fn enable(&mut self) {
+        self.enabled = true;
+        self.apply();
+    }
+
+    fn get_duty(&self) -> u16 {
+        self.duty
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        PWM_MAX_DUTY
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        self.duty = duty.min(PWM_MAX_DUTY);
+        self.apply();
+    }
+}
+
+struct PwmPins {
+    max_v0: Pwm,
+    max_v1: Pwm,
+    max_i_pos0: Pwm,
+    max_i_pos1: Pwm,
+    max_i_neg0: Pwm,
+    max_i_neg1: Pwm,
+}
+
+impl PwmPins {
+    fn setup(plant: &Rc<RefCell<Plant>>) -> PwmPins {
+        fn init_pwm_pin(channel: usize, limit: Limit, plant: &Rc<RefCell<Plant>>) -> Pwm {
+            let mut pin = Pwm::new(channel, limit, plant.clone());
+            hal::PwmPin::set_duty(&mut pin, 0);
+            hal::PwmPin::enable(&mut pin);
+            pin
+        }
+
+        PwmPins {
+            max_v0: init_pwm_pin(0, Limit::MaxV, plant),
+            max_v1: init_pwm_pin(1, Limit::MaxV, plant),
+            max_i_pos0: init_pwm_pin(0, Limit::MaxIPos, plant),
+            max_i_pos1: init_pwm_pin(1, Limit::MaxIPos, plant),
+            max_i_neg0: init_pwm_pin(0, Limit::MaxINeg, plant),
+            max_i_neg1: init_pwm_pin(1, Limit::MaxINeg, plant),
+        }
+    }
+}
+
+pub struct ChannelPinSet<C: ChannelPins> {
+    pub dac_spi: C::DacSpi,
+    pub dac_sync: C::DacSync,
+    pub shdn: C::Shdn,
+    pub vref_pin: C::VRefPin,
+    pub itec_pin: C::ITecPin,
+    pub dac_feedback_pin: C::DacFeedbackPin,
+    pub tec_u_meas_pin: C::TecUMeasPin,
+    pub max_v: C::MaxV,
+    pub max_i_pos: C::MaxIPos,
+    pub max_i_neg: C::MaxINeg,
+}
+
+/// Output of `stable_output`, logged as it changes
+pub struct StablePin;
+
+impl StablePin {
+    pub fn set_high(&mut self) {
+        info!("stable output high");
+    }
+
+    pub fn set_low(&mut self) {
+        info!("stable output low");
+    }
+}
+
+/// Level of a hardware revision pin
+pub struct Level(bool);
+
+impl Level {
+    pub fn is_high(&self) -> bool {
+        self.0
+    }
+}
+
+pub struct HWRevPins {
+    pub hwrev0: Level,
+    pub hwrev1: Level,
+    pub hwrev2: Level,
+    pub hwrev3: Level,
+}
+
+/// 24AA02E48 on the I2C bus: 256 bytes whose upper half, with the
+/// EUI-48, is write-protected
+pub struct EepromI2c {
+    data: [u8; EEPROM_SIZE],
+    /// Address of the next byte to read
+    address: usize,
+}
+
+impl EepromI2c {
+    fn new() -> Self {
+        let mut data = [0xFF; EEPROM_SIZE];
+        data[EUI48_ADDRESS..EUI48_ADDRESS + EUI48.len()].copy_from_slice(&EUI48);
+        EepromI2c { data, address: 0 }
+    }
+}
+
+impl Write for EepromI2c {
+    type Error = Infallible;
+
+    fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Infallible> {
+        if let Some((&address, data)) = bytes.split_first() {
+            self.address = usize::from(address);
+            for &byte in data {
+                if self.address < EEPROM_PROTECTED {
+                    self.data[self.address] = byte;
+                }
+                // Within a page of 8 bytes
+                self.address = (self.address & !7) | ((self.address + 1) & 7);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for EepromI2c {
+    type Error = Infallible;
+
+    fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), Infallible> {
+        for byte in buffer.iter_mut() {
+            *byte = self.data[self.address];
+            self.address = (self.address + 1) % EEPROM_SIZE;
+        }
+        Ok(())
+    }
+}
+
+impl WriteRead for EepromI2c {
+    type Error = Infallible;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Infallible> {
+        self.write(address, bytes)?;
+        self.read(address, buffer)
+    }
+}
+
+pub struct Pins {
+    pub adc_spi: AdcSpi,
+    pub adc_nss: AdcNss,
+    pub pins_adc: PinsAdc,
+    pub channel0: ChannelPinSet<Channel0>,
+    pub channel1: ChannelPinSet<Channel1>,
+}
+
+impl Pins {
+    /// Connect the emulated chips to `plant`
+    pub fn setup(plant: Rc<RefCell<Plant>>) -> (Self, Eeprom, HWRev, HWSettings, StablePin) {
+        let ad7172 = Rc::new(RefCell::new(Ad7172::new(plant.clone())));
+        let adc_spi = AdcSpi(ad7172.clone());
+        let adc_nss = AdcNss(ad7172);
+
+        let PwmPins {
+            max_v0,
+            max_v1,
+            max_i_pos0,
+            max_i_pos1,
+            max_i_neg0,
+            max_i_neg1,
+        } = PwmPins::setup(&plant);
+
+        // All low, of no known revision
+        let hwrev = HWRev::detect_hw_rev(&HWRevPins {
+            hwrev0: Level(false),
+            hwrev1: Level(false),
+            hwrev2: Level(false),
+            hwrev3: Level(false),
+        });
+        let hw_settings = hwrev.settings();
+
+        let analog = |channel, signal| AnalogPin {
+            channel,
+            signal,
+            plant: plant.clone(),
+        };
+        let mut shdn0 = Shdn {
+            channel: 0,
+            plant: plant.clone(),
+        };
+        let _ = shdn0.set_low();
+        let channel0 = ChannelPinSet {
+            dac_spi: DacSpi {
+                channel: 0,
+                plant: plant.clone(),
+            },
+            dac_sync: DacSync,
+            shdn: shdn0,
+            vref_pin: VRef::Analog(analog(0, Signal::VRef)),
+            itec_pin: analog(0, Signal::ITec),
+            dac_feedback_pin: analog(0, Signal::DacFeedback),
+            tec_u_meas_pin: analog(0, Signal::TecUMeas),
+            max_v: max_v0,
+            max_i_pos: max_i_pos0,
+            max_i_neg: max_i_neg0,
+        };
+
+        let mut shdn1 = Shdn {
+            channel: 1,
+            plant: plant.clone(),
+        };
+        let _ = shdn1.set_low();
+        let channel1 = ChannelPinSet {
+            dac_spi: DacSpi {
+                channel: 1,
+                plant: plant.clone(),
+            },
+            dac_sync: DacSync,
+            shdn: shdn1,
+            vref_pin: VRef::Analog(analog(1, Signal::VRef)),
+            itec_pin: analog(1, Signal::ITec),
+            dac_feedback_pin: analog(1, Signal::DacFeedback),
+            tec_u_meas_pin: analog(1, Signal::TecUMeas),
+            max_v: max_v1,
+            max_i_pos: max_i_pos1,
+            max_i_neg: max_i_neg1,
+        };
+
+        let pins = Pins {
+            adc_spi,
+            adc_nss,
+            pins_adc: PinsAdc,
+            channel0,
+            channel1,
+        };
+
+        let eeprom = Eeprom24x::new_24x02(EepromI2c::new(), eeprom24x::SlaveAddr::default());
+
+        (pins, eeprom, hwrev, hw_settings, StablePin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_eeprom() {
+        let mut eeprom = Eeprom24x::new_24x02(EepromI2c::new(), eeprom24x::SlaveAddr::default());
+        let mut eui48 = [0; 6];
+        eeprom.read_data(0xFA, &mut eui48).unwrap();
+        assert_eq!(eui48, EUI48);
+
+        eeprom.write_page(0x10, &[1, 2, 3]).unwrap();
+        eeprom.write_page(0xF8, &[0; 8]).unwrap();
+        let mut data = [0; 3];
+        eeprom.read_data(0x10, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        eeprom.read_data(0xFA, &mut eui48).unwrap();
+        assert_eq!(eui48, EUI48);
+    }
+
+    #[test]
+    fn test_pins_adc() {
+        let mut pins_adc = PinsAdc;
+        pins_adc.enable_internal();
+        assert_eq!(pins_adc.vdda(4).get::<volt>(), PINS_ADC_V_MAX);
+        assert_eq!(pins_adc.temperature(4), MCU_TEMPERATURE);
+        let mv = pins_adc.millivolts(sample(1.5));
+        assert!((1499..=1500).contains(&mv));
+    }
+}
@@ -0,0 +1,194 @@
+//! Model of what a Thermostat drives: on each channel a MAX1968 TEC
+//! driver, and a load held by the TEC against a heat sink at ambient
+//! temperature, sensed by a 10 kΩ NTC thermistor with the default
+//! B-parameter config.
+
+use crate::channels::CHANNELS;
+
+/// Temperature of the heat sink and the air around the loads in °C
+pub const AMBIENT: f64 = 25.0;
+/// Seebeck coefficient of the TEC in V/K
+const SEEBECK: f64 = 0.05;
+/// Electrical resistance of the TEC in Ω
+pub const TEC_R: f64 = 2.0;
+/// Thermal conductance of the TEC from the load to the heat sink in
+/// W/K
+const TEC_K: f64 = 0.25;
+/// Thermal conductance from the load to the air in W/K
+const LOSS_K: f64 = 0.02;
+/// Heat capacity of the load in J/K
+const HEAT_CAPACITY: f64 = 10.0;
+/// Thermistor resistance in Ω at `NTC_T0` in °C, and its beta in K
+const NTC_R0: f64 = 10_000.0;
+const NTC_T0: f64 = 25.0;
+const NTC_B: f64 = 3800.0;
+const ZERO_CELSIUS: f64 = 273.15;
+/// Longest step of the integration in milliseconds
+const STEP: u64 = 10;
+
+/// VREF of the MAX1968 in Volts, the i_set of zero current
+pub const VREF: f64 = 1.5;
+/// Current sense resistor of the MAX1968 in Ω
+const R_SENSE: f64 = 0.05;
+/// Limit input of the MAX1968 in Volts at full duty cycle of its PWM
+const PWM_V: f64 = 3.3;
+
+/// MAX1968 TEC driver, as set through the pins of its channel
+#[derive(Clone, Debug, Default)]
+pub struct Driver {
+    /// i_set from the DAC in Volts
+    pub i_set: f64,
+    pub powered: bool,
+    /// Duty cycles of the limit PWMs
+    pub max_v: f64,
+    pub max_i_pos: f64,
+    pub max_i_neg: f64,
+}
+
+impl Driver {
+    /// Current through the TEC of `load` in Amperes, positive cooling
+    /// it, within the current and voltage limits
+    pub fn current(&self, load: &Load) -> f64 {
+        if !self.powered {
+            return 0.0;
+        }
+        let i = (self.i_set - VREF) / (10.0 * R_SENSE);
+        let i = i.clamp(
+            -self.max_i_neg * PWM_V / (10.0 * R_SENSE),
+            self.max_i_pos * PWM_V / (10.0 * R_SENSE),
+        );
+        // The voltage across the TEC includes its Seebeck voltage
+        let max_v = 4.0 * self.max_v * PWM_V;
+        let seebeck = load.seebeck_voltage();
+        i.clamp((-max_v - seebeck) / TEC_R, (max_v - seebeck) / TEC_R)
+    }
+}
+
+/// Load of a channel
+#[derive(Clone, Debug)]
+pub struct Load {
+    /// Temperature in °C
+    pub temperature: f64,
+}
+
+impl Load {
+    pub fn new() -> Self {
+        Load {
+            temperature: AMBIENT,
+        }
+    }
+
+    /// Advance by `dt` seconds with `current` through the TEC
+    fn step(&mut self, current: f64, dt: f64) {
+        let peltier = SEEBECK * (self.temperature + ZERO_CELSIUS) * current;
+        let joule = TEC_R * current * current / 2.0;
+        let conduction = (TEC_K + LOSS_K) * (AMBIENT - self.temperature);
+        self.temperature += (joule + conduction - peltier) / HEAT_CAPACITY * dt;
+    }
+
+    /// Voltage of the TEC from the temperature difference across it
+    fn seebeck_voltage(&self) -> f64 {
+        SEEBECK * (AMBIENT - self.temperature)
+    }
+
+    /// Voltage across the TEC at `current`
+    pub fn tec_voltage(&self, current: f64) -> f64 {
+        TEC_R * current + self.seebeck_voltage()
+    }
+
+    /// Resistance of the thermistor in Ω
+    pub fn sensor_resistance(&self) -> f64 {
+        let t = self.temperature + ZERO_CELSIUS;
+        NTC_R0 * (NTC_B * (1.0 / t - 1.0 / (NTC_T0 + ZERO_CELSIUS))).exp()
+    }
+}
+
+/// State of all channels, advanced to the time of each access
+pub struct Plant {
+    pub drivers: [Driver; CHANNELS],
+    pub loads: [Load; CHANNELS],
+    /// Time of the state in milliseconds since boot
+    time: u64,
+}
+
+impl Plant {
+    pub fn new(time: u64) -> Self {
+        Plant {
+            drivers: Default::default(),
+            loads: core::array::from_fn(|_| Load::new()),
+            time,
+        }
+    }
+
+    /// Integrate up to `time` in milliseconds since boot
+    pub fn advance(&mut self, time: u64) {
+        while self.time < time {
+            let dt = (time - self.time).min(STEP);
+            for (driver, load) in self.drivers.iter().zip(self.loads.iter_mut()) {
+                let current = driver.current(load);
+                load.step(current, dt as f64 / 1000.0);
+            }
+            self.time += dt;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn driver(i_set: f64) -> Driver {
+        Driver {
+            i_set,
+            powered: true,
+            max_v: 1.0,
+            max_i_pos: 1.0,
+            max_i_neg: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_current() {
+        let load = Load::new();
+        assert_eq!(driver(VREF + 0.5).current(&load), 1.0);
+        assert_eq!(driver(VREF - 0.25).current(&load), -0.5);
+        let mut off = driver(VREF + 0.5);
+        off.powered = false;
+        assert_eq!(off.current(&load), 0.0);
+        // 1 A limit
+        let mut limited = driver(VREF + 1.0);
+        limited.max_i_pos = 0.5 / PWM_V;
+        assert!((limited.current(&load) - 1.0).abs() < 1e-9);
+        // 1 V across 2 Ω
+        limited.max_i_pos = 1.0;
+        limited.max_v = 0.25 / PWM_V;
+        assert!((limited.current(&load) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_plant() {
+        let mut plant = Plant::new(0);
+        plant.advance(10_000);
+        assert_eq!(plant.loads[0].temperature, AMBIENT);
+        assert!((plant.loads[0].sensor_resistance() - NTC_R0).abs() < 1e-6);
+
+        // Positive current cools, negative heats
+        plant.drivers[0] = driver(VREF + 0.25);
+        plant.drivers[1] = driver(VREF - 0.25);
+        plant.advance(600_000);
+        let [cooled, heated] = &plant.loads;
+        assert!(cooled.temperature < AMBIENT - 10.0);
+        assert!(heated.temperature > AMBIENT + 10.0);
+        assert!(cooled.sensor_resistance() > NTC_R0);
+        assert!(heated.sensor_resistance() < NTC_R0);
+        // The Seebeck voltage adds to the drive of the cooled load
+        assert!(cooled.tec_voltage(0.5) > TEC_R * 0.5);
+
+        // Unpowered, the loads return to ambient
+        plant.drivers = Default::default();
+        plant.advance(2_400_000);
+        for load in &plant.loads {
+            assert!((load.temperature - AMBIENT).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Flash store of the simulation, held in memory and written through to
+//! a file, if one is given, so that saved settings survive a restart
+
+use crate::flash_store::FLASH_SECTOR_SIZE;
+use log::error;
+use sfkv::StoreBackend;
+use std::{fs, io, path::PathBuf};
+use stm32f4xx_hal::flash::Error;
+
+/// Size of the store, that of one flash sector on the hardware
+const SIZE: usize = FLASH_SECTOR_SIZE;
+/// Content of erased flash
+const ERASED: u8 = 0xFF;
+static mut BACKUP_SPACE: [u8; SIZE] = [0; SIZE];
+
+pub struct FileBackend {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+}
+
+impl FileBackend {
+    /// Read the store from `path`, erased if there is no such file
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut data = vec![ERASED; SIZE];
+        if let Some(path) = &path {
+            match fs::read(path) {
+                Ok(contents) => {
+                    let len = contents.len().min(SIZE);
+                    data[..len].copy_from_slice(&contents[..len]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!("cannot read store {}: {}", path.display(), e),
+            }
+        }
+        FileBackend { data, path }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::write(path, &self.data) {
+                error!("cannot write store {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl StoreBackend for FileBackend {
+    type Data = [u8];
+
+    fn data(&self) -> &Self::Data {
+        &self.data
+    }
+
+    type Error = Error;
+
+    fn erase(&mut self) -> Result<(), Self::Error> {
+        self.data.fill(ERASED);
+        self.save();
+        Ok(())
+    }
+
+    /// Programming clears bits, as on flash
+    fn program(&mut self, offset: usize, payload: &[u8]) -> Result<(), Self::Error> {
+        for (byte, &value) in self.data[offset..(offset + payload.len())]
+            .iter_mut()
+            .zip(payload)
+        {
+            *byte &= value;
+        }
+        self.save();
+        Ok(())
+    }
+
+    fn backup_space(&self) -> &'static mut [u8] {
+        unsafe { &mut BACKUP_SPACE[..] }
+    }
+}
@@ -0,0 +1,119 @@
+//! Digital output driven high while the selected channels are stable,
+//! so that hardware such as shutters or DAQ triggers can gate on
+//! thermal lock without polling.
+//!
+//! The channels are selected with `stable output <0/1/all/off>`, and
+//! their stability criterion is the window and time of `stable`.
+
+use crate::{
+    channels::{Channels, CHANNELS},
+    command_handler::JsonBuffer,
+    flash_store::{FlashInterface, FlashStore, StoreError},
+    interrupt::{self, Mutex},
+    pins::StablePin,
+};
+use core::cell::RefCell;
+use log::error;
+use serde::Serialize;
+
+const FLASH_KEY: &str = "stable_output";
+
+struct State {
+    /// Channels that must be stable for the output to be high
+    selected: [bool; CHANNELS],
+    /// Last level driven
+    level: bool,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    selected: [false; CHANNELS],
+    level: false,
+}));
+
+/// High when at least one channel is selected, and all selected
+/// channels are stable
+fn level(selected: [bool; CHANNELS], stable: [bool; CHANNELS]) -> bool {
+    selected.contains(&true)
+        && selected
+            .iter()
+            .zip(stable.iter())
+            .all(|(&selected, &stable)| !selected || stable)
+}
+
+/// Read the selection from flash at boot
+pub fn load(store: &mut FlashStore) {
+    match store.read_value::<[bool; CHANNELS]>(FLASH_KEY) {
+        Ok(Some(selected)) => {
+            interrupt::free(|cs| STATE.borrow(cs).borrow_mut().selected = selected)
+        }
+        Ok(None) => {}
+        Err(e) => error!("cannot read stable output: {:?}", e),
+    }
+}
+
+/// Select the channels, none to keep the output low, and save the
+/// selection to flash
+pub fn set(store: &mut impl FlashInterface, selected: [bool; CHANNELS]) -> Result<(), StoreError> {
+    store.write_value(FLASH_KEY, &selected, [0; 16])?;
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().selected = selected);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StableOutputSummary {
+    channels: [bool; CHANNELS],
+    level: bool,
+}
+
+pub fn summary_json() -> Result<JsonBuffer, serde_json_core::ser::Error> {
+    let summary = interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+        StableOutputSummary {
+            channels: state.selected,
+            level: state.level,
+        }
+    });
+    serde_json_core::to_vec(&summary)
+}
+
+pub struct StableOutput {
+    pin: StablePin,
+}
+
+impl StableOutput {
+    pub fn new(mut pin: StablePin) -> Self {
+        pin.set_low();
+        StableOutput { pin }
+    }
+
+    /// Drive the pin from the stability of the selected channels
+    pub fn update(&mut self, channels: &mut Channels) {
+        let stable = core::array::from_fn(|channel| channels.channel_state(channel).is_stable());
+        let changed = interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            let level = level(state.selected, stable);
+            let changed = level != state.level;
+            state.level = level;
+            changed.then_some(level)
+        });
+        match changed {
+            Some(true) => self.pin.set_high(),
+            Some(false) => self.pin.set_low(),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert!(!level([false, false], [true, true]));
+        assert!(level([true, false], [true, false]));
+        assert!(!level([false, true], [true, false]));
+        assert!(!level([true, true], [true, false]));
+        assert!(level([true, true], [true, true]));
+    }
+}
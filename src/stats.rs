@@ -0,0 +1,73 @@
+use num_traits::float::Float;
+use serde::Serialize;
+
+/// Running statistics of a series of values
+///
+/// Uses Welford's algorithm for a numerically stable variance.
+#[derive(Clone, Default)]
+pub struct Statistics {
+    count: u32,
+    min: f64,
+    max: f64,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+}
+
+impl Statistics {
+    pub fn update(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn summary(&self) -> Summary {
+        if self.count == 0 {
+            return Summary::default();
+        }
+        Summary {
+            count: self.count,
+            min: Some(self.min),
+            max: Some(self.max),
+            mean: Some(self.mean),
+            std: Some((self.m2 / self.count as f64).sqrt()),
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct Summary {
+    count: u32,
+    min: Option<f64>,
+    max: Option<f64>,
+    mean: Option<f64>,
+    /// Population standard deviation
+    std: Option<f64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statistics() {
+        let mut stats = Statistics::default();
+        for value in &[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+            stats.update(*value);
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.min, Some(2.0));
+        assert_eq!(summary.max, Some(9.0));
+        assert_eq!(summary.mean, Some(5.0));
+        assert_eq!(summary.std, Some(2.0));
+    }
+}
@@ -0,0 +1,49 @@
+use num_traits::float::Float;
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    electrical_resistance::ohm,
+    f64::{ElectricalResistance, ThermodynamicTemperature},
+    thermodynamic_temperature::kelvin,
+};
+
+/// Steinhart-Hart equation parameters
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Parameters {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl Parameters {
+    /// Perform the resistance to temperature conversion:
+    /// 1/T = A + B ln R + C (ln R)^3
+    pub fn get_temperature(&self, r: ElectricalResistance) -> ThermodynamicTemperature {
+        let ln_r = r.get::<ohm>().ln();
+        let inv_temp = self.a + self.b * ln_r + self.c * ln_r.powi(3);
+        ThermodynamicTemperature::new::<kelvin>(inv_temp.recip())
+    }
+}
+
+impl Default for Parameters {
+    /// Coefficients of a common 10 kΩ NTC thermistor
+    fn default() -> Self {
+        Parameters {
+            a: 1.129148e-3,
+            b: 2.34125e-4,
+            c: 8.76741e-8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uom::si::thermodynamic_temperature::degree_celsius;
+
+    #[test]
+    fn test_10k_at_25c() {
+        let sh = Parameters::default();
+        let temperature = sh.get_temperature(ElectricalResistance::new::<ohm>(10_000.0));
+        assert!((temperature.get::<degree_celsius>() - 25.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,92 @@
+//! Open-loop step response of a channel, for estimating the
+//! parameters of a first-order plus dead time model to tune the PID
+//! controller with.
+
+use uom::si::f64::ElectricCurrent;
+
+pub struct StepTest {
+    pub delta_i: ElectricCurrent,
+    /// Duration of the step in milliseconds
+    pub duration: u32,
+    /// Time of the step in milliseconds, once applied
+    pub start: Option<u32>,
+    /// Output settings to restore afterwards
+    pub i_set: ElectricCurrent,
+    pub pid_engaged: bool,
+    pub powered: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepResponse {
+    /// Steady-state temperature change per current step in K/A
+    pub gain: f64,
+    /// Time from 10% to 90% of the temperature change in seconds
+    pub rise_time: f64,
+    /// Dead time of the model in seconds
+    pub dead_time: f64,
+    /// Time constant of the model in seconds
+    pub time_constant: f64,
+}
+
+/// Estimate the step response from temperatures taken `interval`
+/// seconds apart, the first of them before the current step of
+/// `delta_i` Amperes. The temperature change is taken from the last
+/// tenth of the samples, by which it should have settled.
+///
+/// Dead time and time constant use the two-point method of Smith, at
+/// 28.3% and 63.2% of the temperature change.
+pub fn analyze(samples: &[f32], interval: f64, delta_i: f64) -> Option<StepResponse> {
+    if samples.len() < 2 || delta_i == 0.0 {
+        return None;
+    }
+    let t0 = samples[0] as f64;
+    let tail = &samples[samples.len() - (samples.len() / 10).max(1)..];
+    let t_final = tail.iter().map(|&t| t as f64).sum::<f64>() / tail.len() as f64;
+    let delta_t = t_final - t0;
+    if delta_t == 0.0 {
+        return None;
+    }
+    let crossing = |fraction: f64| {
+        samples
+            .iter()
+            .position(|&t| (t as f64 - t0) / delta_t >= fraction)
+            .map(|i| i as f64 * interval)
+    };
+    let t10 = crossing(0.1)?;
+    let t28 = crossing(0.283)?;
+    let t63 = crossing(0.632)?;
+    let t90 = crossing(0.9)?;
+    let time_constant = 1.5 * (t63 - t28);
+    Some(StepResponse {
+        gain: delta_t / delta_i,
+        rise_time: t90 - t10,
+        dead_time: (t63 - time_constant).max(0.0),
+        time_constant,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analyze() {
+        // 2 K/A, 5 s dead time, 20 s time constant
+        let interval = 0.1;
+        let mut samples = [0.0; 2000];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let t = i as f64 * interval - 5.0;
+            let rise = if t > 0.0 {
+                1.0 - (-t / 20.0).exp()
+            } else {
+                0.0
+            };
+            *sample = (25.0 + 2.0 * 0.5 * rise) as f32;
+        }
+        let response = analyze(&samples, interval, 0.5).unwrap();
+        assert!((response.gain - 2.0).abs() < 0.01);
+        assert!((response.rise_time - 20.0 * 9f64.ln()).abs() < 0.5);
+        assert!((response.dead_time - 5.0).abs() < 0.5);
+        assert!((response.time_constant - 20.0).abs() < 0.5);
+    }
+}
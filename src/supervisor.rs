@@ -0,0 +1,90 @@
+//! Supervision of the parts of the main loop by the watchdog.
+//!
+//! Each part checks in with `check_in()` whenever it makes progress,
+//! and the watchdog is fed only while all of them have done so within
+//! their deadlines. An ADC that stops delivering samples, or a network
+//! stack that keeps failing, then resets the device like a hung main
+//! loop does. Once the ADC has latched the `AdcSilent` fault, the main
+//! loop checks in for it, as the fault keeps the outputs off.
+
+use log::error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Task {
+    /// Samples read from the AD7172
+    Adc,
+    /// Polls of the network interface
+    Network,
+}
+
+const TASKS: usize = 2;
+
+impl Task {
+    const ALL: [Task; TASKS] = [Task::Adc, Task::Network];
+
+    /// Longest time between check-ins in milliseconds
+    fn deadline(self) -> u32 {
+        match self {
+            // The slowest data rates take seconds for all ADC channels
+            Task::Adc => 10_000,
+            Task::Network => 5_000,
+        }
+    }
+}
+
+pub struct Supervisor {
+    check_ins: [u32; TASKS],
+    /// A missed deadline has been logged
+    reported: bool,
+}
+
+impl Supervisor {
+    pub fn new(now: u32) -> Self {
+        Supervisor {
+            check_ins: [now; TASKS],
+            reported: false,
+        }
+    }
+
+    pub fn check_in(&mut self, task: Task, now: u32) {
+        self.check_ins[task as usize] = now;
+    }
+
+    /// First task that has missed its deadline
+    pub fn overdue(&self, now: u32) -> Option<Task> {
+        Task::ALL
+            .iter()
+            .copied()
+            .find(|&task| now.wrapping_sub(self.check_ins[task as usize]) > task.deadline())
+    }
+
+    /// Whether the watchdog may be fed
+    pub fn healthy(&mut self, now: u32) -> bool {
+        match self.overdue(now) {
+            None => true,
+            Some(task) => {
+                if !self.reported {
+                    self.reported = true;
+                    error!("{:?} missed its watchdog deadline", task);
+                }
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overdue() {
+        let mut supervisor = Supervisor::new(1_000);
+        supervisor.check_in(Task::Network, 5_000);
+        assert_eq!(supervisor.overdue(6_000), None);
+        // Only the ADC has been quiet for long enough
+        assert_eq!(supervisor.overdue(11_001), Some(Task::Adc));
+        supervisor.check_in(Task::Adc, 11_001);
+        assert_eq!(supervisor.overdue(11_001), Some(Task::Network));
+    }
+}
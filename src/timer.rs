@@ -1,43 +1,33 @@
-use core::cell::RefCell;
-use core::ops::Deref;
-use cortex_m::interrupt::Mutex;
-use cortex_m_rt::exception;
-use stm32f4xx_hal::{
-    rcc::Clocks,
-    stm32::SYST,
-    time::U32Ext,
-    timer::{Event as TimerEvent, Timer},
-};
+//! Time since boot in milliseconds, from the tick of the board
 
-/// Rate in Hz
-const TIMER_RATE: u32 = 500;
-/// Interval duration in milliseconds
-const TIMER_DELTA: u32 = 1000 / TIMER_RATE;
-/// Elapsed time in milliseconds
-static TIMER_MS: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(0));
+use crate::board::{Board, Mcu};
 
-/// Setup SysTick exception
-pub fn setup(syst: SYST, clocks: Clocks) {
-    let timer = Timer::syst(syst, &clocks);
-    let mut countdown = timer.start_count_down(TIMER_RATE.hz());
-    countdown.listen(TimerEvent::TimeOut);
-}
-
-/// SysTick exception (Timer)
-#[exception]
-fn SysTick() {
-    cortex_m::interrupt::free(|cs| {
-        *TIMER_MS.borrow(cs).borrow_mut() += TIMER_DELTA;
-    });
+/// Obtain current time in milliseconds. It wraps around after 49
+/// days, so compare times with `wrapping_sub()`.
+pub fn now() -> u32 {
+    now_u64() as u32
 }
 
-/// Obtain current time in milliseconds
-pub fn now() -> u32 {
-    cortex_m::interrupt::free(|cs| *TIMER_MS.borrow(cs).borrow().deref())
+/// Obtain current time in milliseconds, without wrapping around
+pub fn now_u64() -> u64 {
+    Mcu::now_u64()
 }
 
 /// block for at least `amount` milliseconds
 pub fn sleep(amount: u32) {
     let start = now();
-    while now() - start <= amount {}
+    while now().wrapping_sub(start) <= amount {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sleep() {
+        let start = now_u64();
+        sleep(10);
+        assert!(now_u64() - start > 10);
+        assert_eq!(now() as u64, now_u64() - 1);
+    }
 }
@@ -1,8 +1,12 @@
+use crate::{command_handler::Response, interrupt::free, log_buffer};
 use core::{
     fmt::{self, Write},
     mem::MaybeUninit,
 };
-use cortex_m::interrupt::free;
+use heapless::{
+    consts::{U2048, U256},
+    spsc::Queue,
+};
 use log::{Log, Metadata, Record};
 use stm32f4xx_hal::{
     otg_fs::{UsbBus as Bus, USB},
@@ -10,7 +14,7 @@ use stm32f4xx_hal::{
 };
 use usb_device::{
     class_prelude::UsbBusAllocator,
-    prelude::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    prelude::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
 };
 use usbd_serial::SerialPort;
 
@@ -23,6 +27,14 @@ static mut STATE: Option<State> = None;
 pub struct State {
     serial: SerialPort<'static, Bus<USB>>,
     dev: UsbDevice<'static, Bus<USB>>,
+    /// Received bytes, until read by a session
+    rx: Queue<u8, U256>,
+    /// Bytes to send that do not fit into the buffer of the serial
+    /// port yet, so that responses are not cut short
+    tx: Queue<u8, U2048>,
+    /// Log lines are written to the serial port, which they would
+    /// corrupt with length-prefixed responses
+    log_output: bool,
 }
 
 impl State {
@@ -40,7 +52,13 @@ impl State {
             .build();
 
         free(|_| unsafe {
-            STATE = Some(State { serial, dev });
+            STATE = Some(State {
+                serial,
+                dev,
+                rx: Queue::new(),
+                tx: Queue::new(),
+                log_output: true,
+            });
         });
 
         unsafe {
@@ -55,14 +73,118 @@ impl State {
     pub fn poll() {
         if let Some(ref mut s) = Self::get() {
             if s.dev.poll(&mut [&mut s.serial]) {
-                // discard any input
                 let mut buf = [0u8; 64];
-                let _ = s.serial.read(&mut buf);
+                if let Ok(len) = s.serial.read(&mut buf) {
+                    for &b in &buf[..len] {
+                        // Input beyond a full queue is discarded
+                        let _ = s.rx.enqueue(b);
+                    }
+                }
+            }
+            s.flush_tx();
+        }
+    }
+
+    /// Move queued bytes into the buffer of the serial port
+    fn flush_tx(&mut self) {
+        while !self.tx.is_empty() {
+            let mut buf = [0u8; 64];
+            let mut len = 0;
+            for (b, &queued) in buf.iter_mut().zip(self.tx.iter()) {
+                *b = queued;
+                len += 1;
+            }
+            match self.serial.write(&buf[..len]) {
+                Ok(written) if written > 0 => {
+                    for _ in 0..written {
+                        self.tx.dequeue();
+                    }
+                }
+                _ => break,
             }
         }
     }
 }
 
+/// Pass received bytes to `f`, which returns how many of them it has
+/// consumed, like `TcpSocket::recv()`
+pub fn recv<F: FnOnce(&[u8]) -> (usize, R), R>(f: F) -> Option<R> {
+    let mut buf = [0u8; 64];
+    let len = free(|_| {
+        let state = State::get()?;
+        let mut len = 0;
+        for (b, &received) in buf.iter_mut().zip(state.rx.iter()) {
+            *b = received;
+            len += 1;
+        }
+        Some(len)
+    })?;
+    if len == 0 {
+        return None;
+    }
+    let (consumed, result) = f(&buf[..len]);
+    free(|_| {
+        if let Some(state) = State::get() {
+            for _ in 0..consumed {
+                state.rx.dequeue();
+            }
+        }
+    });
+    Some(result)
+}
+
+/// Whether a host has the serial port open, or bytes are still queued
+/// in either direction
+pub fn active() -> bool {
+    free(|_| match State::get() {
+        Some(state) => {
+            (state.dev.state() == UsbDeviceState::Configured && state.serial.dtr())
+                || !state.rx.is_empty()
+                || !state.tx.is_empty()
+        }
+        None => false,
+    })
+}
+
+/// Bytes that can be queued for sending
+pub fn send_capacity() -> usize {
+    free(|_| State::get().map(|state| state.tx.capacity()).unwrap_or(0))
+}
+
+/// Bytes queued for sending
+pub fn send_queue() -> usize {
+    free(|_| State::get().map(|state| state.tx.len()).unwrap_or(0))
+}
+
+/// Queue as many bytes of `data` as fit, returns how many
+pub fn send_slice(data: &[u8]) -> usize {
+    free(|_| match State::get() {
+        Some(state) => {
+            let sent = data
+                .iter()
+                .take_while(|&&b| state.tx.enqueue(b).is_ok())
+                .count();
+            state.flush_tx();
+            sent
+        }
+        None => 0,
+    })
+}
+
+/// Write log lines to the serial port, or keep them only in the log
+/// buffer
+pub fn set_log_output(enabled: bool) {
+    free(|_| {
+        if let Some(state) = State::get() {
+            state.log_output = enabled;
+        }
+    });
+}
+
+fn log_output() -> bool {
+    free(|_| State::get().map(|state| state.log_output).unwrap_or(false))
+}
+
 #[interrupt]
 fn OTG_FS() {
     free(|_| {
@@ -79,8 +201,16 @@ impl Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let mut output = SerialOutput;
-            let _ = writeln!(&mut output, "{} - {}", record.level(), record.args());
+            let _ = writeln!(
+                &mut log_buffer::Output,
+                "{} - {}",
+                record.level(),
+                record.args()
+            );
+            if log_output() {
+                let mut output = SerialOutput;
+                let _ = writeln!(&mut output, "{} - {}", record.level(), record.args());
+            }
         }
     }
 
@@ -91,10 +221,41 @@ impl Log for Logger {
     }
 }
 
+/// The serial port as the transport of a session
+pub struct SerialResponse;
+
+impl Response for SerialResponse {
+    fn send_capacity(&self) -> usize {
+        send_capacity()
+    }
+
+    fn send_queue(&self) -> usize {
+        send_queue()
+    }
+
+    fn send_slice(&mut self, data: &[u8]) -> usize {
+        send_slice(data)
+    }
+}
+
 pub struct SerialOutput;
 
 impl Write for SerialOutput {
     fn write_str(&mut self, s: &str) -> core::result::Result<(), core::fmt::Error> {
+        // Keep the order with queued responses
+        let queued = free(|_| {
+            State::get().map(|state| {
+                state.flush_tx();
+                !state.tx.is_empty()
+            })
+        });
+        if queued == Some(true) {
+            return if send_slice(s.as_bytes()) == s.len() {
+                Ok(())
+            } else {
+                Err(fmt::Error)
+            };
+        }
         if let Some(ref mut state) = State::get() {
             for chunk in s.as_bytes().chunks(16) {
                 free(|_| state.serial.write(chunk)).map_err(|_| fmt::Error)?;